@@ -52,6 +52,7 @@ abstract class Operator<T> {
 class BaseEntity<T> extends Operator<T> {
     constructor(
         public name: string,
+        public includeDeleted: boolean = false,
     ) {
         super(OpType.BaseEntity, undefined);
     }
@@ -232,7 +233,7 @@ class SortKey<T> {
  */
 class SortBy<T> extends Operator<T> {
     constructor(
-        private keys: SortKey<T>[],
+        public readonly keys: SortKey<T>[],
         inner: Operator<T>,
     ) {
         super(OpType.SortBy, inner);
@@ -262,7 +263,12 @@ class SortBy<T> extends Operator<T> {
                                 return l < r ? 1 : -1;
                             }
                         }
-                        return 0;
+                        // Like the database, break ties by id.
+                        const [l, r] = [
+                            lhs["id" as keyof T],
+                            rhs["id" as keyof T],
+                        ];
+                        return l == r ? 0 : l < r ? -1 : 1;
                     },
                 );
                 for (const e of elements) {
@@ -273,6 +279,67 @@ class SortBy<T> extends Operator<T> {
     }
 }
 
+/**
+ * A whole query, which the backend runs as a single statement: the elements
+ * matching `filter` are sorted, then `offset` of them are skipped and at most
+ * `limit` of the rest are returned, holding only the `select` fields if given.
+ */
+type QuerySpec<T> = {
+    typeName: string;
+    filter?: Record<string, unknown>;
+    sort?: SortKey<T>[];
+    limit?: number;
+    offset?: number;
+    select?: (keyof T)[];
+    includeDeleted: boolean;
+};
+
+/**
+ * Flattens the operator chain ending in `op` into a `QuerySpec`, if its
+ * operators come in an order that a single query can apply: filters and a
+ * sort, then skip, then take. Returns undefined otherwise.
+ */
+function toQuerySpec<T>(op: Operator<T>): QuerySpec<T> | undefined {
+    const ops: Operator<T>[] = [];
+    for (let o: Operator<T> | undefined = op; o !== undefined; o = o.inner) {
+        ops.unshift(o);
+    }
+    const base = ops[0] as BaseEntity<T>;
+    const spec: QuerySpec<T> = {
+        typeName: base.name,
+        includeDeleted: base.includeDeleted,
+    };
+    for (const o of ops.slice(1)) {
+        const sliced = spec.offset !== undefined || spec.limit !== undefined;
+        if (o instanceof ExpressionFilter && !sliced) {
+            spec.filter = spec.filter === undefined ? o.expression : {
+                exprType: "Binary",
+                left: spec.filter,
+                op: "And",
+                right: o.expression,
+            };
+        } else if (o instanceof SortBy && !sliced && spec.sort === undefined) {
+            spec.sort = o.keys;
+        } else if (o instanceof ColumnsSelect && spec.select === undefined) {
+            spec.select = o.columns;
+        } else if (o instanceof Skip && !sliced) {
+            spec.offset = o.count;
+        } else if (o instanceof Take && spec.limit === undefined) {
+            spec.limit = o.count;
+        } else {
+            return undefined;
+        }
+    }
+    return spec;
+}
+
+/** A page of the elements of a cursor, as returned by `ChiselCursor.page()`. */
+export type CursorPage<T> = {
+    results: T[];
+    /** Token that fetches the next page, if there may be one. */
+    next?: string;
+};
+
 /** ChiselCursor is a lazy iterator that will be used by ChiselStrike to construct an optimized query. */
 export class ChiselCursor<T> {
     constructor(
@@ -377,15 +444,32 @@ export class ChiselCursor<T> {
      * @param key specifies which attribute of `T` is to be used as a sort key.
      * @param ascending if true, the sort will be ascending. Descending otherwise.
      *
-     * Note: the sort is not guaranteed to be stable.
+     * To sort by several attributes, pass a list of keys instead, each of which
+     * orders the elements that the previous ones leave tied:
+     *
+     * ```typescript
+     * Person.cursor().sortBy([
+     *     { fieldName: "lastName" },
+     *     { fieldName: "createdAt", ascending: false },
+     * ]);
+     * ```
+     *
+     * Elements with equal keys are ordered by id.
      */
-    sortBy(key: keyof T, ascending = true): ChiselCursor<T> {
+    sortBy(key: keyof T, ascending?: boolean): ChiselCursor<T>;
+    sortBy(
+        keys: { fieldName: keyof T; ascending?: boolean }[],
+    ): ChiselCursor<T>;
+    sortBy(
+        key: keyof T | { fieldName: keyof T; ascending?: boolean }[],
+        ascending = true,
+    ): ChiselCursor<T> {
+        const keys = Array.isArray(key)
+            ? key.map((k) => new SortKey<T>(k.fieldName, k.ascending ?? true))
+            : [new SortKey<T>(key, ascending)];
         return new ChiselCursor(
             this.baseConstructor,
-            new SortBy(
-                [new SortKey<T>(key, ascending)],
-                this.inner,
-            ),
+            new SortBy(keys, this.inner),
         );
     }
 
@@ -408,6 +492,56 @@ export class ChiselCursor<T> {
         return arr;
     }
 
+    /**
+     * Fetches at most `size` elements of this cursor, starting after the
+     * position that `token` encodes, or at the beginning if there is no token.
+     * The page comes with the token of the next page if there may be one, so
+     * a client can resume a big export on a later request.
+     *
+     * Elements are ordered by the sort of this cursor, or by id, and the id
+     * breaks ties. Only one sort key is allowed, as the token only records
+     * the value of one. Paging can't be combined with filter predicates that
+     * run in TypeScript.
+     *
+     * Example:
+     * ```typescript
+     * const { results, next } = await Post.cursor().sortBy("date").page(100, token);
+     * ```
+     */
+    async page(size: number, token?: string): Promise<CursorPage<T>> {
+        if (this.inner.containsType(OpType.PredicateFilter)) {
+            throw new Error(
+                "page() can't be used with a filter predicate that can't run in the database",
+            );
+        }
+        // The outermost sort is the one that orders the page.
+        let op: Operator<T> | undefined = this.inner;
+        while (op !== undefined && op.type != OpType.SortBy) {
+            op = op.inner;
+        }
+        if (op !== undefined && (op as SortBy<T>).keys.length > 1) {
+            throw new Error(
+                "page() can only sort by one field, but the cursor sorts by " +
+                    (op as SortBy<T>).keys.map((k) => String(k.fieldName))
+                        .join(", "),
+            );
+        }
+        const page = await Deno.core.opAsync(
+            "op_chisel_relational_query_page",
+            this.inner,
+            requestContext,
+            size,
+            token,
+        );
+        const ctor = this.inner.containsType(OpType.ColumnsSelect)
+            ? undefined
+            : this.baseConstructor;
+        const results = page.results.map((
+            properties: Record<string, unknown>,
+        ) => fromQueryResult(properties, page.shape, ctor));
+        return { results, next: page.nextCursor ?? undefined };
+    }
+
     /** ChiselCursor implements asyncIterator, meaning you can use it in any asynchronous context. */
     [Symbol.asyncIterator](): AsyncIterator<T> {
         let iter = this.makeTransformedQueryIter(this.inner);
@@ -450,13 +584,21 @@ export class ChiselCursor<T> {
         const ctor = op.containsType(OpType.ColumnsSelect)
             ? undefined
             : this.baseConstructor;
+        // Chains that fit in a single query are sent as one.
+        const spec = toQuerySpec(op);
         return {
             [Symbol.asyncIterator]: async function* () {
-                const rid = Deno.core.opSync(
-                    "op_chisel_relational_query_create",
-                    op,
-                    requestContext,
-                );
+                const { rid, shape } = spec === undefined
+                    ? Deno.core.opSync(
+                        "op_chisel_relational_query_create",
+                        op,
+                        requestContext,
+                    )
+                    : Deno.core.opSync(
+                        "op_chisel_query_create",
+                        spec,
+                        requestContext,
+                    );
                 try {
                     while (true) {
                         const properties = await Deno.core.opAsync(
@@ -467,13 +609,7 @@ export class ChiselCursor<T> {
                         if (properties == undefined) {
                             break;
                         }
-                        if (ctor !== undefined) {
-                            const result = new ctor();
-                            Object.assign(result, properties);
-                            yield result;
-                        } else {
-                            yield properties;
-                        }
+                        yield fromQueryResult(properties, shape, ctor);
                     }
                 } finally {
                     Deno.core.tryClose(rid);
@@ -483,6 +619,71 @@ export class ChiselCursor<T> {
     }
 }
 
+/** Which fields of the entities a query returns hold dates or nested entities. */
+type EntityShape = {
+    typeName: string;
+    dates: string[];
+    entities: Record<string, EntityShape>;
+};
+
+// Rebuilds an entity from the properties a query returned for it, turning the
+// ISO 8601 strings of its date fields back into `Date`s. The entity is an
+// instance of `ctor`, if given.
+function fromQueryResult<T>(
+    properties: Record<string, unknown>,
+    shape: EntityShape,
+    ctor?: { new (): T },
+): T {
+    for (const field of shape.dates) {
+        const value = properties[field];
+        if (typeof value == "string") {
+            properties[field] = new Date(value);
+        }
+    }
+    for (const [field, nested] of Object.entries(shape.entities)) {
+        const value = properties[field];
+        if (value !== undefined && value !== null) {
+            fromQueryResult(value as Record<string, unknown>, nested);
+        }
+    }
+    if (ctor === undefined) {
+        return properties as T;
+    }
+    const result = new ctor();
+    Object.assign(result, properties);
+    return result;
+}
+
+type IdsJson = Map<string, IdsJson>;
+
+// Sets the ids that saving `entity` generated, including those of nested entities.
+function backfillIds(entity: ChiselEntity, jsonIds: IdsJson) {
+    for (const [fieldName, value] of Object.entries(jsonIds)) {
+        if (fieldName == "id") {
+            entity.id = value as string;
+        } else {
+            const child = (entity as unknown as Record<string, unknown>)[
+                fieldName
+            ];
+            backfillIds(child as ChiselEntity, value);
+        }
+    }
+}
+
+type FormPartInfo = {
+    /** Name of the form field. */
+    name: string;
+    /** Name of the uploaded file, for file fields. */
+    filename?: string;
+    contentType?: string;
+};
+
+/** A part of a `multipart/form-data` body, as returned by `ChiselRequest.formParts()`. */
+export type FormPart = FormPartInfo & {
+    /** The part's contents, streamed from the request body. */
+    body: ReadableStream<Uint8Array>;
+};
+
 /** Extends the Request class adding ChiselStrike-specific helpers
  *
  * @property {string} version - The current API Version
@@ -498,10 +699,96 @@ export class ChiselRequest extends Request {
         public endpoint: string,
         public pathParams: string,
         public user?: AuthUser | undefined,
+        private bodyRid?: number,
     ) {
         super(input, init);
     }
 
+    /**
+     * Reads a `multipart/form-data` body, such as a form with file uploads,
+     * one part at a time.
+     *
+     * The body is parsed as it arrives, so each part's `body` has to be
+     * read, if at all, before moving on to the next part. This is instead of
+     * reading the request body in any other way.
+     *
+     * @example
+     * ```typescript
+     * for await (const part of req.formParts()) {
+     *     if (part.filename !== undefined) {
+     *         const text = await new Response(part.body).text();
+     *         // ...
+     *     }
+     * }
+     * ```
+     */
+    async *formParts(): AsyncGenerator<FormPart> {
+        const rid = this.bodyRid;
+        if (rid === undefined) {
+            throw new Error("Request has no body to read form parts from");
+        }
+        const contentType = this.headers.get("content-type") ?? "";
+        type Event = { Part?: FormPartInfo; Data?: Uint8Array } | null;
+        const read = (): Promise<Event> =>
+            Deno.core.opAsync("op_chisel_read_multipart", rid, contentType);
+
+        let event = await read();
+        while (event !== null) {
+            if (event.Part === undefined) {
+                throw new Error("Expected the start of a form part");
+            }
+            let partDone = false;
+            const body = new ReadableStream<Uint8Array>({
+                async pull(controller) {
+                    event = await read();
+                    if (event?.Data !== undefined) {
+                        controller.enqueue(event.Data);
+                    } else {
+                        partDone = true;
+                        controller.close();
+                    }
+                },
+            }, { highWaterMark: 0 });
+            yield { ...event.Part, body };
+            // Skip whatever of the part wasn't read.
+            while (!partDone) {
+                event = await read();
+                partDone = event?.Data === undefined;
+            }
+        }
+    }
+
+    /**
+     * Reads the whole body as text, decoded in the charset that the
+     * `Content-Type` header names, or as UTF-8 if it names none. Unlike
+     * `text()`, bytes that aren't valid in that charset are an error rather
+     * than being replaced. Bodies over 16 MiB are rejected.
+     *
+     * The body can only be read once: call either this or `text()` (or any
+     * other way of reading it), not both.
+     */
+    async decodedText(): Promise<string> {
+        const rid = this.bodyRid;
+        if (rid === undefined) {
+            return "";
+        }
+        const contentType = this.headers.get("content-type") ?? "";
+        return await Deno.core.opAsync("op_chisel_read_text", rid, contentType);
+    }
+
+    /**
+     * Reads the whole body into memory and returns it, rejecting bodies over
+     * 16 MiB. The body can still be read afterwards, in any way, but only
+     * once, as it then comes from memory instead of the client.
+     */
+    async bufferBody(): Promise<Uint8Array> {
+        const rid = this.bodyRid;
+        if (rid === undefined) {
+            return new Uint8Array();
+        }
+        return await Deno.core.opAsync("op_chisel_buffer_body", rid);
+    }
+
     /**
      * Returns each component of the arguments part of the path
      *
@@ -517,10 +804,27 @@ export class ChiselRequest extends Request {
     }
 }
 
+/** Options of the methods that find entities. */
+export type FindOptions = {
+    /**
+     * Whether to find entities that were soft-deleted, too. Entities of a type declared
+     * with `@softDelete` aren't removed by `delete()`, which records when they were
+     * deleted in their `deletedAt` property instead, and they are left out by default.
+     */
+    includeDeleted?: boolean;
+};
+
+/** Options of `ChiselEntity.deleteMany()`. */
+export type DeleteManyOptions = {
+    /** Whether to delete every entity of the type when the restrictions are empty. */
+    all?: boolean;
+};
+
 export function chiselIterator<T extends ChiselEntity>(
     type: { new (): T },
+    options?: FindOptions,
 ) {
-    const b = new BaseEntity<T>(type.name);
+    const b = new BaseEntity<T>(type.name, options?.includeDeleted ?? false);
     return new ChiselCursor<T>(type, b);
 }
 
@@ -533,6 +837,28 @@ export class ChiselEntity {
     /** UUID identifying this object. */
     id?: string;
 
+    /**
+     * The types of the decorated fields of this entity, as spelled in its
+     * source, like `{ email: "string" }`, including those it inherits. They
+     * are only kept if the project sets `keep_field_types` in `Chisel.toml`;
+     * otherwise this returns an empty object.
+     */
+    static fieldTypes(): Record<string, string> {
+        type Described = { __fieldTypes?: Record<string, string> };
+        let types: Record<string, string> = {};
+        for (
+            let ctor = this;
+            ctor !== ChiselEntity;
+            ctor = Object.getPrototypeOf(ctor)
+        ) {
+            // Fields declared by subclasses take precedence.
+            if (Object.prototype.hasOwnProperty.call(ctor, "__fieldTypes")) {
+                types = { ...(ctor as Described).__fieldTypes, ...types };
+            }
+        }
+        return types;
+    }
+
     /**
      * Builds a new entity.
      *
@@ -576,31 +902,125 @@ export class ChiselEntity {
         ensureNotGet();
         const jsonIds = await Deno.core.opAsync("op_chisel_store", {
             name: this.constructor.name,
-            value: this,
+            value: withDatesAsStrings(this),
         }, requestContext);
-        type IdsJson = Map<string, IdsJson>;
-        function backfillIds(this_: ChiselEntity, jsonIds: IdsJson) {
-            for (const [fieldName, value] of Object.entries(jsonIds)) {
-                if (fieldName == "id") {
-                    this_.id = value as string;
-                } else {
-                    const child = (this_ as unknown as Record<string, unknown>)[
-                        fieldName
-                    ];
-                    backfillIds(child as ChiselEntity, value);
-                }
-            }
-        }
         backfillIds(this, jsonIds);
     }
 
+    /**
+     * Saves many entities of this type at once, which is much faster than
+     * saving them one by one. Either all of them are saved or, if saving any
+     * of them fails, none is.
+     *
+     * @example
+     * ```typescript
+     * const people = names.map((name) => Person.build({ name }));
+     * await Person.saveMany(people);
+     * ```
+     */
+    static async saveMany<T extends ChiselEntity>(
+        this: { new (): T },
+        entities: T[],
+    ) {
+        ensureNotGet();
+        const jsonIds = await Deno.core.opAsync("op_chisel_store_many", {
+            name: this.name,
+            values: entities.map(withDatesAsStrings),
+        }, requestContext);
+        entities.forEach((entity, i) => backfillIds(entity, jsonIds[i]));
+    }
+
+    /**
+     * Updates only the given properties of the entity with the given `id`, leaving all others as
+     * they are. A property holding a nested entity can itself be given a partial object, which
+     * updates that entity in the same way.
+     *
+     * @example
+     * ```typescript
+     * const user = await User.update(id, { email: "alice@example.org" });
+     * ```
+     * @returns The updated entity, or `undefined` if there is no entity with that `id`, or it was
+     * soft-deleted.
+     */
+    static async update<T extends ChiselEntity>(
+        this: { new (): T },
+        id: string,
+        properties: Record<string, unknown>,
+    ): Promise<T | undefined> {
+        ensureNotGet();
+        const found = await Deno.core.opAsync("op_chisel_update", {
+            name: this.name,
+            id,
+            value: withDatesAsStrings(properties),
+        }, requestContext);
+        if (!found) {
+            return undefined;
+        }
+        return await (this as unknown as ChiselEntityClass<T>).findOne({ id });
+    }
+
+    /**
+     * Adds `delta` to the number property `field` of the entity with the given `id`, and returns
+     * the result. The database does the addition, so increments made at the same time by
+     * concurrent requests all count, unlike reading the entity and saving it back.
+     *
+     * @example
+     * ```typescript
+     * const views = await Page.increment(id, "views");
+     * ```
+     * @returns The new value, or `undefined` if there is no entity with that `id`, or it was
+     * soft-deleted.
+     */
+    static async increment<T extends ChiselEntity>(
+        this: { new (): T },
+        id: string,
+        field: keyof T & string,
+        delta = 1,
+    ): Promise<number | undefined> {
+        ensureNotGet();
+        return await Deno.core.opAsync("op_chisel_increment", {
+            name: this.name,
+            id,
+            field,
+            delta,
+        }, requestContext) ?? undefined;
+    }
+
+    /**
+     * Saves a new entity with the given properties or, if an entity with the same values for all
+     * of `conflictFields` exists, writes the given properties to that entity instead. The
+     * conflict fields must be declared unique, either with `@unique` or together with
+     * `@uniqueTogether`.
+     *
+     * @example
+     * ```typescript
+     * const member = await Member.upsert(["tenant", "email"], { tenant, email, name });
+     * ```
+     * @returns The saved entity.
+     */
+    static async upsert<T extends ChiselEntity>(
+        this: { new (): T },
+        conflictFields: (keyof T)[],
+        properties: Partial<T>,
+    ): Promise<T> {
+        ensureNotGet();
+        const id = await Deno.core.opAsync("op_chisel_upsert", {
+            name: this.name,
+            conflictFields,
+            value: withDatesAsStrings(properties),
+        }, requestContext);
+        const entity = await (this as unknown as ChiselEntityClass<T>).findOne({ id });
+        return entity!;
+    }
+
     /** Returns a `ChiselCursor` containing all elements of type T known to ChiselStrike.
      *
      * Note that `ChiselCursor` is a lazy iterator, so this doesn't mean a query will be generating fetching all elements at this point. */
     static cursor<T extends ChiselEntity>(
         this: { new (): T },
+        options?: FindOptions,
     ): ChiselCursor<T> {
-        return chiselIterator<T>(this);
+        return chiselIterator<T>(this, options);
     }
 
     /**
@@ -609,8 +1029,9 @@ export class ChiselEntity {
     static async findAll<T extends ChiselEntity>(
         this: { new (): T },
         take?: number,
+        options?: FindOptions,
     ): Promise<T[]> {
-        let it = chiselIterator<T>(this);
+        let it = chiselIterator<T>(this, options);
         if (take) {
             it = it.take(take);
         }
@@ -626,6 +1047,7 @@ export class ChiselEntity {
         this: { new (): T },
         predicate: (arg: T) => boolean,
         take?: number,
+        options?: FindOptions,
     ): Promise<T[]>;
 
     /**
@@ -637,18 +1059,20 @@ export class ChiselEntity {
         this: { new (): T },
         restrictions: Partial<T>,
         take?: number,
+        options?: FindOptions,
     ): Promise<T[]>;
 
     static async findMany<T extends ChiselEntity>(
         this: { new (): T },
         arg1: ((arg: T) => boolean) | Partial<T>,
         take?: number,
+        options?: FindOptions,
     ): Promise<T[]> {
         let it = undefined;
         if (typeof arg1 == "function") {
-            it = chiselIterator<T>(this).filter(arg1);
+            it = chiselIterator<T>(this, options).filter(arg1);
         } else {
-            it = chiselIterator<T>(this).filter(arg1);
+            it = chiselIterator<T>(this, options).filter(arg1);
         }
         if (take !== undefined) {
             it = it.take(take);
@@ -681,6 +1105,7 @@ export class ChiselEntity {
     static async findOne<T extends ChiselEntity>(
         this: { new (): T },
         predicate: (arg: T) => boolean,
+        options?: FindOptions,
     ): Promise<T | undefined>;
 
     /** Returns a single object that matches the `Partial` object `restrictions` passed as its parameter.
@@ -689,17 +1114,19 @@ export class ChiselEntity {
     static async findOne<T extends ChiselEntity>(
         this: { new (): T },
         restrictions: Partial<T>,
+        options?: FindOptions,
     ): Promise<T | undefined>;
 
     static async findOne<T extends ChiselEntity>(
         this: { new (): T },
         arg1: ((arg: T) => boolean) | Partial<T>,
+        options?: FindOptions,
     ): Promise<T | undefined> {
         let it = undefined;
         if (typeof arg1 == "function") {
-            it = chiselIterator<T>(this).filter(arg1);
+            it = chiselIterator<T>(this, options).filter(arg1);
         } else {
-            it = chiselIterator<T>(this).filter(arg1);
+            it = chiselIterator<T>(this, options).filter(arg1);
         }
         for await (const value of it) {
             return value;
@@ -726,6 +1153,10 @@ export class ChiselEntity {
     /**
      * Deletes all entities that match the `restrictions` object.
      *
+     * If the type is declared with `@softDelete`, the entities are only marked as deleted,
+     * with the time of deletion in their `deletedAt` property, and finding entities leaves
+     * them out unless `includeDeleted` is set in `FindOptions`.
+     *
      * @example
      * ```typescript
      * export class User extends ChiselEntity {
@@ -750,7 +1181,32 @@ export class ChiselEntity {
     }
 
     /**
-     * Generates endpoint code to handle REST methods GET/PUT/POST/DELETE for this entity.
+     * Deletes all entities that match the `restrictions` object and returns how many there were.
+     *
+     * Unlike `delete()`, empty restrictions are an error, so that a bug can't wipe out every entity of the type.
+     * Pass `{ all: true }` to delete them all on purpose. Policies apply as they do when finding entities.
+     *
+     * @example
+     * ```typescript
+     * const removed = await Session.deleteMany({ expired: true });
+     * await Session.deleteMany({}, { all: true });
+     * ```
+     */
+    static async deleteMany<T extends ChiselEntity>(
+        this: { new (): T },
+        restrictions: Partial<T>,
+        options?: DeleteManyOptions,
+    ): Promise<number> {
+        ensureNotGet();
+        return await Deno.core.opAsync("op_chisel_delete_many", {
+            typeName: this.name,
+            filterExpr: restrictionsToFilterExpr(restrictions),
+            all: options?.all ?? false,
+        }, requestContext);
+    }
+
+    /**
+     * Generates endpoint code to handle REST methods GET/PUT/PATCH/POST/DELETE for this entity.
      *
      * @example
      *
@@ -764,6 +1220,7 @@ export class ChiselEntity {
      *
      * * **POST:**
      *     * `/comments`             Creates a new object. Payload is a JSON with the properties of `Comment` as keys.
+     *                               Answers `201 Created` with the new object, whose URL is in the `Location` header.
      *
      * * **GET:**
      *     * `/comments`                  returns an array with all elements (use carefully in datasets expected to be large)
@@ -778,6 +1235,9 @@ export class ChiselEntity {
      * * **PUT:**
      *     * `/comments/:id`         overwrites the element with the given ID. Payload is a JSON with the properties of `Comment` as keys
      *
+     * * **PATCH:**
+     *     * `/comments/:id`         updates only the properties of the element with the given ID that are present in the payload
+     *
      * If you need more control over which method to generate and their behavior, see the top-level `crud()` function
      *
      * @returns A request-handling function suitable as a default export in an endpoint.
@@ -811,6 +1271,23 @@ export class ChiselEntity {
     }
 }
 
+// Ops see Date objects as empty objects, so dates are passed to them as
+// ISO 8601 strings instead.
+function withDatesAsStrings(value: unknown): unknown {
+    if (value instanceof Date) {
+        return value.toISOString();
+    }
+    if (Array.isArray(value)) {
+        return value.map(withDatesAsStrings);
+    }
+    if (value !== null && typeof value === "object") {
+        return Object.fromEntries(
+            Object.entries(value).map(([k, v]) => [k, withDatesAsStrings(v)]),
+        );
+    }
+    return value;
+}
+
 function restrictionsToFilterExpr<T extends ChiselEntity>(
     restrictions: Partial<T>,
 ): Record<string, unknown> | undefined {
@@ -829,7 +1306,7 @@ function restrictionsToFilterExpr<T extends ChiselEntity>(
             op: "Eq",
             right: {
                 exprType: "Literal",
-                value: restrictions[key],
+                value: withDatesAsStrings(restrictions[key]),
             },
         };
         if (expr === undefined) {
@@ -854,13 +1331,10 @@ export class AuthUser extends ChiselEntity {
 }
 
 /**
- * Gets a secret from the environment
- *
- * To allow a secret to be used, the server has to be run with * --allow-env <YOUR_SECRET>
- *
- * In development mode, all of your environment variables are accessible
+ * Any JSON value. Entity fields of this type hold free-form documents, which
+ * are stored serialized and read back as they were saved.
  */
-type JSONValue =
+export type JSONValue =
     | string
     | number
     | boolean
@@ -868,6 +1342,13 @@ type JSONValue =
     | { [x: string]: JSONValue }
     | Array<JSONValue>;
 
+/**
+ * Gets a secret from the environment
+ *
+ * To allow a secret to be used, the server has to be run with * --allow-env <YOUR_SECRET>
+ *
+ * In development mode, all of your environment variables are accessible
+ */
 export function getSecret(key: string): JSONValue | undefined {
     const secret = Deno.core.opSync("op_chisel_get_secret", key);
     if (secret === undefined || secret === null) {
@@ -876,47 +1357,933 @@ export function getSecret(key: string): JSONValue | undefined {
     return secret;
 }
 
-export function responseFromJson(body: unknown, status = 200) {
-    // https://fetch.spec.whatwg.org/#null-body-status
-    const isNullBody = (status: number): boolean => {
-        return status == 101 || status == 204 || status == 205 || status == 304;
-    };
+/**
+ * Runs an SQL statement that the query API can't express, returning the rows
+ * it produces as objects keyed by column name. Types are referred to by name
+ * in braces, and values are passed as parameters $1, $2, ... so that they are
+ * never interpreted as SQL.
+ *
+ * Only endpoints whose policy has `raw_queries: allow` may run raw queries.
+ * Label policies don't apply to their results, so they can read any field.
+ *
+ * @example
+ * ```typescript
+ * const rows = await rawQuery(
+ *     "SELECT name, age FROM {Person} WHERE age > $1 ORDER BY age",
+ *     [30],
+ * );
+ * ```
+ */
+export async function rawQuery(
+    sql: string,
+    params: (string | number | boolean)[] = [],
+): Promise<Record<string, JSONValue>[]> {
+    return await Deno.core.opAsync(
+        "op_chisel_raw_query",
+        { sql, params },
+        requestContext,
+    );
+}
 
-    const json = isNullBody(status) ? null : JSON.stringify(body, null, 2);
-    return new Response(json, {
-        status: status,
-        headers: [
-            ["content-type", "application/json"],
-        ],
-    });
+/**
+ * Runs the named query `name`, which was registered with `chisel query set`,
+ * with `params` as the values of its parameters. Returns the entities it
+ * finds, as plain objects. Label policies apply to them as to any other query.
+ *
+ * @example
+ * ```typescript
+ * const adults = await runNamedQuery("olderThan", { minAge: 18 });
+ * ```
+ */
+export async function runNamedQuery(
+    name: string,
+    params: Record<string, string | number | boolean | null> = {},
+): Promise<Record<string, unknown>[]> {
+    const { rid, shape } = Deno.core.opSync(
+        "op_chisel_run_named_query",
+        name,
+        params,
+        requestContext,
+    );
+    const results: Record<string, unknown>[] = [];
+    try {
+        while (true) {
+            const properties = await Deno.core.opAsync(
+                "op_chisel_query_next",
+                rid,
+            );
+            if (properties == undefined) {
+                break;
+            }
+            results.push(
+                fromQueryResult<Record<string, unknown>>(properties, shape),
+            );
+        }
+    } finally {
+        Deno.core.tryClose(rid);
+    }
+    return results;
 }
 
-export function labels(..._val: string[]) {
-    return <T>(_target: T, _propertyName: string) => {
-        // chisel-decorator, no content
-    };
+/**
+ * Returns the runtime configuration value `key`, or undefined if it is not
+ * set. Operators change these with `chisel config set`, and endpoints see
+ * the new value on their next request, without being applied again.
+ *
+ * Unlike secrets, configuration values are not sensitive: changes to them
+ * are logged with their values.
+ */
+export function getConfig(key: string): JSONValue | undefined {
+    const value = Deno.core.opSync("op_chisel_config", key);
+    if (value === undefined || value === null) {
+        return undefined;
+    }
+    return value;
 }
 
-export function unique(_target: unknown, _name: string): void {
-    // chisel-decorator, no content
+/**
+ * Returns the current time of the server, with the millisecond precision
+ * that `Date` fields are stored with.
+ */
+export function now(): Date {
+    return new Date(Deno.core.opSync("op_chisel_now"));
 }
 
-/** Returns the currently logged-in user or null if no one is logged in. */
-export async function loggedInUser(): Promise<AuthUser | undefined> {
-    const id = requestContext.userId;
-    if (id === undefined) {
-        return undefined;
-    }
-    return await AuthUser.findOne({ id });
+/**
+ * Returns `length` cryptographically secure random bytes, from the
+ * operating system's generator. At most 65536 bytes can be asked for at once.
+ */
+export function randomBytes(length: number): Uint8Array {
+    return Deno.core.opSync("op_chisel_random_bytes", length);
 }
 
-function ensureNotGet() {
-    if (requestContext.method === "GET") {
-        throw new Error("Mutating the backend is not allowed during GET");
-    }
+/**
+ * Returns a cryptographically secure random token of 256 bits, encoded as
+ * URL-safe base64 so it fits in URLs and cookies as it is.
+ */
+export function randomToken(): string {
+    return Deno.core.opSync("op_chisel_random_token");
 }
 
-export const requestContext: {
+export type Base64Options = {
+    /** Use `-` and `_` instead of `+` and `/`, and leave out the padding. */
+    urlSafe?: boolean;
+};
+
+function toBytes(data: Uint8Array | string): Uint8Array {
+    return typeof data === "string" ? new TextEncoder().encode(data) : data;
+}
+
+/**
+ * Encodes bytes, or a string as UTF-8, in base64.
+ */
+export function encodeBase64(
+    data: Uint8Array | string,
+    options?: Base64Options,
+): string {
+    return Deno.core.opSync(
+        "op_chisel_base64_encode",
+        toBytes(data),
+        options?.urlSafe ?? false,
+    );
+}
+
+/**
+ * Decodes base64 as produced by `encodeBase64()` with the same options.
+ * Throws if `text` is not valid base64, rather than skipping what it can't
+ * decode.
+ */
+export function decodeBase64(
+    text: string,
+    options?: Base64Options,
+): Uint8Array {
+    return Deno.core.opSync(
+        "op_chisel_base64_decode",
+        text,
+        options?.urlSafe ?? false,
+    );
+}
+
+/**
+ * Encodes bytes, or a string as UTF-8, in lowercase hex.
+ */
+export function encodeHex(data: Uint8Array | string): string {
+    return Deno.core.opSync("op_chisel_hex_encode", toBytes(data));
+}
+
+/**
+ * Decodes hex in either case. Throws if `text` has an odd number of digits
+ * or anything but hex digits.
+ */
+export function decodeHex(text: string): Uint8Array {
+    return Deno.core.opSync("op_chisel_hex_decode", text);
+}
+
+/**
+ * Signs a value so that it can be handed to clients (in a cookie, for
+ * instance) and later checked with `verify()`.
+ *
+ * Signing uses the `CHISELD_SIGNING_KEY` secret, which may be a string or
+ * an array of strings. Values are signed with the first key and verified
+ * against all of them, which allows rotating keys.
+ */
+export function sign(value: string): string {
+    return Deno.core.opSync("op_chisel_sign", value);
+}
+
+/**
+ * Checks a value produced by `sign()`, returning the original value, or
+ * null if the signature doesn't match.
+ */
+export function verify(signed: string): string | null {
+    return Deno.core.opSync("op_chisel_verify", signed);
+}
+
+/**
+ * Hashes a password for storing, with Argon2id and a random salt. The
+ * result includes the salt and parameters, so only it needs to be stored.
+ */
+export async function hashPassword(password: string): Promise<string> {
+    return await Deno.core.opAsync("op_chisel_hash_password", password);
+}
+
+/**
+ * Checks a password against a hash produced by `hashPassword()`. The
+ * comparison takes the same time wherever the hashes differ.
+ */
+export async function verifyPassword(
+    password: string,
+    hash: string,
+): Promise<boolean> {
+    return await Deno.core.opAsync(
+        "op_chisel_verify_password",
+        password,
+        hash,
+    );
+}
+
+/**
+ * Returns the cookies sent with the current request, by name.
+ *
+ * Quoted values are unquoted. If a cookie name is repeated, the first value wins.
+ */
+export function cookies(): Map<string, string> {
+    const parsed: Record<string, string> = Deno.core.opSync(
+        "op_chisel_cookies",
+    );
+    return new Map(Object.entries(parsed));
+}
+
+/** A task queued with `enqueue`, as handed to the worker that runs it. */
+export type BackgroundTask = {
+    // The endpoint module that exports the task, and the name of the export.
+    url: string;
+    name: string;
+    args: unknown[];
+    // A copy of the context of the request that queued the task, which it
+    // runs in.
+    context: typeof requestContext;
+    // Id of the job that tracks the task, if queued with enqueueJob().
+    jobId?: string;
+};
+
+// Tasks queued by the current request, which start once it commits.
+let backgroundTasks: BackgroundTask[] = [];
+
+// The functions exported by endpoint modules, which are the ones that can be
+// queued, and where each comes from.
+const taskExports = new Map<unknown, { url: string; name: string }>();
+
+/** Records the functions exported by the endpoint module at `url`. */
+export function registerTaskExports(
+    url: string,
+    mod: Record<string, unknown>,
+) {
+    for (const [name, value] of Object.entries(mod)) {
+        if (typeof value == "function") {
+            taskExports.set(value, { url, name });
+        }
+    }
+}
+
+function backgroundTask(task: unknown, args: unknown[]): BackgroundTask {
+    const source = taskExports.get(task);
+    if (source === undefined) {
+        throw new Error(
+            "enqueue() takes a function exported by an endpoint file",
+        );
+    }
+    return {
+        ...source,
+        args: structuredClone(args),
+        context: { ...requestContext },
+    };
+}
+
+/**
+ * Runs `task(...args)` in the background once the current request is
+ * complete, so that the client doesn't wait for it.
+ *
+ * Tasks run on a worker of their own, one after another, so they don't hold
+ * up requests. That worker can't share values with the endpoint, so `task`
+ * must be a function exported by an endpoint file, and `args` are copied to
+ * it, which rules out functions and class instances other than built-ins
+ * like `Date` and `Map`.
+ *
+ * The task runs in its own transaction, with the same user and endpoint as
+ * the current request. If it fails, the error is logged and its changes are
+ * rolled back. If the current request fails, the task is dropped.
+ *
+ * @example
+ * ```typescript
+ * export async function countPost(name: string) {
+ *     const stats = await Stats.findOne({ name });
+ *     stats.count += 1;
+ *     await stats.save();
+ * }
+ *
+ * export default async function (req: Request) {
+ *     enqueue(countPost, "posts");
+ *     return new Response("ok");
+ * }
+ * ```
+ */
+export function enqueue<A extends unknown[]>(
+    task: (...args: A) => Promise<unknown>,
+    ...args: A
+) {
+    backgroundTasks.push(backgroundTask(task, args));
+}
+
+/**
+ * Like `enqueue`, but tracks the task as a job, whose status clients can
+ * read at `/__chiselstrike/jobs/<id>`: one of `queued`, `running`,
+ * `succeeded` or `failed`. Only the user logged in to the current request,
+ * if any, can read it. Returns the id of the job, which `responseFromJob`
+ * answers the request with.
+ *
+ * @example
+ * ```typescript
+ * export async function buildReport(month: string) {
+ *     await Report.build(await computeReport(month)).save();
+ * }
+ *
+ * export default async function (req: Request) {
+ *     const jobId = enqueueJob(buildReport, "2022-05");
+ *     return responseFromJob(jobId);
+ * }
+ * ```
+ */
+export function enqueueJob<A extends unknown[]>(
+    task: (...args: A) => Promise<unknown>,
+    ...args: A
+): string {
+    const queued = backgroundTask(task, args);
+    const jobId: string = Deno.core.opSync(
+        "op_chisel_create_job",
+        requestContext.userId,
+    );
+    backgroundTasks.push({ ...queued, jobId });
+    return jobId;
+}
+
+/**
+ * A `202 Accepted` response for the job `jobId` queued with `enqueueJob`,
+ * with a `Location` header pointing at the status of the job.
+ */
+export function responseFromJob(jobId: string): Response {
+    const location = `/__chiselstrike/jobs/${jobId}`;
+    const response = responseFromJson({ id: jobId, status: "queued" }, 202);
+    response.headers.set("location", location);
+    return response;
+}
+
+/** Removes and returns the tasks queued by the current request. */
+export function takeBackgroundTasks(): BackgroundTask[] {
+    const tasks = backgroundTasks;
+    backgroundTasks = [];
+    return tasks;
+}
+
+/**
+ * The default export of the file named by `middleware` in Chisel.toml. It
+ * wraps every endpoint: `next()` runs the endpoint and returns its response,
+ * so the middleware can act before and after it, change the response, or
+ * answer by itself without calling `next()` at all.
+ *
+ * Example:
+ * ```typescript
+ * export default async function (req: ChiselRequest, next: () => Promise<Response>) {
+ *     if (req.user === undefined) {
+ *         return new Response("Unauthorized", { status: 401 });
+ *     }
+ *     return await next();
+ * }
+ * ```
+ */
+export type Middleware = (
+    req: ChiselRequest,
+    next: () => Promise<Response>,
+) => Promise<Response>;
+
+/** The path of a request, both as requested and as the route that matched it. */
+export type RequestRoute = {
+    /** Path as requested, such as `/dev/posts/123`. */
+    path: string;
+    /** Template of the matched route, such as `/dev/posts/:id`. Unlike
+     * the path, this is the same for every request to a route, so it is
+     * what to group requests by in logs. */
+    route: string;
+};
+
+/**
+ * Returns the path of the current request and the route that matched it.
+ *
+ * The route is the endpoint's path, or the more specific template of the
+ * endpoint's `crud()` handler when it matches.
+ */
+export function requestRoute(): RequestRoute {
+    return Deno.core.opSync("op_chisel_request_route");
+}
+
+/**
+ * Returns the IP address of the client that sent the current request.
+ *
+ * This is the address the request came from, unless that is a proxy given to
+ * `chiseld --trusted-proxy`, in which case the client is taken from the
+ * X-Forwarded-For header it adds.
+ */
+export function remoteAddr(): string | undefined {
+    return Deno.core.opSync("op_chisel_remote_addr") ?? undefined;
+}
+
+/** A field of a type, as `typeInfo()` describes it. */
+export type FieldInfo = {
+    name: string;
+    /** `string`, `number`, `boolean`, `Date`, `JSONValue` or a type name. */
+    type: string;
+    labels: string[];
+    default: string | null;
+    optional: boolean;
+    unique: boolean;
+    indexed: boolean;
+    /** The only values a string field takes, or empty if any. */
+    allowedValues: string[];
+    /** Whether the field is declared as `T | null`. */
+    nullable: boolean;
+};
+
+/** A type defined in the models, as `typeInfo()` describes it. */
+export type TypeInfo = {
+    name: string;
+    /** The fields declared in the model, without the `id` every type has. */
+    fields: FieldInfo[];
+    /** The sets of fields declared with `@uniqueTogether`. */
+    uniqueTogether: string[][];
+    /** Whether the type is declared with `@softDelete`. */
+    softDelete: boolean;
+};
+
+/**
+ * Whether the user called `username`, or an anonymous user if it is not
+ * given, may call the endpoint at `path` of the current API version, as
+ * decided by the `users` and `public_paths` of the policy. The path doesn't
+ * include the version, as in policy files. Nothing is called, so this can
+ * preview access for tooling, like an authorization UI.
+ *
+ * @example
+ * ```typescript
+ * const canAdmin = isAllowed("/admin", "alice@example.com");
+ * ```
+ */
+export function isAllowed(path: string, username?: string): boolean {
+    return Deno.core.opSync(
+        "op_chisel_is_allowed",
+        path,
+        username ?? null,
+        requestContext,
+    );
+}
+
+/**
+ * Describes the type called `name` in the models of the current API
+ * version, for code that works on any type, like generic forms. Throws if
+ * there is no such type.
+ */
+export function typeInfo(name: string): TypeInfo {
+    return Deno.core.opSync("op_chisel_type_info", name, requestContext);
+}
+
+type WebSocketEvent =
+    | { kind: "text"; data: string }
+    | { kind: "binary"; data: Uint8Array }
+    | { kind: "close"; code: number; reason: string };
+
+/**
+ * The server side of a WebSocket connection, as returned by
+ * `upgradeWebSocket()`.
+ *
+ * Messages from the client arrive as `message` events, and the end of the
+ * connection, by either side, as a `close` event. Message handlers run after
+ * the request that opened the connection is over, so they have no request
+ * context of their own.
+ */
+export class ChiselWebSocket extends EventTarget {
+    onmessage: ((event: MessageEvent) => void) | null = null;
+    onclose: ((event: CloseEvent) => void) | null = null;
+    /** Whether the connection is closed, or closing. */
+    closed = false;
+    // Settles once the connection is handed over, which is after the
+    // response accepting it is sent.
+    private accepted: Promise<void>;
+    // The last message sent, which the next one waits on to keep them in order.
+    private sending: Promise<void>;
+
+    constructor(private rid: number) {
+        super();
+        this.accepted = Deno.core.opAsync("op_chisel_websocket_accept", rid);
+        this.sending = this.accepted;
+        this.receive();
+    }
+
+    /** Sends a message: a text one for strings, a binary one otherwise. */
+    send(data: string | ArrayBuffer | ArrayBufferView) {
+        if (this.closed) {
+            throw new Error("WebSocket is closed");
+        }
+        const [op, arg] = typeof data == "string"
+            ? ["op_chisel_websocket_send_text", data]
+            : [
+                "op_chisel_websocket_send_binary",
+                data instanceof ArrayBuffer
+                    ? new Uint8Array(data)
+                    : new Uint8Array(data.buffer, data.byteOffset, data.byteLength),
+            ];
+        this.sending = this.sending.then(() =>
+            Deno.core.opAsync(op, this.rid, arg)
+        );
+        // A failed send means the connection is gone, which the close event reports.
+        this.sending = this.sending.catch(() => {});
+    }
+
+    /**
+     * Closes the connection. The `close` event follows once the client
+     * acknowledges it.
+     */
+    close(code = 1000, reason = "") {
+        if (this.closed) {
+            return;
+        }
+        this.closed = true;
+        this.sending = this.sending.then(() =>
+            Deno.core.opAsync("op_chisel_websocket_close", this.rid, code, reason)
+        ).catch(() => {});
+    }
+
+    private async receive() {
+        // Without a close frame, the connection ended abnormally.
+        let code = 1006;
+        let reason = "";
+        try {
+            await this.accepted;
+            for (;;) {
+                const event: WebSocketEvent = await Deno.core.opAsync(
+                    "op_chisel_websocket_next",
+                    this.rid,
+                );
+                if (event.kind == "close") {
+                    ({ code, reason } = event);
+                    break;
+                }
+                const e = new MessageEvent("message", { data: event.data });
+                this.dispatchEvent(e);
+                this.onmessage?.(e);
+            }
+        } catch (_) {
+            // The connection was never handed over.
+        }
+        this.closed = true;
+        try {
+            Deno.core.opSync("op_close", this.rid);
+        } catch (_) {
+            // Already closed.
+        }
+        const e = new CloseEvent("close", {
+            code,
+            reason,
+            wasClean: code != 1006,
+        });
+        this.dispatchEvent(e);
+        this.onclose?.(e);
+    }
+}
+
+// Responses that accept a WebSocket upgrade, which are sent with status 101.
+const webSocketUpgrades = new WeakSet<Response>();
+
+/**
+ * Accepts the request to switch to the WebSocket protocol. The endpoint must
+ * return the `response`, and then talks to the client through the `socket`.
+ *
+ * @example
+ * ```typescript
+ * export default async function chisel(req: ChiselRequest) {
+ *     const { socket, response } = upgradeWebSocket(req);
+ *     socket.onmessage = (e) => socket.send("echo: " + e.data);
+ *     return response;
+ * }
+ * ```
+ */
+export function upgradeWebSocket(
+    req: Request,
+): { socket: ChiselWebSocket; response: Response } {
+    if (req.headers.get("upgrade")?.toLowerCase() != "websocket") {
+        throw new Error("The request is not a WebSocket upgrade");
+    }
+    const [rid, accept] = Deno.core.opSync("op_chisel_upgrade_websocket");
+    const response = new Response(null, {
+        headers: {
+            "upgrade": "websocket",
+            "connection": "Upgrade",
+            "sec-websocket-accept": accept,
+        },
+    });
+    webSocketUpgrades.add(response);
+    return { socket: new ChiselWebSocket(rid), response };
+}
+
+/** Whether `res` is the response of `upgradeWebSocket()`. */
+export function isWebSocketUpgrade(res: Response): boolean {
+    return webSocketUpgrades.has(res);
+}
+
+/** A place where a value doesn't conform to a JSON Schema. */
+export type ValidationError = {
+    /** JSON pointer to the offending part of the value, empty for the value itself. */
+    path: string;
+    message: string;
+};
+
+/**
+ * Checks a value, such as a parsed request body, against a JSON Schema.
+ * Returns the violations found, which is an empty array for a valid value.
+ *
+ * The keywords `type`, `enum`, `const`, `properties`, `required`,
+ * `additionalProperties`, `items`, `minItems`, `maxItems`, `minLength`,
+ * `maxLength`, `pattern`, `minimum`, `maximum`, `exclusiveMinimum` and
+ * `exclusiveMaximum` are supported, along with annotations such as `title`
+ * and `description`. Throws if the schema is malformed or uses any other
+ * keyword, such as `$ref`, `anyOf` or `format`.
+ */
+export function validate(
+    schema: Record<string, unknown> | boolean,
+    value: unknown,
+): ValidationError[] {
+    return Deno.core.opSync("op_chisel_validate", schema, value);
+}
+
+/**
+ * JSON Schemas that the requests of an endpoint must conform to, declared by
+ * exporting them as `schema` from the endpoint module. Requests that don't
+ * are answered with `400 Bad Request` before the endpoint runs.
+ *
+ * @example
+ * ```typescript
+ * export const schema: RequestSchema = {
+ *     body: { type: "object", required: ["name"] },
+ * };
+ * ```
+ */
+export type RequestSchema = {
+    /**
+     * Schema of the request body, parsed as JSON. Requests to GET and HEAD,
+     * which have no body, aren't checked against it.
+     */
+    body?: Record<string, unknown> | boolean;
+    /**
+     * Schema of the query parameters, as an object with the first value of
+     * each parameter. The values are strings.
+     */
+    query?: Record<string, unknown> | boolean;
+};
+
+/**
+ * Throws if a schema of `schema` is malformed or uses an unsupported keyword,
+ * so that an endpoint with a bad schema fails to load instead of failing its
+ * requests.
+ */
+export function checkRequestSchema(schema: RequestSchema) {
+    for (const part of ["body", "query"] as const) {
+        const partSchema = schema[part];
+        if (partSchema === undefined) {
+            continue;
+        }
+        try {
+            Deno.core.opSync("op_chisel_check_schema", partSchema);
+        } catch (e) {
+            throw new Error(`invalid ${part} schema: ${e.message}`);
+        }
+    }
+}
+
+/** A place where a request doesn't conform to a `RequestSchema`. */
+export type RequestValidationError = ValidationError & {
+    /** Which part of the request is wrong. */
+    in: "body" | "query";
+};
+
+/**
+ * Checks `req` against `schema`, returning the violations found. The body is
+ * read with `bufferBody()`, so `req` can still be read as usual.
+ */
+export async function validateRequest(
+    schema: RequestSchema,
+    req: ChiselRequest,
+): Promise<RequestValidationError[]> {
+    const errors: RequestValidationError[] = [];
+    if (schema.query !== undefined) {
+        const query: Record<string, string> = {};
+        for (const [name, value] of new URL(req.url).searchParams) {
+            query[name] ??= value;
+        }
+        for (const error of validate(schema.query, query)) {
+            errors.push({ in: "query", ...error });
+        }
+    }
+    if (
+        schema.body !== undefined && req.method != "GET" &&
+        req.method != "HEAD"
+    ) {
+        const text = new TextDecoder().decode(await req.bufferBody());
+        let body;
+        try {
+            body = text === "" ? null : JSON.parse(text);
+        } catch (_) {
+            errors.push({ in: "body", path: "", message: "is not valid JSON" });
+            return errors;
+        }
+        for (const error of validate(schema.body, body)) {
+            errors.push({ in: "body", ...error });
+        }
+    }
+    return errors;
+}
+
+/**
+ * An error that is reported to the client with `status`, rather than as a
+ * `500 Internal Server Error`, when it escapes an endpoint. Writes that would
+ * duplicate the value of a unique field, for instance, fail with one whose
+ * status is 409.
+ */
+export class ChiselError extends Error {
+    constructor(message: string, public readonly status: number) {
+        super(message);
+    }
+}
+
+export function responseFromJson(body: unknown, status = 200) {
+    // https://fetch.spec.whatwg.org/#null-body-status
+    const isNullBody = (status: number): boolean => {
+        return status == 101 || status == 204 || status == 205 || status == 304;
+    };
+
+    const json = isNullBody(status) ? null : JSON.stringify(body, null, 2);
+    return new Response(json, {
+        status: status,
+        headers: [
+            ["content-type", "application/json"],
+        ],
+    });
+}
+
+/** Options of `redirect()`. */
+export type RedirectOptions = {
+    /**
+     * 301, 302, 303, 307 or 308. Defaults to 302 Found; 307 Temporary
+     * Redirect has clients repeat the method and body of the request.
+     */
+    status?: 301 | 302 | 303 | 307 | 308;
+    /**
+     * Origins, like `https://example.com`, that absolute targets must be on.
+     * Without it, any http or https URL is a valid target.
+     */
+    allowedOrigins?: string[];
+};
+
+/**
+ * Returns a response redirecting the client to `location`, which is either
+ * a path on this server, like `/dev/posts`, or an absolute http or https URL.
+ *
+ * Anything else throws, including paths starting with `//`, which browsers
+ * take as URLs on another host. To keep a redirect whose target comes from
+ * the request from sending clients to other sites, list the sites it may go
+ * to in `allowedOrigins`.
+ */
+export function redirect(
+    location: string,
+    options: RedirectOptions = {},
+): Response {
+    const status = options.status ?? 302;
+    if (![301, 302, 303, 307, 308].includes(status)) {
+        throw new Error(`${status} is not a redirect status`);
+    }
+    if (location.startsWith("/")) {
+        if (location.startsWith("//") || location.startsWith("/\\")) {
+            throw new Error(`Redirect target ${location} is not a path`);
+        }
+    } else {
+        let url;
+        try {
+            url = new URL(location);
+        } catch (_) {
+            throw new Error(
+                `Redirect target ${location} is neither a path nor a URL`,
+            );
+        }
+        if (url.protocol != "http:" && url.protocol != "https:") {
+            throw new Error(`Redirect target ${location} is not an http URL`);
+        }
+        const allowed = options.allowedOrigins;
+        if (allowed !== undefined && !allowed.includes(url.origin)) {
+            throw new Error(
+                `Redirect target ${location} is not on an allowed origin`,
+            );
+        }
+    }
+    return new Response(null, { status, headers: { "Location": location } });
+}
+
+/**
+ * Yielded by the generator of `responseFromGenerator()` to send what it
+ * yielded so far right away.
+ */
+export const flush: unique symbol = Symbol("flush");
+
+// Buffered output that is sent even without a flush.
+const maxUnflushedBytes = 64 * 1024;
+
+/**
+ * Returns a streaming response whose body is what `generator` yields.
+ *
+ * Strings and bytes are buffered, and sent together when the generator
+ * yields `flush` or finishes, so that many small writes make up a single
+ * chunk on the network:
+ *
+ * ```typescript
+ * async function* rows() {
+ *     for await (const row of Row.cursor()) {
+ *         yield JSON.stringify(row) + "\n";
+ *     }
+ * }
+ * return responseFromGenerator(rows(), {
+ *     headers: { "content-type": "application/x-ndjson" },
+ * });
+ * ```
+ *
+ * Latency-sensitive output, like a server-sent event, should be followed by
+ * `flush`. Buffers of more than 64 KiB are sent without waiting for one.
+ */
+export function responseFromGenerator(
+    generator: AsyncGenerator<string | Uint8Array | typeof flush, unknown>,
+    init?: ResponseInit,
+): Response {
+    const encoder = new TextEncoder();
+    const stream = new ReadableStream<Uint8Array>({
+        async pull(controller) {
+            const chunks: Uint8Array[] = [];
+            let size = 0;
+            const send = () => {
+                const chunk = new Uint8Array(size);
+                let offset = 0;
+                for (const c of chunks) {
+                    chunk.set(c, offset);
+                    offset += c.length;
+                }
+                controller.enqueue(chunk);
+            };
+            for (;;) {
+                const { value, done } = await generator.next();
+                if (done) {
+                    if (size > 0) {
+                        send();
+                    }
+                    controller.close();
+                    return;
+                }
+                if (value === flush) {
+                    if (size > 0) {
+                        send();
+                        return;
+                    }
+                    continue;
+                }
+                const bytes = typeof value == "string"
+                    ? encoder.encode(value)
+                    : value;
+                chunks.push(bytes);
+                size += bytes.length;
+                if (size > maxUnflushedBytes) {
+                    send();
+                    return;
+                }
+            }
+        },
+        async cancel() {
+            await generator.return(undefined);
+        },
+    });
+    return new Response(stream, init);
+}
+
+export function labels(..._val: string[]) {
+    return <T>(_target: T, _propertyName: string) => {
+        // chisel-decorator, no content
+    };
+}
+
+export function unique(_target: unknown, _name: string): void {
+    // chisel-decorator, no content
+}
+
+export function indexed(_target: unknown, _name: string): void {
+    // chisel-decorator, no content
+}
+
+/**
+ * Makes the combination of values of `fields` unique: no two entities may
+ * have the same values for all of them.
+ */
+export function uniqueTogether(..._fields: string[]) {
+    return <T>(_target: T) => {
+        // chisel-decorator, no content
+    };
+}
+
+/**
+ * Makes deleting entities only set their `deletedAt` property, which the
+ * type has to declare as an optional `number` or `Date`, to the time of
+ * deletion. Finding entities leaves the deleted ones out.
+ */
+export function softDelete(_target: unknown): void {
+    // chisel-decorator, no content
+}
+
+/** Returns the currently logged-in user or null if no one is logged in. */
+export async function loggedInUser(): Promise<AuthUser | undefined> {
+    const id = requestContext.userId;
+    if (id === undefined) {
+        return undefined;
+    }
+    return await AuthUser.findOne({ id });
+}
+
+function ensureNotGet() {
+    if (requestContext.method === "GET") {
+        throw new Error("Mutating the backend is not allowed during GET");
+    }
+}
+
+export const requestContext: {
     path: string;
     method: string;
     apiVersion: string;
@@ -989,6 +2356,10 @@ type ChiselEntityClass<T extends ChiselEntity> = {
     findOne: (_: { id: string }) => Promise<T | undefined>;
     findMany: (_: Partial<T>) => Promise<T[]>;
     build: (...properties: Record<string, unknown>[]) => T;
+    update: (
+        id: string,
+        properties: Record<string, unknown>,
+    ) => Promise<T | undefined>;
     delete: (restrictions: Partial<T>) => Promise<void>;
     cursor: () => ChiselCursor<T>;
 };
@@ -1081,6 +2452,7 @@ export type CRUDMethods<
     GET: CRUDMethodSignature<T, E, P>;
     POST: CRUDMethodSignature<T, E, P>;
     PUT: CRUDMethodSignature<T, E, P>;
+    PATCH: CRUDMethodSignature<T, E, P>;
     DELETE: CRUDMethodSignature<T, E, P>;
 };
 
@@ -1092,14 +2464,29 @@ export type CRUDCreateResponses<
     [K in keyof CRUDMethods<T, E, P>]: CRUDCreateResponse;
 };
 
+/**
+ * A page of results of a CRUD query.
+ */
+type CRUDPage<T extends ChiselEntity> = {
+    results: T[];
+    /** Value of the `cursor` parameter fetching the next page, if the query used a cursor and there may be more results. */
+    nextCursor?: string;
+    /** Limit the query ran with, if any, which the server's page size bounds may have set or lowered. */
+    limit?: number;
+    /** Number of entities matching the query's filters over all pages, if the query asked for an envelope. */
+    total?: number;
+    /** Whether there are entities after these, if the query asked for an envelope. */
+    hasMore?: boolean;
+};
+
 /**
  * Fetches crud data based on crud `url`.
  */
 async function fetchEntitiesCrud<T extends ChiselEntity>(
     type: { new (): T },
     url: string,
-): Promise<T[]> {
-    const results = await Deno.core.opAsync(
+): Promise<CRUDPage<T>> {
+    return await Deno.core.opAsync(
         "op_chisel_crud_query",
         {
             typeName: type.name,
@@ -1107,7 +2494,114 @@ async function fetchEntitiesCrud<T extends ChiselEntity>(
         },
         requestContext,
     );
-    return results;
+}
+
+/**
+ * Streams all the entities matching crud `url` as CSV lines, after a header
+ * line with the names of the fields of `type`.
+ */
+function csvEntitiesCrud<T extends ChiselEntity>(
+    type: { new (): T },
+    url: string,
+): AsyncGenerator<string> {
+    // Started right away, so that a bad query fails the request instead of the
+    // body.
+    const { rid, header } = Deno.core.opSync(
+        "op_chisel_crud_query_csv",
+        {
+            typeName: type.name,
+            url,
+        },
+        requestContext,
+    );
+    return (async function* () {
+        try {
+            yield header;
+            while (true) {
+                const line = await Deno.core.opAsync(
+                    "op_chisel_query_next_csv",
+                    rid,
+                );
+                if (line == undefined) {
+                    break;
+                }
+                yield line;
+            }
+        } finally {
+            Deno.core.tryClose(rid);
+        }
+    })();
+}
+
+/**
+ * Streams all the entities matching crud `url` as the pieces of a JSON array,
+ * which is left unfinished if the query fails midway.
+ */
+function jsonEntitiesCrud<T extends ChiselEntity>(
+    type: { new (): T },
+    url: string,
+): AsyncGenerator<string> {
+    // Started right away, like csvEntitiesCrud.
+    const rid = Deno.core.opSync(
+        "op_chisel_crud_query_json",
+        {
+            typeName: type.name,
+            url,
+        },
+        requestContext,
+    );
+    return (async function* () {
+        try {
+            while (true) {
+                const piece = await Deno.core.opAsync(
+                    "op_chisel_query_next_json",
+                    rid,
+                );
+                if (piece == undefined) {
+                    break;
+                }
+                yield piece;
+            }
+        } finally {
+            Deno.core.tryClose(rid);
+        }
+    })();
+}
+
+/**
+ * When the entities of `type` last changed, truncated to whole seconds like
+ * HTTP dates, or undefined if they changed within the current second.
+ */
+function lastModifiedCrud<T extends ChiselEntity>(
+    type: { new (): T },
+): number | undefined {
+    return Deno.core.opSync(
+        "op_chisel_last_modified",
+        type.name,
+        requestContext,
+    ) ?? undefined;
+}
+
+/** A weak ETag for `body`, from the SHA-1 hash of its JSON. */
+async function weakETag(body: unknown): Promise<string> {
+    const json = new TextEncoder().encode(JSON.stringify(body));
+    const hash = new Uint8Array(await crypto.subtle.digest("SHA-1", json));
+    const hex = Array.from(hash, (b) => b.toString(16).padStart(2, "0"));
+    return `W/"${hex.join("")}"`;
+}
+
+/**
+ * Whether an If-None-Match header matches `etag`, comparing weakly as
+ * RFC 7232 asks for it.
+ */
+function matchesETag(ifNoneMatch: string | null, etag: string): boolean {
+    if (ifNoneMatch === null) {
+        return false;
+    }
+    const opaque = (tag: string) => tag.trim().replace(/^W\//, "");
+    return ifNoneMatch.split(",").some((tag) =>
+        tag.trim() === "*" || opaque(tag) === opaque(etag)
+    );
 }
 
 async function deleteEntitiesCrud<T extends ChiselEntity>(
@@ -1127,38 +2621,107 @@ async function deleteEntitiesCrud<T extends ChiselEntity>(
 const defaultCrudMethods: CRUDMethods<ChiselEntity, GenericChiselEntityClass> =
     {
         // Returns a specific entity matching params.id (if present) or all entities matching the filter in the `filter` URL parameter.
+        // With `format=csv`, the entities are streamed as CSV instead, and with `format=json-stream` as a JSON array
+        // that is written as they are read.
+        // The ChiselStrike-Limit header has the limit the query ran with, if it had one.
+        // Answers 304 Not Modified if no entity of the type changed since the request's If-Modified-Since date.
+        // Lists of entities get a weak ETag hashed from their JSON, and a 304 if it matches the request's If-None-Match,
+        // which takes precedence over If-Modified-Since.
         GET: async (
             entity: GenericChiselEntityClass,
-            _req: Request,
+            req: Request,
             params: CRUDBaseParams,
             url: URL,
             createResponse: CRUDCreateResponse,
         ) => {
+            const lastModified = lastModifiedCrud(entity);
+            const since = Date.parse(
+                req.headers.get("If-Modified-Since") ?? "",
+            );
+            if (
+                lastModified !== undefined && lastModified <= since &&
+                !req.headers.has("If-None-Match")
+            ) {
+                return new Response(null, {
+                    status: 304,
+                    headers: {
+                        "Last-Modified": new Date(lastModified).toUTCString(),
+                    },
+                });
+            }
             const { id } = params;
+            let response;
             if (id) {
                 const u = await entity.findOne({ id });
-                return createResponse(u ?? "Not found", u ? 200 : 404);
+                if (!u) {
+                    return createResponse("Not found", 404);
+                }
+                response = await createResponse(u, 200);
+            } else if (url.searchParams.get("format") === "csv") {
+                response = responseFromGenerator(
+                    csvEntitiesCrud(entity, url.href),
+                    { headers: { "Content-Type": "text/csv; charset=utf-8" } },
+                );
+            } else if (url.searchParams.get("format") === "json-stream") {
+                response = responseFromGenerator(
+                    jsonEntitiesCrud(entity, url.href),
+                    { headers: { "Content-Type": "application/json" } },
+                );
             } else {
-                return createResponse(
-                    await fetchEntitiesCrud(entity, url.href),
-                    200,
+                const page = await fetchEntitiesCrud(entity, url.href);
+                // With `envelope=true`, the results come with the total and the next cursor.
+                // Queries paginating with a cursor get the next one along with the results.
+                const body = url.searchParams.get("envelope") === "true"
+                    ? {
+                        data: page.results,
+                        page: {
+                            total: page.total,
+                            nextCursor: page.nextCursor ?? null,
+                            hasMore: page.hasMore,
+                        },
+                    }
+                    : url.searchParams.has("cursor")
+                    ? { results: page.results, nextCursor: page.nextCursor }
+                    : page.results;
+                const etag = await weakETag(body);
+                if (matchesETag(req.headers.get("If-None-Match"), etag)) {
+                    return new Response(null, {
+                        status: 304,
+                        headers: { "ETag": etag },
+                    });
+                }
+                response = await createResponse(body, 200);
+                response.headers.set("ETag", etag);
+                if (page.limit !== undefined) {
+                    response.headers.set("ChiselStrike-Limit", `${page.limit}`);
+                }
+            }
+            if (lastModified !== undefined) {
+                response.headers.set(
+                    "Last-Modified",
+                    new Date(lastModified).toUTCString(),
                 );
             }
+            return response;
         },
         // Creates and returns a new entity from the `req` payload. Ignores the payload's id property and assigns a fresh one.
+        // Answers 201 Created, with the URL of the new entity in the Location header.
         POST: async (
             entity: GenericChiselEntityClass,
             req: Request,
             _params: CRUDBaseParams,
-            _url: URL,
+            url: URL,
             createResponse: CRUDCreateResponse,
         ) => {
             const u = entity.build(await req.json());
             u.id = undefined;
             await u.save();
-            return createResponse(u, 200);
+            const response = createResponse(u, 201);
+            const collection = url.pathname.replace(/\/+$/, "");
+            response.headers.set("Location", `${collection}/${u.id}`);
+            return response;
         },
-        // Updates and returns the entity matching params.id (which must be set) from the `req` payload.
+        // Replaces and returns the entity matching params.id (which must be set) with the `req` payload.
         PUT: async (
             entity: GenericChiselEntityClass,
             req: Request,
@@ -1178,6 +2741,32 @@ const defaultCrudMethods: CRUDMethods<ChiselEntity, GenericChiselEntityClass> =
             await u.save();
             return createResponse(u, 200);
         },
+        // Updates the properties present in the `req` payload of the entity matching params.id (which must be set),
+        // and returns the whole entity.
+        PATCH: async (
+            entity: GenericChiselEntityClass,
+            req: Request,
+            params: CRUDBaseParams,
+            _url: URL,
+            createResponse: CRUDCreateResponse,
+        ) => {
+            const { id } = params;
+            if (!id) {
+                return createResponse(
+                    "PATCH requires item ID in the URL",
+                    400,
+                );
+            }
+            const properties = await req.json();
+            if (
+                typeof properties !== "object" || properties === null ||
+                Array.isArray(properties)
+            ) {
+                return createResponse("PATCH requires a JSON object", 400);
+            }
+            const u = await entity.update(id, properties);
+            return createResponse(u ?? "Not found", u ? 200 : 404);
+        },
         // Deletes the entity matching params.id (if present) or all entities matching the filter in the `filter` URL parameter. One of the two must be present.
         DELETE: async (
             entity: GenericChiselEntityClass,
@@ -1242,7 +2831,7 @@ export const standardCRUDMethods = {
 } as const;
 
 /**
- * Generates endpoint code to handle REST methods GET/PUT/POST/DELETE for this entity.
+ * Generates endpoint code to handle REST methods GET/PUT/PATCH/POST/DELETE for this entity.
  * @example
  * Put this in the file 'endpoints/comments.ts':
  * ```typescript
@@ -1269,7 +2858,8 @@ export const standardCRUDMethods = {
  *     Defaults to `responseFromJson()`.
  *  - `parsePath`: parses the URL path instead of https://deno.land/x/regexparam. The parsing result is passed to
  *     CRUD methods as the `params` argument.
- * @returns A request-handling function suitable as a default export in an endpoint.
+ * @returns A request-handling function suitable as a default export in an endpoint. It answers `OPTIONS` requests
+ *   with an `Allow` header listing the methods that are not rejected with `standardCRUDMethods.methodNotAllowed`.
  */
 export function crud<
     T extends ChiselEntity,
@@ -1293,6 +2883,7 @@ export function crud<
             : `${urlTemplateSuffix}/:id`);
 
     const pathTemplate = pathTemplateRaw.replace(/\/+/g, "/"); // in case we end up with foo///bar somehow.
+    const routePattern = regExParamParse(pathTemplate, false).pattern;
 
     const defaultCreateResponse = config?.defaultCreateResponse ||
         responseFromJson;
@@ -1303,8 +2894,12 @@ export function crud<
     const methods = config?.customMethods
         ? { ...localDefaultCrudMethods, ...config?.customMethods }
         : localDefaultCrudMethods;
+    // Reported in the Allow header of OPTIONS responses.
+    const allowedMethods = Object.entries(methods)
+        .filter(([_, m]) => m && m !== standardCRUDMethods.methodNotAllowed)
+        .map(([name, _]) => name);
 
-    return (req: Request): Promise<Response> => {
+    const handler = (req: Request): Promise<Response> => {
         const methodName = req.method as keyof typeof methods; // assume valid, will be handled gracefully
         const createResponse = config?.createResponses?.[methodName] ||
             defaultCreateResponse;
@@ -1316,8 +2911,16 @@ export function crud<
         }
 
         const url = new URL(req.url);
+        if (routePattern.test(url.pathname)) {
+            Deno.core.opSync(
+                "op_chisel_set_route",
+                "/" + requestContext.apiVersion +
+                    pathTemplate.substring("/:chiselVersion".length),
+            );
+        }
         const params = parsePath(url);
         return method(entity, req, params, url, createResponse);
     };
+    return Object.assign(handler, { allowedMethods });
 }
 // TODO: END: this should be in another file: crud.ts
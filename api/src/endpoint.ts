@@ -17,6 +17,61 @@ type Resolver = {
     msg: unknown;
 };
 const resolvers: Resolver[] = [];
+
+// Background tasks run on a worker of their own, so that they don't hold up
+// requests. It gets the same updates of its state as the endpoint worker,
+// but no requests.
+const taskWorker = new Worker("file:///worker.js", {
+    type: "module",
+    name: "taskWorker",
+    deno: {
+        namespace: true,
+    },
+});
+const taskResolvers: Resolver[] = [];
+taskWorker.onmessageerror = function (e) {
+    throw e;
+};
+taskWorker.onerror = function (e) {
+    throw e;
+};
+taskWorker.onmessage = function (event) {
+    const { msg, value, err } = event.data;
+    if (msg == "tasks") {
+        runTasks(value);
+        return;
+    }
+    const resolver = taskResolvers.shift()!;
+    if (taskResolvers.length != 0) {
+        taskWorker.postMessage(taskResolvers[0].msg);
+    }
+    if (err) {
+        resolver.reject(err);
+    } else {
+        resolver.resolve(value);
+    }
+};
+
+function toTaskWorker(msg: unknown) {
+    const p = new Promise((resolve, reject) => {
+        taskResolvers.push({ resolve, reject, msg });
+    });
+    if (taskResolvers.length == 1) {
+        taskWorker.postMessage(msg);
+    }
+    return p;
+}
+
+// Queues tasks handed over by a worker once the request or task that queued
+// them committed.
+function runTasks(tasks: unknown[]) {
+    for (const task of tasks) {
+        toTaskWorker({ cmd: "runTask", task }).catch((e) =>
+            console.error("Could not start background task:", e)
+        );
+    }
+}
+
 endpointWorker.onmessageerror = function (e) {
     throw e;
 };
@@ -54,6 +109,8 @@ endpointWorker.onmessage = function (event) {
                 resolveBody = undefined;
             }
         }
+    } else if (msg == "tasks") {
+        runTasks(value);
     } else {
         const resolver = resolvers[0];
         if (err) {
@@ -95,12 +152,28 @@ function endMsgProcessing() {
     }
 }
 
-export async function initWorker(id: number) {
-    await toWorker({ cmd: "initWorker", id });
+export async function initWorker(
+    id: number,
+    taskWorkerId: number,
+    coerceResponses: boolean,
+    bodyReadTimeoutMs?: number,
+    queryTimeoutMs?: number,
+) {
+    const msg = {
+        cmd: "initWorker",
+        coerceResponses,
+        bodyReadTimeoutMs,
+        queryTimeoutMs,
+    };
+    await Promise.all([
+        toWorker({ ...msg, id }),
+        toTaskWorker({ ...msg, id: taskWorkerId }),
+    ]);
 }
 
 export async function readWorkerChannel() {
-    await toWorker({ cmd: "readWorkerChannel" });
+    const msg = { cmd: "readWorkerChannel" };
+    await Promise.all([toWorker(msg), toTaskWorker(msg)]);
 }
 
 export async function importEndpoint(
@@ -116,13 +189,25 @@ export async function importEndpoint(
     });
 }
 
-export async function activateEndpoint(path: string) {
+export async function activateEndpoints(
+    prefix: string | undefined,
+    paths: string[],
+) {
     await toWorker({
-        cmd: "activateEndpoint",
-        path,
+        cmd: "activateEndpoints",
+        prefix,
+        paths,
     });
 }
 
+export async function setMiddleware(
+    apiVersion: string,
+    path: string,
+    version?: number,
+) {
+    await toWorker({ cmd: "setMiddleware", apiVersion, path, version });
+}
+
 export function endOfRequest(id: number) {
     endpointWorker.postMessage({ cmd: "endOfRequest", id });
     delete bodyParts[id];
@@ -148,7 +233,7 @@ export async function callHandler(
             path,
             apiVersion,
             id,
-        }) as { status: number; headers: number };
+        }) as { status: number; headers: number; hasBody?: boolean };
     } catch (e) {
         clear();
         throw e;
@@ -187,6 +272,7 @@ export async function callHandler(
     return {
         "status": res.status,
         "headers": res.headers,
+        "hasBody": res.hasBody,
         "read": read,
     };
 }
@@ -19,14 +19,25 @@ Deno.core.setPromiseRejectCallback((type, _promise, reason) => {
     }
 });
 
-type requestHandler = (req: Request) => Promise<Response>;
+type requestHandler = ((req: Request) => Promise<Response>) & {
+    // Set by handlers that dispatch on the request method, like crud().
+    allowedMethods?: string[];
+    // Exported as `schema` by the endpoint module, if it declares one.
+    requestSchema?: Chisel.RequestSchema;
+};
 // Handlers that have been compiled but are not yet serving
-// requests. The function activateEndpoint moves handler from
+// requests. The function activateEndpoints moves handlers from
 // nextHandlers to handlers.
 const nextHandlers: Record<string, requestHandler> = {};
 // A map from paths to functions that handle requests for that path.
 const handlers: Record<string, requestHandler> = {};
 
+// The middleware that wraps every endpoint of an API version, by version.
+const middleware: Record<string, Chisel.Middleware> = {};
+
+// Whether endpoints can return strings and objects instead of a Response.
+let coerceResponses = false;
+
 const requestContext = Chisel.requestContext;
 const ChiselRequest = Chisel.ChiselRequest;
 const loggedInUser = Chisel.loggedInUser;
@@ -50,9 +61,20 @@ async function handleMsg(func: () => unknown) {
     postMessage({ msg: "reply", value, err });
 }
 
-function initWorker(id: number) {
+function initWorker(
+    id: number,
+    coerce: boolean,
+    bodyReadTimeoutMs?: number,
+    queryTimeoutMs?: number,
+) {
     handleMsg(() => {
-        Deno.core.opSync("op_chisel_init_worker", id);
+        coerceResponses = coerce;
+        Deno.core.opSync(
+            "op_chisel_init_worker",
+            id,
+            bodyReadTimeoutMs,
+            queryTimeoutMs,
+        );
     });
 }
 
@@ -85,19 +107,106 @@ async function importEndpointImpl(
     // at once, since then we can create a new isolate for it.
     const url = `file:///${path}.js?ver=${version}`;
     const mod = await import(url);
-    const handler = mod.default;
-    if (typeof handler !== "function") {
-        throw new Error(
+    Chisel.registerTaskExports(url, mod);
+    const handler = moduleHandler(mod);
+    if (mod.schema !== undefined) {
+        Chisel.checkRequestSchema(mod.schema);
+        handler.requestSchema = mod.schema;
+    }
+    nextHandlers[path] = handler;
+}
+
+// Methods that an endpoint module can export a handler for by name, as in
+// `export function POST(req: Request)`.
+const methodExports = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"];
+
+// The handler of an endpoint module. A module that exports handlers named
+// after methods gets one that dispatches to them, falling back to the
+// default export, if any, for the other methods. HEAD requests go to the
+// GET handler if there is no HEAD one. Other modules are handled by their
+// default export.
+function moduleHandler(mod: Record<string, unknown>): requestHandler {
+    const notAFunction = () =>
+        new Error(
             "expected type `v8::data::Function`, got `v8::data::Value`",
         );
+    const fallback = mod.default;
+    if (fallback !== undefined && typeof fallback !== "function") {
+        throw notAFunction();
     }
-    nextHandlers[path] = handler;
+    const byMethod: Record<string, requestHandler> = {};
+    for (const method of methodExports) {
+        const h = mod[method];
+        if (h === undefined) {
+            continue;
+        }
+        if (typeof h !== "function") {
+            throw new Error(
+                `export ${method} of an endpoint must be a function`,
+            );
+        }
+        byMethod[method] = h as requestHandler;
+    }
+    if (byMethod.GET !== undefined && byMethod.HEAD === undefined) {
+        byMethod.HEAD = byMethod.GET;
+    }
+    if (Object.keys(byMethod).length == 0) {
+        if (fallback === undefined) {
+            throw notAFunction();
+        }
+        return fallback as requestHandler;
+    }
+    const allowedMethods = Object.keys(byMethod);
+    const handler = (req: Request): Promise<Response> => {
+        const h = byMethod[req.method] ??
+            fallback as requestHandler | undefined;
+        if (h === undefined) {
+            const allow = [...allowedMethods, "OPTIONS"].join(", ");
+            return Promise.resolve(
+                new Response(`Unsupported HTTP method: ${req.method}\n`, {
+                    status: 405,
+                    headers: { allow },
+                }),
+            );
+        }
+        return h(req);
+    };
+    // With a default export, any method may be handled.
+    return fallback === undefined
+        ? Object.assign(handler, { allowedMethods })
+        : handler;
 }
 
-function activateEndpoint(path: string) {
+// Replaces the handlers under prefix, if given, with the ones at
+// paths. This happens in a single message, so no request can see some
+// of the new handlers next to some of the old ones.
+function activateEndpoints(prefix: string | undefined, paths: string[]) {
     handleMsg(() => {
-        handlers[path] = nextHandlers[path];
-        delete nextHandlers[path];
+        if (prefix !== undefined) {
+            for (const path of Object.keys(handlers)) {
+                if (path.startsWith(prefix)) {
+                    delete handlers[path];
+                }
+            }
+        }
+        for (const path of paths) {
+            handlers[path] = nextHandlers[path];
+            delete nextHandlers[path];
+        }
+    });
+}
+
+function setMiddleware(apiVersion: string, path: string, version?: number) {
+    handleMsg(async () => {
+        if (version === undefined) {
+            delete middleware[apiVersion];
+            return;
+        }
+        const mod = await import(`file:///${path}.js?ver=${version}`);
+        if (typeof mod.default !== "function") {
+            throw new Error("middleware must export a function by default");
+        }
+        middleware[apiVersion] = mod.default;
     });
 }
 
@@ -107,13 +216,37 @@ async function rollback_on_failure<T>(func: () => Promise<T>): Promise<T> {
     } catch (e) {
         closeResources();
         Deno.core.opSync("op_chisel_rollback_transaction");
+        dropBackgroundTasks();
         throw e;
     }
 }
 
+// Hands the tasks queued by the request or task that just committed to the
+// task worker.
+function startBackgroundTasks() {
+    const tasks = Chisel.takeBackgroundTasks();
+    if (tasks.length != 0) {
+        postMessage({ msg: "tasks", value: tasks });
+    }
+}
+
+// Drops the tasks queued by a request or task that failed, as its writes
+// were rolled back.
+function dropBackgroundTasks() {
+    for (const task of Chisel.takeBackgroundTasks()) {
+        if (task.jobId !== undefined) {
+            setJobStatus(task.jobId, "failed");
+        }
+    }
+}
+
+// Chunks are read from the connection as the endpoint asks for them, so
+// an endpoint reading the body incrementally never holds more than a chunk
+// or two of it. None is read ahead, which leaves the whole body to endpoints
+// that read it through its resource instead.
 function buildReadableStreamForBody(rid: number) {
-    return new ReadableStream<string>({
-        async pull(controller: ReadableStreamDefaultController) {
+    return new ReadableStream<Uint8Array>({
+        async pull(controller: ReadableStreamDefaultController<Uint8Array>) {
             const chunk = await Deno.core.opAsync("op_chisel_read_body", rid);
             if (chunk) {
                 controller.enqueue(chunk);
@@ -125,26 +258,106 @@ function buildReadableStreamForBody(rid: number) {
         cancel() {
             Deno.core.opSync("op_close", rid);
         },
-    });
+    }, { highWaterMark: 0 });
 }
 
+// Closes the resources of the request that just ended. WebSockets that it
+// accepted live on.
 function closeResources() {
     const resources = Deno.core.resources();
     for (const k in resources) {
-        if (parseInt(k) > 2) {
+        if (parseInt(k) > 2 && resources[k] != "webSocket") {
             Deno.core.opSync("op_close", k);
         }
     }
 }
 
+type ReadResult = ReadableStreamReadResult<Uint8Array>;
+
+// The start of a response body, read before the headers are sent.
+type BodyStart = {
+    chunks: Uint8Array[];
+    // Whether the chunks are all of the body.
+    done: boolean;
+    // A read that didn't complete right away.
+    pending?: Promise<ReadResult>;
+};
+
+// Answers OPTIONS requests that are not CORS preflights, which are
+// answered before getting here.
+function optionsResponse(handler: requestHandler): Response {
+    if (handler.allowedMethods === undefined) {
+        return new Response("ok");
+    }
+    const allow = [...handler.allowedMethods, "OPTIONS"].join(", ");
+    return new Response(null, { status: 204, headers: { allow } });
+}
+
+function toResponse(value: unknown, path: string): Response {
+    if (value instanceof Response) {
+        return value;
+    }
+    if (coerceResponses && typeof value == "string") {
+        return new Response(value);
+    }
+    if (coerceResponses && typeof value == "object" && value !== null) {
+        return Chisel.responseFromJson(value);
+    }
+    const got = value === null ? "null" : typeof value;
+    throw new Error(
+        `Endpoint ${path} didn't return a Response, its default export returned ${got}`,
+    );
+}
+
+// Bodies bigger than this are streamed even if they are readily available.
+const maxBufferedBody = 64 * 1024;
+
+// Reads as much of the body as is readily available. That is all of it for
+// bodies given as a string or a buffer, which lets us send their length as
+// Content-Length. Actual streams usually have to wait for their data, at
+// which point we stop and stream the rest.
+async function readBodyStart(
+    reader: ReadableStreamDefaultReader<Uint8Array>,
+): Promise<BodyStart> {
+    const chunks: Uint8Array[] = [];
+    let size = 0;
+    // Reads that complete right away resolve before this timer fires.
+    const notReady = new Promise<undefined>((resolve) =>
+        setTimeout(resolve, 0)
+    );
+    while (size <= maxBufferedBody) {
+        const pending = reader.read();
+        const v = await Promise.race([pending, notReady]);
+        if (v === undefined) {
+            return { chunks, done: false, pending };
+        }
+        if (v.done) {
+            return { chunks, done: true };
+        }
+        chunks.push(v.value);
+        size += v.value.length;
+    }
+    return { chunks, done: false };
+}
+
+// Readers of the response bodies still being sent, by request id.
+const bodyReaders = new Map<number, ReadableStreamDefaultReader<Uint8Array>>();
+
 async function sendBody(
     reader: ReadableStreamDefaultReader<Uint8Array> | undefined,
     id: number,
+    start: BodyStart | undefined,
 ) {
     try {
-        if (reader !== undefined) {
+        for (const chunk of start?.chunks ?? []) {
+            sendBodyPart(chunk, id);
+        }
+        if (reader !== undefined && !start?.done) {
+            bodyReaders.set(id, reader);
+            let pending = start?.pending;
             for (let i = 0;; i += 1) {
-                const v = await reader.read();
+                const v = await (pending ?? reader.read());
+                pending = undefined;
                 // FIXME: Is this the correct way to yield in async JS?
                 if (i % 16 == 0) {
                     await new Promise((resolve) => setTimeout(resolve, 0));
@@ -154,14 +367,18 @@ async function sendBody(
                 }
                 sendBodyPart(v.value, id);
             }
+            bodyReaders.delete(id);
         }
         closeResources();
         await Deno.core.opAsync("op_chisel_commit_transaction");
+        startBackgroundTasks();
 
         sendBodyPart(undefined, id);
     } catch (e) {
+        bodyReaders.delete(id);
         closeResources();
         Deno.core.opSync("op_chisel_rollback_transaction");
+        dropBackgroundTasks();
 
         sendBodyPart(undefined, id, e);
     }
@@ -202,6 +419,7 @@ async function callHandlerImpl(
         init.body = body;
     }
     const fullPath = "/" + apiVersion + path;
+    Deno.core.opSync("op_chisel_set_route", fullPath);
     const pathParams = new URL(url).pathname.replace(
         /\/+/g,
         "/",
@@ -214,21 +432,70 @@ async function callHandlerImpl(
         path,
         pathParams,
         user,
+        body_rid,
     );
 
-    const res = await handlers[fullPath](req);
+    const handler = handlers[fullPath];
+    const next = async () => {
+        // Requests that don't match the schema never reach the handler.
+        if (handler.requestSchema !== undefined) {
+            const errors = await Chisel.validateRequest(
+                handler.requestSchema,
+                req,
+            );
+            if (errors.length > 0) {
+                return Chisel.responseFromJson({ errors }, 400);
+            }
+        }
+        return toResponse(await handler(req), fullPath);
+    };
+    const wrapper = middleware[apiVersion];
+    let res;
+    if (method == "OPTIONS") {
+        res = optionsResponse(handler);
+    } else if (wrapper === undefined) {
+        res = await next();
+    } else {
+        res = toResponse(await wrapper(req, next), fullPath);
+    }
     const resHeaders = [];
     for (const h of res.headers) {
         resHeaders.push(h);
     }
     const reader = res.body?.getReader();
+    const start = reader === undefined ? undefined : await readBodyStart(reader);
+    if (start?.done && !res.headers.has("content-length")) {
+        const length = start.chunks.reduce((n, c) => n + c.length, 0);
+        resHeaders.push(["content-length", length.toString()]);
+    }
 
     // Don't wait on sendBody as we want to send the body as a
     // background job.
-    sendBody(reader, id);
+    sendBody(reader, id, start);
+
+    const status = Chisel.isWebSocketUpgrade(res) ? 101 : res.status;
+    return { status, headers: resHeaders, hasBody: reader !== undefined };
+}
 
-    const status = res.status;
-    return { status, headers: resHeaders };
+// Ops fail with errors of these classes when the request, rather than the
+// server, is at fault, as when a write would duplicate the value of a unique
+// field or reading the body took too long. They are reported to the client
+// with the status of their class.
+const errorStatuses: Record<string, number> = {
+    ChiselBadRequest: 400,
+    ChiselRequestTimeout: 408,
+    ChiselConflict: 409,
+    ChiselGatewayTimeout: 504,
+};
+for (const [className, status] of Object.entries(errorStatuses)) {
+    Deno.core.registerErrorClass(
+        className,
+        class extends Chisel.ChiselError {
+            constructor(message: string) {
+                super(message, status);
+            }
+        },
+    );
 }
 
 function callHandler(
@@ -236,21 +503,86 @@ function callHandler(
     apiVersion: string,
     id: number,
 ) {
+    handleMsg(async () => {
+        try {
+            return await rollback_on_failure(() => {
+                return callHandlerImpl(
+                    path,
+                    apiVersion,
+                    id,
+                );
+            });
+        } catch (e) {
+            if (!(e instanceof Chisel.ChiselError)) {
+                throw e;
+            }
+            sendBodyPart(new TextEncoder().encode(e.message + "\n"), id);
+            sendBodyPart(undefined, id);
+            return { status: e.status, headers: [] };
+        }
+    });
+}
+
+// Tasks run on the task worker one after another, each in its own
+// transaction. They are chained here rather than run by handleMsg, so that
+// updates of the state of the worker don't wait for them.
+let lastTask: Promise<void> = Promise.resolve();
+
+function runTask(task: Chisel.BackgroundTask) {
     handleMsg(() => {
-        return rollback_on_failure(() => {
-            return callHandlerImpl(
-                path,
-                apiVersion,
-                id,
-            );
-        });
+        lastTask = lastTask.then(() => runTaskImpl(task));
     });
 }
 
+// Records that the job `jobId` moved to `status`. The error of a failed job
+// is only logged, as it may tell clients about the internals of the backend.
+function setJobStatus(
+    jobId: string,
+    status: "running" | "succeeded" | "failed",
+) {
+    Deno.core.opSync("op_chisel_set_job_status", jobId, { status });
+}
+
+async function runTaskImpl(task: Chisel.BackgroundTask) {
+    // No request runs on this worker, so the context is the task's alone.
+    Object.assign(requestContext, { userId: undefined }, task.context);
+    const jobId = task.jobId;
+    if (jobId !== undefined) {
+        setJobStatus(jobId, "running");
+    }
+    try {
+        const mod = await import(task.url);
+        Chisel.registerTaskExports(task.url, mod);
+        await Deno.core.opAsync("op_chisel_create_transaction");
+        await rollback_on_failure(async () => {
+            await mod[task.name](...task.args);
+            closeResources();
+            await Deno.core.opAsync("op_chisel_commit_transaction");
+        });
+        startBackgroundTasks();
+        if (jobId !== undefined) {
+            setJobStatus(jobId, "succeeded");
+        }
+    } catch (e) {
+        console.error("Background task failed:", e);
+        if (jobId !== undefined) {
+            setJobStatus(jobId, "failed");
+        }
+    }
+}
+
 function endOfRequest(id: number) {
     if (id == currentRequestId) {
         currentRequestId = undefined;
     }
+    // If the client went away before the end of the body, like a browser
+    // closing an EventSource, tell the source of the body to stop. The
+    // pending read then completes as done.
+    const reader = bodyReaders.get(id);
+    if (reader !== undefined) {
+        bodyReaders.delete(id);
+        reader.cancel().catch(() => {});
+    }
 }
 
 onmessage = function (e) {
@@ -260,13 +592,18 @@ onmessage = function (e) {
             readWorkerChannel();
             break;
         case "initWorker":
-            initWorker(d.id);
+            initWorker(
+                d.id,
+                d.coerceResponses,
+                d.bodyReadTimeoutMs,
+                d.queryTimeoutMs,
+            );
             break;
         case "importEndpoint":
             importEndpoint(d.path, d.apiVersion, d.version);
             break;
-        case "activateEndpoint":
-            activateEndpoint(d.path);
+        case "activateEndpoints":
+            activateEndpoints(d.prefix, d.paths);
             break;
         case "callHandler":
             callHandler(
@@ -278,6 +615,12 @@ onmessage = function (e) {
         case "endOfRequest":
             endOfRequest(d.id);
             break;
+        case "setMiddleware":
+            setMiddleware(d.apiVersion, d.path, d.version);
+            break;
+        case "runTask":
+            runTask(d.task);
+            break;
         default:
             throw new Error("unknown command");
     }
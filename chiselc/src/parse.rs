@@ -84,7 +84,15 @@ pub fn compile(
     })?;
 
     let rewriter = Rewriter::new(target.clone(), symbols);
-    let module = rewriter.rewrite(module);
+    let module = match rewriter.rewrite(module.clone()) {
+        Ok(module) => module,
+        Err(err) => {
+            // The code still works as it is, only without the queries being optimized.
+            handler.span_warn(err.span(), &format!("not optimizing queries: {}", err));
+            eprint!("{}", err_buf.get());
+            module
+        }
+    };
 
     let module = match target {
         Target::JavaScript => {
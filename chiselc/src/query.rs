@@ -16,6 +16,8 @@ pub enum Expr {
     Identifier(String),
     /// A literal expression.
     Literal(Literal),
+    /// A membership test.
+    In(InExpr),
 }
 
 /// A binary expression.
@@ -26,6 +28,15 @@ pub struct BinaryExpr {
     pub right: Box<Expr>,
 }
 
+/// A membership test, like `["open", "closed"].includes(p.status)`.
+#[derive(Debug)]
+pub struct InExpr {
+    /// The expression that is looked up.
+    pub value: Box<Expr>,
+    /// The literals it is looked up in.
+    pub list: Vec<Literal>,
+}
+
 /// A property access expression.
 #[derive(Debug)]
 pub struct PropertyAccessExpr {
@@ -4,11 +4,13 @@ use crate::query::BinaryExpr as QBinaryExpr;
 use crate::query::BinaryOp as QBinaryOp;
 use crate::query::Expr as QExpr;
 use crate::query::Filter;
+use crate::query::InExpr as QInExpr;
 use crate::query::Literal as QLiteral;
 use crate::query::Operator;
 use crate::query::PropertyAccessExpr;
 use crate::symbols::Symbols;
-use crate::transforms::filter::infer_filter;
+use crate::transforms::filter::{filtered_call, infer_filter};
+use std::fmt;
 use std::str::FromStr;
 use swc_ecmascript::ast::ExportDefaultDecl;
 use swc_ecmascript::ast::FnExpr;
@@ -19,9 +21,10 @@ use swc_ecmascript::ast::Number;
 use swc_atoms::JsWord;
 use swc_common::Span;
 use swc_ecmascript::ast::{
-    ArrowExpr, AwaitExpr, BlockStmt, BlockStmtOrExpr, Bool, CallExpr, Callee, Decl, DefaultDecl,
-    Expr, ExprOrSpread, ExprStmt, Ident, KeyValueProp, Lit, MemberExpr, MemberProp, Module,
-    ModuleItem, ObjectLit, Prop, PropName, PropOrSpread, Stmt, Str, Super, VarDecl, VarDeclarator,
+    ArrayLit, ArrowExpr, AwaitExpr, BinExpr, BinaryOp, BindingIdent, BlockStmt, BlockStmtOrExpr,
+    Bool, CallExpr, Callee, Decl, DefaultDecl, Expr, ExprOrSpread, ExprStmt, Ident, KeyValueProp,
+    Lit, MemberExpr, MemberProp, Module, ModuleItem, ObjectLit, ParenExpr, Pat, Prop, PropName,
+    PropOrSpread, Stmt, Str, Super, VarDecl, VarDeclarator,
 };
 
 /// The query language target
@@ -46,6 +49,40 @@ impl FromStr for Target {
     }
 }
 
+/// Code that the rewriter can't handle. The code is then better left as it is.
+#[derive(Debug, PartialEq)]
+pub enum RewriteError {
+    /// A query operator the target can't express.
+    UnsupportedOperator(Span),
+    /// A filter() call whose callee is not a method call.
+    UnsupportedCallee(Span),
+}
+
+impl RewriteError {
+    pub fn span(&self) -> Span {
+        match self {
+            RewriteError::UnsupportedOperator(span) | RewriteError::UnsupportedCallee(span) => {
+                *span
+            }
+        }
+    }
+}
+
+impl fmt::Display for RewriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RewriteError::UnsupportedOperator(_) => {
+                write!(f, "the target only supports filtering")
+            }
+            RewriteError::UnsupportedCallee(_) => {
+                write!(f, "filter() is not called as a method")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RewriteError {}
+
 pub struct Rewriter {
     target: Target,
     symbols: Symbols,
@@ -56,54 +93,55 @@ impl Rewriter {
         Self { target, symbols }
     }
 
-    pub fn rewrite(&self, module: Module) -> Module {
+    pub fn rewrite(&self, module: Module) -> Result<Module, RewriteError> {
         let mut body = Vec::new();
         for item in module.body {
-            body.push(self.rewrite_item(&item));
+            body.push(self.rewrite_item(&item)?);
         }
-        Module {
+        Ok(Module {
             span: module.span,
             body,
             shebang: module.shebang,
-        }
+        })
     }
 
-    fn rewrite_item(&self, item: &ModuleItem) -> ModuleItem {
-        match item {
+    fn rewrite_item(&self, item: &ModuleItem) -> Result<ModuleItem, RewriteError> {
+        Ok(match item {
             ModuleItem::ModuleDecl(decl) => {
-                let decl = self.rewrite_module_decl(decl);
+                let decl = self.rewrite_module_decl(decl)?;
                 ModuleItem::ModuleDecl(decl)
             }
             ModuleItem::Stmt(stmt) => {
-                let stmt = self.rewrite_stmt(stmt);
+                let stmt = self.rewrite_stmt(stmt)?;
                 ModuleItem::Stmt(stmt)
             }
-        }
+        })
     }
 
-    fn rewrite_module_decl(&self, module_decl: &ModuleDecl) -> ModuleDecl {
-        match module_decl {
+    fn rewrite_module_decl(&self, module_decl: &ModuleDecl) -> Result<ModuleDecl, RewriteError> {
+        Ok(match module_decl {
             ModuleDecl::ExportDefaultDecl(ExportDefaultDecl {
                 span,
                 decl: DefaultDecl::Fn(fn_expr),
             }) => {
-                let fn_expr = self.rewrite_fn_expr(fn_expr);
+                let fn_expr = self.rewrite_fn_expr(fn_expr)?;
                 ModuleDecl::ExportDefaultDecl(ExportDefaultDecl {
                     span: *span,
                     decl: DefaultDecl::Fn(fn_expr),
                 })
             }
             _ => module_decl.clone(),
-        }
+        })
     }
 
-    fn rewrite_fn_expr(&self, fn_expr: &FnExpr) -> FnExpr {
+    fn rewrite_fn_expr(&self, fn_expr: &FnExpr) -> Result<FnExpr, RewriteError> {
         let body = fn_expr
             .function
             .body
             .as_ref()
-            .map(|body| self.rewrite_block_stmt(body));
-        FnExpr {
+            .map(|body| self.rewrite_block_stmt(body))
+            .transpose()?;
+        Ok(FnExpr {
             ident: fn_expr.ident.clone(),
             function: Function {
                 params: fn_expr.function.params.clone(),
@@ -115,17 +153,17 @@ impl Rewriter {
                 type_params: fn_expr.function.type_params.clone(),
                 return_type: fn_expr.function.return_type.clone(),
             },
-        }
+        })
     }
 
-    fn rewrite_stmt(&self, stmt: &Stmt) -> Stmt {
-        match stmt {
+    fn rewrite_stmt(&self, stmt: &Stmt) -> Result<Stmt, RewriteError> {
+        Ok(match stmt {
             Stmt::Decl(decl) => {
-                let decl = self.rewrite_decl(decl);
+                let decl = self.rewrite_decl(decl)?;
                 Stmt::Decl(decl)
             }
             Stmt::Expr(expr_stmt) => {
-                let expr = self.rewrite_expr(&*expr_stmt.expr);
+                let expr = self.rewrite_expr(&*expr_stmt.expr)?;
                 let expr_stmt = ExprStmt {
                     span: expr_stmt.span,
                     expr: Box::new(expr),
@@ -133,15 +171,15 @@ impl Rewriter {
                 Stmt::Expr(expr_stmt)
             }
             _ => stmt.clone(),
-        }
+        })
     }
 
-    fn rewrite_decl(&self, decl: &Decl) -> Decl {
-        match decl {
+    fn rewrite_decl(&self, decl: &Decl) -> Result<Decl, RewriteError> {
+        Ok(match decl {
             Decl::Var(var_decl) => {
                 let mut decls = Vec::new();
                 for decl in &var_decl.decls {
-                    let decl = self.rewrite_var_declarator(decl);
+                    let decl = self.rewrite_var_declarator(decl)?;
                     decls.push(decl);
                 }
                 Decl::Var(VarDecl {
@@ -152,56 +190,60 @@ impl Rewriter {
                 })
             }
             _ => decl.clone(),
-        }
+        })
     }
 
-    fn rewrite_var_declarator(&self, var_declarator: &VarDeclarator) -> VarDeclarator {
+    fn rewrite_var_declarator(
+        &self,
+        var_declarator: &VarDeclarator,
+    ) -> Result<VarDeclarator, RewriteError> {
         let init = var_declarator
             .init
             .as_ref()
-            .map(|init| Box::new(self.rewrite_expr(init)));
-        VarDeclarator {
+            .map(|init| self.rewrite_expr(init).map(Box::new))
+            .transpose()?;
+        Ok(VarDeclarator {
             span: var_declarator.span,
             name: var_declarator.name.clone(),
             init,
             definite: var_declarator.definite,
-        }
+        })
     }
 
-    fn rewrite_expr(&self, expr: &Expr) -> Expr {
-        match expr {
+    fn rewrite_expr(&self, expr: &Expr) -> Result<Expr, RewriteError> {
+        Ok(match expr {
             Expr::Arrow(arrow_expr) => {
-                let arrow_expr = self.rewrite_arrow_expr(arrow_expr);
+                let arrow_expr = self.rewrite_arrow_expr(arrow_expr)?;
                 Expr::Arrow(arrow_expr)
             }
             Expr::Await(await_expr) => {
-                let await_expr = self.rewrite_await_expr(await_expr);
+                let await_expr = self.rewrite_await_expr(await_expr)?;
                 Expr::Await(await_expr)
             }
             Expr::Call(call_expr) => {
-                let call_expr = self.rewrite_call_expr(call_expr);
+                let call_expr = self.rewrite_call_expr(call_expr)?;
                 Expr::Call(call_expr)
             }
             Expr::Member(member_expr) => {
-                let member_expr = self.rewrite_member_expr(member_expr);
+                let member_expr = self.rewrite_member_expr(member_expr)?;
                 Expr::Member(member_expr)
             }
             _ => expr.clone(),
-        }
+        })
     }
 
-    fn rewrite_arrow_expr(&self, arrow_expr: &ArrowExpr) -> ArrowExpr {
+    fn rewrite_arrow_expr(&self, arrow_expr: &ArrowExpr) -> Result<ArrowExpr, RewriteError> {
         let body = match &arrow_expr.body {
             BlockStmtOrExpr::BlockStmt(block_stmt) => {
-                let block_stmt = self.rewrite_block_stmt(block_stmt);
+                let block_stmt = self.rewrite_block_stmt(block_stmt)?;
                 BlockStmtOrExpr::BlockStmt(block_stmt)
             }
             BlockStmtOrExpr::Expr(expr) => {
-                let expr = self.rewrite_expr(expr);
+                let expr = self.rewrite_expr(expr)?;
                 BlockStmtOrExpr::Expr(Box::new(expr))
             }
         };
-        ArrowExpr {
+        Ok(ArrowExpr {
             span: arrow_expr.span,
             params: arrow_expr.params.clone(),
             body,
@@ -209,44 +251,47 @@ impl Rewriter {
             is_generator: arrow_expr.is_generator,
             type_params: arrow_expr.type_params.clone(),
             return_type: arrow_expr.return_type.clone(),
-        }
+        })
     }
 
-    fn rewrite_block_stmt(&self, block_stmt: &BlockStmt) -> BlockStmt {
+    fn rewrite_block_stmt(&self, block_stmt: &BlockStmt) -> Result<BlockStmt, RewriteError> {
         let mut stmts = vec![];
         for stmt in &block_stmt.stmts {
-            stmts.push(self.rewrite_stmt(stmt));
+            stmts.push(self.rewrite_stmt(stmt)?);
         }
-        BlockStmt {
+        Ok(BlockStmt {
             span: block_stmt.span,
             stmts,
-        }
+        })
     }
 
-    fn rewrite_await_expr(&self, await_expr: &AwaitExpr) -> AwaitExpr {
-        AwaitExpr {
+    fn rewrite_await_expr(&self, await_expr: &AwaitExpr) -> Result<AwaitExpr, RewriteError> {
+        Ok(AwaitExpr {
             span: await_expr.span,
-            arg: Box::new(self.rewrite_expr(&await_expr.arg)),
-        }
+            arg: Box::new(self.rewrite_expr(&await_expr.arg)?),
+        })
     }
 
-    fn rewrite_callee(&self, callee: &Callee) -> Callee {
-        match callee {
+    fn rewrite_callee(&self, callee: &Callee) -> Result<Callee, RewriteError> {
+        Ok(match callee {
             Callee::Super(Super { span }) => Callee::Super(Super { span: *span }),
             Callee::Import(import) => Callee::Import(*import),
-            Callee::Expr(expr) => Callee::Expr(Box::new(self.rewrite_expr(expr))),
-        }
+            Callee::Expr(expr) => Callee::Expr(Box::new(self.rewrite_expr(expr)?)),
+        })
     }
 
-    fn rewrite_expr_or_spread(&self, expr_or_spread: &ExprOrSpread) -> ExprOrSpread {
-        let expr = self.rewrite_expr(&*expr_or_spread.expr);
-        ExprOrSpread {
+    fn rewrite_expr_or_spread(
+        &self,
+        expr_or_spread: &ExprOrSpread,
+    ) -> Result<ExprOrSpread, RewriteError> {
+        let expr = self.rewrite_expr(&*expr_or_spread.expr)?;
+        Ok(ExprOrSpread {
             spread: expr_or_spread.spread,
             expr: Box::new(expr),
-        }
+        })
     }
 
-    fn rewrite_call_expr(&self, call_expr: &CallExpr) -> CallExpr {
+    fn rewrite_call_expr(&self, call_expr: &CallExpr) -> Result<CallExpr, RewriteError> {
         if let Some(filter) = infer_filter(call_expr, &self.symbols) {
             match self.target {
                 Target::JavaScript | Target::TypeScript => {
@@ -258,41 +303,56 @@ impl Rewriter {
             .args
             .iter()
             .map(|expr| self.rewrite_expr_or_spread(expr))
-            .collect();
-        CallExpr {
+            .collect::<Result<_, _>>()?;
+        Ok(CallExpr {
             span: call_expr.span,
-            callee: self.rewrite_callee(&call_expr.callee),
+            callee: self.rewrite_callee(&call_expr.callee)?,
             args,
             type_args: call_expr.type_args.clone(),
-        }
+        })
     }
 
-    fn to_ts_expr(&self, call_expr: &CallExpr, filter: &Operator) -> CallExpr {
+    fn to_ts_expr(
+        &self,
+        call_expr: &CallExpr,
+        filter: &Operator,
+    ) -> Result<CallExpr, RewriteError> {
         match filter {
             Operator::Filter(filter) => {
-                let callee = self.rewrite_filter_callee(&call_expr.callee);
+                // Consecutive filter() calls become a single one, so the predicates of all of
+                // them are passed on together.
+                let mut calls = vec![call_expr];
+                let mut first = call_expr;
+                while let Some(inner) = filtered_call(&first.callee) {
+                    calls.push(inner);
+                    first = inner;
+                }
+                let callee = self.rewrite_filter_callee(&first.callee, call_expr.span)?;
                 let expr = self.filter_to_ts(filter, call_expr.span);
                 let expr = ExprOrSpread {
                     spread: None,
                     expr: Box::new(expr),
                 };
-                let mut args = call_expr.args.clone();
+                let mut args = if calls.len() == 1 {
+                    call_expr.args.clone()
+                } else {
+                    let predicates = calls.iter().rev().map(|call| call.args[0].clone());
+                    vec![conjoin_predicates(predicates.collect(), call_expr.span)]
+                };
                 args.push(expr);
-                CallExpr {
+                Ok(CallExpr {
                     span: call_expr.span,
                     callee,
                     args,
                     type_args: call_expr.type_args.clone(),
-                }
-            }
-            _ => {
-                todo!("TypeScript target only supports filtering.");
+                })
             }
+            _ => Err(RewriteError::UnsupportedOperator(call_expr.span)),
         }
     }
 
     /// Rewrites the filter() call with __filterWithExpression().
-    fn rewrite_filter_callee(&self, callee: &Callee) -> Callee {
+    fn rewrite_filter_callee(&self, callee: &Callee, span: Span) -> Result<Callee, RewriteError> {
         match callee {
             Callee::Expr(expr) => match &**expr {
                 Expr::Member(member_expr) => {
@@ -303,20 +363,23 @@ impl Rewriter {
                         optional: false,
                     });
                     member_expr.prop = prop;
-                    Callee::Expr(Box::new(Expr::Member(member_expr)))
-                }
-                _ => {
-                    todo!();
+                    Ok(Callee::Expr(Box::new(Expr::Member(member_expr))))
                 }
+                _ => Err(RewriteError::UnsupportedCallee(span)),
             },
-            _ => {
-                todo!();
-            }
+            _ => Err(RewriteError::UnsupportedCallee(span)),
         }
     }
 
     fn filter_to_ts(&self, filter: &Filter, span: Span) -> Expr {
-        self.expr_to_ts(&filter.predicate, &filter.parameters, span)
+        let expr = self.expr_to_ts(&filter.predicate, &filter.parameters, span);
+        match &*filter.input {
+            Operator::Filter(input) => {
+                let input = self.filter_to_ts(input, span);
+                self.make_binary_expr(input, &QBinaryOp::And, expr, span)
+            }
+            Operator::Scan(_) => expr,
+        }
     }
 
     fn expr_to_ts(&self, expr: &QExpr, params: &[String], span: Span) -> Expr {
@@ -327,12 +390,51 @@ impl Rewriter {
             }
             QExpr::Identifier(ident) => self.identifier_to_ts(ident, params, span),
             QExpr::Literal(lit) => self.literal_to_ts(lit, span),
+            QExpr::In(in_expr) => self.in_expr_to_ts(in_expr, params, span),
         }
     }
 
+    fn in_expr_to_ts(&self, in_expr: &QInExpr, params: &[String], span: Span) -> Expr {
+        let mut props = vec![make_expr_type("In", span)];
+        let value = PropOrSpread::Prop(Box::new(Prop::KeyValue(KeyValueProp {
+            key: PropName::Ident(Ident {
+                span,
+                sym: JsWord::from("value"),
+                optional: false,
+            }),
+            value: Box::new(self.expr_to_ts(&in_expr.value, params, span)),
+        })));
+        props.push(value);
+        let elems = in_expr
+            .list
+            .iter()
+            .map(|lit| {
+                Some(ExprOrSpread {
+                    spread: None,
+                    expr: Box::new(make_lit(lit, span)),
+                })
+            })
+            .collect();
+        let list = PropOrSpread::Prop(Box::new(Prop::KeyValue(KeyValueProp {
+            key: PropName::Ident(Ident {
+                span,
+                sym: JsWord::from("list"),
+                optional: false,
+            }),
+            value: Box::new(Expr::Array(ArrayLit { span, elems })),
+        })));
+        props.push(list);
+        Expr::Object(ObjectLit { span, props })
+    }
+
     fn binary_expr_to_ts(&self, binary_expr: &QBinaryExpr, params: &[String], span: Span) -> Expr {
-        let mut props = vec![make_expr_type("Binary", span)];
         let left = self.expr_to_ts(&binary_expr.left, params, span);
+        let right = self.expr_to_ts(&binary_expr.right, params, span);
+        self.make_binary_expr(left, &binary_expr.op, right, span)
+    }
+
+    fn make_binary_expr(&self, left: Expr, op: &QBinaryOp, right: Expr, span: Span) -> Expr {
+        let mut props = vec![make_expr_type("Binary", span)];
         let left = PropOrSpread::Prop(Box::new(Prop::KeyValue(KeyValueProp {
             key: PropName::Ident(Ident {
                 span,
@@ -342,7 +444,7 @@ impl Rewriter {
             value: Box::new(left),
         })));
         props.push(left);
-        let op = self.binary_op_to_ts(&binary_expr.op, span);
+        let op = self.binary_op_to_ts(op, span);
         let op = PropOrSpread::Prop(Box::new(Prop::KeyValue(KeyValueProp {
             key: PropName::Ident(Ident {
                 span,
@@ -352,7 +454,6 @@ impl Rewriter {
             value: Box::new(op),
         })));
         props.push(op);
-        let right = self.expr_to_ts(&binary_expr.right, params, span);
         let right = PropOrSpread::Prop(Box::new(Prop::KeyValue(KeyValueProp {
             key: PropName::Ident(Ident {
                 span,
@@ -437,29 +538,24 @@ impl Rewriter {
 
     fn literal_to_ts(&self, lit: &QLiteral, span: Span) -> Expr {
         let mut props = vec![make_expr_type("Literal", span)];
-        let lit = match lit {
-            QLiteral::Bool(v) => make_bool_lit(*v, span),
-            QLiteral::Str(s) => make_str_lit(s, span),
-            QLiteral::Num(n) => make_num_lit(n, span),
-        };
         let lit = PropOrSpread::Prop(Box::new(Prop::KeyValue(KeyValueProp {
             key: PropName::Ident(Ident {
                 span,
                 sym: JsWord::from("value"),
                 optional: false,
             }),
-            value: Box::new(lit),
+            value: Box::new(make_lit(lit, span)),
         })));
         props.push(lit);
         Expr::Object(ObjectLit { span, props })
     }
 
-    fn rewrite_member_expr(&self, member_expr: &MemberExpr) -> MemberExpr {
-        MemberExpr {
+    fn rewrite_member_expr(&self, member_expr: &MemberExpr) -> Result<MemberExpr, RewriteError> {
+        Ok(MemberExpr {
             span: member_expr.span,
-            obj: Box::new(self.rewrite_expr(&member_expr.obj)),
+            obj: Box::new(self.rewrite_expr(&member_expr.obj)?),
             prop: self.rewrite_member_prop(&member_expr.prop),
-        }
+        })
     }
 
     fn rewrite_member_prop(&self, member_prop: &MemberProp) -> MemberProp {
@@ -468,6 +564,75 @@ impl Rewriter {
     }
 }
 
+/// Makes a predicate that holds when all of `predicates` hold:
+///
+///   ((f0, f1) => (e) => f0(e) && f1(e))(predicate0, predicate1)
+///
+/// The predicates are evaluated where they were, so the names they capture keep their meaning.
+fn conjoin_predicates(predicates: Vec<ExprOrSpread>, span: Span) -> ExprOrSpread {
+    let make_ident = |name: &str| Ident {
+        span,
+        sym: JsWord::from(name),
+        optional: false,
+    };
+    let make_param = |name: &str| {
+        Pat::Ident(BindingIdent {
+            id: make_ident(name),
+            type_ann: None,
+        })
+    };
+    let make_arrow = |params: Vec<Pat>, body: Expr| ArrowExpr {
+        span,
+        params,
+        body: BlockStmtOrExpr::Expr(Box::new(body)),
+        is_async: false,
+        is_generator: false,
+        type_params: None,
+        return_type: None,
+    };
+    let names: Vec<_> = (0..predicates.len()).map(|i| format!("f{}", i)).collect();
+    let body = names
+        .iter()
+        .map(|name| {
+            Expr::Call(CallExpr {
+                span,
+                callee: Callee::Expr(Box::new(Expr::Ident(make_ident(name)))),
+                args: vec![ExprOrSpread {
+                    spread: None,
+                    expr: Box::new(Expr::Ident(make_ident("e"))),
+                }],
+                type_args: None,
+            })
+        })
+        .reduce(|left, right| {
+            Expr::Bin(BinExpr {
+                span,
+                op: BinaryOp::LogicalAnd,
+                left: Box::new(left),
+                right: Box::new(right),
+            })
+        })
+        .expect("there is at least one predicate");
+    let predicate = make_arrow(vec![make_param("e")], body);
+    let combine = make_arrow(
+        names.iter().map(|name| make_param(name)).collect(),
+        Expr::Arrow(predicate),
+    );
+    let call = CallExpr {
+        span,
+        callee: Callee::Expr(Box::new(Expr::Paren(ParenExpr {
+            span,
+            expr: Box::new(Expr::Arrow(combine)),
+        }))),
+        args: predicates,
+        type_args: None,
+    };
+    ExprOrSpread {
+        spread: None,
+        expr: Box::new(Expr::Call(call)),
+    }
+}
+
 fn make_expr_type(expr_type: &str, span: Span) -> PropOrSpread {
     PropOrSpread::Prop(Box::new(Prop::KeyValue(KeyValueProp {
         key: PropName::Ident(Ident {
@@ -479,6 +644,14 @@ fn make_expr_type(expr_type: &str, span: Span) -> PropOrSpread {
     })))
 }
 
+fn make_lit(lit: &QLiteral, span: Span) -> Expr {
+    match lit {
+        QLiteral::Bool(v) => make_bool_lit(*v, span),
+        QLiteral::Str(s) => make_str_lit(s, span),
+        QLiteral::Num(n) => make_num_lit(n, span),
+    }
+}
+
 fn make_bool_lit(value: bool, span: Span) -> Expr {
     Expr::Lit(Lit::Bool(Bool { span, value }))
 }
@@ -498,3 +671,70 @@ fn make_num_lit(num: &f64, span: Span) -> Expr {
         raw: None,
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{RewriteError, Rewriter, Target};
+    use crate::query::{Operator, Scan};
+    use crate::symbols::Symbols;
+    use swc_atoms::JsWord;
+    use swc_common::{BytePos, Span, DUMMY_SP};
+    use swc_ecmascript::ast::{CallExpr, Callee, Expr, Ident, Super};
+
+    fn rewriter() -> Rewriter {
+        let mut symbols = Symbols::new();
+        symbols.register_entity("Person");
+        Rewriter::new(Target::TypeScript, symbols)
+    }
+
+    fn span() -> Span {
+        Span::new(BytePos(3), BytePos(7), Default::default())
+    }
+
+    fn call(callee: Callee) -> CallExpr {
+        CallExpr {
+            span: span(),
+            callee,
+            args: vec![],
+            type_args: None,
+        }
+    }
+
+    fn ident(name: &str) -> Callee {
+        Callee::Expr(Box::new(Expr::Ident(Ident {
+            span: DUMMY_SP,
+            sym: JsWord::from(name),
+            optional: false,
+        })))
+    }
+
+    #[test]
+    fn unsupported_operator() {
+        let call_expr = call(ident("filter"));
+        let scan = Operator::Scan(Scan {
+            entity_type: "Person".to_string(),
+            alias: "p".to_string(),
+        });
+        let err = rewriter().to_ts_expr(&call_expr, &scan).unwrap_err();
+        assert_eq!(err, RewriteError::UnsupportedOperator(span()));
+        assert_eq!(err.to_string(), "the target only supports filtering");
+    }
+
+    #[test]
+    fn unsupported_callee() {
+        let rewriter = rewriter();
+        for callee in [ident("filter"), Callee::Super(Super { span: DUMMY_SP })] {
+            let err = rewriter.rewrite_filter_callee(&callee, span()).unwrap_err();
+            assert_eq!(err, RewriteError::UnsupportedCallee(span()));
+            assert_eq!(err.span(), span());
+        }
+    }
+
+    #[test]
+    fn unmodified_call() {
+        // A filter() call that isn't on a cursor is left as it is.
+        let call_expr = call(ident("filter"));
+        let rewritten = rewriter().rewrite_call_expr(&call_expr).unwrap();
+        assert_eq!(rewritten, call_expr);
+    }
+}
@@ -2,6 +2,7 @@ use crate::query::BinaryExpr as QBinaryExpr;
 use crate::query::BinaryOp as QBinaryOp;
 use crate::query::Expr as QExpr;
 use crate::query::Filter as QFilter;
+use crate::query::InExpr as QInExpr;
 use crate::query::Literal as QLiteral;
 use crate::query::Operator as QOperator;
 use crate::query::PropertyAccessExpr as QPropertyAccessExpr;
@@ -11,11 +12,14 @@ use crate::utils::{is_call_to_entity_cursor, is_ident_member_prop, pat_to_string
 use anyhow::{anyhow, Result};
 
 use swc_ecmascript::ast::{
-    BinExpr, BinaryOp, BlockStmtOrExpr, CallExpr, Callee, Expr, Ident, Lit, MemberExpr, MemberProp,
-    Stmt,
+    BinExpr, BinaryOp, BlockStmtOrExpr, CallExpr, Callee, Expr, ExprOrSpread, Ident, Lit,
+    MemberExpr, MemberProp, Stmt,
 };
 
 /// Infer filter operator from the lambda predicate of to filter()
+///
+/// Filters applied to the result of other filters, as in `.filter(a).filter(b)`, take the
+/// operator inferred for the inner call as their input.
 pub fn infer_filter(call_expr: &CallExpr, symbols: &Symbols) -> Option<Box<QOperator>> {
     if !is_rewritable_filter(&call_expr.callee, symbols) {
         return None;
@@ -24,9 +28,10 @@ pub fn infer_filter(call_expr: &CallExpr, symbols: &Symbols) -> Option<Box<QOper
         Ok(entity_type) => entity_type,
         _ => return None,
     };
-    let args = &call_expr.args;
-    assert_eq!(args.len(), 1);
-    let arg = &args[0];
+    let arg = match &call_expr.args[..] {
+        [arg] => arg,
+        _ => return None,
+    };
     let arrow = match &*arg.expr {
         Expr::Arrow(arrow_expr) => arrow_expr,
         _ => {
@@ -34,15 +39,14 @@ pub fn infer_filter(call_expr: &CallExpr, symbols: &Symbols) -> Option<Box<QOper
             return None;
         }
     };
-    let params = &arrow.params;
-    assert_eq!(params.len(), 1);
-    let param = &params[0];
-    let param = pat_to_string(param).unwrap();
+    let param = match &arrow.params[..] {
+        [param] => pat_to_string(param)?,
+        _ => return None,
+    };
     let expr = match &arrow.body {
         BlockStmtOrExpr::BlockStmt(block_stmt) => {
-            assert_eq!(block_stmt.stmts.len(), 1);
-            let return_stmt = match &block_stmt.stmts[0] {
-                Stmt::Return(return_stmt) => return_stmt,
+            let return_stmt = match &block_stmt.stmts[..] {
+                [Stmt::Return(return_stmt)] => return_stmt,
                 _ => {
                     return None;
                 }
@@ -50,31 +54,39 @@ pub fn infer_filter(call_expr: &CallExpr, symbols: &Symbols) -> Option<Box<QOper
             match &return_stmt.arg {
                 Some(expr) => match &**expr {
                     Expr::Bin(bin_expr) => convert_bin_expr(bin_expr),
+                    Expr::Call(call_expr) => convert_includes(call_expr),
                     Expr::Lit(Lit::Bool(value)) => Ok(QExpr::Literal(QLiteral::Bool(value.value))),
-                    _ => {
-                        todo!();
-                    }
+                    _ => Err(anyhow!(
+                        "Unsupported filter predicate expression: {:?}",
+                        expr
+                    )),
                 },
-                None => {
-                    todo!();
-                }
+                None => Err(anyhow!("Filter predicate returns nothing")),
             }
         }
         BlockStmtOrExpr::Expr(expr) => match &**expr {
             Expr::Bin(bin_expr) => convert_bin_expr(bin_expr),
-            _ => todo!("Unsupported filter predicate expression: {:?}", expr),
+            Expr::Call(call_expr) => convert_includes(call_expr),
+            _ => Err(anyhow!(
+                "Unsupported filter predicate expression: {:?}",
+                expr
+            )),
         },
     };
     let expr = match expr {
         Ok(expr) => expr,
         Err(_) => return None,
     };
-    Some(Box::new(QOperator::Filter(QFilter {
-        parameters: vec![param.clone()],
-        input: Box::new(QOperator::Scan(QScan {
+    let input = match filtered_call(&call_expr.callee) {
+        Some(inner) => infer_filter(inner, symbols)?,
+        None => Box::new(QOperator::Scan(QScan {
             entity_type,
-            alias: param,
+            alias: param.clone(),
         })),
+    };
+    Some(Box::new(QOperator::Filter(QFilter {
+        parameters: vec![param],
+        input,
         predicate: expr,
     })))
 }
@@ -84,7 +96,8 @@ fn is_rewritable_filter(callee: &Callee, symbols: &Symbols) -> bool {
         Callee::Expr(expr) => match &**expr {
             Expr::Member(member_expr) => {
                 is_ident_member_prop(&member_expr.prop, "filter")
-                    && is_call_to_entity_cursor(&member_expr.obj, symbols)
+                    && (is_call_to_entity_cursor(&member_expr.obj, symbols)
+                        || filtered_call(callee).is_some())
             }
             _ => false,
         },
@@ -92,11 +105,35 @@ fn is_rewritable_filter(callee: &Callee, symbols: &Symbols) -> bool {
     }
 }
 
+/// Returns the call whose result the filter() method `callee` is called on, if that call is
+/// itself a filter().
+pub fn filtered_call(callee: &Callee) -> Option<&CallExpr> {
+    let member_expr = match callee {
+        Callee::Expr(expr) => match &**expr {
+            Expr::Member(member_expr) => member_expr,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    match &*member_expr.obj {
+        Expr::Call(call_expr) => match &call_expr.callee {
+            Callee::Expr(expr) => match &**expr {
+                Expr::Member(inner) if is_ident_member_prop(&inner.prop, "filter") => {
+                    Some(call_expr)
+                }
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 fn lookup_callee_entity_type(callee: &Callee) -> Result<String> {
     match callee {
         Callee::Expr(expr) => lookup_entity_type(expr),
         _ => {
-            todo!();
+            anyhow::bail!("Failed to look up entity type from callee")
         }
     }
 }
@@ -130,7 +167,7 @@ fn convert_expr(expr: &Expr) -> Result<QExpr> {
             let prop = match &member_expr.prop {
                 MemberProp::Ident(ident) => ident.sym.to_string(),
                 _ => {
-                    todo!();
+                    anyhow::bail!("Unsupported property access: {:#?}", member_expr.prop)
                 }
             };
             Ok(QExpr::PropertyAccess(QPropertyAccessExpr {
@@ -139,10 +176,49 @@ fn convert_expr(expr: &Expr) -> Result<QExpr> {
             }))
         }
         Expr::Ident(ident) => Ok(QExpr::Identifier(ident.sym.to_string())),
+        Expr::Call(call_expr) => convert_includes(call_expr),
         _ => Err(anyhow!("Unsupported expression: {:#?}", expr)),
     }
 }
 
+/// Converts `[a, b, c].includes(x)`, where the array elements are literals, to a membership test.
+fn convert_includes(call_expr: &CallExpr) -> Result<QExpr> {
+    let member_expr = match &call_expr.callee {
+        Callee::Expr(expr) => match &**expr {
+            Expr::Member(member_expr) if is_ident_member_prop(&member_expr.prop, "includes") => {
+                member_expr
+            }
+            _ => anyhow::bail!("Unsupported call: {:#?}", call_expr),
+        },
+        _ => anyhow::bail!("Unsupported call: {:#?}", call_expr),
+    };
+    let elems = match &*member_expr.obj {
+        Expr::Array(array_lit) => &array_lit.elems,
+        _ => anyhow::bail!("includes() is only supported on array literals"),
+    };
+    let mut list = vec![];
+    for elem in elems {
+        let lit = match elem {
+            Some(ExprOrSpread { spread: None, expr }) => match &**expr {
+                Expr::Lit(Lit::Bool(value)) => QLiteral::Bool(value.value),
+                Expr::Lit(Lit::Num(number)) => QLiteral::Num(number.value),
+                Expr::Lit(Lit::Str(s)) => QLiteral::Str(format!("{}", s.value)),
+                _ => anyhow::bail!("Unsupported array element: {:#?}", expr),
+            },
+            _ => anyhow::bail!("Unsupported array element: {:#?}", elem),
+        };
+        list.push(lit);
+    }
+    let value = match &call_expr.args[..] {
+        [ExprOrSpread { spread: None, expr }] => convert_expr(expr)?,
+        _ => anyhow::bail!("includes() takes the value to look up"),
+    };
+    Ok(QExpr::In(QInExpr {
+        value: Box::new(value),
+        list,
+    }))
+}
+
 fn convert_binary_op(op: &BinaryOp) -> Result<QBinaryOp> {
     Ok(match op {
         BinaryOp::EqEq => QBinaryOp::Eq,
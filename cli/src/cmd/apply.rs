@@ -1,10 +1,12 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::chisel::chisel_rpc_client::ChiselRpcClient;
-use crate::chisel::{ChiselApplyRequest, EndPointCreationRequest, PolicyUpdateRequest};
-use crate::project::{read_manifest, read_to_string, Module, Optimize};
+use crate::chisel::{
+    ChiselApplyRequest, EndPointCreationRequest, PolicyUpdateRequest, SetMiddlewareRequest,
+};
+use crate::project::{read_manifest, read_to_string, Endpoint, Module, Optimize};
 use anyhow::{anyhow, Context, Result};
-use compile::compile_ts_code as swc_compile;
+use compile::{compile_ts_code as swc_compile, CompileOptions};
 use endpoint_tsc::compile_endpoint;
 use std::env;
 use std::io::Write;
@@ -72,10 +74,33 @@ pub(crate) async fn apply<S: ToString>(
 
     let manifest = read_manifest().with_context(|| "Reading manifest file".to_string())?;
     let models = manifest.models()?;
-    let endpoints = manifest.endpoints()?;
+    let mut endpoints = manifest.endpoints()?;
     let policies = manifest.policies()?;
 
-    let types_req = crate::ts::parse_types(&models)?;
+    // The middleware is compiled just like an endpoint, as the last one.
+    if let Some(middleware) = &manifest.middleware {
+        endpoints.push(Endpoint {
+            name: "middleware".to_string(),
+            file_path: middleware.clone(),
+        });
+    }
+
+    let mut types_req = crate::ts::parse_types(&models)?;
+    for seed in manifest.seeds()? {
+        // file_stem returns None only if there is no file name.
+        let type_name = seed.file_stem().unwrap().to_string_lossy();
+        let type_req = types_req
+            .iter_mut()
+            .find(|type_req| type_req.name == type_name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Seed file {} is for type {}, which is not defined",
+                    seed.display(),
+                    type_name
+                )
+            })?;
+        type_req.seeds = read_to_string(&seed)?;
+    }
     let mut endpoints_req = vec![];
     let mut policy_req = vec![];
 
@@ -87,7 +112,16 @@ pub(crate) async fn apply<S: ToString>(
         .iter()
         .map(|type_req| type_req.name.clone())
         .collect();
-    let use_chiselc = is_chiselc_available() && manifest.optimize == Optimize::Yes;
+    anyhow::ensure!(
+        !manifest.keep_field_types || manifest.modules == Module::Deno,
+        "keep_field_types requires modules = \"deno\" in Chisel.toml"
+    );
+    // chiselc strips the types itself.
+    let use_chiselc =
+        is_chiselc_available() && manifest.optimize == Optimize::Yes && !manifest.keep_field_types;
+    let compile_options = CompileOptions {
+        keep_field_types: manifest.keep_field_types,
+    };
     if manifest.modules == Module::Node {
         let tsc = match type_check {
             TypeChecking::Yes => Some(npx(
@@ -212,7 +246,7 @@ pub(crate) async fn apply<S: ToString>(
                 let output = chiselc_output(code, &entities)?;
                 output_to_string(&output).unwrap()
             } else {
-                swc_compile(code)?
+                swc_compile(code, &compile_options)?
             };
             endpoints_req.push(EndPointCreationRequest {
                 path: f.name.clone(),
@@ -221,6 +255,11 @@ pub(crate) async fn apply<S: ToString>(
         }
     }
 
+    let middleware_code = match manifest.middleware {
+        Some(_) => endpoints_req.pop().map(|m| m.code),
+        None => None,
+    };
+
     for p in policies {
         policy_req.push(PolicyUpdateRequest {
             policy_config: read_to_string(p)?,
@@ -265,7 +304,7 @@ pub(crate) async fn apply<S: ToString>(
                 endpoints: endpoints_req,
                 policies: policy_req,
                 allow_type_deletion: allow_type_deletion.into(),
-                version,
+                version: version.clone(),
                 version_tag,
                 app_name,
             }))
@@ -284,6 +323,18 @@ pub(crate) async fn apply<S: ToString>(
         println!("Policy defined for label {}", lbl);
     }
 
+    execute!(
+        client
+            .set_middleware(tonic::Request::new(SetMiddlewareRequest {
+                code: middleware_code,
+                version,
+            }))
+            .await
+    );
+    if let Some(middleware) = manifest.middleware {
+        println!("Middleware defined: {}", middleware.display());
+    }
+
     Ok(())
 }
 
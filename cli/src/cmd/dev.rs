@@ -16,7 +16,7 @@ use std::time::Duration;
 pub(crate) async fn cmd_dev(server_url: String, type_check: bool) -> Result<()> {
     let type_check = type_check.into();
     let manifest = read_manifest()?;
-    let mut server = start_server()?;
+    let mut server = start_server(true)?;
     wait(server_url.clone()).await?;
     apply_from_dev(server_url.clone(), type_check).await;
     let (mut tx, mut rx) = channel(1);
@@ -39,6 +39,9 @@ pub(crate) async fn cmd_dev(server_url: String, type_check: bool) -> Result<()>
         let policies_dir = Path::new(policies_dir);
         apply_watcher.watch(policies_dir, RecursiveMode::Recursive)?;
     }
+    if let Some(middleware) = &manifest.middleware {
+        apply_watcher.watch(middleware, RecursiveMode::NonRecursive)?;
+    }
     while let Some(res) = rx.next().await {
         match res {
             Ok(Event {
@@ -2,12 +2,14 @@
 
 use crate::cmd::apply::apply;
 use crate::cmd::dev::cmd_dev;
-use crate::project::{create_project, CreateProjectOptions};
+use crate::project::{create_project, read_manifest, read_to_string, CreateProjectOptions};
 use crate::server::{start_server, wait};
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use chisel::chisel_rpc_client::ChiselRpcClient;
 use chisel::{
-    ChiselDeleteRequest, DescribeRequest, PopulateRequest, RestartRequest, StatusRequest,
+    ChiselDeleteRequest, DescribeRequest, ExportPoliciesRequest, GetEndpointRequest,
+    ListPolicyVersionsRequest, PolicyUpdateRequest, PopulateRequest, ReloadPoliciesRequest,
+    RestartRequest, SetConfigRequest, SetNamedQueryRequest, StatusRequest,
 };
 use std::env;
 use std::fs;
@@ -53,7 +55,10 @@ enum Command {
         optimize: bool,
     },
     /// Describe the endpoints, types, and policies.
-    Describe,
+    Describe {
+        #[structopt(subcommand)]
+        what: Option<DescribeCommand>,
+    },
     /// Start a ChiselStrike server for local development.
     Dev {
         /// calls tsc --noEmit to check types. Useful if your IDE isn't doing it.
@@ -100,6 +105,130 @@ enum Command {
         #[structopt(long)]
         from: String,
     },
+    /// Replace the policies of a version with those of the project, without applying anything
+    /// else. Requests that are running finish under the previous policies.
+    ReloadPolicies {
+        #[structopt(long, default_value = DEFAULT_API_VERSION, parse(try_from_str=parse_version))]
+        version: String,
+    },
+    /// Change the runtime configuration, which endpoints read with `getConfig()`.
+    Config {
+        #[structopt(subcommand)]
+        cmd: ConfigCommand,
+    },
+    /// Manage the named queries that endpoints run with `runNamedQuery()`.
+    Query {
+        #[structopt(subcommand)]
+        cmd: QueryCommand,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum ConfigCommand {
+    /// Set a value. Values that are not valid JSON are taken as strings.
+    Set { name: String, value: String },
+    /// Remove a value.
+    Unset { name: String },
+}
+
+#[derive(StructOpt, Debug)]
+enum QueryCommand {
+    /// Register a query, or replace the query of the same name. The file holds its spec in JSON.
+    Set {
+        name: String,
+        file: String,
+        #[structopt(long, default_value = DEFAULT_API_VERSION, parse(try_from_str=parse_version))]
+        version: String,
+    },
+    /// Remove a query.
+    Unset {
+        name: String,
+        #[structopt(long, default_value = DEFAULT_API_VERSION, parse(try_from_str=parse_version))]
+        version: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum DescribeCommand {
+    /// Show the policies currently in effect.
+    Policies,
+    /// Summarize the policies of each version.
+    PolicyVersions,
+    /// Show which version of an endpoint is running.
+    Endpoint {
+        /// Path of the endpoint, such as /dev/hello.
+        path: String,
+        /// Also print the code of the endpoint.
+        #[structopt(long)]
+        code: bool,
+    },
+}
+
+async fn describe_policies(server_url: String) -> Result<()> {
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+    let request = tonic::Request::new(ExportPoliciesRequest {});
+    let response = execute!(client.export_policies(request).await);
+
+    for version in response.versions {
+        println!("Version: {} {{", version.version);
+        for label in &version.labels {
+            println!("  Label: {} {{", label.label);
+            for pattern in &label.field_patterns {
+                println!("    field_pattern: {}", pattern);
+            }
+            for rule in &label.rules {
+                if rule.methods.is_empty() {
+                    println!("    {} except_uri: {}", rule.kind, rule.except_uri);
+                } else {
+                    println!(
+                        "    {} except_uri: {} methods: {}",
+                        rule.kind,
+                        rule.except_uri,
+                        rule.methods.join(", ")
+                    );
+                }
+            }
+            println!("  }}");
+        }
+        for auth in &version.user_authorization {
+            if auth.public {
+                println!("  Endpoint: {} public", auth.path);
+            } else {
+                println!("  Endpoint: {} users: {}", auth.path, auth.users);
+            }
+        }
+        println!("}}");
+    }
+    Ok(())
+}
+
+async fn describe_policy_versions(server_url: String) -> Result<()> {
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+    let request = tonic::Request::new(ListPolicyVersionsRequest {});
+    let response = execute!(client.list_policy_versions(request).await);
+
+    for version in response.versions {
+        println!(
+            "Version: {} labels: {} authorization rules: {} transforms: [{}]",
+            version.version,
+            version.label_count,
+            version.authorization_rule_count,
+            version.transforms.join(", ")
+        );
+    }
+    Ok(())
+}
+
+async fn describe_endpoint(server_url: String, path: String, code: bool) -> Result<()> {
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+    let request = tonic::Request::new(GetEndpointRequest { path: path.clone() });
+    let response = execute!(client.get_endpoint(request).await);
+
+    println!("Endpoint {} version {}", path, response.version);
+    if code {
+        println!("{}", response.code);
+    }
+    Ok(())
 }
 
 async fn delete<S: ToString>(server_url: String, version: S) -> Result<()> {
@@ -130,6 +259,80 @@ async fn populate(server_url: String, to_version: String, from_version: String)
     Ok(())
 }
 
+async fn reload_policies(server_url: String, version: String) -> Result<()> {
+    let manifest = read_manifest().context("Reading manifest file")?;
+    let mut policies = vec![];
+    for p in manifest.policies()? {
+        policies.push(PolicyUpdateRequest {
+            policy_config: read_to_string(p)?,
+        });
+    }
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+
+    let msg = execute!(
+        client
+            .reload_policies(tonic::Request::new(ReloadPoliciesRequest {
+                version: version.clone(),
+                policies,
+            }))
+            .await
+    );
+    println!("Policies of version {} reloaded", version);
+    for label in msg.labels {
+        println!("Policy defined for label {}", label);
+    }
+    Ok(())
+}
+
+async fn set_config(server_url: String, name: String, value: Option<String>) -> Result<()> {
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+
+    let value = value.map(|v| match serde_json::from_str::<serde_json::Value>(&v) {
+        Ok(_) => v,
+        Err(_) => serde_json::Value::String(v).to_string(),
+    });
+    execute!(
+        client
+            .set_config(tonic::Request::new(SetConfigRequest {
+                name: name.clone(),
+                value: value.clone(),
+            }))
+            .await
+    );
+    match value {
+        Some(value) => println!("Config {} set to {}", name, value),
+        None => println!("Config {} unset", name),
+    }
+    Ok(())
+}
+
+async fn set_named_query(
+    server_url: String,
+    version: String,
+    name: String,
+    file: Option<String>,
+) -> Result<()> {
+    let spec = file
+        .map(|file| fs::read_to_string(&file).with_context(|| format!("Reading {}", file)))
+        .transpose()?;
+    let mut client = ChiselRpcClient::connect(server_url).await?;
+
+    execute!(
+        client
+            .set_named_query(tonic::Request::new(SetNamedQueryRequest {
+                version,
+                name: name.clone(),
+                spec: spec.clone(),
+            }))
+            .await
+    );
+    match spec {
+        Some(_) => println!("Query {} set", name),
+        None => println!("Query {} unset", name),
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let opt = Opt::from_args();
@@ -148,7 +351,22 @@ async fn main() -> Result<()> {
             };
             create_project(&cwd, opts)?;
         }
-        Command::Describe => {
+        Command::Describe {
+            what: Some(DescribeCommand::Policies),
+        } => {
+            describe_policies(server_url).await?;
+        }
+        Command::Describe {
+            what: Some(DescribeCommand::PolicyVersions),
+        } => {
+            describe_policy_versions(server_url).await?;
+        }
+        Command::Describe {
+            what: Some(DescribeCommand::Endpoint { path, code }),
+        } => {
+            describe_endpoint(server_url, path, code).await?;
+        }
+        Command::Describe { what: None } => {
             let mut client = ChiselRpcClient::connect(server_url).await?;
             let request = tonic::Request::new(DescribeRequest {});
             let response = execute!(client.describe(request).await);
@@ -156,6 +374,18 @@ async fn main() -> Result<()> {
             for version_def in response.version_defs {
                 println!("Version: {} {{", version_def.version);
                 for def in &version_def.type_defs {
+                    for constraint in &def.unique_constraints {
+                        let fields = constraint
+                            .fields
+                            .iter()
+                            .map(|x| format!("\"{}\"", x))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        println!("  @uniqueTogether({})", fields);
+                    }
+                    if def.soft_delete {
+                        println!("  @softDelete");
+                    }
                     println!("  class {} {{", def.name);
                     for field in &def.field_defs {
                         let labels = if field.labels.is_empty() {
@@ -172,13 +402,27 @@ async fn main() -> Result<()> {
                             labels.pop();
                             format!("@labels({}) ", labels)
                         };
+                        let mut field_type = if field.allowed_values.is_empty() {
+                            field.field_type.clone()
+                        } else {
+                            field
+                                .allowed_values
+                                .iter()
+                                .map(|v| format!("\"{}\"", v))
+                                .collect::<Vec<_>>()
+                                .join(" | ")
+                        };
+                        if field.is_nullable {
+                            field_type.push_str(" | null");
+                        }
                         println!(
-                            "    {}{}{}{}: {}{};",
+                            "    {}{}{}{}{}: {}{};",
                             if field.is_unique { "@unique " } else { "" },
+                            if field.is_indexed { "@indexed " } else { "" },
                             labels,
                             field.name,
                             if field.is_optional { "?" } else { "" },
-                            field.field_type,
+                            field_type,
                             field
                                 .default_value
                                 .as_ref()
@@ -232,7 +476,7 @@ async fn main() -> Result<()> {
             create_project(path, opts)?;
         }
         Command::Start => {
-            let mut server = start_server()?;
+            let mut server = start_server(false)?;
             wait(server_url).await?;
             server.wait()?;
         }
@@ -277,6 +521,34 @@ async fn main() -> Result<()> {
         Command::Populate { version, from } => {
             populate(server_url, version, from).await?;
         }
+        Command::ReloadPolicies { version } => {
+            reload_policies(server_url, version).await?;
+        }
+        Command::Config {
+            cmd: ConfigCommand::Set { name, value },
+        } => {
+            set_config(server_url, name, Some(value)).await?;
+        }
+        Command::Config {
+            cmd: ConfigCommand::Unset { name },
+        } => {
+            set_config(server_url, name, None).await?;
+        }
+        Command::Query {
+            cmd:
+                QueryCommand::Set {
+                    name,
+                    file,
+                    version,
+                },
+        } => {
+            set_named_query(server_url, version, name, Some(file)).await?;
+        }
+        Command::Query {
+            cmd: QueryCommand::Unset { name, version },
+        } => {
+            set_named_query(server_url, version, name, None).await?;
+        }
     }
     Ok(())
 }
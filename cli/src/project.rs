@@ -69,6 +69,18 @@ pub(crate) struct Manifest {
     /// Enable or disable query optimization with the `chiselc` compiler.
     #[serde(default)]
     pub(crate) optimize: Optimize,
+    /// Vector of directories to scan for seed data.  Each file holds a JSON array of objects for the type it is
+    /// named after, like `Country.json`, which are stored while the type is empty.
+    #[serde(default)]
+    pub(crate) seeds: Vec<String>,
+    /// File whose default export wraps every endpoint of the version being applied. Applying a
+    /// project without one removes the middleware of that version from the server.
+    #[serde(default)]
+    pub(crate) middleware: Option<PathBuf>,
+    /// Keep the types of decorated entity fields, which `ChiselEntity.fieldTypes()` returns at
+    /// runtime. Only deno-style modules support it, and it turns off `chiselc` optimization.
+    #[serde(default)]
+    pub(crate) keep_field_types: bool,
 }
 
 impl Manifest {
@@ -111,6 +123,10 @@ impl Manifest {
         Self::dirs_to_paths(&self.policies)
     }
 
+    pub fn seeds(&self) -> anyhow::Result<Vec<PathBuf>> {
+        Self::dirs_to_paths(&self.seeds)
+    }
+
     fn dirs_to_paths(dirs: &[String]) -> anyhow::Result<Vec<PathBuf>> {
         let mut paths = vec![];
         for dir in dirs {
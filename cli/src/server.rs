@@ -9,7 +9,8 @@ use std::thread;
 use std::time::Duration;
 use tonic::transport::Channel;
 
-pub(crate) fn start_server() -> anyhow::Result<std::process::Child> {
+/// Starts `chiseld`, in dev mode if `dev_mode` is set.
+pub(crate) fn start_server(dev_mode: bool) -> anyhow::Result<std::process::Child> {
     println!("🚀 Thank you for your interest in the ChiselStrike beta! 🚀");
     println!();
     println!("⚠️  This software is for evaluation purposes only. Do not use it in production. ⚠️ ");
@@ -26,7 +27,11 @@ pub(crate) fn start_server() -> anyhow::Result<std::process::Child> {
     let mut cmd = std::env::current_exe()?;
     cmd.pop();
     cmd.push("chiseld");
-    let server = match std::process::Command::new(cmd.clone()).spawn() {
+    let mut command = std::process::Command::new(cmd.clone());
+    if dev_mode {
+        command.arg("--dev-mode");
+    }
+    let server = match command.spawn() {
         Ok(server) => server,
         Err(e) => {
             match e.kind() {
@@ -1,4 +1,4 @@
-use crate::chisel::{AddTypeRequest, FieldDefinition};
+use crate::chisel::{AddTypeRequest, FieldDefinition, UniqueConstraint};
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use compile::swc_common;
 use compile::swc_ecmascript;
@@ -12,7 +12,8 @@ use swc_common::{
 use swc_ecma_ast::PropName;
 use swc_ecma_ast::{
     ClassMember, ClassProp, Decl, Decorator, Expr, Ident, Lit, ModuleDecl, ModuleItem,
-    TsEntityName, TsKeywordTypeKind, TsType, TsTypeAnn,
+    TsEntityName, TsKeywordTypeKind, TsLit, TsLitType, TsType, TsTypeAnn,
+    TsUnionOrIntersectionType, TsUnionType,
 };
 use swc_ecma_parser::{lexer::Lexer, Parser, StringInput, Syntax, TsConfig};
 use swc_ecmascript::ast as swc_ecma_ast;
@@ -61,10 +62,72 @@ fn type_to_string(handler: &Handler, x: &TsType) -> Result<String> {
     }
 }
 
+/// The strings of a type like `"open" | "closed"`, which limits a string field to those values.
+fn string_literals(x: &TsType) -> Option<Vec<String>> {
+    match x {
+        TsType::TsLitType(TsLitType {
+            lit: TsLit::Str(s), ..
+        }) => Some(vec![s.value.to_string()]),
+        TsType::TsUnionOrIntersectionType(TsUnionOrIntersectionType::TsUnionType(union)) => {
+            let mut values = vec![];
+            for ty in &union.types {
+                values.extend(string_literals(ty)?);
+            }
+            Some(values)
+        }
+        _ => None,
+    }
+}
+
+/// Splits a type like `string | null` into the type without `null` and whether `null` was there, which makes the
+/// field nullable.
+fn without_null(x: &TsType) -> (TsType, bool) {
+    let is_null = |ty: &TsType| match ty {
+        TsType::TsKeywordType(kw) => kw.kind == TsKeywordTypeKind::TsNullKeyword,
+        _ => false,
+    };
+    if let TsType::TsUnionOrIntersectionType(TsUnionOrIntersectionType::TsUnionType(union)) = x {
+        if union.types.iter().any(|ty| is_null(ty)) {
+            let mut types: Vec<_> = union
+                .types
+                .iter()
+                .filter(|ty| !is_null(ty))
+                .cloned()
+                .collect();
+            let ty = if types.len() == 1 {
+                *types.remove(0)
+            } else {
+                TsType::TsUnionOrIntersectionType(TsUnionOrIntersectionType::TsUnionType(
+                    TsUnionType {
+                        span: union.span,
+                        types,
+                    },
+                ))
+            };
+            return (ty, true);
+        }
+    }
+    (x.clone(), false)
+}
+
 fn get_field_type(handler: &Handler, x: &Option<TsTypeAnn>) -> Result<String> {
     let t = x.clone().context("type ann temporarily mandatory")?;
+    let (ty, _) = without_null(&t.type_ann);
+
+    if string_literals(&ty).is_some() {
+        return Ok("string".into());
+    }
+    type_to_string(handler, &ty)
+}
+
+fn get_allowed_values(x: &Option<TsTypeAnn>) -> Vec<String> {
+    x.as_ref()
+        .and_then(|t| string_literals(&without_null(&t.type_ann).0))
+        .unwrap_or_default()
+}
 
-    type_to_string(handler, &t.type_ann)
+fn get_is_nullable(x: &Option<TsTypeAnn>) -> bool {
+    x.as_ref().map_or(false, |t| without_null(&t.type_ann).1)
 }
 
 fn lit_to_string(handler: &Handler, x: &Lit) -> Result<String> {
@@ -108,9 +171,10 @@ fn get_field_value(handler: &Handler, x: &Option<Box<Expr>>) -> Result<Option<(S
     }
 }
 
-fn get_type_decorators(handler: &Handler, x: &[Decorator]) -> Result<(Vec<String>, bool)> {
+fn get_type_decorators(handler: &Handler, x: &[Decorator]) -> Result<(Vec<String>, bool, bool)> {
     let mut output = vec![];
     let mut is_unique = false;
+    let mut is_indexed = false;
     for dec in x.iter() {
         match &*dec.expr {
             Expr::Call(call) => {
@@ -133,18 +197,60 @@ fn get_type_decorators(handler: &Handler, x: &[Decorator]) -> Result<(Vec<String
                 let name = ident_to_string(x);
                 ensure!(name != "labels", "expected a call-like decorator");
 
-                ensure!(
-                    name == "unique",
-                    format!("decorator '{}' is not supported by ChiselStrike", name)
-                );
-                is_unique = true;
+                match name.as_str() {
+                    "unique" => is_unique = true,
+                    "indexed" => is_indexed = true,
+                    _ => bail!("decorator '{}' is not supported by ChiselStrike", name),
+                }
             }
             z => {
                 return Err(swc_err(handler, z, "expected a call-like decorator"));
             }
         };
     }
-    Ok((output, is_unique))
+    Ok((output, is_unique, is_indexed))
+}
+
+fn get_class_decorators(
+    handler: &Handler,
+    x: &[Decorator],
+) -> Result<(Vec<UniqueConstraint>, bool)> {
+    let mut unique_constraints = vec![];
+    let mut soft_delete = false;
+    for dec in x.iter() {
+        let call = match &*dec.expr {
+            Expr::Call(call) => call,
+            Expr::Ident(x) if ident_to_string(x) == "softDelete" => {
+                soft_delete = true;
+                continue;
+            }
+            z => return Err(swc_err(handler, z, "expected a call-like decorator")),
+        };
+        let callee =
+            call.callee.clone().expr().ok_or_else(|| {
+                anyhow!("expected expression, got {:?} instead", call.callee.clone())
+            })?;
+        let name = get_ident_string(handler, &callee)?;
+        ensure!(
+            name == "uniqueTogether",
+            format!(
+                "class decorator '{}' is not supported by ChiselStrike",
+                name
+            )
+        );
+        let mut fields = vec![];
+        for arg in &call.args {
+            if let Some((field, ty)) = get_field_value(handler, &Some(arg.expr.clone()))? {
+                ensure!(
+                    ty == "string",
+                    "Only field names accepted by uniqueTogether"
+                );
+                fields.push(field);
+            }
+        }
+        unique_constraints.push(UniqueConstraint { fields });
+    }
+    Ok((unique_constraints, soft_delete))
 }
 
 fn validate_type_vec(type_vec: &[AddTypeRequest], valid_types: &BTreeSet<String>) -> Result<()> {
@@ -152,6 +258,8 @@ fn validate_type_vec(type_vec: &[AddTypeRequest], valid_types: &BTreeSet<String>
     builtin_types.insert("string");
     builtin_types.insert("number");
     builtin_types.insert("boolean");
+    builtin_types.insert("Date");
+    builtin_types.insert("JSONValue");
     builtin_types.insert("AuthUser");
 
     for t in type_vec {
@@ -177,15 +285,20 @@ fn parse_class_prop(x: &ClassProp, class_name: &str, handler: &Handler) -> Resul
 
     anyhow::ensure!(field_name != "id", "Creating a field with the name `id` is not supported. 😟\nBut don't worry! ChiselStrike creates an id field automatically, and you can access it in your endpoints as {}.id 🤩", class_name);
 
-    let (labels, is_unique) = get_type_decorators(handler, &x.decorators)?;
+    let (labels, is_unique, is_indexed) = get_type_decorators(handler, &x.decorators)?;
+    let allowed_values = get_allowed_values(&x.type_ann);
+    let is_nullable = get_is_nullable(&x.type_ann);
 
     Ok(FieldDefinition {
         name: field_name,
         is_optional,
+        is_nullable,
         is_unique,
+        is_indexed,
         default_value,
         field_type,
         labels,
+        allowed_values,
     })
 }
 
@@ -222,7 +335,15 @@ fn parse_class_decl<P: AsRef<Path>>(
                     _ => {}
                 }
             }
-            type_vec.push(AddTypeRequest { name, field_defs });
+            let (unique_constraints, soft_delete) =
+                get_class_decorators(handler, &x.class.decorators)?;
+            type_vec.push(AddTypeRequest {
+                name,
+                field_defs,
+                unique_constraints,
+                seeds: String::new(),
+                soft_delete,
+            });
         }
         z => {
             handler.span_err(z.span(), "Only class definitions allowed in the types file");
@@ -174,6 +174,23 @@ fn run_tests(opt: Opt, optimize: bool) -> bool {
                             "CHISELD_RPC_HOST".into(),
                             format!("127.0.0.1:{}", 50051 + i),
                         ),
+                        // For tests that start a second server, with $SECOND_CHISELD.
+                        (
+                            "SECOND_CHISEL".into(),
+                            format!("{} --rpc-addr http://127.0.0.1:{}", chisel(), 60051 + i),
+                        ),
+                        (
+                            "SECOND_CHISELD_HOST".into(),
+                            format!("127.0.0.1:{}", 18080 + i),
+                        ),
+                        (
+                            "SECOND_CHISELD_INTERNAL".into(),
+                            format!("127.0.0.1:{}", 19090 + i),
+                        ),
+                        (
+                            "SECOND_CHISELD_RPC_HOST".into(),
+                            format!("127.0.0.1:{}", 60051 + i),
+                        ),
                     ])
                 },
             )
@@ -5,7 +5,25 @@ set -e
 # Enables backtraces on panic and anyhow errors.
 export RUST_BACKTRACE=1
 
+# Tests that need a server with flags of its own run "$SECOND_CHISELD <flags> &", which
+# comes back here. The server listens on the ports the harness set aside for it, on a
+# database of the same kind as the shared one's, and is reached with $SECOND_CHISEL.
+if [ "x$1" == "xsecond-chiseld" ]; then
+    shift
+    if [ "x$TEST_DATABASE" == "xpostgres" ]; then
+        psql "$DATABASE_URL_PREFIX" -c "CREATE DATABASE $SECOND_DATADB" > /dev/null
+        DB_URL="$DATABASE_URL_PREFIX/$SECOND_DATADB"
+    else
+        DB_URL="sqlite://$TEMPDIR/second-chiseld.db?mode=rwc"
+    fi
+    echo $$ > "$TEMPDIR/second-chiseld.pid"
+    exec $CHISELD --db-uri "$DB_URL" --api-listen-addr "$SECOND_CHISELD_HOST" \
+        --internal-routes-listen-addr "$SECOND_CHISELD_INTERNAL" \
+        --rpc-listen-addr "$SECOND_CHISELD_RPC_HOST" "$@"
+fi
+
 export TEMPDIR=$(mktemp -d)
+export SECOND_CHISELD="$0 second-chiseld"
 
 cwd=$(pwd)
 
@@ -27,6 +45,7 @@ if [ "x$TEST_DATABASE" == "xpostgres" ]; then
     psql "$DATABASE_URL_PREFIX" -c "CREATE DATABASE $DATADB"
 
     DB_URL="$DATABASE_URL_PREFIX/$DATADB"
+    export SECOND_DATADB="${DATADB}_second"
 else
     DB_URL="sqlite://$TEMPDIR/chiseld.db?mode=rwc"
 fi
@@ -37,9 +56,18 @@ PID=$!
 function cleanup() {
     kill $PID
     wait $PID
+    # The second server isn't our child, so wait for it to go away before dropping its database.
+    if [ -f "$TEMPDIR/second-chiseld.pid" ]; then
+        SECOND_PID=$(cat "$TEMPDIR/second-chiseld.pid")
+        kill $SECOND_PID 2> /dev/null || true
+        while kill -0 $SECOND_PID 2> /dev/null; do
+            sleep 0.1
+        done
+    fi
     rm -rf "$TEMPDIR"
     if [ "x$TEST_DATABASE" == "xpostgres" ]; then
         psql "$DATABASE_URL_PREFIX" -c "DROP DATABASE $DATADB"
+        psql "$DATABASE_URL_PREFIX" -c "DROP DATABASE IF EXISTS $SECOND_DATADB"
     fi
 }
 
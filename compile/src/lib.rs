@@ -10,18 +10,93 @@ use swc_common::{
     errors::{emitter, Handler},
     source_map::FileName,
     sync::Lrc,
-    SourceMap,
+    SourceMap, Spanned, DUMMY_SP,
+};
+use swc_ecma_ast::{
+    Class, ClassMember, ClassProp, Expr, Ident, KeyValueProp, Lit, ObjectLit, Prop, PropName,
+    PropOrSpread, Str,
 };
 use swc_ecma_codegen::{text_writer::JsWriter, Emitter};
 use swc_ecma_parser::{lexer::Lexer, Parser, StringInput, Syntax};
-use swc_ecma_visit::FoldWith;
+use swc_ecma_visit::{Fold, FoldWith};
 pub use swc_ecmascript;
+use swc_ecmascript::ast as swc_ecma_ast;
 use swc_ecmascript::codegen as swc_ecma_codegen;
 use swc_ecmascript::parser as swc_ecma_parser;
 use swc_ecmascript::transforms::typescript as swc_ecma_transforms_typescript;
 use swc_ecmascript::visit as swc_ecma_visit;
 
-pub fn compile_ts_code(code: String) -> Result<String> {
+/// Name of the static property that `keep_field_types` adds to classes.
+pub const FIELD_TYPES_PROPERTY: &str = "__fieldTypes";
+
+#[derive(Clone, Debug, Default)]
+pub struct CompileOptions {
+    /// Keep the types of decorated class fields, which are otherwise stripped with the rest of
+    /// the TypeScript types. Each class with such fields gets a static `__fieldTypes` object
+    /// that maps their names to their types, as spelled in the source.
+    pub keep_field_types: bool,
+}
+
+/// Adds the `__fieldTypes` property to classes with decorated fields.
+struct FieldTypes {
+    cm: Lrc<SourceMap>,
+}
+
+impl Fold for FieldTypes {
+    fn fold_class(&mut self, class: Class) -> Class {
+        let mut class = class.fold_children_with(self);
+        let mut props = vec![];
+        for member in &class.body {
+            let prop = match member {
+                ClassMember::ClassProp(prop) if !prop.decorators.is_empty() => prop,
+                _ => continue,
+            };
+            let (name, type_ann) = match (&prop.key, &prop.type_ann) {
+                (PropName::Ident(name), Some(type_ann)) => (name, type_ann),
+                _ => continue,
+            };
+            let ty = match self.cm.span_to_snippet(type_ann.type_ann.span()) {
+                Ok(ty) => ty,
+                Err(_) => continue,
+            };
+            props.push(PropOrSpread::Prop(Box::new(Prop::KeyValue(KeyValueProp {
+                key: PropName::Ident(name.clone()),
+                value: Box::new(Expr::Lit(Lit::Str(Str {
+                    span: DUMMY_SP,
+                    value: ty.into(),
+                    raw: None,
+                }))),
+            }))));
+        }
+        if !props.is_empty() {
+            class.body.push(ClassMember::ClassProp(ClassProp {
+                span: DUMMY_SP,
+                key: PropName::Ident(Ident {
+                    span: DUMMY_SP,
+                    sym: FIELD_TYPES_PROPERTY.into(),
+                    optional: false,
+                }),
+                value: Some(Box::new(Expr::Object(ObjectLit {
+                    span: DUMMY_SP,
+                    props,
+                }))),
+                type_ann: None,
+                is_static: true,
+                decorators: vec![],
+                accessibility: None,
+                is_abstract: false,
+                is_optional: false,
+                is_override: false,
+                readonly: false,
+                declare: false,
+                definite: false,
+            }));
+        }
+        class
+    }
+}
+
+pub fn compile_ts_code(code: String, opts: &CompileOptions) -> Result<String> {
     #[derive(Clone)]
     struct ErrorBuffer {
         inner: Arc<std::sync::Mutex<Vec<u8>>>,
@@ -87,6 +162,12 @@ pub fn compile_ts_code(code: String) -> Result<String> {
         anyhow!("Parse failed: {}", err_buf.get())
     })?;
 
+    let module = if opts.keep_field_types {
+        module.fold_with(&mut FieldTypes { cm: cm.clone() })
+    } else {
+        module
+    };
+
     // Remove typescript types
     let globals = Globals::default();
     let module = GLOBALS.set(&globals, || {
@@ -108,3 +189,40 @@ pub fn compile_ts_code(code: String) -> Result<String> {
     }
     Ok(String::from_utf8_lossy(&buf).to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{compile_ts_code, CompileOptions};
+
+    const ENTITY: &str = r#"
+function labels(...names: string[]) {
+    return (target: any, key: string) => {};
+}
+
+export class Person {
+    @labels("pii") name: string = "";
+    @labels("pii", "contact") emails: string[] = [];
+    age: number = 0;
+}
+"#;
+
+    #[test]
+    fn strips_field_types() {
+        let js = compile_ts_code(ENTITY.to_string(), &Default::default()).unwrap();
+        assert!(!js.contains("__fieldTypes"), "{}", js);
+        assert!(!js.contains("string[]"), "{}", js);
+    }
+
+    #[test]
+    fn keeps_field_types() {
+        let opts = CompileOptions {
+            keep_field_types: true,
+        };
+        let js = compile_ts_code(ENTITY.to_string(), &opts).unwrap();
+        assert!(js.contains("static __fieldTypes = {"), "{}", js);
+        assert!(js.contains("name: \"string\""), "{}", js);
+        assert!(js.contains("emails: \"string[]\""), "{}", js);
+        // Only decorated fields are described.
+        assert!(!js.contains("age: \"number\""), "{}", js);
+    }
+}
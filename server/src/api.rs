@@ -1,26 +1,38 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
+use crate::deno::JsException;
 use crate::prefix_map::PrefixMap;
+use crate::telemetry;
+use crate::tls::{TlsConfig, TlsIncoming};
 use anyhow::{Error, Result};
 use futures::future::LocalBoxFuture;
 use futures::ready;
-use futures::stream::Stream;
-use hyper::body::HttpBody;
-use hyper::header::HeaderValue;
+use futures::stream::{Stream, StreamExt};
+use hyper::body::{HttpBody, SizeHint};
+use hyper::header::{
+    HeaderName, HeaderValue, ACCEPT, CONNECTION, CONTENT_TYPE, RETRY_AFTER, TE, TRAILER,
+    TRANSFER_ENCODING, UPGRADE,
+};
+use hyper::server::accept;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{HeaderMap, Request, Response, Server, StatusCode};
 use socket2::{Domain, Protocol, Socket, Type};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::Infallible;
 use std::convert::TryFrom;
+use std::future::Future;
 use std::io::Cursor;
-use std::net::ToSocketAddrs;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::path::Path;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::server::TlsStream;
+use uuid::Uuid;
 
 type JsStream = Pin<Box<dyn Stream<Item = Result<Box<[u8]>>>>>;
 
@@ -29,6 +41,83 @@ pub(crate) enum Body {
     Stream(JsStream),
 }
 
+impl Body {
+    /// Keeps only the bytes from `start` to `end`, inclusive. A streamed body is still read to the end, so that
+    /// whatever waits on it finishes.
+    pub(crate) fn slice(self, start: u64, end: u64) -> Body {
+        match self {
+            Body::Const(data) => Body::Const(
+                data.map(|data| slice_chunk(&data, 0, start, end).into())
+                    .filter(|data: &Box<[u8]>| !data.is_empty()),
+            ),
+            Body::Stream(stream) => {
+                let mut offset = 0;
+                Body::Stream(Box::pin(stream.filter_map(move |chunk| {
+                    let chunk = chunk.map(|chunk| {
+                        let sliced: Box<[u8]> = slice_chunk(&chunk, offset, start, end).into();
+                        offset += chunk.len() as u64;
+                        sliced
+                    });
+                    futures::future::ready(match chunk {
+                        Ok(chunk) if chunk.is_empty() => None,
+                        chunk => Some(chunk),
+                    })
+                })))
+            }
+        }
+    }
+}
+
+/// The part of `chunk`, which starts at `offset` in a body, that is within bytes `start` to `end` of the body.
+fn slice_chunk(chunk: &[u8], offset: u64, start: u64, end: u64) -> &[u8] {
+    let len = chunk.len() as u64;
+    let from = start.saturating_sub(offset).min(len);
+    let to = (end + 1).saturating_sub(offset).min(len).max(from);
+    &chunk[from as usize..to as usize]
+}
+
+/// The part of a response that a `Range` header asks for.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ByteRange {
+    /// Bytes `start` to `end`, inclusive, which are all in the response.
+    Bytes { start: u64, end: u64 },
+    /// A range that starts past the end of the response.
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header asking for part of a response of `length` bytes.
+///
+/// Only a single range of bytes is supported, like `bytes=0-99`, `bytes=100-` or the last 100 bytes as
+/// `bytes=-100`. Returns None for anything else, including malformed headers, which are then ignored.
+pub(crate) fn parse_range(header: &str, length: u64) -> Option<ByteRange> {
+    let (first, last) = header.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    let range = if first.is_empty() {
+        let suffix: u64 = last.parse().ok()?;
+        if suffix == 0 || length == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        (length.saturating_sub(suffix), length - 1)
+    } else {
+        let first: u64 = first.parse().ok()?;
+        let last = match last {
+            "" => u64::MAX,
+            last => last.parse().ok()?,
+        };
+        if last < first {
+            return None;
+        }
+        if first >= length {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        (first, last.min(length - 1))
+    };
+    Some(ByteRange::Bytes {
+        start: range.0,
+        end: range.1,
+    })
+}
+
 impl From<String> for Body {
     fn from(a: String) -> Self {
         Body::Const(Some(a.into_boxed_str().into_boxed_bytes()))
@@ -58,6 +147,20 @@ impl HttpBody for Body {
         Poll::Ready(r)
     }
 
+    // An empty body lets hyper answer with `Content-Length: 0`, or nothing at all for statuses
+    // like 204 that have no body, instead of an empty chunked body.
+    fn is_end_stream(&self) -> bool {
+        matches!(self, Body::Const(None))
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            Body::Const(None) => SizeHint::with_exact(0),
+            Body::Const(Some(data)) => SizeHint::with_exact(data.len() as u64),
+            Body::Stream(_) => SizeHint::default(),
+        }
+    }
+
     fn poll_trailers(
         self: Pin<&mut Self>,
         _: &mut Context<'_>,
@@ -155,18 +258,239 @@ pub(crate) struct ApiService {
     // have to manually implement Send (which is unsafe).
     paths: Mutex<PrefixMap<RouteFn>>,
     info: Mutex<ApiInfoMap>,
+    /// API version of requests that name none.
+    default_version: Option<String>,
+    /// Header with the id of a request, which is echoed in its response.
+    request_id_header: HeaderName,
+    /// Proxies whose X-Forwarded-For header is believed about the client of a request.
+    trusted_proxies: Vec<IpAddr>,
+    /// Whether error responses show the stack trace of the JavaScript exception behind them.
+    dev_mode: bool,
+}
+
+/// Header listing the clients a request was forwarded for by proxies, each proxy appending the one it got it from.
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+
+/// Address of the other end of the connection a request came on.
+#[derive(Clone, Copy)]
+struct PeerAddr(SocketAddr);
+
+/// Address of the client that sent a request, as far as can be told.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientAddr(pub(crate) IpAddr);
+
+/// Works out the client of a request received from `peer`. Proxies in `trusted_proxies` are believed about who they
+/// forwarded the request for, so the client is the rightmost address in `forwarded_for` that isn't one of theirs.
+/// Anything left of that was made up by the client and is ignored.
+fn client_addr(peer: IpAddr, forwarded_for: &str, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    for addr in forwarded_for.rsplit(',') {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+        match addr.trim().parse() {
+            Ok(addr) => client = addr,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+/// Header naming the API version a request is for, as an alternative to the leading path segment.
+pub(crate) const VERSION_HEADER: &str = "ChiselStrike-Version";
+
+/// Works out the API version `path_and_query` addresses and returns it with that version as its leading segment, or
+/// None if the request names an unknown version.
+///
+/// A version given in the VERSION_HEADER header is prepended unless the path already starts with it; a path that
+/// starts with a different version is unknown rather than nested under the header's one. Without the header, a path that doesn't start with a version gets the default one, if any. Reserved paths, which start with
+/// `__`, and the root are left alone.
+fn versioned_path(
+    path_and_query: &str,
+    header: Option<&str>,
+    is_version: impl Fn(&str) -> bool,
+    default_version: Option<&str>,
+) -> Option<String> {
+    let first_segment = path_and_query
+        .trim_start_matches('/')
+        .split(&['/', '?'])
+        .next()
+        .unwrap_or_default();
+    let version = match header {
+        Some(version) if !is_version(version) => return None,
+        Some(version) => version,
+        None if is_version(first_segment)
+            || first_segment.is_empty()
+            || first_segment.starts_with("__") =>
+        {
+            return Some(path_and_query.to_owned())
+        }
+        None => match default_version {
+            Some(version) => version,
+            None => return Some(path_and_query.to_owned()),
+        },
+    };
+    if first_segment == version {
+        Some(path_and_query.to_owned())
+    } else if header.is_some() && is_version(first_segment) {
+        None
+    } else {
+        Some(format!("/{}{}", version, path_and_query))
+    }
+}
+
+/// Headers that are about the connection rather than the response, which only hyper may set.
+const HOP_BY_HOP_HEADERS: [HeaderName; 5] = [CONNECTION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE];
+
+/// Response headers that endpoints may not set: the hop-by-hop ones, plus any given with
+/// `--deny-response-header`.
+#[derive(Clone, Debug)]
+pub(crate) struct DeniedHeaders(HashSet<HeaderName>);
+
+impl DeniedHeaders {
+    pub(crate) fn new(extra: impl IntoIterator<Item = HeaderName>) -> Self {
+        let mut denied: HashSet<_> = HOP_BY_HOP_HEADERS.into_iter().collect();
+        // Not standard, but still about the connection.
+        denied.insert(HeaderName::from_static("keep-alive"));
+        denied.insert(HeaderName::from_static("proxy-connection"));
+        denied.extend(extra);
+        Self(denied)
+    }
+
+    /// Whether an endpoint may set header `name` in a response with `status`.  Accepting a
+    /// WebSocket upgrade takes the `Connection` and `Upgrade` headers.
+    pub(crate) fn allows(&self, name: &HeaderName, status: StatusCode) -> bool {
+        if status == StatusCode::SWITCHING_PROTOCOLS && (name == CONNECTION || name == UPGRADE) {
+            return true;
+        }
+        !self.0.contains(name)
+    }
+}
+
+/// Bounds on the headers of an endpoint's response, so that a runaway endpoint can't have the
+/// server build an enormous response head, which proxies in front of it would likely reject anyway.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ResponseHeaderLimits {
+    /// Most headers a response may have.
+    pub(crate) count: usize,
+    /// Most bytes that the names and values of the headers of a response may add up to.
+    pub(crate) bytes: usize,
+}
+
+impl ResponseHeaderLimits {
+    /// Checks the number of headers of a response, before any of them is copied.
+    pub(crate) fn check_count(&self, count: usize) -> Result<()> {
+        anyhow::ensure!(
+            count <= self.count,
+            "Endpoint returned {} response headers, more than the limit of {}",
+            count,
+            self.count
+        );
+        Ok(())
+    }
+
+    /// Adds a header of `len` bytes to `total`, the size of the headers of a response so far.
+    pub(crate) fn add_bytes(&self, total: &mut usize, len: usize) -> Result<()> {
+        *total += len;
+        anyhow::ensure!(
+            *total <= self.bytes,
+            "Endpoint returned response headers of more than {} bytes",
+            self.bytes
+        );
+        Ok(())
+    }
+}
+
+/// Formats of error responses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ErrorFormat {
+    Json,
+    Text,
+    Html,
+}
+
+/// Picks the error format a client prefers, per the quality values in its Accept header. JSON wins ties and
+/// is the default.
+fn error_format(accept: Option<&str>) -> ErrorFormat {
+    let mut best = (ErrorFormat::Json, 0.0);
+    for range in accept.unwrap_or_default().split(',') {
+        let mut params = range.split(';');
+        let format = match params.next().unwrap_or_default().trim() {
+            "application/json" | "application/*" | "*/*" => ErrorFormat::Json,
+            "text/plain" | "text/*" => ErrorFormat::Text,
+            "text/html" => ErrorFormat::Html,
+            _ => continue,
+        };
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if q > best.1 || (q == best.1 && format == ErrorFormat::Json) {
+            best = (format, q);
+        }
+    }
+    best.0
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 impl ApiService {
-    pub(crate) fn new(mut info: ApiInfoMap) -> Self {
+    pub(crate) fn new(
+        mut info: ApiInfoMap,
+        default_version: Option<String>,
+        request_id_header: HeaderName,
+        trusted_proxies: Vec<IpAddr>,
+        dev_mode: bool,
+    ) -> Self {
         info.insert("__chiselstrike".into(), ApiInfo::chiselstrike());
         info.insert("".into(), ApiInfo::all_routes());
         Self {
             paths: Default::default(),
             info: Mutex::new(info),
+            default_version,
+            request_id_header,
+            trusted_proxies,
+            dev_mode,
         }
     }
 
+    /// Rewrites the path of `req` to start with the API version it is for, so that routing and policies see it.
+    /// Returns None if the request names an unknown version.
+    fn with_version(
+        &self,
+        mut req: Request<hyper::Body>,
+        header: Option<&str>,
+    ) -> Result<Option<Request<hyper::Body>>> {
+        let path_and_query = match req.uri().path_and_query() {
+            Some(pq) => pq.as_str(),
+            None => "/",
+        };
+        let path_and_query = {
+            let info = self.info.lock().unwrap();
+            let is_version = |v: &str| !v.is_empty() && info.contains_key(Path::new(v));
+            match versioned_path(
+                path_and_query,
+                header,
+                is_version,
+                self.default_version.as_deref(),
+            ) {
+                Some(versioned) => versioned,
+                None => return Ok(None),
+            }
+        };
+        if path_and_query != req.uri().path_and_query().map_or("/", |pq| pq.as_str()) {
+            let mut parts = req.uri().clone().into_parts();
+            parts.path_and_query = Some(path_and_query.parse()?);
+            *req.uri_mut() = hyper::Uri::from_parts(parts)?;
+        }
+        Ok(Some(req))
+    }
+
     /// Finds the right RouteFn for this request.
     fn find_route_fn<S: AsRef<Path>>(&self, request: S) -> Option<RouteFn> {
         match self.paths.lock().unwrap().longest_prefix(request.as_ref()) {
@@ -212,17 +536,74 @@ impl ApiService {
     }
 
     async fn route_impl(&self, req: Request<hyper::Body>) -> Result<Response<Body>> {
+        let header = match req.headers().get(VERSION_HEADER).map(|h| h.to_str()) {
+            Some(Ok(header)) => Some(header.to_owned()),
+            Some(Err(_)) => {
+                return ApiService::bad_request(&format!(
+                    "{} header must be ASCII\n",
+                    VERSION_HEADER
+                ))
+            }
+            None => None,
+        };
+        let req = match self.with_version(req, header.as_deref())? {
+            Some(req) => req,
+            None => return ApiService::not_found(),
+        };
         if let Some(route_fn) = self.find_route_fn(req.uri().path()) {
             return route_fn(req).await;
         }
         ApiService::not_found()
     }
 
-    async fn route(&self, req: Request<hyper::Body>) -> hyper::http::Result<Response<Body>> {
-        match self.route_impl(req).await {
-            Ok(val) => Ok(val),
-            Err(err) => Self::internal_error(err),
+    async fn route(&self, mut req: Request<hyper::Body>) -> hyper::http::Result<Response<Body>> {
+        // Endpoints see the id in the request headers, whether it came from the client or not.
+        let request_id = req
+            .headers_mut()
+            .entry(&self.request_id_header)
+            .or_insert_with(|| HeaderValue::from_str(&Uuid::new_v4().to_string()).unwrap())
+            .clone();
+        let span = telemetry::start_request(&mut req, request_id.to_str().unwrap_or_default());
+        if let Some(&PeerAddr(peer)) = req.extensions().get::<PeerAddr>() {
+            let forwarded_for = req
+                .headers()
+                .get_all(X_FORWARDED_FOR)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(",");
+            let client = client_addr(peer.ip(), &forwarded_for, &self.trusted_proxies);
+            req.extensions_mut().insert(ClientAddr(client));
         }
+        let accept = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|a| a.to_str().ok())
+            .map(str::to_owned);
+        let mut res = match self.route_impl(req).await {
+            Ok(val) => val,
+            Err(err) => {
+                span.set_error(&err);
+                let trace = JsException::trace_of(&err);
+                match trace {
+                    Some(trace) => warn!("request {:?} failed: {:?}\n{}", request_id, err, trace),
+                    None => warn!("request {:?} failed: {:?}", request_id, err),
+                }
+                let trace = trace.filter(|_| self.dev_mode);
+                Self::internal_error(&err, trace, error_format(accept.as_deref()))?
+            }
+        };
+        res.headers_mut()
+            .insert(self.request_id_header.clone(), request_id);
+        span.set_status(res.status().as_u16());
+        // A streamed response is still being handled until its stream ends.
+        Ok(res.map(|body| match body {
+            Body::Stream(stream) => Body::Stream(Box::pin(stream.map(move |chunk| {
+                let _ = &span;
+                chunk
+            }))),
+            body => body,
+        }))
     }
 
     pub(crate) fn not_found() -> Result<Response<Body>> {
@@ -231,10 +612,42 @@ impl ApiService {
             .body(Body::default())?)
     }
 
-    fn internal_error(err: anyhow::Error) -> hyper::http::Result<Response<Body>> {
+    /// Builds the response for a request that failed with `err`, showing `trace` after the error
+    /// if given.
+    fn internal_error(
+        err: &anyhow::Error,
+        trace: Option<&str>,
+        format: ErrorFormat,
+    ) -> hyper::http::Result<Response<Body>> {
+        let message = match trace {
+            Some(trace) => format!("{:?}\n{}", err, trace),
+            None => format!("{:?}", err),
+        };
+        let (content_type, body) = match format {
+            ErrorFormat::Json => (
+                "application/json",
+                format!("{}\n", serde_json::json!({ "error": message })),
+            ),
+            ErrorFormat::Text => ("text/plain; charset=utf-8", format!("{}\n", message)),
+            ErrorFormat::Html => (
+                "text/html; charset=utf-8",
+                format!(
+                    "<!DOCTYPE html>\n<html><head><title>Internal Server Error</title></head>\n\
+                     <body><h1>Internal Server Error</h1><pre>{}</pre></body></html>\n",
+                    escape_html(&message)
+                ),
+            ),
+        };
         Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(format!("{:?}\n", err).into())
+            .header(CONTENT_TYPE, content_type)
+            .body(body.into())
+    }
+
+    pub(crate) fn bad_request(err: &str) -> Result<Response<Body>> {
+        Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(err.to_string().into())?)
     }
 
     pub(crate) fn forbidden(err: &str) -> Result<Response<Body>> {
@@ -242,6 +655,13 @@ impl ApiService {
             .status(StatusCode::FORBIDDEN)
             .body(err.to_string().into())?)
     }
+
+    pub(crate) fn service_unavailable(retry_after_secs: u64) -> Result<Response<Body>> {
+        Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(RETRY_AFTER, retry_after_secs.to_string())
+            .body("Too many concurrent requests\n".to_string().into())?)
+    }
 }
 
 #[derive(Clone)]
@@ -265,6 +685,7 @@ where
 pub(crate) fn spawn(
     api: Rc<ApiService>,
     listen_addr: String,
+    tls: Option<Arc<TlsConfig>>,
     shutdown: async_channel::Receiver<()>,
 ) -> Result<Vec<tokio::task::JoinHandle<Result<(), hyper::Error>>>> {
     let mut tasks = Vec::new();
@@ -284,32 +705,55 @@ pub(crate) fn spawn(
         sk.bind(&addr)?;
         sk.listen(1024)?;
 
-        let make_svc = make_service_fn(move |_conn| {
+        // Each connection gets a service that tags its requests with the address they came from.
+        let new_service = move |peer: Option<SocketAddr>| {
             let api = api.clone();
             async move {
-                Ok::<_, Infallible>(service_fn(move |req| {
+                Ok::<_, Infallible>(service_fn(move |mut req| {
                     let api = api.clone();
+                    if let Some(peer) = peer {
+                        req.extensions_mut().insert(PeerAddr(peer));
+                    }
                     async move { api.route(req).await }
                 }))
             }
-        });
-        let server = Server::from_tcp(sk.into_tcp_listener())?
-            .executor(LocalExec)
-            .serve(make_svc);
-        let task = tokio::task::spawn_local(async move {
-            let ret = server
-                .with_graceful_shutdown(async {
-                    shutdown.recv().await.ok();
-                })
-                .await;
-            debug!("hyper shutdown");
-            ret
-        });
+        };
+        let shutdown = async move {
+            shutdown.recv().await.ok();
+        };
+        let listener = sk.into_tcp_listener();
+        let task = match &tls {
+            None => {
+                let make_svc =
+                    make_service_fn(move |conn: &AddrStream| new_service(Some(conn.remote_addr())));
+                let server = Server::from_tcp(listener)?
+                    .executor(LocalExec)
+                    .serve(make_svc);
+                tokio::task::spawn_local(serve(server.with_graceful_shutdown(shutdown)))
+            }
+            Some(tls) => {
+                listener.set_nonblocking(true)?;
+                let incoming = TlsIncoming::new(TcpListener::from_std(listener)?, tls.clone());
+                let make_svc = make_service_fn(move |conn: &TlsStream<TcpStream>| {
+                    new_service(conn.get_ref().0.peer_addr().ok())
+                });
+                let server = Server::builder(accept::from_stream(incoming))
+                    .executor(LocalExec)
+                    .serve(make_svc);
+                tokio::task::spawn_local(serve(server.with_graceful_shutdown(shutdown)))
+            }
+        };
         tasks.push(task);
     }
     Ok(tasks)
 }
 
+async fn serve(server: impl Future<Output = Result<(), hyper::Error>>) -> Result<(), hyper::Error> {
+    let ret = server.await;
+    debug!("hyper shutdown");
+    ret
+}
+
 pub(crate) fn response_template() -> http::response::Builder {
     Response::builder()
         // TODO: Let the user control this.
@@ -320,3 +764,205 @@ pub(crate) fn response_template() -> http::response::Builder {
         )
         .header("Access-Control-Allow-Headers", "Content-Type,ChiselUID")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        client_addr, error_format, parse_range, versioned_path, Body, ByteRange, DeniedHeaders,
+        ErrorFormat, ResponseHeaderLimits,
+    };
+    use anyhow::Result;
+    use futures::StreamExt;
+    use hyper::body::HttpBody;
+    use hyper::header::{HeaderName, CONNECTION, CONTENT_TYPE, TRANSFER_ENCODING};
+    use hyper::StatusCode;
+    use std::net::IpAddr;
+
+    fn route(path: &str, header: Option<&str>, default_version: Option<&str>) -> Option<String> {
+        let is_version = |v: &str| ["v1", "v2", "__chiselstrike"].contains(&v);
+        versioned_path(path, header, is_version, default_version)
+    }
+
+    #[test]
+    fn version_from_path() {
+        assert_eq!(route("/v1/hello", None, None).as_deref(), Some("/v1/hello"));
+        assert_eq!(
+            route("/v2/hello?x=1", None, Some("v1")).as_deref(),
+            Some("/v2/hello?x=1")
+        );
+        assert_eq!(route("/hello", None, None).as_deref(), Some("/hello"));
+    }
+
+    #[test]
+    fn version_from_header() {
+        assert_eq!(
+            route("/hello?x=1", Some("v2"), Some("v1")).as_deref(),
+            Some("/v2/hello?x=1")
+        );
+        assert_eq!(
+            route("/v2/hello", Some("v2"), None).as_deref(),
+            Some("/v2/hello")
+        );
+        assert_eq!(route("/hello", Some("v3"), None), None);
+        // A path that already names another version isn't nested under the header's one.
+        assert_eq!(route("/v1/hello", Some("v2"), None), None);
+        assert_eq!(
+            route("/hello/v1", Some("v2"), None).as_deref(),
+            Some("/v2/hello/v1")
+        );
+    }
+
+    #[test]
+    fn default_version() {
+        assert_eq!(
+            route("/hello", None, Some("v1")).as_deref(),
+            Some("/v1/hello")
+        );
+        assert_eq!(
+            route("/hello/v2", None, Some("v1")).as_deref(),
+            Some("/v1/hello/v2")
+        );
+        // Reserved routes and the root don't belong to a version.
+        assert_eq!(
+            route("/__schema", None, Some("v1")).as_deref(),
+            Some("/__schema")
+        );
+        assert_eq!(
+            route("/__chiselstrike/auth/users", None, Some("v1")).as_deref(),
+            Some("/__chiselstrike/auth/users")
+        );
+        assert_eq!(route("/", None, Some("v1")).as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn error_format_negotiation() {
+        assert_eq!(error_format(None), ErrorFormat::Json);
+        assert_eq!(error_format(Some("*/*")), ErrorFormat::Json);
+        assert_eq!(error_format(Some("text/plain")), ErrorFormat::Text);
+        assert_eq!(
+            error_format(Some("text/html,application/xhtml+xml,*/*;q=0.8")),
+            ErrorFormat::Html
+        );
+        assert_eq!(
+            error_format(Some("text/plain;q=0.5, application/json")),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            error_format(Some("text/html, text/plain")),
+            ErrorFormat::Html
+        );
+        assert_eq!(error_format(Some("image/png")), ErrorFormat::Json);
+    }
+
+    #[test]
+    fn denied_headers() {
+        let denied = DeniedHeaders::new([HeaderName::from_static("x-internal")]);
+        let ok = StatusCode::OK;
+        assert!(denied.allows(&CONTENT_TYPE, ok));
+        assert!(denied.allows(&HeaderName::from_static("x-custom"), ok));
+        assert!(!denied.allows(&TRANSFER_ENCODING, ok));
+        assert!(!denied.allows(&CONNECTION, ok));
+        assert!(!denied.allows(&HeaderName::from_static("keep-alive"), ok));
+        assert!(!denied.allows(&HeaderName::from_static("x-internal"), ok));
+
+        let upgrade = StatusCode::SWITCHING_PROTOCOLS;
+        assert!(denied.allows(&CONNECTION, upgrade));
+        assert!(denied.allows(&HeaderName::from_static("upgrade"), upgrade));
+        assert!(!denied.allows(&TRANSFER_ENCODING, upgrade));
+    }
+
+    #[test]
+    fn response_header_limits() {
+        let limits = ResponseHeaderLimits {
+            count: 2,
+            bytes: 10,
+        };
+        assert!(limits.check_count(2).is_ok());
+        assert_eq!(
+            limits.check_count(3).unwrap_err().to_string(),
+            "Endpoint returned 3 response headers, more than the limit of 2"
+        );
+
+        let mut total = 0;
+        assert!(limits.add_bytes(&mut total, 6).is_ok());
+        assert!(limits.add_bytes(&mut total, 4).is_ok());
+        assert_eq!(
+            limits.add_bytes(&mut total, 1).unwrap_err().to_string(),
+            "Endpoint returned response headers of more than 10 bytes"
+        );
+    }
+
+    #[test]
+    fn client_from_forwarded_for() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let peer = ip("10.0.0.1");
+        assert_eq!(client_addr(peer, "", &proxies), peer);
+        assert_eq!(client_addr(peer, "1.2.3.4", &proxies), ip("1.2.3.4"));
+        assert_eq!(
+            client_addr(peer, "6.6.6.6, 1.2.3.4, 10.0.0.2", &proxies),
+            ip("1.2.3.4")
+        );
+        assert_eq!(client_addr(peer, "10.0.0.2", &proxies), ip("10.0.0.2"));
+        assert_eq!(
+            client_addr(peer, "junk, 10.0.0.2", &proxies),
+            ip("10.0.0.2")
+        );
+        assert_eq!(client_addr(peer, "::1", &proxies), ip("::1"));
+
+        let untrusted = ip("1.2.3.4");
+        assert_eq!(client_addr(untrusted, "6.6.6.6", &proxies), untrusted);
+        assert_eq!(client_addr(peer, "6.6.6.6", &[]), peer);
+    }
+
+    #[test]
+    fn ranges() {
+        let bytes = |start, end| Some(ByteRange::Bytes { start, end });
+        assert_eq!(parse_range("bytes=0-9", 100), bytes(0, 9));
+        assert_eq!(parse_range("bytes=90-", 100), bytes(90, 99));
+        assert_eq!(parse_range("bytes=90-200", 100), bytes(90, 99));
+        assert_eq!(parse_range("bytes=-10", 100), bytes(90, 99));
+        assert_eq!(parse_range("bytes=-200", 100), bytes(0, 99));
+        assert_eq!(
+            parse_range("bytes=100-", 100),
+            Some(ByteRange::Unsatisfiable)
+        );
+        assert_eq!(parse_range("bytes=-0", 100), Some(ByteRange::Unsatisfiable));
+        assert_eq!(parse_range("bytes=-5", 0), Some(ByteRange::Unsatisfiable));
+        assert_eq!(parse_range("bytes=9-0", 100), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("lines=0-1", 100), None);
+        assert_eq!(parse_range("bytes=x-", 100), None);
+    }
+
+    async fn collect(mut body: Body) -> Vec<u8> {
+        let mut data = vec![];
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(chunk.unwrap().get_ref());
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn sliced_bodies() {
+        let body = Body::from("0123456789".to_string());
+        assert_eq!(collect(body.slice(2, 4)).await, b"234");
+
+        let chunks =
+            ["012", "345", "6789"].map(|c| -> Result<Box<[u8]>> { Ok(c.as_bytes().into()) });
+        let body = Body::Stream(Box::pin(futures::stream::iter(chunks)));
+        assert_eq!(collect(body.slice(2, 7)).await, b"234567");
+
+        // The chunks after the range are still read.
+        let read = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counter = read.clone();
+        let chunks =
+            futures::stream::iter(["012", "345", "6789"]).map(move |c| -> Result<Box<[u8]>> {
+                counter.set(counter.get() + 1);
+                Ok(c.as_bytes().into())
+            });
+        let body = Body::Stream(Box::pin(chunks));
+        assert_eq!(collect(body.slice(0, 1)).await, b"01");
+        assert_eq!(read.get(), 3);
+    }
+}
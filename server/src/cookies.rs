@@ -0,0 +1,102 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Parsing of the `Cookie` request header.
+
+use std::collections::HashMap;
+
+/// Parses the value of a `Cookie` header into `cookies`.
+///
+/// The header is a `;`-separated list of `name=value` pairs, where a value may be wrapped in double quotes.  If a
+/// name repeats, the first value wins: browsers send the cookie with the most specific path first.  Pairs without a
+/// `=` or with an empty name are ignored.
+pub(crate) fn parse(header: &str, cookies: &mut HashMap<String, String>) {
+    let mut rest = header;
+    while !rest.is_empty() {
+        let end = rest
+            .find(|c: char| c == '=' || c == ';')
+            .unwrap_or(rest.len());
+        let name = rest[..end].trim();
+        if !rest[end..].starts_with('=') {
+            rest = rest.get(end + 1..).unwrap_or("");
+            continue;
+        }
+
+        let value = rest[end + 1..].trim_start();
+        let (value, tail) = match value
+            .strip_prefix('"')
+            .and_then(|quoted| quoted.split_once('"'))
+        {
+            Some((quoted, tail)) => (quoted, tail),
+            None => {
+                let end = value.find(';').unwrap_or(value.len());
+                (value[..end].trim_end(), &value[end..])
+            }
+        };
+        if !name.is_empty() {
+            cookies
+                .entry(name.to_owned())
+                .or_insert_with(|| value.to_owned());
+        }
+        rest = tail.split_once(';').map(|(_, tail)| tail).unwrap_or("");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_one(header: &str) -> HashMap<String, String> {
+        let mut cookies = HashMap::new();
+        parse(header, &mut cookies);
+        cookies
+    }
+
+    fn map(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn multiple_cookies() {
+        assert_eq!(
+            parse_one("session=abc.def; theme=dark;lang = en-US ; empty="),
+            map(&[
+                ("session", "abc.def"),
+                ("theme", "dark"),
+                ("lang", "en-US"),
+                ("empty", "")
+            ])
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(
+            parse_one(r#"a="quoted; value"; b="x=y"; c="unterminated"#),
+            map(&[
+                ("a", "quoted; value"),
+                ("b", "x=y"),
+                ("c", "\"unterminated")
+            ])
+        );
+    }
+
+    #[test]
+    fn repeated_and_malformed() {
+        assert_eq!(
+            parse_one("id=first; garbage; =nameless; id=second;;"),
+            map(&[("id", "first")])
+        );
+        assert!(parse_one("").is_empty());
+    }
+
+    #[test]
+    fn several_headers() {
+        let mut cookies = HashMap::new();
+        parse("a=1; b=2", &mut cookies);
+        parse("b=3; c=4", &mut cookies);
+        assert_eq!(cookies, map(&[("a", "1"), ("b", "2"), ("c", "4")]));
+    }
+}
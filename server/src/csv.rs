@@ -0,0 +1,119 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Serialization of query results as CSV, following RFC 4180.
+
+use crate::types::ObjectType;
+use crate::JsonObject;
+use serde_json::Value;
+
+/// Writes the entities of a type as CSV lines: a header with the names of its fields, then a line per entity with
+/// its values in the same order.
+///
+/// Entities are written as the query returned them, so the field policies of the request have already been
+/// applied to their values.
+pub(crate) struct CsvWriter {
+    fields: Vec<String>,
+}
+
+impl CsvWriter {
+    pub(crate) fn new(ty: &ObjectType) -> Self {
+        let fields = ty.all_fields().map(|f| f.name.clone()).collect();
+        Self { fields }
+    }
+
+    pub(crate) fn header(&self) -> String {
+        let mut line = String::new();
+        for (i, field) in self.fields.iter().enumerate() {
+            if i > 0 {
+                line.push(',');
+            }
+            write_field(&mut line, field);
+        }
+        line.push_str("\r\n");
+        line
+    }
+
+    /// Missing and null values are empty, strings are written as they are and numbers like JavaScript would, so
+    /// whole ones have no decimals. Anything else is written as JSON.
+    pub(crate) fn row(&self, entity: &JsonObject) -> String {
+        let mut line = String::new();
+        for (i, field) in self.fields.iter().enumerate() {
+            if i > 0 {
+                line.push(',');
+            }
+            match entity.get(field) {
+                None | Some(Value::Null) => {}
+                Some(Value::String(s)) => write_field(&mut line, s),
+                Some(Value::Number(n)) => match n.as_f64() {
+                    Some(f) if n.is_f64() => write_field(&mut line, &f.to_string()),
+                    _ => write_field(&mut line, &n.to_string()),
+                },
+                Some(v) => write_field(&mut line, &v.to_string()),
+            }
+        }
+        line.push_str("\r\n");
+        line
+    }
+}
+
+/// Appends `value` to `line`, quoted if it has a character that would otherwise end the field or the line.
+fn write_field(line: &mut String, value: &str) {
+    if value.contains(|c| matches!(c, ',' | '"' | '\r' | '\n')) {
+        line.push('"');
+        line.push_str(&value.replace('"', "\"\""));
+        line.push('"');
+    } else {
+        line.push_str(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn row(fields: &[&str], entity: Value) -> String {
+        let writer = CsvWriter {
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+        };
+        match entity {
+            Value::Object(entity) => writer.row(&entity),
+            _ => panic!("not an object"),
+        }
+    }
+
+    #[test]
+    fn plain_values() {
+        let entity = json!({"id": "a1", "name": "Jim", "age": 42.0, "height": 1.8, "admin": false});
+        assert_eq!(
+            row(&["id", "name", "age", "height", "admin"], entity),
+            "a1,Jim,42,1.8,false\r\n"
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        let entity = json!({"name": "Doe, John\nJr.", "quote": "say \"hi\"", "plain": "x"});
+        assert_eq!(
+            row(&["name", "quote", "plain"], entity),
+            "\"Doe, John\nJr.\",\"say \"\"hi\"\"\",x\r\n"
+        );
+    }
+
+    #[test]
+    fn missing_and_nested_values() {
+        let entity = json!({"a": null, "c": {"x": 1}, "d": [1, 2]});
+        assert_eq!(
+            row(&["a", "b", "c", "d"], entity),
+            ",,\"{\"\"x\"\":1}\",\"[1,2]\"\r\n"
+        );
+    }
+
+    #[test]
+    fn header() {
+        let writer = CsvWriter {
+            fields: vec!["id".into(), "a,b".into()],
+        };
+        assert_eq!(writer.header(), "id,\"a,b\"\r\n");
+    }
+}
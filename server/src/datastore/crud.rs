@@ -1,6 +1,10 @@
-use crate::datastore::engine::{QueryEngine, TransactionStatic};
+use crate::datastore::engine::{QueryEngine, QueryResults, TransactionStatic};
 use crate::datastore::expr::{BinaryExpr, BinaryOp, Expr, Literal, PropertyAccess};
-use crate::datastore::query::{Mutation, QueryOp, QueryPlan, RequestContext, SortBy, SortKey};
+use crate::datastore::query::{
+    escape_like, EntityShape, Mutation, QueryOp, QueryOpChain, QueryPlan, RequestContext, SortBy,
+    SortKey,
+};
+use crate::dates;
 use crate::types::{ObjectType, Type};
 use crate::JsonObject;
 use anyhow::{Context, Result};
@@ -16,6 +20,54 @@ pub(crate) struct QueryParams {
     url: String,
 }
 
+impl QueryParams {
+    pub(crate) fn type_name(&self) -> &str {
+        &self.type_name
+    }
+}
+
+/// Bounds on the number of rows a CRUD query returns, so that a client can't fetch a whole
+/// table by accident.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct PageLimits {
+    /// Limit of queries that don't set one.
+    pub(crate) default: Option<u64>,
+    /// Largest limit a query gets; larger ones are lowered to it.
+    pub(crate) max: Option<u64>,
+}
+
+impl PageLimits {
+    /// The limit of a query that asks for `requested` rows.
+    fn effective(&self, requested: Option<u64>) -> Option<u64> {
+        match (requested.or(self.default), self.max) {
+            (Some(limit), Some(max)) => Some(limit.min(max)),
+            (limit, max) => limit.or(max),
+        }
+    }
+}
+
+/// Results of a CRUD query.
+#[derive(Serialize)]
+pub(crate) struct QueryPage {
+    pub(crate) results: Vec<JsonObject>,
+    /// Limit the query ran with, which the page limits may have set or lowered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) limit: Option<u64>,
+    /// Value of the `cursor` parameter that fetches the rows after these, for queries that
+    /// paginate with a cursor and may have more rows.
+    #[serde(rename = "nextCursor")]
+    pub(crate) next_cursor: Option<String>,
+    /// Shape of the results, for queries whose results are rebuilt as typed entities.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) shape: Option<EntityShape>,
+    /// Number of rows matching the query's filters, over all pages, for queries asking for an envelope.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) total: Option<u64>,
+    /// Whether there are rows after these, for queries asking for an envelope.
+    #[serde(rename = "hasMore", skip_serializing_if = "Option::is_none")]
+    pub(crate) has_more: Option<bool>,
+}
+
 /// Parses CRUD `params` and runs the query with provided `query_engine` returning
 /// JSON of results.
 pub(crate) fn run_query(
@@ -23,29 +75,379 @@ pub(crate) fn run_query(
     params: QueryParams,
     query_engine: Arc<QueryEngine>,
     tr: TransactionStatic,
-) -> impl Future<Output = Result<Vec<JsonObject>>> {
-    let stream = make_stream(context, params, query_engine, tr);
+) -> impl Future<Output = Result<QueryPage>> {
+    let page_limits = query_engine.page_limits();
+    let stream = make_stream(
+        context,
+        params,
+        page_limits,
+        query_engine.clone(),
+        tr.clone(),
+    );
+    async move {
+        let (stream, pagination, limit, counts) = stream?;
+        let page = collect_page(stream, pagination).await?;
+        let counts = match counts {
+            Some(counts) => counts,
+            None => return Ok(QueryPage { limit, ..page }),
+        };
+        let total = query_engine.count(tr.clone(), counts.total).await?;
+        let from_start = match counts.from_start {
+            Some(plan) => query_engine.count(tr, plan).await?,
+            None => total,
+        };
+        let remaining = from_start.saturating_sub(counts.offset);
+        let has_more = remaining > page.results.len() as u64;
+        Ok(QueryPage {
+            limit,
+            total: Some(total),
+            has_more: Some(has_more),
+            next_cursor: page.next_cursor.filter(|_| has_more),
+            ..page
+        })
+    }
+}
+
+/// Parses CRUD `params` and starts the query, for reading all of its results a row at a time
+/// rather than as a page. A `cursor` parameter makes no difference here, and as the results are
+/// not held in memory, no page limits apply.
+pub(crate) fn run_query_stream(
+    context: &RequestContext<'_>,
+    params: QueryParams,
+    query_engine: Arc<QueryEngine>,
+    tr: TransactionStatic,
+) -> Result<QueryResults> {
+    let (stream, _, _, _) = make_stream(context, params, PageLimits::default(), query_engine, tr)?;
+    Ok(Box::pin(stream))
+}
+
+async fn collect_page(
+    stream: impl Stream<Item = Result<JsonObject>>,
+    pagination: Option<KeysetPagination>,
+) -> Result<QueryPage> {
+    let mut results = stream
+        .collect::<Vec<Result<JsonObject>>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()
+        .context("failed to collect result rows from the database")?;
+    let next_cursor = match pagination {
+        Some(pagination) => {
+            let next_cursor = pagination.next_cursor(&results)?;
+            pagination.hide_fields(&mut results);
+            next_cursor
+        }
+        None => None,
+    };
+    Ok(QueryPage {
+        results,
+        limit: None,
+        next_cursor,
+        shape: None,
+        total: None,
+        has_more: None,
+    })
+}
+
+/// Runs the query of `op_chain` a page at a time: at most `size` rows, starting after the position
+/// that `token` encodes. The page comes with the token of the next one, if there may be one, so a
+/// client can resume an export on a later request instead of holding a query stream open.
+///
+/// Rows are ordered by the last sort of the chain, or by id, and the id breaks ties. That sort
+/// can only have one key. If the chain selects fields, the id and the sort field are fetched too,
+/// to compute the token, but only the selected fields are returned.
+pub(crate) fn run_op_chain_page(
+    context: &RequestContext<'_>,
+    op_chain: QueryOpChain,
+    size: u64,
+    token: Option<String>,
+    query_engine: Arc<QueryEngine>,
+    tr: TransactionStatic,
+) -> impl Future<Output = Result<QueryPage>> {
+    let stream = make_op_chain_page_stream(context, op_chain, size, token, query_engine, tr);
     async {
-        stream?
-            .collect::<Vec<Result<JsonObject>>>()
-            .await
-            .into_iter()
-            .collect::<Result<_>>()
-            .context("failed to collect result rows from the database")
+        let (stream, pagination, shape) = stream?;
+        let page = collect_page(stream, Some(pagination)).await?;
+        Ok(QueryPage {
+            shape: Some(shape),
+            ..page
+        })
     }
 }
 
+fn make_op_chain_page_stream(
+    context: &RequestContext<'_>,
+    op_chain: QueryOpChain,
+    size: u64,
+    token: Option<String>,
+    query_engine: Arc<QueryEngine>,
+    tr: TransactionStatic,
+) -> Result<(
+    impl Stream<Item = Result<JsonObject>>,
+    KeysetPagination,
+    EntityShape,
+)> {
+    let mut sort_keys = None;
+    let mut selected = None;
+    let mut op = &op_chain;
+    let entity_name = loop {
+        op = match op {
+            QueryOpChain::BaseEntity { name, .. } => break name,
+            QueryOpChain::SortBy { keys, inner } => {
+                // The outermost sort is the one that orders the results.
+                sort_keys = sort_keys.or(Some(keys));
+                inner
+            }
+            QueryOpChain::Projection { fields, inner } => {
+                // Likewise, the outermost projection picks the fields.
+                selected = selected.or(Some(fields));
+                inner
+            }
+            QueryOpChain::Filter { inner, .. }
+            | QueryOpChain::Take { inner, .. }
+            | QueryOpChain::Skip { inner, .. } => inner,
+        };
+    };
+    let sort_key = match sort_keys {
+        Some(keys) => {
+            anyhow::ensure!(
+                keys.len() == 1,
+                "cursor pagination can only sort by one field"
+            );
+            keys.first().cloned()
+        }
+        None => None,
+    };
+    let base_type = context
+        .ts
+        .lookup_object_type(entity_name, &context.api_version)
+        .context("unexpected type name as paged query base type")?;
+    let key = sort_key.unwrap_or_else(id_sort_key);
+    let cursor = token.as_deref().map(Cursor::decode).transpose()?;
+    let (sort, after) = keyset_ops(&base_type, &key, cursor.as_ref())?;
+
+    // The cursor is made of the id and the sort field, so they are fetched even if not selected.
+    let selected = selected.cloned();
+    let mut hidden_fields: Vec<String> = vec![];
+    if let Some(selected) = &selected {
+        for field in ["id", key.field_name.as_str()] {
+            if !selected.iter().any(|f| f == field) && !hidden_fields.iter().any(|f| f == field) {
+                hidden_fields.push(field.to_owned());
+            }
+        }
+    }
+    let op_chain = match selected {
+        Some(selected) if !hidden_fields.is_empty() => QueryOpChain::Projection {
+            fields: selected.into_iter().chain(hidden_fields.clone()).collect(),
+            inner: op_chain.into(),
+        },
+        _ => op_chain,
+    };
+
+    let mut op_chain = QueryOpChain::SortBy {
+        keys: sort.keys,
+        inner: op_chain.into(),
+    };
+    if let Some(expression) = after {
+        op_chain = QueryOpChain::Filter {
+            expression,
+            inner: op_chain.into(),
+        };
+    }
+    let op_chain = QueryOpChain::Take {
+        count: size,
+        inner: op_chain.into(),
+    };
+    let query_plan = QueryPlan::from_op_chain(context, op_chain)?;
+    let shape = query_plan.entity_shape();
+    let stream = query_engine.query(tr, query_plan)?;
+    let pagination = KeysetPagination {
+        sort_field: key.field_name,
+        limit: Some(size),
+        hidden_fields,
+    };
+    Ok((stream, pagination, shape))
+}
+
+/// Starts the query of CRUD `params`, within `page_limits`. Returns its results, how to paginate
+/// them, the limit it ran with and, if it asked for an envelope, how to count its rows.
 fn make_stream(
     context: &RequestContext<'_>,
     params: QueryParams,
+    page_limits: PageLimits,
     query_engine: Arc<QueryEngine>,
     tr: TransactionStatic,
-) -> Result<impl Stream<Item = Result<JsonObject>>> {
+) -> Result<(
+    impl Stream<Item = Result<JsonObject>>,
+    Option<KeysetPagination>,
+    Option<u64>,
+    Option<PageCounts>,
+)> {
     let url = Url::parse(&params.url)
         .with_context(|| format!("crud endpoint failed to parse url: '{}'", params.url))?;
-    let query = Query::from_url(context, &params.type_name, &url)?;
+    let mut query = Query::from_url(context, &params.type_name, &url)?;
+    query.limit = page_limits.effective(query.limit);
     let query_plan = query.make_query_plan()?;
-    query_engine.query(tr.clone(), query_plan)
+    let counts = query
+        .envelope
+        .then(|| query.make_page_counts())
+        .transpose()?;
+    let stream = query_engine.query(tr.clone(), query_plan)?;
+    Ok((stream, query.keyset_pagination(), query.limit, counts))
+}
+
+/// How to count the rows of a CRUD query, to tell how many there are over all pages and whether
+/// there are more after the current one.
+struct PageCounts {
+    /// Counts the rows matching the filters.
+    total: QueryPlan,
+    /// Counts the rows from the cursor of the current page on, for pages that have one.
+    from_start: Option<QueryPlan>,
+    /// Rows skipped from there by an offset.
+    offset: u64,
+}
+
+/// Position in a keyset-paginated query: the last row of the previous page.
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    /// Value of the sort field in that row.
+    key: serde_json::Value,
+    /// Id of that row, which orders rows with the same key.
+    id: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> Result<String> {
+        let json = serde_json::to_string(self)?;
+        Ok(base64::encode_config(json, base64::URL_SAFE_NO_PAD))
+    }
+
+    fn decode(encoded: &str) -> Result<Self> {
+        let json =
+            base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).context("malformed cursor")?;
+        serde_json::from_slice(&json).context("malformed cursor")
+    }
+}
+
+fn id_sort_key() -> SortKey {
+    SortKey {
+        field_name: "id".to_owned(),
+        ascending: true,
+    }
+}
+
+/// The sort and the filter that make a query page by `key`, continuing after `cursor`.
+fn keyset_ops(
+    base_type: &Arc<ObjectType>,
+    key: &SortKey,
+    cursor: Option<&Cursor>,
+) -> Result<(SortBy, Option<Expr>)> {
+    let field = base_type.get_field(&key.field_name).with_context(|| {
+        format!(
+            "type '{}' has no field '{}' to sort by",
+            base_type.name(),
+            key.field_name
+        )
+    })?;
+    anyhow::ensure!(
+        !field.is_optional,
+        "cursor pagination can't sort by optional field '{}'",
+        field.name
+    );
+    anyhow::ensure!(
+        !field.is_nullable,
+        "cursor pagination can't sort by nullable field '{}'",
+        field.name
+    );
+    let mut keys = vec![key.clone()];
+    if key.field_name != "id" {
+        keys.push(SortKey {
+            field_name: "id".to_owned(),
+            ascending: key.ascending,
+        });
+    }
+    let after = cursor
+        .map(|cursor| after_cursor(base_type, key, cursor))
+        .transpose()?;
+    Ok((SortBy { keys }, after))
+}
+
+/// Makes a filter matching the rows that come after `cursor` when sorting by `key`.
+fn after_cursor(base_type: &Arc<ObjectType>, key: &SortKey, cursor: &Cursor) -> Result<Expr> {
+    let op = if key.ascending {
+        BinaryOp::Gt
+    } else {
+        BinaryOp::Lt
+    };
+    let (id_property, _) = make_property_chain(base_type, &["id"])?;
+    let after_id = BinaryExpr::new(
+        op.clone(),
+        id_property,
+        Literal::String(cursor.id.clone()).into(),
+    );
+    if key.field_name == "id" {
+        return Ok(after_id.into());
+    }
+
+    let (property, field_type) = make_property_chain(base_type, &[&key.field_name])?;
+    let invalid = || anyhow::anyhow!("cursor doesn't match the sort field");
+    let literal: Expr = match field_type {
+        Type::String | Type::Id => {
+            Literal::String(cursor.key.as_str().ok_or_else(invalid)?.to_owned())
+        }
+        Type::Float => Literal::F64(cursor.key.as_f64().ok_or_else(invalid)?),
+        Type::Boolean => Literal::Bool(cursor.key.as_bool().ok_or_else(invalid)?),
+        Type::Date => Literal::F64(dates::parse(&cursor.key).map_err(|_| invalid())?),
+        Type::Object(_) => anyhow::bail!("cursor pagination can't sort by an object"),
+        Type::Json => anyhow::bail!("cursor pagination can't sort by a JSON value"),
+    }
+    .into();
+    let after_key = BinaryExpr::new(op, property.clone(), literal.clone());
+    let same_key = BinaryExpr::eq(property, literal);
+    Ok(BinaryExpr::or(
+        after_key.into(),
+        BinaryExpr::and(same_key, after_id.into()),
+    ))
+}
+
+/// Pagination that continues from the last row seen instead of skipping rows. Unlike an offset,
+/// it neither repeats nor skips rows when rows are inserted while paging through results.
+struct KeysetPagination {
+    sort_field: String,
+    limit: Option<u64>,
+    /// Fields fetched only to compute the cursor, which the results don't include.
+    hidden_fields: Vec<String>,
+}
+
+impl KeysetPagination {
+    /// Computes the cursor of the page after `results`, if there may be one.
+    fn next_cursor(&self, results: &[JsonObject]) -> Result<Option<String>> {
+        let last = match results.last() {
+            Some(last) if Some(results.len() as u64) == self.limit => last,
+            _ => return Ok(None),
+        };
+        let id = last
+            .get("id")
+            .and_then(|id| id.as_str())
+            .context("result row has no id")?;
+        let cursor = Cursor {
+            key: last
+                .get(&self.sort_field)
+                .cloned()
+                .unwrap_or(serde_json::Value::Null),
+            id: id.to_owned(),
+        };
+        Ok(Some(cursor.encode()?))
+    }
+
+    /// Removes the fields that were only fetched to compute the cursor from `results`.
+    fn hide_fields(&self, results: &mut [JsonObject]) {
+        for row in results {
+            for field in &self.hidden_fields {
+                row.remove(field);
+            }
+        }
+    }
 }
 
 /// Constructs Delete Mutation from CRUD url.
@@ -78,6 +480,12 @@ struct Query<'a> {
     sort: Option<SortBy>,
     /// Filters restricting the result set. They will be joined in AND-fashion.
     filters: Vec<Expr>,
+    /// Whether the query paginates with a cursor rather than an offset.
+    paginate: bool,
+    /// Where the page starts, if not at the beginning.
+    cursor: Option<Cursor>,
+    /// Whether the results come with the total number of rows and whether there are more.
+    envelope: bool,
 }
 
 impl<'a> Query<'a> {
@@ -89,6 +497,9 @@ impl<'a> Query<'a> {
             offset: None,
             sort: None,
             filters: vec![],
+            paginate: false,
+            cursor: None,
+            envelope: false,
         }
     }
 
@@ -115,6 +526,13 @@ impl<'a> Query<'a> {
                     })?;
                     q.offset = Some(o);
                 }
+                "envelope" => q.envelope = value == "true",
+                "cursor" => {
+                    q.paginate = true;
+                    if !value.is_empty() {
+                        q.cursor = Some(Cursor::decode(&value)?);
+                    }
+                }
                 _ => {
                     if let Some(param_key) = param_key.strip_prefix('.') {
                         let expr =
@@ -126,6 +544,10 @@ impl<'a> Query<'a> {
                 }
             }
         }
+        // An envelope comes with the cursor of the next page, unless the query pages by offset.
+        if q.envelope && q.offset.is_none() {
+            q.paginate = true;
+        }
         Ok(q)
     }
 
@@ -134,7 +556,22 @@ impl<'a> Query<'a> {
     fn make_query_plan(&self) -> Result<QueryPlan> {
         let mut ops = vec![];
 
-        if let Some(sort) = &self.sort {
+        if self.paginate {
+            anyhow::ensure!(
+                self.offset.is_none(),
+                "cursor and offset can't be used together"
+            );
+            anyhow::ensure!(
+                self.sort.as_ref().map_or(true, |s| s.keys.len() == 1),
+                "cursor pagination can only sort by one field"
+            );
+            let key = self.keyset_sort_key();
+            let (sort, after) = keyset_ops(&self.base_type, &key, self.cursor.as_ref())?;
+            ops.push(QueryOp::SortBy(sort));
+            if let Some(expression) = after {
+                ops.push(QueryOp::Filter { expression });
+            }
+        } else if let Some(sort) = &self.sort {
             ops.push(QueryOp::SortBy(sort.clone()));
         }
         for f_expr in self.filters.iter().cloned() {
@@ -148,6 +585,50 @@ impl<'a> Query<'a> {
         }
         QueryPlan::from_ops(self.context, &self.base_type, ops)
     }
+
+    /// Plans counting the rows that match the filters, over all pages and from the cursor on.
+    fn make_page_counts(&self) -> Result<PageCounts> {
+        let filters: Vec<QueryOp> = self
+            .filters
+            .iter()
+            .cloned()
+            .map(|expression| QueryOp::Filter { expression })
+            .collect();
+        let plan = |ops| QueryPlan::from_ops(self.context, &self.base_type, ops);
+        let total = plan(filters.clone())?;
+        let from_start = match &self.cursor {
+            Some(cursor) => {
+                let (_, after) =
+                    keyset_ops(&self.base_type, &self.keyset_sort_key(), Some(cursor))?;
+                let mut ops = filters;
+                ops.extend(after.map(|expression| QueryOp::Filter { expression }));
+                Some(plan(ops)?)
+            }
+            None => None,
+        };
+        Ok(PageCounts {
+            total,
+            from_start,
+            offset: self.offset.unwrap_or(0),
+        })
+    }
+
+    /// The key cursor pagination orders by: the requested sort, or the id.
+    fn keyset_sort_key(&self) -> SortKey {
+        match self.sort.as_ref().and_then(|s| s.keys.first()) {
+            Some(key) => key.clone(),
+            None => id_sort_key(),
+        }
+    }
+
+    /// How to compute the next cursor, for queries that paginate with one.
+    fn keyset_pagination(&self) -> Option<KeysetPagination> {
+        self.paginate.then(|| KeysetPagination {
+            sort_field: self.keyset_sort_key().field_name,
+            limit: self.limit,
+            hidden_fields: vec![],
+        })
+    }
 }
 
 /// Parses all CRUD query-string filters over `base_type` from provided `url`.
@@ -168,28 +649,37 @@ fn url_to_filter(base_type: &Arc<ObjectType>, url: &str) -> Result<Option<Expr>>
     Ok(filter)
 }
 
+/// Parses a `sort` parameter: a comma-separated list of fields, each of which sorts in descending order if prefixed
+/// with `-` and in ascending order otherwise.  Each field orders the rows that the previous ones leave tied.
 fn parse_sort(base_type: &Arc<ObjectType>, value: &str) -> Result<SortBy> {
-    let mut ascending = true;
-    let field_name = if let Some(suffix) = value.strip_prefix(&['-', '+']) {
-        if value.starts_with('-') {
-            ascending = false;
-        }
-        suffix
-    } else {
-        value
-    };
-    anyhow::ensure!(
-        base_type.has_field(field_name),
-        "trying to sort by non-existent field '{}' on entity {}",
-        field_name,
-        base_type.name(),
-    );
-    Ok(SortBy {
-        keys: vec![SortKey {
+    let mut keys = vec![];
+    for field in value.split(',') {
+        let mut ascending = true;
+        let field_name = if let Some(suffix) = field.strip_prefix(&['-', '+']) {
+            if field.starts_with('-') {
+                ascending = false;
+            }
+            suffix
+        } else {
+            field
+        };
+        anyhow::ensure!(
+            base_type.has_field(field_name),
+            "trying to sort by non-existent field '{}' on entity {}",
+            field_name,
+            base_type.name(),
+        );
+        anyhow::ensure!(
+            !keys.iter().any(|k: &SortKey| k.field_name == field_name),
+            "trying to sort by field '{}' more than once",
+            field_name,
+        );
+        keys.push(SortKey {
             field_name: field_name.to_owned(),
             ascending,
-        }],
-    })
+        });
+    }
+    Ok(SortBy { keys })
 }
 
 /// Constructs results filter by parsing query string's `param_key` and `value`.
@@ -201,10 +691,17 @@ fn filter_from_param(base_type: &Arc<ObjectType>, param_key: &str, value: &str)
         param_key
     );
     let fields: Vec<_> = tokens[0].split('.').collect();
-    let operator = tokens.get(1).copied();
-    let operator = convert_operator(operator)?;
+    // `contains` matches the value as it is, while `like` takes it as a pattern.
+    let (operator, literal_match) = match tokens.get(1).copied() {
+        Some("contains") => (BinaryOp::Like, true),
+        Some("icontains") => (BinaryOp::ILike, true),
+        op => (convert_operator(op)?, false),
+    };
 
     let (property_chain, field_type) = make_property_chain(base_type, &fields)?;
+    if matches!(operator, BinaryOp::Eq | BinaryOp::NotEq) {
+        warn_if_disallowed(base_type, &fields, value);
+    }
 
     let err_msg = |ty_name| format!("failed to convert filter value '{}' to {}", value, ty_name);
     let literal = match field_type {
@@ -213,14 +710,52 @@ fn filter_from_param(base_type: &Arc<ObjectType>, param_key: &str, value: &str)
             fields.last().unwrap(),
             ty.name()
         ),
+        Type::Json => anyhow::bail!(
+            "trying to filter by property '{}' of type 'JSONValue' which is not supported",
+            fields.last().unwrap()
+        ),
+        Type::String | Type::Id if literal_match => {
+            Literal::String(format!("%{}%", escape_like(value)))
+        }
+        _ if literal_match => anyhow::bail!(
+            "trying to search in property '{}' which is not a string",
+            fields.last().unwrap()
+        ),
         Type::String | Type::Id => Literal::String(value.to_owned()),
         Type::Float => Literal::F64(value.parse::<f64>().with_context(|| err_msg("f64"))?),
         Type::Boolean => Literal::Bool(value.parse::<bool>().with_context(|| err_msg("bool"))?),
+        Type::Date => Literal::F64(dates::parse_str(value).with_context(|| err_msg("date"))?),
     };
 
     Ok(BinaryExpr::new(operator, property_chain, literal.into()).into())
 }
 
+/// Warns about filters comparing a field to a value that it doesn't allow, so no row can have it.  These are
+/// most likely typos in the query.
+fn warn_if_disallowed(base_type: &Arc<ObjectType>, fields: &[&str], value: &str) {
+    let (last, path) = match fields.split_last() {
+        Some(split) => split,
+        None => return,
+    };
+    let mut ty = base_type.clone();
+    for name in path {
+        ty = match ty.get_field(name).map(|f| &f.type_) {
+            Some(Type::Object(nested)) => nested.clone(),
+            _ => return,
+        };
+    }
+    if let Some(field) = ty.get_field(last) {
+        if !field.allows(value) {
+            warn!(
+                "Filter compares field `{}` of {} to {:?}, which is not one of its allowed values",
+                field.name,
+                ty.name(),
+                value
+            );
+        }
+    }
+}
+
 /// Converts `fields` of `base_type` into PropertyAccess expression while ensuring that
 /// provided fields are, in fact, applicable to `base_type`.
 fn make_property_chain(base_type: &Arc<ObjectType>, fields: &[&str]) -> Result<(Expr, Type)> {
@@ -268,6 +803,8 @@ fn convert_operator(op_str: Option<&str>) -> Result<BinaryOp> {
         "gte" => BinaryOp::GtEq,
         "like" => BinaryOp::Like,
         "unlike" => BinaryOp::NotLike,
+        "ilike" => BinaryOp::ILike,
+        "unilike" => BinaryOp::NotILike,
         op => anyhow::bail!("found unsupported operator '{}'", op),
     };
     Ok(op)
@@ -418,11 +955,7 @@ mod tests {
         }
     }
 
-    async fn run_query(
-        entity_name: &str,
-        url: String,
-        qe: &QueryEngine,
-    ) -> Result<Vec<JsonObject>> {
+    async fn run_query_page(entity_name: &str, url: String, qe: &QueryEngine) -> Result<QueryPage> {
         let qe = Arc::new(qe.clone());
         let tr = qe.clone().start_transaction_static().await.unwrap();
         super::run_query(
@@ -432,6 +965,7 @@ mod tests {
                 api_version: VERSION.to_owned(),
                 user_id: None,
                 path: "".to_string(),
+                method: "GET".to_string(),
             },
             QueryParams {
                 type_name: entity_name.to_owned(),
@@ -443,6 +977,16 @@ mod tests {
         .await
     }
 
+    async fn run_query(
+        entity_name: &str,
+        url: String,
+        qe: &QueryEngine,
+    ) -> Result<Vec<JsonObject>> {
+        run_query_page(entity_name, url, qe)
+            .await
+            .map(|page| page.results)
+    }
+
     async fn run_query_vec(entity_name: &str, url: String, qe: &QueryEngine) -> Vec<String> {
         let r = run_query(entity_name, url, qe).await.unwrap();
         collect_names(&r)
@@ -461,7 +1005,7 @@ mod tests {
         let john = json!({"name": "John", "age": json!(20f32)});
         let steve = json!({"name": "Steve", "age": json!(29f32)});
 
-        let (query_engine, _db_file) = setup_clear_db(&*ENTITIES).await;
+        let query_engine = setup_clear_db(&*ENTITIES).await;
         let qe = &query_engine;
         add_row(qe, &PERSON_TY, &alan).await;
         add_row(qe, &PERSON_TY, &john).await;
@@ -502,6 +1046,13 @@ mod tests {
         r.sort();
         assert_eq!(r, vec!["John", "Steve"]);
 
+        let r = run_query_vec("Person", url(".name~ilike=al%25"), qe).await;
+        assert_eq!(r, vec!["Alan"]);
+
+        let mut r = run_query_vec("Person", url(".name~unilike=al%25"), qe).await;
+        r.sort();
+        assert_eq!(r, vec!["John", "Steve"]);
+
         add_row(qe, &PERSON_TY, &alex).await;
 
         // Test permutations of parameters
@@ -530,9 +1081,377 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_like_escaping() {
+        let query_engine = setup_clear_db(&*ENTITIES).await;
+        let qe = &query_engine;
+        for name in ["50% off", "5000", "a_b", "axb"] {
+            add_row(qe, &PERSON_TY, &json!({"name": name, "age": 1.})).await;
+        }
+        let query = |query_string: &str| {
+            let query_string = query_string.to_owned();
+            async move {
+                let mut r = run_query_vec("Person", url(&query_string), qe).await;
+                r.sort();
+                r
+            }
+        };
+
+        // Wildcards in the searched text match only themselves.
+        assert_eq!(query(".name~contains=50%25").await, vec!["50% off"]);
+        assert_eq!(query(".name~icontains=A_B").await, vec!["a_b"]);
+        assert_eq!(query(".name~contains=%25").await, vec!["50% off"]);
+
+        // Patterns keep their wildcards, unless escaped.
+        assert_eq!(query(".name~like=50%25").await, vec!["50% off", "5000"]);
+        assert_eq!(query(".name~like=a_b").await, vec!["a_b", "axb"]);
+        assert_eq!(query(".name~like=50%5C%25%25").await, vec!["50% off"]);
+
+        assert!(filter_from_param(&PERSON_TY, "age~contains", "1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_page_limits() {
+        let limits = PageLimits {
+            default: Some(2),
+            max: Some(3),
+        };
+        assert_eq!(limits.effective(None), Some(2));
+        assert_eq!(limits.effective(Some(1)), Some(1));
+        assert_eq!(limits.effective(Some(100)), Some(3));
+        let max_only = PageLimits {
+            default: None,
+            max: Some(3),
+        };
+        assert_eq!(max_only.effective(None), Some(3));
+        assert_eq!(PageLimits::default().effective(None), None);
+        assert_eq!(PageLimits::default().effective(Some(100)), Some(100));
+
+        let query_engine = setup_clear_db(&*ENTITIES).await.with_page_limits(limits);
+        let qe = &query_engine;
+        for name in ["A", "B", "C", "D", "E"] {
+            add_row(qe, &PERSON_TY, &json!({"name": name, "age": 1f32})).await;
+        }
+        let page = run_query_page("Person", url(""), qe).await.unwrap();
+        assert_eq!(page.results.len(), 2);
+        assert_eq!(page.limit, Some(2));
+        let page = run_query_page("Person", url("limit=100"), qe)
+            .await
+            .unwrap();
+        assert_eq!(page.results.len(), 3);
+        assert_eq!(page.limit, Some(3));
+
+        // Cursors page through everything, a page of the default size at a time.
+        let page = run_query_page("Person", url("cursor="), qe).await.unwrap();
+        assert_eq!(page.results.len(), 2);
+        assert!(page.next_cursor.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cursor_pagination() {
+        let query_engine = setup_clear_db(&*ENTITIES).await;
+        let qe = &query_engine;
+        for (name, age) in [("A", 1), ("B", 2), ("C", 2), ("D", 3), ("E", 4)] {
+            add_row(qe, &PERSON_TY, &json!({"name": name, "age": age as f32})).await;
+        }
+
+        let mut seen = vec![];
+        let mut cursor = String::new();
+        for page_number in 0.. {
+            let page = run_query_page(
+                "Person",
+                url(&format!("sort=age&limit=2&cursor={cursor}")),
+                qe,
+            )
+            .await
+            .unwrap();
+            assert!(page.results.len() <= 2);
+            seen.extend(collect_names(&page.results));
+            if page_number == 0 {
+                // Rows inserted while paging show up only if they sort after the current page.
+                add_row(qe, &PERSON_TY, &json!({"name": "Before", "age": 0f32})).await;
+                add_row(qe, &PERSON_TY, &json!({"name": "After", "age": 5f32})).await;
+            }
+            match page.next_cursor {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+        assert_eq!(&seen[..1], ["A"]);
+        seen.sort();
+        assert_eq!(seen, vec!["A", "After", "B", "C", "D", "E"]);
+
+        // Descending order pages backwards, and the id breaks ties.
+        let page = run_query_page("Person", url("sort=-age&limit=3&cursor="), qe)
+            .await
+            .unwrap();
+        assert_eq!(collect_names(&page.results), vec!["After", "E", "D"]);
+        let cursor = page.next_cursor.unwrap();
+        let page = run_query_page(
+            "Person",
+            url(&format!("sort=-age&limit=3&cursor={cursor}")),
+            qe,
+        )
+        .await
+        .unwrap();
+        let mut names = collect_names(&page.results);
+        names[..2].sort();
+        assert_eq!(names, vec!["B", "C", "A"]);
+
+        // Without a cursor parameter there is no next cursor.
+        let page = run_query_page("Person", url("limit=2"), qe).await.unwrap();
+        assert!(page.next_cursor.is_none());
+
+        assert!(run_query("Person", url("cursor=garbage"), qe)
+            .await
+            .is_err());
+        assert!(run_query("Person", url("cursor=&offset=1"), qe)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_envelope() {
+        let query_engine = setup_clear_db(&*ENTITIES).await;
+        let qe = &query_engine;
+        for (name, age) in [("A", 1), ("B", 2), ("C", 3), ("D", 4), ("E", 5), ("F", 6)] {
+            add_row(qe, &PERSON_TY, &json!({"name": name, "age": age as f32})).await;
+        }
+
+        // The total counts the rows matching the filters, not just those of the page.
+        let first = run_query_page(
+            "Person",
+            url("envelope=true&sort=age&limit=2&.age~gt=1"),
+            qe,
+        )
+        .await
+        .unwrap();
+        assert_eq!(collect_names(&first.results), vec!["B", "C"]);
+        assert_eq!(first.total, Some(5));
+        assert_eq!(first.has_more, Some(true));
+
+        // The next cursor fetches the following page.
+        let cursor = first.next_cursor.unwrap();
+        let second = run_query_page(
+            "Person",
+            url(&format!(
+                "envelope=true&sort=age&limit=2&.age~gt=1&cursor={cursor}"
+            )),
+            qe,
+        )
+        .await
+        .unwrap();
+        assert_eq!(collect_names(&second.results), vec!["D", "E"]);
+        assert_eq!(second.total, Some(5));
+        assert_eq!(second.has_more, Some(true));
+
+        // A full last page has no next cursor.
+        let cursor = second.next_cursor.unwrap();
+        let last = run_query_page(
+            "Person",
+            url(&format!(
+                "envelope=true&sort=age&limit=1&.age~gt=1&cursor={cursor}"
+            )),
+            qe,
+        )
+        .await
+        .unwrap();
+        assert_eq!(collect_names(&last.results), vec!["F"]);
+        assert_eq!(last.has_more, Some(false));
+        assert!(last.next_cursor.is_none());
+
+        // Paging by offset tells whether there are more, without a cursor.
+        let page = run_query_page("Person", url("envelope=true&sort=age&limit=2&offset=3"), qe)
+            .await
+            .unwrap();
+        assert_eq!(collect_names(&page.results), vec!["D", "E"]);
+        assert_eq!(page.total, Some(6));
+        assert_eq!(page.has_more, Some(true));
+        assert!(page.next_cursor.is_none());
+
+        // Without an envelope there are no counts.
+        let page = run_query_page("Person", url("sort=age&limit=2"), qe)
+            .await
+            .unwrap();
+        assert_eq!(page.total, None);
+        assert_eq!(page.has_more, None);
+    }
+
+    async fn run_op_chain_page(
+        op_chain: QueryOpChain,
+        size: u64,
+        token: Option<String>,
+        qe: &QueryEngine,
+    ) -> Result<QueryPage> {
+        let qe = Arc::new(qe.clone());
+        let tr = qe.clone().start_transaction_static().await.unwrap();
+        super::run_op_chain_page(
+            &RequestContext {
+                policies: &Policies::default(),
+                ts: &make_type_system(&*ENTITIES),
+                api_version: VERSION.to_owned(),
+                user_id: None,
+                path: "".to_string(),
+                method: "GET".to_string(),
+            },
+            op_chain,
+            size,
+            token,
+            qe,
+            tr,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_op_chain_pages() {
+        let query_engine = setup_clear_db(&*ENTITIES).await;
+        let qe = &query_engine;
+        for (name, age) in [("A", 1), ("B", 2), ("C", 2), ("D", 2), ("E", 3)] {
+            add_row(qe, &PERSON_TY, &json!({"name": name, "age": age as f32})).await;
+        }
+        let by_age = || QueryOpChain::SortBy {
+            keys: vec![SortKey {
+                field_name: "age".to_owned(),
+                ascending: true,
+            }],
+            inner: QueryOpChain::BaseEntity {
+                name: "Person".to_owned(),
+                include_deleted: false,
+            }
+            .into(),
+        };
+
+        // The first page ends in the middle of the rows with age 2, the id tells where.
+        let first = run_op_chain_page(by_age(), 3, None, qe).await.unwrap();
+        assert_eq!(first.results.len(), 3);
+        let token = first.next_cursor;
+        assert!(token.is_some());
+        let second = run_op_chain_page(by_age(), 3, token, qe).await.unwrap();
+        assert_eq!(second.results.len(), 2);
+        assert!(second.next_cursor.is_none());
+
+        let rows: Vec<_> = first.results.iter().chain(&second.results).collect();
+        let ages: Vec<_> = rows.iter().map(|r| r["age"].as_f64().unwrap()).collect();
+        assert_eq!(ages, vec![1., 2., 2., 2., 3.]);
+        let mut names: Vec<_> = rows.iter().map(|r| r["name"].as_str().unwrap()).collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["A", "B", "C", "D", "E"]);
+
+        // Without a sort, pages are ordered by id.
+        let base = || QueryOpChain::BaseEntity {
+            name: "Person".to_owned(),
+            include_deleted: false,
+        };
+        let first = run_op_chain_page(base(), 4, None, qe).await.unwrap();
+        let second = run_op_chain_page(base(), 4, first.next_cursor, qe)
+            .await
+            .unwrap();
+        let mut names = collect_names(&first.results);
+        names.extend(collect_names(&second.results));
+        names.sort();
+        assert_eq!(names, vec!["A", "B", "C", "D", "E"]);
+
+        assert!(run_op_chain_page(base(), 2, Some("garbage".to_owned()), qe)
+            .await
+            .is_err());
+
+        // Selecting fields leaves out the id and the sort field, which the token is made of.
+        let names_by_age = || QueryOpChain::Projection {
+            fields: vec!["name".to_owned()],
+            inner: by_age().into(),
+        };
+        let first = run_op_chain_page(names_by_age(), 3, None, qe)
+            .await
+            .unwrap();
+        assert_eq!(
+            first.results[0],
+            json!({"name": "A"}).as_object().unwrap().clone()
+        );
+        let second = run_op_chain_page(names_by_age(), 3, first.next_cursor, qe)
+            .await
+            .unwrap();
+        let mut names = collect_names(&first.results);
+        names.extend(collect_names(&second.results));
+        names.sort();
+        assert_eq!(names, vec!["A", "B", "C", "D", "E"]);
+
+        let by_age_and_name = QueryOpChain::SortBy {
+            keys: vec![
+                SortKey {
+                    field_name: "age".to_owned(),
+                    ascending: true,
+                },
+                SortKey {
+                    field_name: "name".to_owned(),
+                    ascending: true,
+                },
+            ],
+            inner: base().into(),
+        };
+        let err = run_op_chain_page(by_age_and_name, 2, None, qe)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cursor pagination can only sort by one field"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multi_field_sort() {
+        let query_engine = setup_clear_db(&*ENTITIES).await;
+        let qe = &query_engine;
+        for (name, age) in [
+            ("Alan", 30.0),
+            ("John", 20.0),
+            ("Steve", 30.0),
+            ("Kim", 20.0),
+            ("Alex", 30.0),
+            ("Zed", 10.0),
+            ("Zed", 10.0),
+        ] {
+            add_row(qe, &PERSON_TY, &json!({"name": name, "age": age})).await;
+        }
+
+        let r = run_query_vec("Person", url("sort=-age,name"), qe).await;
+        assert_eq!(
+            r,
+            vec!["Alan", "Alex", "Steve", "John", "Kim", "Zed", "Zed"]
+        );
+
+        let r = run_query_vec("Person", url("sort=age,-name"), qe).await;
+        assert_eq!(
+            r,
+            vec!["Zed", "Zed", "Kim", "John", "Steve", "Alex", "Alan"]
+        );
+
+        let r = run_query_vec("Person", url("sort=%2Bage,-name&offset=3&limit=2"), qe).await;
+        assert_eq!(r, vec!["John", "Steve"]);
+
+        // Rows with equal keys come in the order of their ids.
+        let r = run_query("Person", url("sort=name,age&limit=2&offset=5"), qe)
+            .await
+            .unwrap();
+        let ids: Vec<_> = r.iter().map(|row| row["id"].as_str().unwrap()).collect();
+        assert_eq!(collect_names(&r), vec!["Zed", "Zed"]);
+        assert!(ids[0] < ids[1], "{:?}", ids);
+
+        assert!(run_query("Person", url("sort=age,nope"), qe).await.is_err());
+        assert!(run_query("Person", url("sort=age,-age"), qe).await.is_err());
+        assert!(run_query("Person", url("sort=age,"), qe).await.is_err());
+        let err = run_query("Person", url("sort=age,name&cursor="), qe)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cursor pagination can only sort by one field"
+        );
+    }
+
     #[tokio::test]
     async fn test_query_str_to_ops_errors() {
-        let (query_engine, _db_file) = setup_clear_db(&*ENTITIES).await;
+        let query_engine = setup_clear_db(&*ENTITIES).await;
         let qe = &query_engine;
 
         assert!(run_query("Person", url("limit=two"), qe).await.is_err());
@@ -575,6 +1494,7 @@ mod tests {
                     api_version: VERSION.to_owned(),
                     user_id: None,
                     path: "".to_string(),
+                    method: "GET".to_string(),
                 },
                 entity_name,
                 url,
@@ -585,7 +1505,7 @@ mod tests {
         let john = json!({"name": "John", "age": json!(20f32)});
         let alan = json!({"name": "Alan", "age": json!(30f32)});
         {
-            let (qe, _db_file) = setup_clear_db(&*ENTITIES).await;
+            let qe = setup_clear_db(&*ENTITIES).await;
             add_row(&qe, &PERSON_TY, &john).await;
 
             let mutation = delete_from_url("Person", &url(".name=John"));
@@ -594,7 +1514,7 @@ mod tests {
             assert_eq!(fetch_rows(&qe, &PERSON_TY).await.len(), 0);
         }
         {
-            let (qe, _db_file) = setup_clear_db(&*ENTITIES).await;
+            let qe = setup_clear_db(&*ENTITIES).await;
             add_row(&qe, &PERSON_TY, &john).await;
             add_row(&qe, &PERSON_TY, &alan).await;
 
@@ -608,7 +1528,7 @@ mod tests {
 
         let chiselstrike = json!({"name": "ChiselStrike", "ceo": john});
         {
-            let (qe, _db_file) = setup_clear_db(&*ENTITIES).await;
+            let qe = setup_clear_db(&*ENTITIES).await;
             add_row(&qe, &COMPANY_TY, &chiselstrike).await;
 
             let mutation = delete_from_url("Company", &url(".ceo.name=John"));
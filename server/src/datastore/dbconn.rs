@@ -40,11 +40,25 @@ pub(crate) struct DbConnection {
     pub(crate) conn_uri: String,
 }
 
+/// Whether `uri` names a SQLite database held in memory.
+fn is_in_memory(uri: &str) -> bool {
+    uri.starts_with("sqlite:") && (uri.contains(":memory:") || uri.contains("mode=memory"))
+}
+
 impl DbConnection {
+    /// Connects to the database at `uri`.  Connecting to an in-memory SQLite database, like
+    /// `sqlite::memory:`, creates a new, empty one that the pool's connections share.
     pub(crate) async fn connect(uri: &str, nr_conn: usize) -> Result<Self> {
         let opts = AnyConnectOptions::from_str(uri)?;
-        let pool = AnyPoolOptions::new()
-            .max_connections(nr_conn as _)
+        let mut pool_opts = AnyPoolOptions::new().max_connections(nr_conn as _);
+        if is_in_memory(uri) {
+            // The database only lives as long as some connection to it, so keep one open.
+            pool_opts = pool_opts
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None);
+        }
+        let pool = pool_opts
             .connect(uri)
             .await
             .with_context(|| format!("connecting to {}", uri))?;
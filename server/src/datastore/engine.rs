@@ -1,10 +1,12 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
+use crate::datastore::crud::PageLimits;
 use crate::datastore::query::{
     Mutation, QueriedEntity, QueryField, QueryPlan, SqlValue, TargetDatabase,
 };
 use crate::datastore::{DbConnection, Kind};
-use crate::types::{Field, ObjectDelta, ObjectType, Type};
+use crate::dates;
+use crate::types::{Field, ObjectDelta, ObjectType, Type, SOFT_DELETE_FIELD};
 use crate::JsonObject;
 use anyhow::{anyhow, Context as AnyhowContext, Result};
 use async_lock::Mutex;
@@ -19,11 +21,15 @@ use sea_query::{Alias, ColumnDef, Table};
 use serde::Serialize;
 use serde_json::json;
 use sqlx::any::{Any, AnyArguments, AnyPool, AnyRow};
-use sqlx::{Executor, Row, Transaction, ValueRef};
-use std::collections::{HashMap, HashSet};
+use sqlx::postgres::PgDatabaseError;
+use sqlx::{Acquire, Column, Executor, Row, Transaction, ValueRef};
+use std::collections::HashMap;
+use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 /// A query row is a JSON object that represent the queried entities.
@@ -39,6 +45,101 @@ pub(crate) fn extract_transaction(transaction: TransactionStatic) -> Transaction
     transaction.into_inner()
 }
 
+/// Warns about SQL statements that take longer than a threshold to execute.
+#[derive(Clone, Default)]
+pub(crate) struct SlowQueryLog {
+    threshold: Option<Duration>,
+    /// Number of slow statements seen so far.
+    count: Arc<AtomicU64>,
+}
+
+impl SlowQueryLog {
+    /// Checks a statement that started executing at `started` and has just finished. `what` describes the
+    /// operation and the type it was on.
+    fn check(&self, started: Instant, sql: &str, what: &str) {
+        if let Some(threshold) = self.threshold {
+            let elapsed = started.elapsed();
+            if elapsed > threshold {
+                self.count.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Slow query ({} took {:?}, threshold is {:?}): {}",
+                    what, elapsed, threshold, sql
+                );
+            }
+        }
+    }
+}
+
+/// When the data of each type last changed, so that reads can tell clients whether what they already have is
+/// still current.  Only changes made through this server are seen, so anything from before it started counts as
+/// changed when it started.
+#[derive(Clone)]
+pub(crate) struct ModificationLog {
+    modifications: Arc<std::sync::Mutex<Modifications>>,
+}
+
+struct Modifications {
+    /// Time of the last change that could have touched any table, in milliseconds since the epoch.
+    all: f64,
+    /// Time of the last change to each backing table.
+    tables: HashMap<String, f64>,
+}
+
+impl Default for ModificationLog {
+    fn default() -> Self {
+        let modifications = Modifications {
+            all: dates::now(),
+            tables: HashMap::new(),
+        };
+        Self {
+            modifications: Arc::new(std::sync::Mutex::new(modifications)),
+        }
+    }
+}
+
+impl ModificationLog {
+    /// Records a change to the objects of type `ty`, which may have changed the objects nested in them too.
+    pub(crate) fn record(&self, ty: &ObjectType) {
+        let now = dates::now();
+        let mut modifications = self.modifications.lock().unwrap();
+        for table in read_tables(ty) {
+            modifications.tables.insert(table, now);
+        }
+    }
+
+    /// Records a change that may have touched any type.
+    pub(crate) fn record_all(&self) {
+        self.modifications.lock().unwrap().all = dates::now();
+    }
+
+    /// When the objects read as type `ty`, including the ones nested in them, last changed.
+    pub(crate) fn last_modified(&self, ty: &ObjectType) -> f64 {
+        let modifications = self.modifications.lock().unwrap();
+        read_tables(ty)
+            .iter()
+            .filter_map(|table| modifications.tables.get(table))
+            .fold(modifications.all, |a, b| a.max(*b))
+    }
+}
+
+/// The backing tables that reading `ty` reads from: its own and those of the types nested in it.
+fn read_tables(ty: &ObjectType) -> Vec<String> {
+    let mut tables = vec![ty.backing_table().to_owned()];
+    let mut pending = vec![ty];
+    while let Some(ty) = pending.pop() {
+        for field in ty.all_fields() {
+            if let Type::Object(nested) = &field.type_ {
+                let table = nested.backing_table().to_owned();
+                if !tables.contains(&table) {
+                    tables.push(table);
+                    pending.push(nested);
+                }
+            }
+        }
+    }
+    tables
+}
+
 /// `RawQueryResults` represents the raw query results from the backing stor
 ///  before policies are applied.
 #[pin_project]
@@ -47,11 +148,20 @@ struct RawQueryResults<T> {
     tr: MutexGuardArc<Transaction<'static, Any>>,
     #[pin]
     stream: T,
+    slow_query_log: SlowQueryLog,
+    /// Describes the query for the slow query log.
+    what: String,
+    /// When the statement started executing, which is when the stream was first polled.
+    started: Option<Instant>,
+    /// Whether the statement finished executing, which is when the first row (or the end) arrived.
+    executed: bool,
 }
 
 async fn make_transactioned_stream(
     tr: TransactionStatic,
     raw_query: String,
+    slow_query_log: SlowQueryLog,
+    what: String,
 ) -> impl Stream<Item = anyhow::Result<AnyRow>> {
     let mut tr = tr.lock_arc().await;
 
@@ -66,21 +176,37 @@ async fn make_transactioned_stream(
         tr,
         raw_query,
         stream,
+        slow_query_log,
+        what,
+        started: None,
+        executed: false,
     }
 }
 
 pub(crate) fn new_query_results(
     raw_query: String,
     tr: TransactionStatic,
+    slow_query_log: SlowQueryLog,
+    what: String,
 ) -> impl Stream<Item = anyhow::Result<AnyRow>> {
-    make_transactioned_stream(tr, raw_query).flatten_stream()
+    make_transactioned_stream(tr, raw_query, slow_query_log, what).flatten_stream()
 }
 
 impl<T: Stream<Item = Result<AnyRow>>> Stream for RawQueryResults<T> {
     type Item = Result<AnyRow>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        self.project().stream.poll_next(cx)
+        let this = self.project();
+        let started = *this.started.get_or_insert_with(Instant::now);
+        let poll = this.stream.poll_next(cx);
+        if poll.is_ready() && !*this.executed {
+            // Only time the statement itself, not however long the consumer takes to go through
+            // the rows.
+            *this.executed = true;
+            this.slow_query_log
+                .check(started, this.raw_query, this.what);
+        }
+        poll
     }
 }
 
@@ -92,9 +218,9 @@ impl TryFrom<&Field> for ColumnDef {
             column_def.unique_key();
         }
         match field.type_ {
-            Type::String => column_def.text(),
+            Type::String | Type::Json => column_def.text(),
             Type::Id => column_def.text().primary_key(),
-            Type::Float => column_def.double(),
+            Type::Float | Type::Date => column_def.double(),
             Type::Boolean => column_def.boolean(),
             Type::Object(_) => column_def.text(), // Foreign key, must the be same type as Type::Id
         };
@@ -157,6 +283,34 @@ fn column_is_null(row: &AnyRow, column_idx: usize) -> bool {
     row.try_get_raw(column_idx).unwrap().is_null()
 }
 
+/// Converts a row of a raw query, whose column types are only known from the row itself.
+fn raw_row_to_json(row: &AnyRow) -> Result<JsonObject> {
+    let mut ret = JsonObject::default();
+    for (idx, column) in row.columns().iter().enumerate() {
+        let val = if column_is_null(row, idx) {
+            serde_json::Value::Null
+        } else if let Ok(s) = row.try_get::<String, _>(idx) {
+            json!(s)
+        } else if let Ok(i) = row.try_get::<i64, _>(idx) {
+            json!(i)
+        } else if let Ok(i) = row.try_get::<i32, _>(idx) {
+            json!(i)
+        } else if let Ok(f) = row.try_get::<f64, _>(idx) {
+            json!(f)
+        } else if let Ok(b) = row.try_get::<bool, _>(idx) {
+            json!(b)
+        } else {
+            anyhow::bail!(
+                "Column {} of a raw query has unsupported type {}",
+                column.name(),
+                column.type_info()
+            );
+        };
+        ret.insert(column.name().to_owned(), val);
+    }
+    Ok(ret)
+}
+
 fn id_idx(entity: &QueriedEntity) -> usize {
     for f in &entity.fields {
         match f {
@@ -169,6 +323,244 @@ fn id_idx(entity: &QueriedEntity) -> usize {
     panic!("No id field among Entity children");
 }
 
+/// Records the definition each backing table was created with, so that DDL is only issued when a definition
+/// actually changes.  This survives restarts, making it cheap to re-apply an unchanged application.
+pub(crate) const SCHEMA_VERSION_TABLE: &str = "_chisel_schema_version";
+
+/// Describes the columns of the backing table for `ty`, for comparison against `SCHEMA_VERSION_TABLE`.
+fn table_definition(ty: &ObjectType) -> String {
+    let columns = ty.all_fields().map(|f| {
+        format!(
+            "{}:{}{}{}{}",
+            f.name,
+            f.type_.name(),
+            if f.is_optional { "?" } else { "" },
+            if f.is_unique { "!" } else { "" },
+            if f.is_indexed { "#" } else { "" }
+        )
+    });
+    let constraints = ty
+        .unique_constraints()
+        .iter()
+        .map(|fields| format!("!({})", fields.join(",")));
+    columns.chain(constraints).join(",")
+}
+
+/// Returned when a write would give unique fields values that another row already has.
+#[derive(thiserror::Error, Debug)]
+#[error(
+    "Conflict: another {type_name} already has the same value for {}",
+    describe_unique_fields(.fields)
+)]
+pub(crate) struct UniqueViolation {
+    type_name: String,
+    /// The fields whose values collided, if the database told which.
+    fields: Vec<String>,
+}
+
+fn describe_unique_fields(fields: &[String]) -> String {
+    match fields {
+        [] => "a unique field".to_owned(),
+        [name] => name.to_owned(),
+        _ => format!("({})", fields.join(", ")),
+    }
+}
+
+fn is_unique_violation(err: &sqlx::Error) -> bool {
+    match err {
+        // SQLITE_CONSTRAINT_UNIQUE, SQLITE_CONSTRAINT_PRIMARYKEY and postgres' unique_violation.
+        sqlx::Error::Database(e) => matches!(e.code().as_deref(), Some("2067" | "1555" | "23505")),
+        _ => false,
+    }
+}
+
+/// The columns whose values collided in the unique violation `err`, as far as its message tells.
+fn conflicting_columns(err: &sqlx::Error) -> Vec<String> {
+    let err = match err {
+        sqlx::Error::Database(e) => e,
+        _ => return vec![],
+    };
+    if let Some(err) = err.try_downcast_ref::<PgDatabaseError>() {
+        // Key (tenant, email)=(acme, alice@acme.com) already exists.
+        let columns = err
+            .detail()
+            .and_then(|d| d.strip_prefix("Key ("))
+            .and_then(|d| d.split_once(")="));
+        return match columns {
+            Some((columns, _)) => columns
+                .split(", ")
+                .map(|c| c.trim_matches('"').to_owned())
+                .collect(),
+            None => vec![],
+        };
+    }
+    // UNIQUE constraint failed: ty_Person.tenant, ty_Person.email
+    match err.message().split_once("constraint failed: ") {
+        Some((_, columns)) => columns
+            .split(", ")
+            .map(|c| c.rsplit('.').next().unwrap_or(c).to_owned())
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// Returned when a write would give a field a value other than the ones it allows.
+#[derive(thiserror::Error, Debug)]
+#[error(
+    "Bad Request: field `{field}` of {type_name} can't be {value:?}, only one of {}",
+    .allowed.iter().map(|v| format!("{:?}", v)).join(", ")
+)]
+pub(crate) struct DisallowedValue {
+    type_name: String,
+    field: String,
+    value: String,
+    allowed: Vec<String>,
+}
+
+/// Checks that `value`, to be written into `field` of `ty`, is one of the values the field allows.
+fn check_allowed_value(
+    ty: &ObjectType,
+    field: &Field,
+    value: Option<&serde_json::Value>,
+) -> Result<()> {
+    match value {
+        Some(serde_json::Value::String(value)) if !field.allows(value) => Err(DisallowedValue {
+            type_name: ty.name().to_owned(),
+            field: field.name.clone(),
+            value: value.clone(),
+            allowed: field.allowed_values.clone(),
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Converts an error writing into the table of `ty` to a `UniqueViolation` where it is one.
+fn write_error(ty: &ObjectType, err: sqlx::Error) -> anyhow::Error {
+    if is_unique_violation(&err) {
+        UniqueViolation {
+            type_name: ty.name().to_owned(),
+            fields: conflicting_columns(&err),
+        }
+        .into()
+    } else {
+        err.into()
+    }
+}
+
+/// The object nested in `value` at `path`, a list of field names that all hold objects.
+fn object_at<'a>(mut value: &'a mut JsonObject, path: &[String]) -> &'a mut JsonObject {
+    for name in path {
+        value = value.get_mut(name).unwrap().as_object_mut().unwrap();
+    }
+    value
+}
+
+/// Condition to add to a WHERE clause on the table of `ty` so that it leaves out soft-deleted rows.
+fn live_rows(ty: &ObjectType) -> String {
+    if ty.soft_deletes() {
+        format!(" AND \"{}\" IS NULL", SOFT_DELETE_FIELD)
+    } else {
+        String::new()
+    }
+}
+
+fn index_name(table: &str, field: &Field, unique: bool) -> String {
+    format!(
+        "{}_{}_{}",
+        table,
+        field.name,
+        if unique { "unique" } else { "index" }
+    )
+}
+
+fn unique_constraint_name(table: &str, fields: &[String]) -> String {
+    format!("{}_{}_unique_together", table, fields.join("_"))
+}
+
+/// How to retry database operations that fail with a transient error, like a reset connection or a lock held by
+/// another transaction.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RetryPolicy {
+    /// How many times to retry; zero disables retrying.
+    pub(crate) retries: u32,
+    /// How long to wait before the first retry, doubled before each following one.
+    pub(crate) base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Runs the operation `op` makes, making it again while it fails with a transient error and retries are
+    /// left. Only for operations that have no effect when they fail, so that running them twice is harmless.
+    pub(crate) async fn run<T, F, Fut>(&self, mut op: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        for retry in 1..=self.retries {
+            match op().await {
+                Err(err) if is_transient(&err) => self.pause(retry, &err).await,
+                result => return result,
+            }
+        }
+        op().await
+    }
+
+    /// Logs `err` and waits before making retry number `retry`, counting from one.
+    async fn pause(&self, retry: u32, err: &anyhow::Error) {
+        let delay = self.base_delay * 2u32.saturating_pow(retry - 1);
+        warn!(
+            "Transient database error, retry {} of {} in {:?}: {:#}",
+            retry, self.retries, delay, err
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// The code of the database error in `err`, if it comes from one.
+fn database_error_code(err: &anyhow::Error) -> Option<String> {
+    err.chain()
+        .find_map(|cause| match cause.downcast_ref::<sqlx::Error>() {
+            Some(sqlx::Error::Database(e)) => e.code().map(|code| code.into_owned()),
+            _ => None,
+        })
+}
+
+/// Whether `err` comes from a statement that ran into another transaction: SQLite's SQLITE_BUSY and
+/// SQLITE_LOCKED, with their extended codes, and Postgres' serialization_failure and deadlock_detected.
+/// Running the statement again, once the other transaction is done, may succeed.
+fn is_contention(err: &anyhow::Error) -> bool {
+    is_statement_contention(err) || database_error_code(err).as_deref() == Some("40001")
+}
+
+/// Whether `err` comes from a statement that ran into another transaction, and that may succeed if run again
+/// in the same transaction: all of `is_contention` except Postgres' serialization_failure, which is about the
+/// snapshot the whole transaction reads from, so only a new transaction can get past it.
+fn is_statement_contention(err: &anyhow::Error) -> bool {
+    matches!(
+        database_error_code(err).as_deref(),
+        Some("5" | "6" | "261" | "262" | "517" | "773" | "40P01")
+    )
+}
+
+/// Whether `err` comes from losing the connection to the database, or failing to make one. Postgres reports
+/// these with codes of class 08, or of 57P01 to 57P03 when the server is shutting down or starting up.
+fn is_connection_error(err: &anyhow::Error) -> bool {
+    let lost = err.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<sqlx::Error>(),
+            Some(sqlx::Error::Io(_) | sqlx::Error::Tls(_))
+        )
+    });
+    lost || matches!(database_error_code(err), Some(code) if code.starts_with("08") || matches!(code.as_str(), "57P01" | "57P02" | "57P03"))
+}
+
+/// Whether `err` comes from a database failure that may not happen again, as opposed to one that a retry would
+/// run into too, like a constraint violation. Timing out waiting for a connection from the pool is not one:
+/// waiting again would only multiply the time a request waits for one.
+fn is_transient(err: &anyhow::Error) -> bool {
+    is_connection_error(err) || is_contention(err)
+}
+
 /// Query engine.
 ///
 /// The query engine provides a way to transactionally mutate entities and
@@ -184,11 +576,71 @@ fn id_idx(entity: &QueriedEntity) -> usize {
 pub(crate) struct QueryEngine {
     kind: Kind,
     pool: AnyPool,
+    slow_query_log: SlowQueryLog,
+    /// Whether stored values may only have fields their type declares.
+    strict_fields: bool,
+    modification_log: ModificationLog,
+    page_limits: PageLimits,
+    retry_policy: RetryPolicy,
 }
 
 impl QueryEngine {
     fn new(kind: Kind, pool: AnyPool) -> Self {
-        Self { kind, pool }
+        Self {
+            kind,
+            pool,
+            slow_query_log: SlowQueryLog::default(),
+            strict_fields: false,
+            modification_log: ModificationLog::default(),
+            page_limits: PageLimits::default(),
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Logs a warning for every statement that takes longer than `threshold` to execute.
+    pub(crate) fn with_slow_query_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_query_log.threshold = threshold;
+        self
+    }
+
+    /// Rejects stored values that have fields their type doesn't declare, instead of dropping those
+    /// fields.
+    pub(crate) fn with_strict_fields(mut self, strict: bool) -> Self {
+        self.strict_fields = strict;
+        self
+    }
+
+    /// Bounds the number of rows that CRUD queries return.
+    pub(crate) fn with_page_limits(mut self, limits: PageLimits) -> Self {
+        self.page_limits = limits;
+        self
+    }
+
+    /// Retries starting transactions that fail with a transient error, and statements that run into another
+    /// transaction. Statements are retried within their transaction, so a lost connection fails them for good.
+    pub(crate) fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    pub(crate) fn page_limits(&self) -> PageLimits {
+        self.page_limits
+    }
+
+    /// Shares `log` with other engines on the same database, so that changes made through any of them are seen.
+    pub(crate) fn with_modification_log(mut self, log: ModificationLog) -> Self {
+        self.modification_log = log;
+        self
+    }
+
+    /// When the data of each type written through this engine last changed.
+    pub(crate) fn modification_log(&self) -> &ModificationLog {
+        &self.modification_log
+    }
+
+    /// Number of statements that exceeded the slow query threshold.
+    pub(crate) fn slow_query_count(&self) -> u64 {
+        self.slow_query_log.count.load(Ordering::Relaxed)
     }
 
     pub(crate) async fn local_connection(conn: &DbConnection, nr_conn: usize) -> Result<Self> {
@@ -196,7 +648,7 @@ impl QueryEngine {
         Ok(Self::new(local.kind, local.pool))
     }
 
-    fn target_db(&self) -> TargetDatabase {
+    pub(crate) fn target_db(&self) -> TargetDatabase {
         match self.kind {
             Kind::Postgres => TargetDatabase::Postgres,
             Kind::Sqlite => TargetDatabase::Sqlite,
@@ -215,15 +667,48 @@ impl QueryEngine {
         let drop_table = sqlx::query(&drop_table);
 
         transaction.execute(drop_table).await?;
+        self.forget_table_definition(transaction, ty.backing_table())
+            .await?;
         Ok(())
     }
 
+    /// Whether the backing table of `ty` holds no rows.
+    pub(crate) async fn is_empty(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        ty: &ObjectType,
+    ) -> Result<bool> {
+        let sql = format!("SELECT 1 FROM \"{}\" LIMIT 1", ty.backing_table());
+        let row = transaction.fetch_optional(sqlx::query(&sql)).await?;
+        Ok(row.is_none())
+    }
+
     pub(crate) async fn start_transaction_static(self: Arc<Self>) -> Result<TransactionStatic> {
-        Ok(Arc::new(Mutex::new(self.pool.begin().await?)))
+        Ok(Arc::new(Mutex::new(self.start_transaction().await?)))
+    }
+
+    /// Has the database cancel statements of `transaction` that run longer than `timeout`.  Only Postgres can do
+    /// this; SQLite statements are only cancelled once the futures running them are dropped.
+    pub(crate) async fn set_statement_timeout(
+        &self,
+        transaction: &TransactionStatic,
+        timeout: Duration,
+    ) -> Result<()> {
+        if let Kind::Postgres = self.kind {
+            let sql = format!(
+                "SET LOCAL statement_timeout = {}",
+                timeout.as_millis().max(1)
+            );
+            let mut transaction = transaction.lock().await;
+            sqlx::query(&sql).execute(&mut *transaction).await?;
+        }
+        Ok(())
     }
 
     pub(crate) async fn start_transaction(&self) -> Result<Transaction<'static, Any>> {
-        Ok(self.pool.begin().await?)
+        self.retry_policy
+            .run(|| async { Ok(self.pool.begin().await?) })
+            .await
     }
 
     pub(crate) async fn commit_transaction(transaction: Transaction<'static, Any>) -> Result<()> {
@@ -237,11 +722,150 @@ impl QueryEngine {
         Ok(())
     }
 
+    async fn create_schema_version_table(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<()> {
+        let create_table = Table::create()
+            .table(Alias::new(SCHEMA_VERSION_TABLE))
+            .if_not_exists()
+            .col(
+                ColumnDef::new(Alias::new("table_name"))
+                    .text()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new(Alias::new("definition")).text().not_null())
+            .to_owned();
+        let create_table = create_table.build_any(DbConnection::get_query_builder(&self.kind));
+        transaction.execute(sqlx::query(&create_table)).await?;
+        Ok(())
+    }
+
+    /// Returns the definition `table` was last created with, if any.
+    async fn applied_table_definition(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        table: &str,
+    ) -> Result<Option<String>> {
+        self.create_schema_version_table(transaction).await?;
+        let sql = format!(
+            "SELECT definition FROM \"{}\" WHERE table_name = $1",
+            SCHEMA_VERSION_TABLE
+        );
+        let row = transaction
+            .fetch_optional(sqlx::query(&sql).bind(table))
+            .await?;
+        Ok(row.map(|row| row.get::<String, _>(0)))
+    }
+
+    async fn record_table_definition(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        table: &str,
+        definition: &str,
+    ) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO \"{}\" (table_name, definition) VALUES ($1, $2) \
+             ON CONFLICT(table_name) DO UPDATE SET definition = $2",
+            SCHEMA_VERSION_TABLE
+        );
+        let query = sqlx::query(&sql).bind(table).bind(definition);
+        transaction.execute(query).await?;
+        Ok(())
+    }
+
+    async fn forget_table_definition(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        table: &str,
+    ) -> Result<()> {
+        self.create_schema_version_table(transaction).await?;
+        let sql = format!(
+            "DELETE FROM \"{}\" WHERE table_name = $1",
+            SCHEMA_VERSION_TABLE
+        );
+        transaction.execute(sqlx::query(&sql).bind(table)).await?;
+        Ok(())
+    }
+
+    async fn create_index(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        table: &str,
+        field: &Field,
+        unique: bool,
+    ) -> Result<()> {
+        let sql = format!(
+            "CREATE {}INDEX IF NOT EXISTS \"{}\" ON \"{}\" (\"{}\")",
+            if unique { "UNIQUE " } else { "" },
+            index_name(table, field, unique),
+            table,
+            field.name
+        );
+        transaction.execute(sqlx::query(&sql)).await?;
+        Ok(())
+    }
+
+    async fn drop_index(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        table: &str,
+        field: &Field,
+        unique: bool,
+    ) -> Result<()> {
+        let sql = format!(
+            "DROP INDEX IF EXISTS \"{}\"",
+            index_name(table, field, unique)
+        );
+        transaction.execute(sqlx::query(&sql)).await?;
+        Ok(())
+    }
+
+    async fn create_unique_constraint(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        table: &str,
+        fields: &[String],
+    ) -> Result<()> {
+        let sql = format!(
+            "CREATE UNIQUE INDEX IF NOT EXISTS \"{}\" ON \"{}\" ({})",
+            unique_constraint_name(table, fields),
+            table,
+            fields.iter().map(|f| format!("\"{}\"", f)).join(", ")
+        );
+        transaction.execute(sqlx::query(&sql)).await?;
+        Ok(())
+    }
+
+    async fn drop_unique_constraint(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        table: &str,
+        fields: &[String],
+    ) -> Result<()> {
+        let sql = format!(
+            "DROP INDEX IF EXISTS \"{}\"",
+            unique_constraint_name(table, fields)
+        );
+        transaction.execute(sqlx::query(&sql)).await?;
+        Ok(())
+    }
+
+    /// Creates the backing table for `ty`, unless a table with the same definition was already created, possibly
+    /// by a previous run of the server.  Returns whether the table's DDL was issued.
     pub(crate) async fn create_table(
         &self,
         transaction: &mut Transaction<'_, Any>,
         ty: &ObjectType,
-    ) -> Result<()> {
+    ) -> Result<bool> {
+        let definition = table_definition(ty);
+        let applied = self
+            .applied_table_definition(transaction, ty.backing_table())
+            .await?;
+        if applied.as_deref() == Some(definition.as_str()) {
+            return Ok(false);
+        }
+
         let mut create_table = Table::create()
             .table(Alias::new(ty.backing_table()))
             .if_not_exists()
@@ -255,7 +879,18 @@ impl QueryEngine {
 
         let create_table = sqlx::query(&create_table);
         transaction.execute(create_table).await?;
-        Ok(())
+        // Unique fields are indexed by their UNIQUE constraint already.
+        for field in ty.all_fields().filter(|f| f.is_indexed && !f.is_unique) {
+            self.create_index(transaction, ty.backing_table(), field, false)
+                .await?;
+        }
+        for fields in ty.unique_constraints() {
+            self.create_unique_constraint(transaction, ty.backing_table(), fields)
+                .await?;
+        }
+        self.record_table_definition(transaction, ty.backing_table(), &definition)
+            .await?;
+        Ok(true)
     }
 
     pub(crate) async fn alter_table(
@@ -289,34 +924,81 @@ impl QueryEngine {
         // In particular, we can't use defaults, which is fine since we can handle that on
         // chiselstrike's side.
         //
-        // FIXME: When we start generating indexes or using foreign keys, we'll have to make sure
-        // that those are still safe. Adding columns is always safe, but removals may not be if
-        // they are used in relations or indexes (see the document above)
+        // SQLite can't add a UNIQUE column either, so uniqueness of added fields is enforced by
+        // a unique index instead. Indexes are dropped before their columns, since SQLite refuses
+        // to drop an indexed column.
+        //
+        // FIXME: When we start using foreign keys, we'll have to make sure that those are still
+        // safe. Adding columns is always safe, but removals may not be if they are used in
+        // relations (see the document above)
+        let table_name = old_ty.backing_table();
+        // Dropped first, as their fields may be about to go away.
+        for fields in delta.removed_unique_constraints.iter() {
+            self.drop_unique_constraint(transaction, table_name, fields)
+                .await?;
+        }
         for field in delta.added_fields.iter() {
-            let mut column_def = ColumnDef::try_from(field)?;
+            let mut plain_field = field.clone();
+            plain_field.is_unique = false;
+            let mut column_def = ColumnDef::try_from(&plain_field)?;
             let table = Table::alter()
-                .table(Alias::new(old_ty.backing_table()))
+                .table(Alias::new(table_name))
                 .add_column(&mut column_def)
                 .to_owned();
 
             do_query!(table)?;
+            if field.is_unique {
+                self.create_index(transaction, table_name, field, true)
+                    .await?;
+            } else if field.is_indexed {
+                self.create_index(transaction, table_name, field, false)
+                    .await?;
+            }
+        }
+
+        for field in delta.updated_fields.iter() {
+            let attrs = match &field.attrs {
+                Some(attrs) => attrs,
+                None => continue,
+            };
+            let old_field = match old_ty.user_fields().find(|f| f.id == Some(field.id)) {
+                Some(old_field) => old_field,
+                None => continue,
+            };
+            if attrs.is_indexed && !old_field.is_indexed && !old_field.is_unique {
+                self.create_index(transaction, table_name, old_field, false)
+                    .await?;
+            } else if !attrs.is_indexed && old_field.is_indexed {
+                self.drop_index(transaction, table_name, old_field, false)
+                    .await?;
+            }
         }
 
         for field in delta.removed_fields.iter() {
+            self.drop_index(transaction, table_name, field, false)
+                .await?;
+            self.drop_index(transaction, table_name, field, true)
+                .await?;
             let table = Table::alter()
-                .table(Alias::new(old_ty.backing_table()))
+                .table(Alias::new(table_name))
                 .drop_column(Alias::new(&field.name))
                 .to_owned();
 
             do_query!(table)?;
         }
-        // We don't loop over the modified part of the delta: SQLite doesn't support modify columns
-        // at all, but that is fine since the currently supported field modifications are handled
-        // by ChiselStrike directly and require no modifications to the tables.
+        // Other than indexes, the modified part of the delta doesn't touch the table: SQLite
+        // doesn't support modify columns at all, but that is fine since the currently supported
+        // field modifications are handled by ChiselStrike directly and require no modifications
+        // to the tables.
         //
         // There are modifications that we can accept on application side (like changing defaults),
         // since we always write with defaults. For all others, we should error out way before we
         // get here.
+
+        // The recorded definition is stale now. Forgetting it just means the next create_table
+        // for this type re-issues its (idempotent) DDL.
+        self.forget_table_definition(transaction, table_name)
+            .await?;
         Ok(())
     }
 
@@ -329,10 +1011,15 @@ impl QueryEngine {
                     type_,
                     column_idx,
                     is_optional,
+                    is_nullable,
                     transform,
                     ..
                 } => {
-                    if *is_optional && column_is_null(row, *column_idx) {
+                    if (*is_optional || *is_nullable) && column_is_null(row, *column_idx) {
+                        // A nullable field holds null as its value, while an optional one just isn't set.
+                        if *is_nullable {
+                            ret.insert(name.clone(), serde_json::Value::Null);
+                        }
                         continue;
                     }
                     macro_rules! to_json {
@@ -348,8 +1035,17 @@ impl QueryEngine {
                             let val: f64 = row.get_unchecked(column_idx);
                             json!(val)
                         }
+                        Type::Date => {
+                            let val: f64 = row.get_unchecked(column_idx);
+                            json!(dates::format(val)?)
+                        }
                         Type::String => to_json!(&str),
                         Type::Id => to_json!(&str),
+                        Type::Json => {
+                            let val: &str = row.get(column_idx);
+                            serde_json::from_str(val)
+                                .context("failed to parse the stored JSON value")?
+                        }
                         Type::Boolean => {
                             // Similarly to the float issue, type information is not filled in
                             // *if* this value was put in as a result of coalesce() (default).
@@ -372,10 +1068,14 @@ impl QueryEngine {
                 QueryField::Entity {
                     name,
                     is_optional,
+                    is_nullable,
                     transform,
                 } => {
                     let child_entity = entity.get_child_entity(name).unwrap();
-                    if *is_optional && column_is_null(row, id_idx(child_entity)) {
+                    if (*is_optional || *is_nullable) && column_is_null(row, id_idx(child_entity)) {
+                        if *is_nullable {
+                            ret.insert(name.clone(), serde_json::Value::Null);
+                        }
                         continue;
                     }
                     let mut val = json!(Self::row_to_json(db_kind, child_entity, row)?);
@@ -390,24 +1090,6 @@ impl QueryEngine {
         Ok(ret)
     }
 
-    fn project(
-        o: Result<ResultRow>,
-        allowed_fields: &Option<HashSet<String>>,
-    ) -> Result<JsonObject> {
-        let mut o = o?;
-        if let Some(allowed_fields) = &allowed_fields {
-            let removed_keys = o
-                .iter()
-                .map(|(k, _)| k.to_owned())
-                .filter(|k| !allowed_fields.contains(k))
-                .collect::<Vec<String>>();
-            for k in &removed_keys {
-                o.remove(k);
-            }
-        }
-        Ok(o)
-    }
-
     /// Execute the given `query` and return a stream to the results.
     pub(crate) fn query(
         &self,
@@ -415,23 +1097,79 @@ impl QueryEngine {
         query_plan: QueryPlan,
     ) -> anyhow::Result<QueryResults> {
         let query = query_plan.build_query(&self.target_db())?;
-        let allowed_fields = query.allowed_fields;
         let db_kind = self.kind;
 
-        let stream = new_query_results(query.raw_sql, tr);
+        let what = format!("query on type {}", query.entity.type_name());
+        let stream = self.retrying_query_results(query.raw_sql, tr, what);
         let stream = stream.map(move |row| Self::row_to_json(db_kind, &query.entity, &row?));
-        let stream = Box::pin(stream.map(move |o| Self::project(o, &allowed_fields)));
-        Ok(stream)
+        Ok(Box::pin(stream))
+    }
+
+    /// Like `new_query_results`, but runs the query again while it runs into another transaction before returning
+    /// any row, and retries are left. Only SQLite queries are retried: a failed statement aborts a Postgres
+    /// transaction, so running it again in the same transaction would fail too.
+    fn retrying_query_results(
+        &self,
+        raw_sql: String,
+        tr: TransactionStatic,
+        what: String,
+    ) -> impl Stream<Item = Result<AnyRow>> {
+        let retry_policy = self.retry_policy;
+        let retries = match self.kind {
+            Kind::Sqlite => retry_policy.retries,
+            Kind::Postgres => 0,
+        };
+        let slow_query_log = self.slow_query_log.clone();
+        async move {
+            let mut retry = 0;
+            loop {
+                let results = new_query_results(
+                    raw_sql.clone(),
+                    tr.clone(),
+                    slow_query_log.clone(),
+                    what.clone(),
+                );
+                let mut results = Box::pin(results);
+                match results.next().await {
+                    Some(Err(err)) if retry < retries && is_statement_contention(&err) => {
+                        // Releases the transaction for the next attempt.
+                        drop(results);
+                        retry += 1;
+                        retry_policy.pause(retry, &err).await;
+                    }
+                    first => return futures::stream::iter(first).chain(results),
+                }
+            }
+        }
+        .flatten_stream()
+    }
+
+    /// Counts the rows `query_plan` would return.
+    pub(crate) async fn count(&self, tr: TransactionStatic, query_plan: QueryPlan) -> Result<u64> {
+        let query = query_plan.build_query(&self.target_db())?;
+        let raw_sql = format!("SELECT COUNT(*) FROM ({}) AS counted", query.raw_sql);
+        let mut tr = tr.lock_arc().await;
+        let started = Instant::now();
+        let row = sqlx::query(&raw_sql).fetch_one(&mut *tr).await?;
+        let what = format!("count on type {}", query.entity.type_name());
+        self.slow_query_log.check(started, &raw_sql, &what);
+        let count: i64 = row.try_get(0)?;
+        Ok(count as u64)
     }
 
     /// Execute the given `mutation`.
-    pub(crate) async fn mutate(&self, mutation: Mutation) -> Result<()> {
+    /// Runs `mutation` in a transaction of its own, returning the number of rows it affected.
+    pub(crate) async fn mutate(&self, mutation: Mutation) -> Result<u64> {
         let mut transaction = self.start_transaction().await?;
         let raw_sql = mutation.build_sql(self.target_db())?;
         let query = sqlx::query(&raw_sql);
-        transaction.execute(query).await?;
+        let started = Instant::now();
+        let result = transaction.execute(query).await?;
+        let what = format!("mutation on type {}", mutation.entity_name());
+        self.slow_query_log.check(started, &raw_sql, &what);
         QueryEngine::commit_transaction(transaction).await?;
-        Ok(())
+        self.modification_log.record(mutation.base_entity());
+        Ok(result.rows_affected())
     }
 
     /// Inserts object of type `ty` and value `ty_value` into the database.
@@ -450,52 +1188,530 @@ impl QueryEngine {
         transaction: Option<&mut Transaction<'_, Any>>,
     ) -> Result<IdTree> {
         let (inserts, id_tree) = self.prepare_insertion(ty, ty_value)?;
-        self.run_sql_queries(&inserts, transaction).await?;
+        match transaction {
+            Some(transaction) if self.retry_policy.retries > 0 => {
+                self.run_sql_queries_retrying(ty, &inserts, transaction)
+                    .await?
+            }
+            transaction => self.run_sql_queries(ty, &inserts, transaction).await?,
+        }
         Ok(id_tree)
     }
 
-    pub(crate) async fn add_row_shallow(
-        &self,
-        ty: &ObjectType,
-        ty_value: &JsonObject,
-    ) -> Result<()> {
-        let query = self.prepare_insertion_shallow(ty, ty_value)?;
-        self.run_sql_queries(&[query], None).await?;
-        Ok(())
+    /// Checks that `ty_value` could be inserted as an object of type `ty`, without inserting it.
+    pub(crate) fn check_row(&self, ty: &ObjectType, ty_value: &JsonObject) -> Result<()> {
+        self.prepare_insertion(ty, ty_value).map(|_| ())
     }
 
-    pub(crate) async fn fetch_one(&self, q: SqlWithArguments) -> Result<AnyRow> {
-        Ok(q.get_sqlx().fetch_one(&self.pool).await?)
+    /// Inserts all of `values` into `ty`, or none of them if any fails. Returns their ids in order.
+    pub(crate) async fn add_rows(
+        &self,
+        ty: &ObjectType,
+        values: &[JsonObject],
+        transaction: Option<&mut Transaction<'_, Any>>,
+    ) -> Result<Vec<IdTree>> {
+        // Check all the values before writing any of them.
+        let mut inserts = vec![];
+        let mut id_trees = vec![];
+        for (i, value) in values.iter().enumerate() {
+            let (queries, id_tree) = self
+                .prepare_insertion(ty, value)
+                .with_context(|| format!("cannot save value {} of the batch", i))?;
+            inserts.extend(queries);
+            id_trees.push(id_tree);
+        }
+        if let Some(transaction) = transaction {
+            // A failure must not leave part of the batch in the enclosing transaction, so
+            // run the batch in a savepoint.
+            let mut savepoint = Acquire::begin(transaction).await?;
+            self.run_sql_queries_in(ty, &inserts, &mut savepoint)
+                .await?;
+            savepoint.commit().await?;
+        } else {
+            self.run_sql_queries(ty, &inserts, None).await?;
+        }
+        Ok(id_trees)
     }
 
-    async fn run_sql_queries(
+    /// Inserts `ty_value` as a new object of type `ty` or, if an object with the same values for
+    /// `conflict_fields` exists, writes the given fields to that object instead, and nested objects
+    /// without an id to the objects it references.  The conflict fields must be unique, alone or as a
+    /// unique constraint.  A soft-deleted object is never written to, so a conflict with one fails.
+    /// Returns the id of the written object.
+    pub(crate) async fn upsert_row(
         &self,
-        queries: &[SqlWithArguments],
+        ty: &ObjectType,
+        conflict_fields: &[String],
+        ty_value: &JsonObject,
         transaction: Option<&mut Transaction<'_, Any>>,
-    ) -> Result<()> {
+    ) -> Result<String> {
+        anyhow::ensure!(
+            ty.is_unique_key(conflict_fields),
+            "cannot upsert into type {}: ({}) is not a unique constraint",
+            ty.name(),
+            conflict_fields.join(", ")
+        );
+        for name in conflict_fields {
+            anyhow::ensure!(
+                ty_value.get(name).map_or(false, |v| !v.is_null()),
+                "cannot upsert into type {}: no value for field {}",
+                ty.name(),
+                name
+            );
+        }
+
         if let Some(transaction) = transaction {
-            for q in queries {
-                transaction.fetch_one(q.get_sqlx()).await?;
-            }
+            let mut savepoint = Acquire::begin(transaction).await?;
+            let id = self
+                .upsert_row_in(ty, conflict_fields, ty_value, &mut savepoint)
+                .await?;
+            savepoint.commit().await?;
+            Ok(id)
         } else {
             let mut transaction = self.start_transaction().await?;
-            for q in queries {
-                transaction.fetch_one(q.get_sqlx()).await?;
-            }
+            let id = self
+                .upsert_row_in(ty, conflict_fields, ty_value, &mut transaction)
+                .await?;
             QueryEngine::commit_transaction(transaction).await?;
+            Ok(id)
         }
-        Ok(())
-    }
-
-    fn incompatible(field: &Field, ty: &ObjectType) -> anyhow::Error {
-        anyhow!(
-            "provided data for field `{}` are incompatible with given type `{}`",
-            field.name,
-            ty.name()
-        )
     }
 
-    /// Recursively generates insert SQL queries necessary to insert object of type `ty`
+    async fn upsert_row_in(
+        &self,
+        ty: &ObjectType,
+        conflict_fields: &[String],
+        ty_value: &JsonObject,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<String> {
+        let mut ty_value = ty_value.clone();
+        if let Some(existing) = self
+            .find_conflicting_row(ty, conflict_fields, &ty_value, transaction)
+            .await?
+        {
+            self.reuse_nested_ids(ty, &mut ty_value, existing, transaction)
+                .await?;
+        }
+        let (mut queries, _) = self.prepare_insertion(ty, &ty_value)?;
+        // The object itself is inserted last, after the ones it references.
+        let mut upsert = queries.pop().unwrap();
+        upsert.sql = self.make_insert_query(ty, &ty_value, Some(conflict_fields))?;
+
+        self.run_sql_queries_in(ty, &queries, transaction).await?;
+        let started = Instant::now();
+        let row = transaction
+            .fetch_optional(upsert.get_sqlx())
+            .await
+            .map_err(|e| write_error(ty, e))?;
+        self.slow_query_log.check(
+            started,
+            &upsert.sql,
+            &format!("upsert into type {}", ty.name()),
+        );
+        // Only a soft-deleted object leaves nothing to return, as it isn't written to.
+        let row = row.ok_or_else(|| UniqueViolation {
+            type_name: ty.name().to_owned(),
+            fields: conflict_fields.to_vec(),
+        })?;
+        Ok(row.try_get::<String, _>("id")?)
+    }
+
+    /// Finds the live object of type `ty` that has the same values for `conflict_fields` as
+    /// `ty_value`, if there is one and it can reference nested objects.
+    async fn find_conflicting_row(
+        &self,
+        ty: &ObjectType,
+        conflict_fields: &[String],
+        ty_value: &JsonObject,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<Option<AnyRow>> {
+        if !ty.user_fields().any(|f| matches!(f.type_, Type::Object(_))) {
+            return Ok(None);
+        }
+        let mut conditions = vec![];
+        let mut args = vec![];
+        for name in conflict_fields {
+            let field = ty.get_field(name).unwrap();
+            if matches!(field.type_, Type::Object(_)) {
+                return Ok(None);
+            }
+            args.push(self.convert_to_argument(field, ty_value)?);
+            conditions.push(format!("\"{}\" = ${}", name, args.len()));
+        }
+        let query = SqlWithArguments {
+            sql: format!(
+                "SELECT * FROM \"{}\" WHERE {}{}",
+                ty.backing_table(),
+                conditions.join(" AND "),
+                live_rows(ty)
+            ),
+            args,
+        };
+        Ok(transaction.fetch_optional(query.get_sqlx()).await?)
+    }
+
+    /// Gives the nested objects in `value` that have no id the ids of the objects that `row`, the
+    /// object of type `ty` that `value` is written to, references, all the way down.  Writing
+    /// `value` then writes over those objects instead of leaving them unreferenced.
+    async fn reuse_nested_ids(
+        &self,
+        ty: &ObjectType,
+        value: &mut JsonObject,
+        row: AnyRow,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<()> {
+        let mut pending = vec![(vec![], ty, row)];
+        while let Some((path, ty, row)) = pending.pop() {
+            for field in ty.user_fields() {
+                let nested_ty = match &field.type_ {
+                    Type::Object(nested_ty) if !nested_ty.is_auth() => nested_ty,
+                    _ => continue,
+                };
+                let nested_id = match row.try_get::<Option<String>, _>(field.name.as_str())? {
+                    Some(id) => id,
+                    None => continue,
+                };
+                let nested_value = object_at(value, &path)
+                    .get_mut(&field.name)
+                    .and_then(|v| v.as_object_mut());
+                match nested_value {
+                    Some(nested_value) if !nested_value.contains_key("id") => {
+                        nested_value.insert("id".to_owned(), nested_id.clone().into());
+                    }
+                    _ => continue,
+                }
+                let query = SqlWithArguments {
+                    sql: format!(
+                        "SELECT * FROM \"{}\" WHERE \"id\" = $1",
+                        nested_ty.backing_table()
+                    ),
+                    args: vec![SqlValue::String(nested_id)],
+                };
+                if let Some(nested_row) = transaction.fetch_optional(query.get_sqlx()).await? {
+                    let mut nested_path = path.clone();
+                    nested_path.push(field.name.clone());
+                    pending.push((nested_path, nested_ty.as_ref(), nested_row));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the fields present in `patch` to the object of type `ty` with the given `id`, leaving
+    /// all other fields as they are.  A nested object field takes a patch of its own, which is applied
+    /// to the object it currently references.  Returns false if there is no such object, which
+    /// includes one that was soft-deleted.
+    pub(crate) async fn update_row(
+        &self,
+        ty: &ObjectType,
+        id: &str,
+        patch: &JsonObject,
+        transaction: Option<&mut Transaction<'_, Any>>,
+    ) -> Result<bool> {
+        if let Some(transaction) = transaction {
+            // Like add_rows, don't leave half of a nested patch in the enclosing transaction.
+            let mut savepoint = Acquire::begin(transaction).await?;
+            let found = self.update_row_in(ty, id, patch, &mut savepoint).await?;
+            savepoint.commit().await?;
+            Ok(found)
+        } else {
+            let mut transaction = self.start_transaction().await?;
+            let found = self.update_row_in(ty, id, patch, &mut transaction).await?;
+            QueryEngine::commit_transaction(transaction).await?;
+            Ok(found)
+        }
+    }
+
+    async fn update_row_in(
+        &self,
+        ty: &ObjectType,
+        id: &str,
+        patch: &JsonObject,
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<bool> {
+        let mut pending = vec![(ty, id.to_owned(), patch)];
+        let mut top_level = true;
+        while let Some((ty, id, patch)) = pending.pop() {
+            let (query, nested) = self.prepare_update(ty, &id, patch)?;
+            let started = Instant::now();
+            let row = transaction
+                .fetch_optional(query.get_sqlx())
+                .await
+                .map_err(|e| write_error(ty, e))?;
+            self.slow_query_log.check(
+                started,
+                &query.sql,
+                &format!("update of type {}", ty.name()),
+            );
+            let row = match row {
+                Some(row) => row,
+                None if top_level => return Ok(false),
+                None => anyhow::bail!("{} object with id {} does not exist", ty.name(), id),
+            };
+            top_level = false;
+            for (field, nested_ty, nested_patch) in nested {
+                let nested_id = row
+                    .try_get::<Option<String>, _>(field.name.as_str())?
+                    .with_context(|| {
+                        format!(
+                            "cannot update field `{}` of type `{}`: it holds no object",
+                            field.name,
+                            ty.name()
+                        )
+                    })?;
+                pending.push((nested_ty, nested_id, nested_patch));
+            }
+        }
+        Ok(true)
+    }
+
+    /// Builds the query updating the scalar fields of `patch` on object `id` of type `ty`, which returns
+    /// the updated row.  Also returns the patches for nested object fields, to be applied to the objects
+    /// that row references.
+    #[allow(clippy::type_complexity)]
+    fn prepare_update<'a>(
+        &self,
+        ty: &'a ObjectType,
+        id: &str,
+        patch: &'a JsonObject,
+    ) -> Result<(
+        SqlWithArguments,
+        Vec<(&'a Field, &'a ObjectType, &'a JsonObject)>,
+    )> {
+        let mut assignments = vec![];
+        let mut args = vec![];
+        let mut nested = vec![];
+        for (name, value) in patch.iter() {
+            let field = ty
+                .get_field(name)
+                .ok_or_else(|| anyhow!("field {} not present in {}", name, ty.name()))?;
+            let incompatible_data = || QueryEngine::incompatible(field, ty);
+            if field.type_ == Type::Id {
+                anyhow::ensure!(
+                    value.as_str() == Some(id),
+                    "cannot change the id of a {} object",
+                    ty.name()
+                );
+                continue;
+            }
+            if value.is_null() {
+                anyhow::ensure!(
+                    field.accepts_null(),
+                    "field `{}` of type `{}` is neither optional nor nullable",
+                    field.name,
+                    ty.name()
+                );
+                // sqlx has trouble binding null values in some cases; set them verbatim.
+                assignments.push(format!("\"{}\" = NULL", field.name));
+                continue;
+            }
+            match &field.type_ {
+                Type::Object(nested_ty) => {
+                    if nested_ty.is_auth() {
+                        anyhow::bail!("Cannot save into type {}.", nested_ty.name());
+                    }
+                    let nested_patch = value
+                        .as_object()
+                        .context("unexpected json type (expected an object)")
+                        .with_context(incompatible_data)?;
+                    nested.push((field, nested_ty.as_ref(), nested_patch));
+                }
+                _ => {
+                    check_allowed_value(ty, field, Some(value))?;
+                    args.push(
+                        self.convert_to_argument(field, patch)
+                            .with_context(incompatible_data)?,
+                    );
+                    assignments.push(format!("\"{}\" = ${}", field.name, args.len()));
+                }
+            }
+        }
+        args.push(SqlValue::String(id.to_owned()));
+        let sql = if assignments.is_empty() {
+            format!(
+                "SELECT * FROM \"{}\" WHERE \"id\" = ${}{}",
+                ty.backing_table(),
+                args.len(),
+                live_rows(ty)
+            )
+        } else {
+            format!(
+                "UPDATE \"{}\" SET {} WHERE \"id\" = ${}{} RETURNING *",
+                ty.backing_table(),
+                assignments.join(","),
+                args.len(),
+                live_rows(ty)
+            )
+        };
+        Ok((SqlWithArguments { sql, args }, nested))
+    }
+
+    /// Adds `delta` to the number field `field_name` of the object of type `ty` with the given `id`.
+    /// This is done by the database in a single statement, so concurrent increments can't lose each
+    /// other's updates the way reading the value and writing it back would.  An optional field that is
+    /// null counts as 0.  Returns the new value, or None if there is no such object.
+    pub(crate) async fn increment(
+        &self,
+        ty: &ObjectType,
+        id: &str,
+        field_name: &str,
+        delta: f64,
+        transaction: Option<&mut Transaction<'_, Any>>,
+    ) -> Result<Option<f64>> {
+        let field = ty
+            .get_field(field_name)
+            .ok_or_else(|| anyhow!("field {} not present in {}", field_name, ty.name()))?;
+        anyhow::ensure!(
+            field.type_ == Type::Float,
+            "cannot increment field `{}` of type `{}`: it is not a number",
+            field.name,
+            ty.name()
+        );
+        let query = SqlWithArguments {
+            sql: format!(
+                "UPDATE \"{table}\" SET \"{field}\" = COALESCE(\"{field}\", 0) + $1 WHERE \"id\" = $2{live} RETURNING \"{field}\"",
+                table = ty.backing_table(),
+                field = field.name,
+                live = live_rows(ty)
+            ),
+            args: vec![SqlValue::F64(delta), SqlValue::String(id.to_owned())],
+        };
+        let started = Instant::now();
+        let row = match transaction {
+            Some(transaction) => transaction.fetch_optional(query.get_sqlx()).await,
+            None => query.get_sqlx().fetch_optional(&self.pool).await,
+        }
+        .map_err(|e| write_error(ty, e))?;
+        self.slow_query_log.check(
+            started,
+            &query.sql,
+            &format!("increment of type {}", ty.name()),
+        );
+        row.map(|row| row.try_get::<f64, _>(0))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    pub(crate) async fn add_row_shallow(
+        &self,
+        ty: &ObjectType,
+        ty_value: &JsonObject,
+    ) -> Result<()> {
+        let query = self.prepare_insertion_shallow(ty, ty_value)?;
+        self.run_sql_queries(ty, &[query], None).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn fetch_one(&self, q: SqlWithArguments) -> Result<AnyRow> {
+        let started = Instant::now();
+        let row = q.get_sqlx().fetch_one(&self.pool).await?;
+        self.slow_query_log
+            .check(started, &q.sql, "single row fetch");
+        Ok(row)
+    }
+
+    /// Runs `sql`, a statement written by the application, binding `params` to its placeholders $1, $2, ....
+    /// Returns the rows it produced as JSON objects keyed by column name.  No policies apply to the results.
+    pub(crate) async fn raw_query(
+        &self,
+        tr: TransactionStatic,
+        sql: String,
+        params: Vec<serde_json::Value>,
+    ) -> Result<Vec<JsonObject>> {
+        let args = params
+            .into_iter()
+            .enumerate()
+            .map(|(i, param)| match param {
+                serde_json::Value::Bool(b) => Ok(SqlValue::Bool(b)),
+                serde_json::Value::Number(n) => Ok(SqlValue::F64(n.as_f64().unwrap())),
+                serde_json::Value::String(s) => Ok(SqlValue::String(s)),
+                v => Err(anyhow!(
+                    "Raw query parameter ${} must be a string, number or boolean, got {}",
+                    i + 1,
+                    v
+                )),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let query = SqlWithArguments { sql, args };
+        let mut tr = tr.lock_arc().await;
+        let started = Instant::now();
+        let rows = query.get_sqlx().fetch_all(&mut *tr).await?;
+        self.slow_query_log.check(started, &query.sql, "raw query");
+        rows.iter().map(raw_row_to_json).collect()
+    }
+
+    /// Runs `queries`, which insert a value of type `ty`.
+    async fn run_sql_queries(
+        &self,
+        ty: &ObjectType,
+        queries: &[SqlWithArguments],
+        transaction: Option<&mut Transaction<'_, Any>>,
+    ) -> Result<()> {
+        if let Some(transaction) = transaction {
+            self.run_sql_queries_in(ty, queries, transaction).await?;
+        } else {
+            let mut transaction = self.start_transaction().await?;
+            self.run_sql_queries_in(ty, queries, &mut transaction)
+                .await?;
+            QueryEngine::commit_transaction(transaction).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs `queries` in a savepoint of `transaction`, running them again while they fail with an error that
+    /// `is_statement_contention` accepts and retries are left. Rolling back to the savepoint undoes what the
+    /// failed attempt wrote, and keeps a Postgres transaction usable after a failed statement.
+    async fn run_sql_queries_retrying(
+        &self,
+        ty: &ObjectType,
+        queries: &[SqlWithArguments],
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<()> {
+        let mut retry = 0;
+        loop {
+            let mut savepoint = Acquire::begin(&mut *transaction).await?;
+            match self.run_sql_queries_in(ty, queries, &mut savepoint).await {
+                Ok(()) => {
+                    savepoint.commit().await?;
+                    return Ok(());
+                }
+                Err(err) if retry < self.retry_policy.retries && is_statement_contention(&err) => {
+                    savepoint.rollback().await?;
+                    retry += 1;
+                    self.retry_policy.pause(retry, &err).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn run_sql_queries_in(
+        &self,
+        ty: &ObjectType,
+        queries: &[SqlWithArguments],
+        transaction: &mut Transaction<'_, Any>,
+    ) -> Result<()> {
+        let what = format!("insert into type {}", ty.name());
+        for q in queries {
+            let started = Instant::now();
+            transaction
+                .fetch_one(q.get_sqlx())
+                .await
+                .map_err(|e| write_error(ty, e))?;
+            self.slow_query_log.check(started, &q.sql, &what);
+        }
+        Ok(())
+    }
+
+    fn incompatible(field: &Field, ty: &ObjectType) -> anyhow::Error {
+        anyhow!(
+            "provided data for field `{}` are incompatible with given type `{}`",
+            field.name,
+            ty.name()
+        )
+    }
+
+    /// Recursively generates insert SQL queries necessary to insert object of type `ty`
     /// and value `ty_value` into database.
     /// Returns vector of SQL insert queries with corresponding arguments and IdTree of
     /// inserted objects.
@@ -504,6 +1720,15 @@ impl QueryEngine {
         ty: &ObjectType,
         ty_value: &JsonObject,
     ) -> Result<(Vec<SqlWithArguments>, IdTree)> {
+        if self.strict_fields {
+            if let Some(name) = ty_value.keys().find(|name| !ty.has_field(name)) {
+                anyhow::bail!(
+                    "provided data has field `{}`, which type `{}` does not have",
+                    name,
+                    ty.name()
+                );
+            }
+        }
         let mut child_ids = HashMap::<String, IdTree>::new();
         let mut obj_id = Option::<String>::None;
         let mut query_args = Vec::<SqlValue>::new();
@@ -511,9 +1736,13 @@ impl QueryEngine {
 
         for field in ty.all_fields() {
             let field_value = ty_value.get(&field.name);
-            if (field_value.is_none() || field_value.unwrap().is_null()) && field.is_optional {
+            let is_null = field_value.map_or(false, |v| v.is_null());
+            if (field_value.is_none() && field.can_be_omitted())
+                || (is_null && field.accepts_null())
+            {
                 continue;
             }
+            check_allowed_value(ty, field, field_value)?;
             let incompatible_data = || QueryEngine::incompatible(field, ty);
             let arg = match &field.type_ {
                 Type::Object(nested_type) => {
@@ -560,7 +1789,7 @@ impl QueryEngine {
         }
 
         inserts.push(SqlWithArguments {
-            sql: self.make_insert_query(ty, ty_value)?,
+            sql: self.make_insert_query(ty, ty_value, None)?,
             args: query_args,
         });
         let obj_id = obj_id
@@ -610,13 +1839,30 @@ impl QueryEngine {
             }
             Type::Float => SqlValue::F64(convert_json_value!(as_f64, f64)),
             Type::Boolean => SqlValue::Bool(convert_json_value!(as_bool, bool)),
+            Type::Json => SqlValue::String(match ty_value.get(&field.name) {
+                Some(value_json) => serde_json::to_string(value_json)?,
+                None => field.generate_value().context("failed to generate value")?,
+            }),
+            Type::Date => SqlValue::F64(match ty_value.get(&field.name) {
+                Some(value_json) => dates::parse(value_json)?,
+                None => {
+                    let value = field.generate_value().context("failed to generate value")?;
+                    dates::parse_str(&value).context("failed to parse default value")?
+                }
+            }),
         };
         Ok(arg)
     }
 
     /// For given object of type `ty` and its value `ty_value` computes a string
-    /// representing SQL query which inserts the object into database.
-    fn make_insert_query(&self, ty: &ObjectType, ty_value: &JsonObject) -> Result<String> {
+    /// representing SQL query which inserts the object into database.  An object with the
+    /// same id is replaced, as is one with the same values for `conflict_fields` if given.
+    fn make_insert_query(
+        &self,
+        ty: &ObjectType,
+        ty_value: &JsonObject,
+        conflict_fields: Option<&[String]>,
+    ) -> Result<String> {
         let mut field_binds = String::new();
         let mut field_names = vec![];
         let mut id_name = String::new();
@@ -626,10 +1872,10 @@ impl QueryEngine {
         let mut i = 0;
         for f in ty.all_fields() {
             let val = ty_value.get(&f.name);
-            if val.is_none() && f.is_optional {
+            if val.is_none() && f.can_be_omitted() {
                 continue;
             }
-            let bind = if f.is_optional && val.unwrap().is_null() {
+            let bind = if f.accepts_null() && val.map_or(false, |v| v.is_null()) {
                 // sqlx has trouble binding null values in some cases; insert them verbatim.
                 "NULL".to_string()
             } else {
@@ -663,6 +1909,40 @@ impl QueryEngine {
             );
         }
 
+        if let Some(conflict_fields) = conflict_fields {
+            // The existing object keeps its id.
+            let update_binds = field_names
+                .iter()
+                .filter(|f| **f != id_name)
+                .map(|f| format!("\"{}\" = excluded.\"{}\"", f, f))
+                .join(",");
+            // A soft-deleted object stays as it is.
+            let live = if ty.soft_deletes() {
+                format!(
+                    " WHERE \"{}\".\"{}\" IS NULL",
+                    ty.backing_table(),
+                    SOFT_DELETE_FIELD
+                )
+            } else {
+                String::new()
+            };
+            return Ok(std::format!(
+                "INSERT INTO \"{}\" ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {}{} RETURNING *",
+                &ty.backing_table(),
+                field_names
+                    .into_iter()
+                    .map(|f| format!("\"{}\"", f))
+                    .join(","),
+                field_binds,
+                conflict_fields
+                    .iter()
+                    .map(|f| format!("\"{}\"", f))
+                    .join(","),
+                update_binds,
+                live,
+            ));
+        }
+
         Ok(std::format!(
             "INSERT INTO \"{}\" ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {} WHERE \"{}\".\"{}\" = {} RETURNING *",
             &ty.backing_table(),
@@ -683,9 +1963,14 @@ impl QueryEngine {
     ) -> Result<SqlWithArguments> {
         let mut query_args = Vec::<SqlValue>::new();
         for field in ty.all_fields() {
-            if ty_value.get(&field.name).is_none() && field.is_optional {
+            let field_value = ty_value.get(&field.name);
+            let is_null = field_value.map_or(false, |v| v.is_null());
+            if (field_value.is_none() && field.can_be_omitted())
+                || (is_null && field.accepts_null())
+            {
                 continue;
             }
+            check_allowed_value(ty, field, ty_value.get(&field.name))?;
             let arg = self
                 .convert_to_argument(field, ty_value)
                 .with_context(|| QueryEngine::incompatible(field, ty))?;
@@ -693,8 +1978,1048 @@ impl QueryEngine {
         }
 
         Ok(SqlWithArguments {
-            sql: self.make_insert_query(ty, ty_value)?,
+            sql: self.make_insert_query(ty, ty_value, None)?,
             args: query_args,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::query::tests::{
+        fetch_rows, in_memory_engine, make_field, make_object, VERSION,
+    };
+    use crate::types::{AuthOrNot, NewObject};
+    use tempfile::NamedTempFile;
+
+    async fn connect(db_file: &NamedTempFile) -> QueryEngine {
+        let db_uri = format!("sqlite://{}?mode=rwc", db_file.path().to_string_lossy());
+        let conn = DbConnection::connect(&db_uri, 1).await.unwrap();
+        QueryEngine::local_connection(&conn, 1).await.unwrap()
+    }
+
+    async fn create_table(qe: &QueryEngine, ty: &ObjectType) -> bool {
+        let mut tr = qe.start_transaction().await.unwrap();
+        let created = qe.create_table(&mut tr, ty).await.unwrap();
+        QueryEngine::commit_transaction(tr).await.unwrap();
+        created
+    }
+
+    fn retry_policy(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            base_delay: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors() {
+        let calls = std::cell::Cell::new(0);
+        let result = retry_policy(3)
+            .run(|| {
+                calls.set(calls.get() + 1);
+                let call = calls.get();
+                async move {
+                    if call == 1 {
+                        Err(sqlx::Error::Io(std::io::ErrorKind::ConnectionReset.into()))
+                            .context("starting transaction")
+                    } else {
+                        Ok(call)
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 2);
+
+        // Errors that are not transient fail without retrying.
+        calls.set(0);
+        let result: Result<()> = retry_policy(3)
+            .run(|| {
+                calls.set(calls.get() + 1);
+                async { Err(sqlx::Error::RowNotFound.into()) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+
+        // Nor is timing out waiting for a connection, which a retry would only wait for again.
+        calls.set(0);
+        let result: Result<()> = retry_policy(3)
+            .run(|| {
+                calls.set(calls.get() + 1);
+                async { Err(sqlx::Error::PoolTimedOut.into()) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+
+        // Once out of retries, the last error is returned.
+        calls.set(0);
+        let result: Result<()> = retry_policy(2)
+            .run(|| {
+                calls.set(calls.get() + 1);
+                async { Err(sqlx::Error::Io(std::io::ErrorKind::BrokenPipe.into()).into()) }
+            })
+            .await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<sqlx::Error>(),
+            Some(sqlx::Error::Io(_))
+        ));
+        assert_eq!(calls.get(), 3);
+    }
+
+    /// A database error with just a code, as the database would report it.
+    #[derive(Debug)]
+    struct CodedError(&'static str);
+
+    impl std::fmt::Display for CodedError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "database error {}", self.0)
+        }
+    }
+
+    impl std::error::Error for CodedError {}
+
+    impl sqlx::error::DatabaseError for CodedError {
+        fn message(&self) -> &str {
+            "database error"
+        }
+
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some(self.0.into())
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+    }
+
+    fn coded(code: &'static str) -> anyhow::Error {
+        anyhow::Error::new(sqlx::Error::Database(Box::new(CodedError(code)))).context("writing")
+    }
+
+    #[test]
+    fn classifies_database_errors() {
+        // SQLITE_BUSY, SQLITE_BUSY_SNAPSHOT, SQLITE_LOCKED, serialization_failure and deadlock_detected.
+        for code in ["5", "517", "6", "40001", "40P01"] {
+            assert!(is_contention(&coded(code)), "{}", code);
+        }
+        // A serialization failure is only retried with a new transaction.
+        assert!(is_statement_contention(&coded("40P01")));
+        assert!(!is_statement_contention(&coded("40001")));
+        // connection_failure and admin_shutdown.
+        for code in ["08006", "57P01"] {
+            assert!(is_connection_error(&coded(code)), "{}", code);
+            assert!(!is_contention(&coded(code)), "{}", code);
+        }
+        // SQLITE_CONSTRAINT_UNIQUE and unique_violation.
+        for code in ["2067", "23505"] {
+            assert!(!is_transient(&coded(code)), "{}", code);
+        }
+        assert!(!is_transient(&sqlx::Error::PoolTimedOut.into()));
+    }
+
+    #[tokio::test]
+    async fn create_table_survives_restart() {
+        let person = make_object(
+            "Person",
+            vec![
+                make_field("name", Type::String),
+                make_field("age", Type::Float),
+            ],
+        );
+        let db_file = NamedTempFile::new().unwrap();
+
+        let qe = connect(&db_file).await;
+        assert!(create_table(&qe, &person).await);
+        assert!(!create_table(&qe, &person).await);
+        drop(qe);
+
+        // A new engine against the same database sees the table is up to date.
+        let qe = connect(&db_file).await;
+        assert!(!create_table(&qe, &person).await);
+
+        // A changed definition is applied, and dropping a table forgets its definition.
+        let person_v2 = make_object(
+            "Person",
+            vec![
+                make_field("name", Type::String),
+                make_field("age", Type::Float),
+                make_field("email", Type::String),
+            ],
+        );
+        let mut tr = qe.start_transaction().await.unwrap();
+        qe.drop_table(&mut tr, &person).await.unwrap();
+        QueryEngine::commit_transaction(tr).await.unwrap();
+        assert!(create_table(&qe, &person_v2).await);
+        assert!(!create_table(&qe, &person_v2).await);
+    }
+
+    async fn index_names(qe: &QueryEngine, table: &str) -> Vec<String> {
+        let rows = sqlx::query(
+            "SELECT name FROM sqlite_master \
+             WHERE type = 'index' AND tbl_name = $1 AND name NOT LIKE 'sqlite_%'",
+        )
+        .bind(table)
+        .fetch_all(&qe.pool)
+        .await
+        .unwrap();
+        rows.iter()
+            .map(|r| r.get::<String, _>(0))
+            .sorted()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn indexed_fields() {
+        let mut email = make_field("email", Type::String);
+        email.is_indexed = true;
+        let person = make_object("Person", vec![make_field("name", Type::String), email]);
+        let table = person.backing_table();
+        let qe = in_memory_engine().await;
+        assert!(create_table(&qe, &person).await);
+        assert_eq!(
+            index_names(&qe, table).await,
+            vec![format!("{}_email_index", table)]
+        );
+
+        // SQLite can't add UNIQUE columns, so added unique fields get a unique index.
+        let mut nickname = make_field("nickname", Type::String);
+        nickname.is_optional = true;
+        nickname.is_unique = true;
+        let delta = ObjectDelta {
+            added_fields: vec![nickname],
+            removed_fields: vec![person.get_field("email").unwrap().clone()],
+            updated_fields: vec![],
+            removed_unique_constraints: vec![],
+            soft_delete: None,
+        };
+        let mut tr = qe.start_transaction().await.unwrap();
+        qe.alter_table(&mut tr, &person, delta).await.unwrap();
+        QueryEngine::commit_transaction(tr).await.unwrap();
+        assert_eq!(
+            index_names(&qe, table).await,
+            vec![format!("{}_nickname_unique", table)]
+        );
+    }
+
+    #[tokio::test]
+    async fn unique_violation() {
+        let mut email = make_field("email", Type::String);
+        email.is_unique = true;
+        let person = make_object("Person", vec![email]);
+        let qe = in_memory_engine().await;
+        create_table(&qe, &person).await;
+
+        let alice =
+            json!({"id": "00000000-0000-0000-0000-000000000001", "email": "alice@example.com"});
+        let alice = alice.as_object().unwrap();
+        qe.add_row(&person, alice, None).await.unwrap();
+        // Saving the same row again updates it, which is no conflict.
+        qe.add_row(&person, alice, None).await.unwrap();
+
+        let impostor =
+            json!({"id": "00000000-0000-0000-0000-000000000002", "email": "alice@example.com"});
+        let err = qe
+            .add_row(&person, impostor.as_object().unwrap(), None)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<UniqueViolation>().is_some());
+        assert_eq!(
+            err.to_string(),
+            "Conflict: another Person already has the same value for email"
+        );
+    }
+
+    #[tokio::test]
+    async fn disallowed_values() {
+        let status = make_field("status", Type::String)
+            .with_allowed_values(vec!["open".into(), "closed".into()]);
+        let ticket = make_object("Ticket", vec![status]);
+        let qe = in_memory_engine().await;
+        create_table(&qe, &ticket).await;
+
+        let id = "00000000-0000-0000-0000-000000000001";
+        let open = json!({"id": id, "status": "open"});
+        qe.add_row(&ticket, open.as_object().unwrap(), None)
+            .await
+            .unwrap();
+
+        let lost = json!({"id": id, "status": "lost"});
+        let err = qe
+            .add_row(&ticket, lost.as_object().unwrap(), None)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<DisallowedValue>().is_some());
+        assert_eq!(
+            err.to_string(),
+            "Bad Request: field `status` of Ticket can't be \"lost\", only one of \"open\", \"closed\""
+        );
+
+        let lost = json!({"status": "lost"});
+        let err = qe
+            .update_row(&ticket, id, lost.as_object().unwrap(), None)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<DisallowedValue>().is_some());
+
+        let closed = json!({"status": "closed"});
+        qe.update_row(&ticket, id, closed.as_object().unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(fetch_rows(&qe, &ticket).await[0]["status"], "closed");
+    }
+
+    #[tokio::test]
+    async fn update_skips_soft_deleted() {
+        let mut deleted_at = make_field(SOFT_DELETE_FIELD, Type::Float);
+        deleted_at.is_optional = true;
+        let fields = vec![make_field("text", Type::String), deleted_at];
+        let note = ObjectType::new(
+            NewObject::new("Note", VERSION),
+            fields,
+            AuthOrNot::IsNotAuth,
+        )
+        .unwrap()
+        .with_soft_delete(true)
+        .unwrap();
+        let note = Arc::new(note);
+        let qe = in_memory_engine().await;
+        create_table(&qe, &note).await;
+
+        let id = "00000000-0000-0000-0000-000000000001";
+        let draft = json!({"id": id, "text": "draft"});
+        qe.add_row(&note, draft.as_object().unwrap(), None)
+            .await
+            .unwrap();
+        let deleted = json!({SOFT_DELETE_FIELD: 1000.0});
+        assert!(qe
+            .update_row(&note, id, deleted.as_object().unwrap(), None)
+            .await
+            .unwrap());
+
+        let edited = json!({"text": "edited"});
+        assert!(!qe
+            .update_row(&note, id, edited.as_object().unwrap(), None)
+            .await
+            .unwrap());
+        assert_eq!(fetch_rows(&qe, &note).await[0]["text"], "draft");
+    }
+
+    fn member_type() -> Arc<ObjectType> {
+        let fields = vec![
+            make_field("tenant", Type::String),
+            make_field("email", Type::String),
+            make_field("name", Type::String),
+        ];
+        let ty = ObjectType::new(
+            NewObject::new("Member", VERSION),
+            fields,
+            AuthOrNot::IsNotAuth,
+        )
+        .unwrap()
+        .with_unique_constraints(vec![vec!["tenant".into(), "email".into()]])
+        .unwrap();
+        Arc::new(ty)
+    }
+
+    #[tokio::test]
+    async fn composite_unique_violation() {
+        let member = member_type();
+        let qe = in_memory_engine().await;
+        create_table(&qe, &member).await;
+
+        let alice = json!({"tenant": "acme", "email": "alice@example.com", "name": "Alice"});
+        qe.add_row(&member, alice.as_object().unwrap(), None)
+            .await
+            .unwrap();
+        // Either field alone may repeat.
+        let other_tenant = json!({"tenant": "initech", "email": "alice@example.com", "name": "A"});
+        qe.add_row(&member, other_tenant.as_object().unwrap(), None)
+            .await
+            .unwrap();
+
+        let impostor = json!({"tenant": "acme", "email": "alice@example.com", "name": "Eve"});
+        let err = qe
+            .add_row(&member, impostor.as_object().unwrap(), None)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<UniqueViolation>().is_some());
+        assert_eq!(
+            err.to_string(),
+            "Conflict: another Member already has the same value for (tenant, email)"
+        );
+    }
+
+    #[tokio::test]
+    async fn upsert_on_conflict() {
+        let member = member_type();
+        let qe = in_memory_engine().await;
+        create_table(&qe, &member).await;
+        let key = ["tenant".to_owned(), "email".to_owned()];
+
+        let alice = json!({"tenant": "acme", "email": "alice@example.com", "name": "Alice"});
+        let id = qe
+            .upsert_row(&member, &key, alice.as_object().unwrap(), None)
+            .await
+            .unwrap();
+        let renamed = json!({"tenant": "acme", "email": "alice@example.com", "name": "Alicia"});
+        let same_id = qe
+            .upsert_row(&member, &key, renamed.as_object().unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(id, same_id);
+
+        let rows = fetch_rows(&qe, &member).await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["id"], id);
+        assert_eq!(rows[0]["name"], "Alicia");
+
+        let err = qe
+            .upsert_row(&member, &key[1..], renamed.as_object().unwrap(), None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot upsert into type Member: (email) is not a unique constraint"
+        );
+    }
+
+    #[tokio::test]
+    async fn upsert_reuses_nested_objects() {
+        let address = make_object("Address", vec![make_field("city", Type::String)]);
+        let mut email = make_field("email", Type::String);
+        email.is_unique = true;
+        let person = make_object(
+            "Person",
+            vec![email, make_field("address", Type::Object(address.clone()))],
+        );
+        let qe = in_memory_engine().await;
+        create_table(&qe, &address).await;
+        create_table(&qe, &person).await;
+        let key = ["email".to_owned()];
+
+        let alice = json!({"email": "alice@example.com", "address": {"city": "Lisbon"}});
+        let id = qe
+            .upsert_row(&person, &key, alice.as_object().unwrap(), None)
+            .await
+            .unwrap();
+        let moved = json!({"email": "alice@example.com", "address": {"city": "Porto"}});
+        let same_id = qe
+            .upsert_row(&person, &key, moved.as_object().unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(id, same_id);
+
+        let addresses = fetch_rows(&qe, &address).await;
+        assert_eq!(addresses.len(), 1);
+        assert_eq!(addresses[0]["city"], "Porto");
+        let people = fetch_rows(&qe, &person).await;
+        assert_eq!(people.len(), 1);
+        assert_eq!(people[0]["address"], addresses[0]["id"]);
+    }
+
+    #[tokio::test]
+    async fn upsert_leaves_soft_deleted_alone() {
+        let mut slug = make_field("slug", Type::String);
+        slug.is_unique = true;
+        let mut deleted_at = make_field(SOFT_DELETE_FIELD, Type::Float);
+        deleted_at.is_optional = true;
+        let note = ObjectType::new(
+            NewObject::new("Note", VERSION),
+            vec![slug, make_field("text", Type::String), deleted_at],
+            AuthOrNot::IsNotAuth,
+        )
+        .unwrap()
+        .with_soft_delete(true)
+        .unwrap();
+        let note = Arc::new(note);
+        let qe = in_memory_engine().await;
+        create_table(&qe, &note).await;
+        let key = ["slug".to_owned()];
+
+        let draft = json!({"slug": "hello", "text": "draft"});
+        let id = qe
+            .upsert_row(&note, &key, draft.as_object().unwrap(), None)
+            .await
+            .unwrap();
+        let deleted = json!({SOFT_DELETE_FIELD: 1000.0});
+        assert!(qe
+            .update_row(&note, &id, deleted.as_object().unwrap(), None)
+            .await
+            .unwrap());
+
+        let edited = json!({"slug": "hello", "text": "edited"});
+        let err = qe
+            .upsert_row(&note, &key, edited.as_object().unwrap(), None)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<UniqueViolation>().is_some());
+        let rows = fetch_rows(&qe, &note).await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["text"], "draft");
+    }
+
+    #[tokio::test]
+    async fn absent_fields_get_defaults() {
+        let optional_field = |name: &str, ty: Type, default: Option<&str>| {
+            let desc = crate::types::NewField::new(name, ty, VERSION).unwrap();
+            Field::new(desc, vec![], default.map(str::to_owned), true, false, false)
+        };
+        let person = make_object(
+            "Person",
+            vec![
+                make_field("name", Type::String),
+                optional_field("nickname", Type::String, Some("anonymous")),
+                optional_field("score", Type::Float, Some("-1")),
+                optional_field("active", Type::Boolean, Some("true")),
+                optional_field("bio", Type::String, None),
+            ],
+        );
+        let qe = in_memory_engine().await;
+        create_table(&qe, &person).await;
+
+        let alice = json!({"id": "00000000-0000-0000-0000-000000000001", "name": "alice"});
+        qe.add_row(&person, alice.as_object().unwrap(), None)
+            .await
+            .unwrap();
+        // Explicit values, including null, are stored as given.
+        let bob = json!({"id": "00000000-0000-0000-0000-000000000002", "name": "bob", "nickname": null, "score": 7.0});
+        qe.add_row(&person, bob.as_object().unwrap(), None)
+            .await
+            .unwrap();
+
+        let rows = sqlx::query(&format!(
+            "SELECT nickname, score, active, bio FROM \"{}\" ORDER BY id",
+            person.backing_table()
+        ))
+        .fetch_all(&qe.pool)
+        .await
+        .unwrap();
+        let row = |i: usize| {
+            (
+                rows[i].get::<Option<String>, _>(0),
+                rows[i].get::<Option<f64>, _>(1),
+                rows[i].get::<Option<bool>, _>(2),
+                rows[i].get::<Option<String>, _>(3),
+            )
+        };
+        assert_eq!(
+            row(0),
+            (Some("anonymous".into()), Some(-1.0), Some(true), None)
+        );
+        assert_eq!(row(1), (None, Some(7.0), Some(true), None));
+    }
+
+    #[tokio::test]
+    async fn unknown_fields() {
+        let person = make_object(
+            "Person",
+            vec![
+                make_field("name", Type::String),
+                make_field("email", Type::String),
+            ],
+        );
+        let typo = json!({
+            "id": "00000000-0000-0000-0000-000000000001",
+            "name": "alice",
+            "email": "alice@example.com",
+            "emial": "alice@example.org",
+        });
+        let typo = typo.as_object().unwrap();
+
+        // By default, fields the type doesn't have are dropped.
+        let qe = in_memory_engine().await;
+        create_table(&qe, &person).await;
+        qe.add_row(&person, typo, None).await.unwrap();
+        let email: String =
+            sqlx::query(&format!("SELECT email FROM \"{}\"", person.backing_table()))
+                .fetch_one(&qe.pool)
+                .await
+                .unwrap()
+                .get(0);
+        assert_eq!(email, "alice@example.com");
+
+        // In strict mode, they are an error that names them.
+        let qe = qe.with_strict_fields(true);
+        let mut typo = typo.clone();
+        typo["id"] = json!("00000000-0000-0000-0000-000000000002");
+        let err = qe.add_row(&person, &typo, None).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "provided data has field `emial`, which type `Person` does not have"
+        );
+        typo.remove("emial");
+        qe.add_row(&person, &typo, None).await.unwrap();
+        assert_eq!(count_rows(&qe, &person).await, 2);
+    }
+
+    #[tokio::test]
+    async fn generated_ids() {
+        let person = make_object("Person", vec![make_field("name", Type::String)]);
+        let qe = in_memory_engine().await;
+        create_table(&qe, &person).await;
+
+        let alice = json!({"name": "alice"});
+        let first = qe
+            .add_row(&person, alice.as_object().unwrap(), None)
+            .await
+            .unwrap();
+        let second = qe
+            .add_row(&person, alice.as_object().unwrap(), None)
+            .await
+            .unwrap();
+        assert_ne!(first.id, second.id);
+        Uuid::parse_str(&first.id).unwrap();
+
+        let ids = sqlx::query(&format!(
+            "SELECT id FROM \"{}\" ORDER BY id",
+            person.backing_table()
+        ))
+        .fetch_all(&qe.pool)
+        .await
+        .unwrap()
+        .iter()
+        .map(|r| r.get::<String, _>(0))
+        .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [first.id, second.id]
+                .into_iter()
+                .sorted()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn partial_update() {
+        let address = make_object(
+            "Address",
+            vec![
+                make_field("city", Type::String),
+                make_field("zip", Type::String),
+            ],
+        );
+        let mut bio = make_field("bio", Type::String);
+        bio.is_optional = true;
+        let person = make_object(
+            "Person",
+            vec![
+                make_field("name", Type::String),
+                make_field("age", Type::Float),
+                bio,
+                make_field("address", Type::Object(address.clone())),
+            ],
+        );
+        let qe = in_memory_engine().await;
+        create_table(&qe, &address).await;
+        create_table(&qe, &person).await;
+
+        let alice_id = Uuid::new_v4().to_string();
+        let alice = json!({
+            "id": alice_id, "name": "alice", "age": 30.0, "bio": "hi",
+            "address": {"city": "Lisbon", "zip": "1000"}
+        });
+        qe.add_row(&person, alice.as_object().unwrap(), None)
+            .await
+            .unwrap();
+
+        let patch = |v: serde_json::Value| v.as_object().unwrap().clone();
+        let update = |p: JsonObject| {
+            let (qe, person, id) = (&qe, &person, &alice_id);
+            async move { qe.update_row(person, id, &p, None).await }
+        };
+        assert!(update(patch(json!({"age": 31.0}))).await.unwrap());
+        assert!(
+            update(patch(json!({"bio": null, "address": {"zip": "2000"}})))
+                .await
+                .unwrap()
+        );
+        assert!(!qe
+            .update_row(
+                &person,
+                &Uuid::new_v4().to_string(),
+                &patch(json!({"age": 1.0})),
+                None
+            )
+            .await
+            .unwrap());
+
+        let row = sqlx::query(&format!(
+            "SELECT p.name, p.age, p.bio, a.city, a.zip FROM \"{}\" p JOIN \"{}\" a ON p.address = a.id",
+            person.backing_table(),
+            address.backing_table()
+        ))
+        .fetch_one(&qe.pool)
+        .await
+        .unwrap();
+        assert_eq!(row.get::<String, _>(0), "alice");
+        assert_eq!(row.get::<f64, _>(1), 31.0);
+        assert_eq!(row.get::<Option<String>, _>(2), None);
+        assert_eq!(row.get::<String, _>(3), "Lisbon");
+        assert_eq!(row.get::<String, _>(4), "2000");
+
+        // Present fields are checked against the type, and nothing is written if any is wrong.
+        for bad in [
+            json!({"age": "old"}),
+            json!({ "name": null }),
+            json!({"nickname": "al"}),
+            json!({"id": Uuid::new_v4().to_string()}),
+            json!({"name": "bob", "address": {"zip": 3000}}),
+        ] {
+            update(patch(bad)).await.unwrap_err();
+        }
+        let name: String = sqlx::query(&format!("SELECT name FROM \"{}\"", person.backing_table()))
+            .fetch_one(&qe.pool)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(name, "alice");
+    }
+
+    #[tokio::test]
+    async fn concurrent_increments() {
+        let mut views = make_field("views", Type::Float);
+        views.is_optional = true;
+        let page = make_object("Page", vec![make_field("title", Type::String), views]);
+        let db_file = NamedTempFile::new().unwrap();
+        let db_uri = format!("sqlite://{}?mode=rwc", db_file.path().to_string_lossy());
+        let conn = DbConnection::connect(&db_uri, 4).await.unwrap();
+        let qe = QueryEngine::local_connection(&conn, 4).await.unwrap();
+        create_table(&qe, &page).await;
+        let id = Uuid::new_v4().to_string();
+        let value = json!({"id": id, "title": "home"});
+        qe.add_row(&page, value.as_object().unwrap(), None)
+            .await
+            .unwrap();
+
+        let increments =
+            (1..=100).map(|delta| qe.increment(&page, &id, "views", delta as f64, None));
+        let values = futures::future::try_join_all(increments).await.unwrap();
+        assert!(values.iter().all(Option::is_some));
+        let views: f64 = sqlx::query(&format!("SELECT views FROM \"{}\"", page.backing_table()))
+            .fetch_one(&qe.pool)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(views, 5050.0);
+
+        let missing = Uuid::new_v4().to_string();
+        assert_eq!(
+            qe.increment(&page, &missing, "views", 1.0, None)
+                .await
+                .unwrap(),
+            None
+        );
+        qe.increment(&page, &id, "title", 1.0, None)
+            .await
+            .unwrap_err();
+        qe.increment(&page, &id, "likes", 1.0, None)
+            .await
+            .unwrap_err();
+    }
+
+    async fn count_rows(qe: &QueryEngine, ty: &ObjectType) -> i64 {
+        sqlx::query(&format!("SELECT COUNT(*) FROM \"{}\"", ty.backing_table()))
+            .fetch_one(&qe.pool)
+            .await
+            .unwrap()
+            .get(0)
+    }
+
+    #[tokio::test]
+    async fn batch_insert() {
+        let person = make_object("Person", vec![make_field("name", Type::String)]);
+        // Every statement counts as slow, so the slow query log counts the statements run.
+        let qe = in_memory_engine()
+            .await
+            .with_slow_query_threshold(Some(Duration::ZERO));
+        create_table(&qe, &person).await;
+
+        let people = (0..1000)
+            .map(|i| {
+                json!({ "name": format!("person {}", i) })
+                    .as_object()
+                    .unwrap()
+                    .clone()
+            })
+            .collect::<Vec<_>>();
+
+        let before = qe.slow_query_count();
+        let ids = qe.add_rows(&person, &people, None).await.unwrap();
+        // A single insert per row.
+        assert_eq!(qe.slow_query_count() - before, people.len() as u64);
+
+        assert_eq!(ids.len(), people.len());
+        assert_eq!(ids.iter().map(|t| &t.id).unique().count(), people.len());
+        assert_eq!(count_rows(&qe, &person).await, 1000);
+    }
+
+    #[tokio::test]
+    async fn batch_insert_is_atomic() {
+        let mut email = make_field("email", Type::String);
+        email.is_unique = true;
+        let person = make_object("Person", vec![email]);
+        let qe = in_memory_engine().await;
+        create_table(&qe, &person).await;
+
+        let people = [
+            "a@example.com",
+            "b@example.com",
+            "a@example.com",
+            "c@example.com",
+        ]
+        .iter()
+        .map(|e| json!({ "email": e }).as_object().unwrap().clone())
+        .collect::<Vec<_>>();
+        let err = qe.add_rows(&person, &people, None).await.unwrap_err();
+        assert!(err.downcast_ref::<UniqueViolation>().is_some());
+        assert_eq!(count_rows(&qe, &person).await, 0);
+
+        // Within a transaction, only the batch is undone.
+        let mut tr = qe.start_transaction().await.unwrap();
+        let zed = json!({"email": "z@example.com"});
+        qe.add_row(&person, zed.as_object().unwrap(), Some(&mut tr))
+            .await
+            .unwrap();
+        qe.add_rows(&person, &people, Some(&mut tr))
+            .await
+            .unwrap_err();
+        QueryEngine::commit_transaction(tr).await.unwrap();
+        assert_eq!(count_rows(&qe, &person).await, 1);
+    }
+
+    fn slow_query() -> SqlWithArguments {
+        // Counting to a few million with a recursive CTE takes way longer than the threshold.
+        SqlWithArguments {
+            sql: "WITH RECURSIVE cnt(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM cnt WHERE x < 3000000) \
+                  SELECT MAX(x) FROM cnt"
+                .to_owned(),
+            args: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_query_log() {
+        let qe = in_memory_engine()
+            .await
+            .with_slow_query_threshold(Some(Duration::from_millis(50)));
+
+        let fast = SqlWithArguments {
+            sql: "SELECT 1".to_owned(),
+            args: vec![],
+        };
+        qe.fetch_one(fast).await.unwrap();
+        assert_eq!(qe.slow_query_count(), 0);
+
+        qe.fetch_one(slow_query()).await.unwrap();
+        assert_eq!(qe.slow_query_count(), 1);
+
+        // Without a threshold nothing is logged.
+        let qe = in_memory_engine().await;
+        qe.fetch_one(slow_query()).await.unwrap();
+        assert_eq!(qe.slow_query_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn typed_rows() {
+        let person = make_object(
+            "Person",
+            vec![
+                make_field("name", Type::String),
+                make_field("born", Type::Date),
+            ],
+        );
+        let company = make_object(
+            "Company",
+            vec![
+                make_field("name", Type::String),
+                make_field("ceo", Type::Object(person.clone())),
+            ],
+        );
+        let qe = in_memory_engine().await;
+        create_table(&qe, &person).await;
+        create_table(&qe, &company).await;
+        // Columns that no field of the type is stored in, like those of removed fields.
+        for ty in [&person, &company] {
+            let sql = format!(
+                "ALTER TABLE \"{}\" ADD COLUMN \"stray\" TEXT DEFAULT 'x'",
+                ty.backing_table()
+            );
+            sqlx::query(&sql).execute(&qe.pool).await.unwrap();
+        }
+        let value = json!({
+            "name": "ChiselStrike",
+            "ceo": {"name": "alice", "born": "1990-01-02T00:00:00.000Z"},
+        });
+        qe.add_row(&company, value.as_object().unwrap(), None)
+            .await
+            .unwrap();
+
+        // Entities have exactly the fields of their types.
+        let rows = fetch_rows(&qe, &company).await;
+        assert_eq!(rows.len(), 1);
+        let keys = |row: &JsonObject| row.keys().cloned().sorted().collect::<Vec<_>>();
+        assert_eq!(keys(&rows[0]), vec!["ceo", "id", "name"]);
+        let ceo = rows[0]["ceo"].as_object().unwrap();
+        assert_eq!(keys(ceo), vec!["born", "id", "name"]);
+        assert_eq!(ceo["born"], "1990-01-02T00:00:00.000Z");
+
+        let shape = QueryPlan::from_type(&company).entity_shape();
+        assert_eq!(
+            serde_json::to_value(shape).unwrap(),
+            json!({
+                "typeName": "Company",
+                "dates": [],
+                "entities": {
+                    "ceo": {"typeName": "Person", "dates": ["born"], "entities": {}},
+                },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn json_fields() {
+        let doc = make_object(
+            "Doc",
+            vec![
+                make_field("name", Type::String),
+                make_field("data", Type::Json),
+            ],
+        );
+        let qe = in_memory_engine().await;
+        create_table(&qe, &doc).await;
+        let data = json!({
+            "title": "nested",
+            "count": 3,
+            "ratio": 0.25,
+            "ok": true,
+            "missing": null,
+            "tags": ["a", ["b", ["c", {"d": [1, 2.5, false, null]}]]],
+            "deep": {"a": {"b": {"c": {"d": {"e": {"f": "bottom", "empty": {}, "none": []}}}}}},
+        });
+        for (name, data) in [("doc", data.clone()), ("scalar", json!("just a string"))] {
+            let value = json!({"name": name, "data": data});
+            qe.add_row(&doc, value.as_object().unwrap(), None)
+                .await
+                .unwrap();
+        }
+
+        let rows = fetch_rows(&qe, &doc).await;
+        assert_eq!(rows.len(), 2);
+        let row = |name| rows.iter().find(|r| r["name"] == name).unwrap();
+        assert_eq!(row("doc")["data"], data);
+        assert_eq!(row("scalar")["data"], json!("just a string"));
+    }
+
+    #[tokio::test]
+    async fn raw_query() {
+        let person = make_object(
+            "Person",
+            vec![
+                make_field("name", Type::String),
+                make_field("age", Type::Float),
+            ],
+        );
+        let qe = in_memory_engine().await;
+        create_table(&qe, &person).await;
+        for (name, age) in [("alice", 30.0), ("bob", 40.0), ("carol", 50.0)] {
+            let value = json!({"name": name, "age": age});
+            qe.add_row(&person, value.as_object().unwrap(), None)
+                .await
+                .unwrap();
+        }
+        let qe = Arc::new(qe);
+        let sql = format!(
+            "SELECT name, age, age > 45 AS old FROM \"{}\" WHERE age >= $1 ORDER BY age",
+            person.backing_table()
+        );
+        let tr = qe.clone().start_transaction_static().await.unwrap();
+        let rows = qe
+            .raw_query(tr, sql.clone(), vec![json!(40)])
+            .await
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                json!({"name": "bob", "age": 40.0, "old": 0}),
+                json!({"name": "carol", "age": 50.0, "old": 1}),
+            ]
+            .into_iter()
+            .map(|row| row.as_object().unwrap().clone())
+            .collect::<Vec<_>>()
+        );
+
+        // Parameters are bound as values, never spliced into the SQL.
+        let sql = format!(
+            "SELECT name FROM \"{}\" WHERE name = $1",
+            person.backing_table()
+        );
+        let tr = qe.clone().start_transaction_static().await.unwrap();
+        let injection = json!("alice' OR '1'='1");
+        let rows = qe
+            .raw_query(tr, sql.clone(), vec![injection])
+            .await
+            .unwrap();
+        assert!(rows.is_empty());
+        let tr = qe.clone().start_transaction_static().await.unwrap();
+        let rows = qe
+            .raw_query(tr, sql.clone(), vec![json!("alice")])
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+
+        let tr = qe.clone().start_transaction_static().await.unwrap();
+        let err = qe
+            .raw_query(tr, sql, vec![json!({"name": "alice"})])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"Raw query parameter $1 must be a string, number or boolean, got {"name":"alice"}"#
+        );
+    }
+
+    #[test]
+    fn modification_log() {
+        let person = make_object("Person", vec![make_field("name", Type::String)]);
+        let company = make_object(
+            "Company",
+            vec![
+                make_field("name", Type::String),
+                make_field("ceo", Type::Object(person.clone())),
+            ],
+        );
+        let tick = || std::thread::sleep(Duration::from_millis(5));
+        let log = ModificationLog::default();
+        let started = log.last_modified(&company);
+        assert_eq!(log.last_modified(&person), started);
+
+        // Reading a company reads its CEO, so changing people changes companies.
+        tick();
+        log.record(&person);
+        let person_modified = log.last_modified(&person);
+        assert!(person_modified > started);
+        assert_eq!(log.last_modified(&company), person_modified);
+
+        tick();
+        log.record(&company);
+        assert!(log.last_modified(&company) > person_modified);
+        assert!(log.last_modified(&person) > person_modified);
+
+        tick();
+        let company_modified = log.last_modified(&company);
+        log.record_all();
+        assert!(log.last_modified(&company) > company_modified);
+        assert_eq!(log.last_modified(&person), log.last_modified(&company));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::datastore::query::TargetDatabase;
 use serde_derive::{Deserialize, Serialize};
 
 /// An expression.
@@ -13,6 +14,8 @@ pub(crate) enum Expr {
     Property(PropertyAccess),
     /// A binary expression.
     Binary(BinaryExpr),
+    /// Whether a value is one of a list of literals.
+    In(InExpr),
 }
 
 impl From<Literal> for Expr {
@@ -27,6 +30,12 @@ impl From<BinaryExpr> for Expr {
     }
 }
 
+impl From<InExpr> for Expr {
+    fn from(expr: InExpr) -> Self {
+        Expr::In(expr)
+    }
+}
+
 impl From<PropertyAccess> for Expr {
     fn from(prop_access: PropertyAccess) -> Self {
         Expr::Property(prop_access)
@@ -103,6 +112,16 @@ pub(crate) struct PropertyAccess {
     pub object: Box<Expr>,
 }
 
+/// A membership test, like `["open", "pending"].includes(p.status)`.
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct InExpr {
+    /// The expression that is looked up.
+    pub value: Box<Expr>,
+    /// The literals it is looked up in.
+    pub list: Vec<Literal>,
+}
+
 /// A binary operator.
 #[cfg_attr(test, derive(PartialEq))]
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -117,10 +136,15 @@ pub(crate) enum BinaryOp {
     Or,
     Like,
     NotLike,
+    /// Case-insensitive LIKE.
+    ILike,
+    /// Case-insensitive NOT LIKE.
+    NotILike,
 }
 
 impl BinaryOp {
-    pub fn to_sql_string(&self) -> &str {
+    /// Spells the operator in the SQL dialect of `target`.
+    pub fn to_sql_string(&self, target: &TargetDatabase) -> &str {
         match &self {
             Self::Eq => "=",
             Self::NotEq => "!=",
@@ -132,8 +156,26 @@ impl BinaryOp {
             Self::Or => "OR",
             Self::Like => "LIKE",
             Self::NotLike => "NOT LIKE",
+            // SQLite's LIKE is already case-insensitive for ASCII, and it ignores
+            // collations, so there is no ILIKE to map to.
+            Self::ILike => match target {
+                TargetDatabase::Postgres => "ILIKE",
+                TargetDatabase::Sqlite => "LIKE",
+            },
+            Self::NotILike => match target {
+                TargetDatabase::Postgres => "NOT ILIKE",
+                TargetDatabase::Sqlite => "NOT LIKE",
+            },
         }
     }
+
+    /// Whether the operator matches against a LIKE pattern.
+    pub fn is_like(&self) -> bool {
+        matches!(
+            self,
+            Self::Like | Self::NotLike | Self::ILike | Self::NotILike
+        )
+    }
 }
 
 /// A binary expression.
@@ -173,6 +215,8 @@ impl BinaryExpr {
     make_op_method! {or, Or}
     make_op_method! {like, Like}
     make_op_method! {not_like, NotLike}
+    make_op_method! {ilike, ILike}
+    make_op_method! {not_ilike, NotILike}
 }
 
 #[cfg(test)]
@@ -313,4 +357,34 @@ mod tests {
         )
         .unwrap();
     }
+
+    #[test]
+    fn test_in_parsing() {
+        let expr: Expr = serde_json::from_str(
+            r#"{
+            "exprType": "In",
+            "value": {
+                "exprType": "Property",
+                "property": "status",
+                "object": {"exprType": "Parameter", "position": 0}
+            },
+            "list": ["open", "pending"]
+        }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            expr,
+            Expr::In(InExpr {
+                value: Box::new(
+                    PropertyAccess {
+                        property: "status".to_owned(),
+                        object: Expr::Parameter { position: 0 }.into(),
+                    }
+                    .into()
+                ),
+                list: vec!["open".into(), "pending".into()],
+            })
+        );
+    }
 }
@@ -3,6 +3,7 @@ pub(crate) mod schema;
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::api::{ApiInfo, ApiInfoMap};
+use crate::datastore::named_query::{NamedQueries, NamedQuery};
 use crate::datastore::{DbConnection, Kind};
 use crate::policies::Policies;
 use crate::prefix_map::PrefixMap;
@@ -10,9 +11,11 @@ use crate::types::AuthOrNot::IsNotAuth;
 use crate::types::{
     ExistingField, ExistingObject, Field, FieldDelta, ObjectDelta, ObjectType, TypeSystem,
 };
+use crate::JsonObject;
 use anyhow::Context;
 use sqlx::any::{Any, AnyPool};
 use sqlx::{Execute, Executor, Row, Transaction};
+use std::collections::HashMap;
 use std::path::Path;
 use std::sync::Arc;
 use tokio::fs;
@@ -21,7 +24,7 @@ use tokio::fs;
 ///
 /// The meta service is responsible for managing metadata such as object
 /// types and labels persistently.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct MetaService {
     kind: Kind,
     pool: AnyPool,
@@ -85,7 +88,7 @@ async fn update_field_query(
         let default_stmt = if field.default.is_none() {
             ""
         } else {
-            ", default_value = $5"
+            ", default_value = $8"
         };
 
         let querystr = format!(
@@ -94,8 +97,11 @@ async fn update_field_query(
             SET
                 field_type = $1,
                 is_optional = $2::bool,
-                is_unique = $4::bool {default_stmt}
-            WHERE field_id = $4"#
+                is_unique = $3::bool,
+                is_indexed = $4::bool,
+                allowed_values = $5,
+                is_nullable = $6::bool {default_stmt}
+            WHERE field_id = $7"#
         );
         let mut query = sqlx::query(&querystr);
 
@@ -103,6 +109,9 @@ async fn update_field_query(
             .bind(field.type_.name())
             .bind(field.is_optional)
             .bind(field.is_unique)
+            .bind(field.is_indexed)
+            .bind(serde_json::to_string(&field.allowed_values)?)
+            .bind(field.is_nullable)
             .bind(field_id);
 
         if let Some(value) = &field.default {
@@ -156,12 +165,20 @@ async fn insert_field_query(
         "logical error. Seems like a type is at the same type pre-existing and recently added??",
     )?;
 
+    let allowed_values = serde_json::to_string(&field.allowed_values)?;
     let add_field = match &field.user_provided_default() {
         None => {
             let query = sqlx::query(
                 r#"
-                INSERT INTO fields (field_type, type_id, is_optional, is_unique)
-                VALUES ($1, $2, $3, $4)
+                INSERT INTO fields (
+                    field_type,
+                    type_id,
+                    is_optional,
+                    is_unique,
+                    is_indexed,
+                    allowed_values,
+                    is_nullable)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
                 RETURNING *"#,
             );
             query
@@ -169,6 +186,9 @@ async fn insert_field_query(
                 .bind(type_id)
                 .bind(field.is_optional)
                 .bind(field.is_unique)
+                .bind(field.is_indexed)
+                .bind(allowed_values)
+                .bind(field.is_nullable)
         }
         Some(value) => {
             let query = sqlx::query(
@@ -178,8 +198,11 @@ async fn insert_field_query(
                     type_id,
                     default_value,
                     is_optional,
-                    is_unique)
-                VALUES ($1, $2, $3, $4, $5)
+                    is_unique,
+                    is_indexed,
+                    allowed_values,
+                    is_nullable)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
                 RETURNING *"#,
             );
             query
@@ -188,6 +211,9 @@ async fn insert_field_query(
                 .bind(value.to_owned())
                 .bind(field.is_optional)
                 .bind(field.is_unique)
+                .bind(field.is_indexed)
+                .bind(allowed_values)
+                .bind(field.is_nullable)
         }
     };
     let add_field_name = sqlx::query(
@@ -495,6 +521,138 @@ impl MetaService {
         Ok(())
     }
 
+    /// Load the middleware of each API version that has one from the metadata store.
+    pub(crate) async fn load_middleware(&self) -> anyhow::Result<HashMap<String, String>> {
+        let query = sqlx::query("SELECT version, code FROM middleware");
+        let rows = fetch_all(&self.pool, query).await?;
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("version"), row.get("code")))
+            .collect())
+    }
+
+    pub(crate) async fn persist_middleware(
+        &self,
+        version: &str,
+        code: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        let drop = sqlx::query("DELETE FROM middleware WHERE version = $1").bind(version);
+        execute(&mut transaction, drop).await?;
+
+        if let Some(code) = code {
+            let insert = sqlx::query("INSERT INTO middleware (version, code) VALUES ($1, $2)")
+                .bind(version)
+                .bind(code);
+            execute(&mut transaction, insert).await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    pub(crate) async fn delete_middleware(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        version: &str,
+    ) -> anyhow::Result<()> {
+        let delete =
+            sqlx::query("DELETE FROM middleware WHERE version = $1").bind(version.to_owned());
+        execute(transaction, delete).await?;
+        Ok(())
+    }
+
+    /// Load the runtime configuration from the metadata store.
+    pub(crate) async fn load_config(&self) -> anyhow::Result<JsonObject> {
+        let query = sqlx::query("SELECT name, value FROM config");
+        let rows = fetch_all(&self.pool, query).await?;
+        let mut config = JsonObject::new();
+        for row in rows {
+            let name: String = row.get("name");
+            let value: &str = row.get("value");
+            let value = serde_json::from_str(value)
+                .with_context(|| format!("Loading config value {}", name))?;
+            config.insert(name, value);
+        }
+        Ok(config)
+    }
+
+    /// Set the config value `name`, or remove it if `value` is None.
+    pub(crate) async fn persist_config(
+        &self,
+        name: &str,
+        value: Option<&serde_json::Value>,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        let drop = sqlx::query("DELETE FROM config WHERE name = $1").bind(name);
+        execute(&mut transaction, drop).await?;
+
+        if let Some(value) = value {
+            let insert = sqlx::query("INSERT INTO config (name, value) VALUES ($1, $2)")
+                .bind(name)
+                .bind(value.to_string());
+            execute(&mut transaction, insert).await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Load the named queries of all versions from the metadata store.
+    pub(crate) async fn load_named_queries(&self) -> anyhow::Result<NamedQueries> {
+        let query = sqlx::query("SELECT version, name, spec FROM named_queries");
+        let rows = fetch_all(&self.pool, query).await?;
+        let mut queries = NamedQueries::default();
+        for row in rows {
+            let version: String = row.get("version");
+            let name: String = row.get("name");
+            let spec: &str = row.get("spec");
+            let query = serde_json::from_str(spec)
+                .map_err(anyhow::Error::from)
+                .and_then(NamedQuery::new)
+                .with_context(|| format!("Loading named query {} of version {}", name, version))?;
+            queries.insert(version, name, query);
+        }
+        Ok(queries)
+    }
+
+    /// Set the named query `name` of `version` to `spec`, or remove it if `spec` is None.
+    pub(crate) async fn persist_named_query(
+        &self,
+        version: &str,
+        name: &str,
+        spec: Option<&serde_json::Value>,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        let drop = sqlx::query("DELETE FROM named_queries WHERE version = $1 AND name = $2")
+            .bind(version)
+            .bind(name);
+        execute(&mut transaction, drop).await?;
+
+        if let Some(spec) = spec {
+            let insert =
+                sqlx::query("INSERT INTO named_queries (version, name, spec) VALUES ($1, $2, $3)")
+                    .bind(version)
+                    .bind(name)
+                    .bind(spec.to_string());
+            execute(&mut transaction, insert).await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    pub(crate) async fn delete_named_queries(
+        &self,
+        transaction: &mut Transaction<'_, Any>,
+        version: &str,
+    ) -> anyhow::Result<()> {
+        let delete =
+            sqlx::query("DELETE FROM named_queries WHERE version = $1").bind(version.to_owned());
+        execute(transaction, delete).await?;
+        Ok(())
+    }
+
     /// Load the type system from metadata store.
     pub(crate) async fn load_type_system<'r>(&self) -> anyhow::Result<TypeSystem> {
         let query = sqlx::query(
@@ -502,6 +660,8 @@ impl MetaService {
             SELECT
                 types.type_id AS type_id,
                 types.backing_table AS backing_table,
+                types.unique_constraints AS unique_constraints,
+                types.soft_delete AS soft_delete,
                 type_names.name AS type_name
             FROM types
             INNER JOIN type_names ON types.type_id = type_names.type_id"#,
@@ -515,8 +675,17 @@ impl MetaService {
             let type_name: &str = row.get("type_name");
             let desc = ExistingObject::new(type_name, backing_table, type_id)?;
             let fields = self.load_type_fields(&ts, type_id).await?;
-
-            let ty = ObjectType::new(desc, fields, IsNotAuth)?;
+            // Types created before unique constraints were supported have NULL here.
+            let unique_constraints: Option<&str> = row.get("unique_constraints");
+            let unique_constraints = match unique_constraints {
+                Some(json) => serde_json::from_str(json)?,
+                None => vec![],
+            };
+            let soft_delete: Option<bool> = row.get("soft_delete");
+
+            let ty = ObjectType::new(desc, fields, IsNotAuth)?
+                .with_unique_constraints(unique_constraints)?
+                .with_soft_delete(soft_delete.unwrap_or(false))?;
             ts.add_type(Arc::new(ty))?;
         }
         Ok(ts)
@@ -531,7 +700,10 @@ impl MetaService {
                 fields.field_type AS field_type,
                 fields.default_value AS default_value,
                 fields.is_optional AS is_optional,
-                fields.is_unique AS is_unique
+                fields.is_unique AS is_unique,
+                fields.is_indexed AS is_indexed,
+                fields.allowed_values AS allowed_values,
+                fields.is_nullable AS is_nullable
             FROM field_names
             INNER JOIN fields
                 ON fields.type_id = $1 AND field_names.field_id = fields.field_id;"#,
@@ -559,6 +731,15 @@ impl MetaService {
             let field_def: Option<String> = row.get("default_value");
             let is_optional: bool = row.get("is_optional");
             let is_unique: bool = row.get("is_unique");
+            // Fields created before indexes were supported have NULL here.
+            let is_indexed: Option<bool> = row.get("is_indexed");
+            // And fields created before allowed values were supported have NULL here.
+            let allowed_values: Option<&str> = row.get("allowed_values");
+            let allowed_values = match allowed_values {
+                Some(json) => serde_json::from_str(json)?,
+                None => vec![],
+            };
+            let is_nullable: Option<bool> = row.get("is_nullable");
 
             let labels_query =
                 sqlx::query("SELECT label_name FROM field_labels WHERE field_id = $1");
@@ -572,7 +753,18 @@ impl MetaService {
                 .map(|r| r.get("label_name"))
                 .collect::<Vec<String>>();
 
-            fields.push(Field::new(desc, labels, field_def, is_optional, is_unique));
+            fields.push(
+                Field::new(
+                    desc,
+                    labels,
+                    field_def,
+                    is_optional,
+                    is_unique,
+                    is_indexed.unwrap_or(false),
+                )
+                .with_allowed_values(allowed_values)
+                .with_nullable(is_nullable.unwrap_or(false)),
+            );
         }
         Ok(fields)
     }
@@ -616,6 +808,31 @@ impl MetaService {
         for field in delta.updated_fields.iter() {
             update_field_query(transaction, field).await?;
         }
+
+        if !delta.removed_unique_constraints.is_empty() {
+            let type_id = ty
+                .meta_id
+                .context("logical error. Trying to update type without id")?;
+            let remaining = ty
+                .unique_constraints()
+                .iter()
+                .filter(|c| !delta.removed_unique_constraints.contains(c))
+                .collect::<Vec<_>>();
+            let query = sqlx::query("UPDATE types SET unique_constraints = $1 WHERE type_id = $2")
+                .bind(serde_json::to_string(&remaining)?)
+                .bind(type_id);
+            execute(transaction, query).await?;
+        }
+
+        if let Some(soft_delete) = delta.soft_delete {
+            let type_id = ty
+                .meta_id
+                .context("logical error. Trying to update type without id")?;
+            let query = sqlx::query("UPDATE types SET soft_delete = $1 WHERE type_id = $2")
+                .bind(soft_delete)
+                .bind(type_id);
+            execute(transaction, query).await?;
+        }
         Ok(())
     }
 
@@ -687,10 +904,15 @@ impl MetaService {
         transaction: &mut Transaction<'_, Any>,
         ty: &ObjectType,
     ) -> anyhow::Result<()> {
-        let add_type = sqlx::query("INSERT INTO types (backing_table) VALUES ($1) RETURNING *");
+        let add_type = sqlx::query(
+            "INSERT INTO types (backing_table, unique_constraints, soft_delete) VALUES ($1, $2, $3) RETURNING *",
+        );
         let add_type_name = sqlx::query("INSERT INTO type_names (type_id, name) VALUES ($1, $2)");
 
-        let add_type = add_type.bind(ty.backing_table().to_owned());
+        let add_type = add_type
+            .bind(ty.backing_table().to_owned())
+            .bind(serde_json::to_string(ty.unique_constraints())?)
+            .bind(ty.soft_deletes());
         let row = fetch_one(transaction, add_type).await?;
 
         let id: i32 = row.get("type_id");
@@ -769,6 +991,8 @@ mod tests {
         meta.maybe_migrate_sqlite_database(&[&meta_path, &data_path], &new_path)
             .await
             .unwrap();
+        // Bring the migrated metadata up to date, like the server does on startup.
+        meta.create_schema().await.unwrap();
 
         let query = QueryEngine::local_connection(&conn, 1).await.unwrap();
 
@@ -27,6 +27,8 @@ enum Types {
     TypeId,
     BackingTable,
     ApiVersion,
+    UniqueConstraints,
+    SoftDelete,
 }
 
 #[derive(Iden)]
@@ -45,6 +47,9 @@ enum Fields {
     DefaultValue,
     IsOptional,
     IsUnique,
+    IsIndexed,
+    AllowedValues,
+    IsNullable,
 }
 
 #[derive(Iden)]
@@ -68,6 +73,28 @@ enum Endpoints {
     Code,
 }
 
+#[derive(Iden)]
+enum Middleware {
+    Table,
+    Version,
+    Code,
+}
+
+#[derive(Iden)]
+enum Config {
+    Table,
+    Name,
+    Value,
+}
+
+#[derive(Iden)]
+enum NamedQueries {
+    Table,
+    Version,
+    Name,
+    Spec,
+}
+
 #[derive(Iden)]
 enum Policies {
     Table,
@@ -75,7 +102,7 @@ enum Policies {
     PolicyStr,
 }
 
-pub(crate) static CURRENT_VERSION: &str = "0.7";
+pub(crate) static CURRENT_VERSION: &str = "0.12";
 
 // Evolves from a version and returns the new version it evolved to
 //
@@ -93,6 +120,41 @@ pub(crate) async fn evolve_from(
                 .to_owned()];
             Ok((v, "0.7".to_string()))
         }
+        "0.7" => {
+            let v = vec![Table::alter()
+                .table(Fields::Table)
+                .add_column(ColumnDef::new(Fields::IsIndexed).boolean())
+                .to_owned()];
+            Ok((v, "0.8".to_string()))
+        }
+        "0.8" => {
+            let v = vec![Table::alter()
+                .table(Types::Table)
+                .add_column(ColumnDef::new(Types::UniqueConstraints).text())
+                .to_owned()];
+            Ok((v, "0.9".to_string()))
+        }
+        "0.9" => {
+            let v = vec![Table::alter()
+                .table(Fields::Table)
+                .add_column(ColumnDef::new(Fields::AllowedValues).text())
+                .to_owned()];
+            Ok((v, "0.10".to_string()))
+        }
+        "0.10" => {
+            let v = vec![Table::alter()
+                .table(Fields::Table)
+                .add_column(ColumnDef::new(Fields::IsNullable).boolean())
+                .to_owned()];
+            Ok((v, "0.11".to_string()))
+        }
+        "0.11" => {
+            let v = vec![Table::alter()
+                .table(Types::Table)
+                .add_column(ColumnDef::new(Types::SoftDelete).boolean())
+                .to_owned()];
+            Ok((v, "0.12".to_string()))
+        }
         v => anyhow::bail!("Don't know how to evolve from version {}", v),
     }
 }
@@ -124,6 +186,8 @@ pub(crate) fn tables() -> Vec<TableCreateStatement> {
         )
         .col(ColumnDef::new(Types::BackingTable).text().unique_key())
         .col(ColumnDef::new(Types::ApiVersion).text().unique_key())
+        .col(ColumnDef::new(Types::UniqueConstraints).text())
+        .col(ColumnDef::new(Types::SoftDelete).boolean())
         .to_owned();
     let type_names = Table::create()
         .table(TypeNames::Table)
@@ -150,6 +214,9 @@ pub(crate) fn tables() -> Vec<TableCreateStatement> {
         .col(ColumnDef::new(Fields::DefaultValue).text())
         .col(ColumnDef::new(Fields::IsOptional).boolean())
         .col(ColumnDef::new(Fields::IsUnique).boolean())
+        .col(ColumnDef::new(Fields::IsIndexed).boolean())
+        .col(ColumnDef::new(Fields::AllowedValues).text())
+        .col(ColumnDef::new(Fields::IsNullable).boolean())
         .col(ColumnDef::new(TypeNames::TypeId).integer())
         .foreign_key(
             ForeignKey::create()
@@ -189,6 +256,29 @@ pub(crate) fn tables() -> Vec<TableCreateStatement> {
         .col(ColumnDef::new(Endpoints::Code).text())
         .to_owned();
 
+    // Holds at most one row per API version.
+    let middleware = Table::create()
+        .table(Middleware::Table)
+        .if_not_exists()
+        .col(ColumnDef::new(Middleware::Version).text().unique_key())
+        .col(ColumnDef::new(Middleware::Code).text())
+        .to_owned();
+
+    let config = Table::create()
+        .table(Config::Table)
+        .if_not_exists()
+        .col(ColumnDef::new(Config::Name).text().unique_key())
+        .col(ColumnDef::new(Config::Value).text()) // JSON
+        .to_owned();
+
+    let named_queries = Table::create()
+        .table(NamedQueries::Table)
+        .if_not_exists()
+        .col(ColumnDef::new(NamedQueries::Version).text())
+        .col(ColumnDef::new(NamedQueries::Name).text())
+        .col(ColumnDef::new(NamedQueries::Spec).text()) // JSON
+        .to_owned();
+
     let policies = Table::create()
         .table(Policies::Table)
         .if_not_exists()
@@ -205,6 +295,9 @@ pub(crate) fn tables() -> Vec<TableCreateStatement> {
         type_fields,
         field_labels,
         endpoints,
+        middleware,
+        config,
+        named_queries,
         policies,
     ]
 }
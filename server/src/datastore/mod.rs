@@ -38,7 +38,7 @@
 //! The ``QueryEngine`` has the following high-level API:
 //!
 //! ```ignore
-//! fn mutate(Mutation) -> Result<();
+//! fn mutate(Mutation) -> Result<u64>;
 //!
 //! fn query(Query) -> Result<QueryResults>;
 //! ```
@@ -57,6 +57,7 @@ mod dbconn;
 pub(crate) mod engine;
 pub(crate) mod expr;
 pub(crate) mod meta;
+pub(crate) mod named_query;
 pub(crate) mod query;
 
 pub(crate) use dbconn::DbConnection;
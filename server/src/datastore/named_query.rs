@@ -0,0 +1,273 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Named queries: query specs that are registered once, under a name, and run from endpoints with
+//! the values of their parameters.
+//!
+//! A named query is a `QuerySpec` in JSON whose expressions may hold parameters, written as
+//! `{"exprType": "NamedParameter", "name": "minAge"}`. Running the query replaces each of them
+//! with a literal holding the value that was given for it.
+
+use crate::datastore::query::{QueryPlan, QuerySpec, RequestContext, TargetDatabase};
+use crate::JsonObject;
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+
+/// The `exprType` of the parameters of a named query.
+const PARAMETER: &str = "NamedParameter";
+
+#[derive(Debug, Clone)]
+pub(crate) struct NamedQuery {
+    spec: Value,
+    /// Names of the parameters the spec refers to.
+    params: BTreeSet<String>,
+}
+
+impl NamedQuery {
+    pub(crate) fn new(spec: Value) -> Result<Self> {
+        let mut params = BTreeSet::new();
+        find_params(&spec, &mut params)?;
+        let query = Self { spec, params };
+        // Catches specs that don't deserialize, before any parameter is given.
+        query.bind_all(&Value::Null)?;
+        Ok(query)
+    }
+
+    /// Checks that the query can run against the types of `context`.
+    pub(crate) fn validate(&self, context: &RequestContext, target: &TargetDatabase) -> Result<()> {
+        // Nulls are valid wherever a literal is, so they test the rest of the query.
+        let spec = self.bind_all(&Value::Null)?;
+        QueryPlan::from_query_spec(context, spec)?.build_query(target)?;
+        Ok(())
+    }
+
+    /// The spec, with the parameters set to `params`, which must name all of them and nothing else.
+    pub(crate) fn bind(&self, params: &JsonObject) -> Result<QuerySpec> {
+        for name in params.keys() {
+            anyhow::ensure!(
+                self.params.contains(name),
+                "named query has no parameter '{}'",
+                name
+            );
+        }
+        if let Some(name) = self.params.iter().find(|name| !params.contains_key(*name)) {
+            anyhow::bail!("missing value of parameter '{}' of named query", name);
+        }
+        let mut spec = self.spec.clone();
+        replace_params(&mut spec, &|name| params[name].clone())?;
+        serde_json::from_value(spec).context("invalid query spec")
+    }
+
+    fn bind_all(&self, value: &Value) -> Result<QuerySpec> {
+        let mut spec = self.spec.clone();
+        replace_params(&mut spec, &|_| value.clone())?;
+        serde_json::from_value(spec).context("invalid query spec")
+    }
+}
+
+/// Named queries of all the API versions.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct NamedQueries {
+    queries: HashMap<(String, String), NamedQuery>,
+}
+
+impl NamedQueries {
+    pub(crate) fn insert(&mut self, version: String, name: String, query: NamedQuery) {
+        self.queries.insert((version, name), query);
+    }
+
+    pub(crate) fn get(&self, version: &str, name: &str) -> Result<&NamedQuery> {
+        self.queries
+            .get(&(version.to_owned(), name.to_owned()))
+            .ok_or_else(|| anyhow!("no named query '{}' in version {}", name, version))
+    }
+}
+
+fn param_name(object: &serde_json::Map<String, Value>) -> Option<Result<&str>> {
+    if object.get("exprType")?.as_str()? != PARAMETER {
+        return None;
+    }
+    Some(
+        object
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("parameter of named query has no name")),
+    )
+}
+
+fn find_params(value: &Value, params: &mut BTreeSet<String>) -> Result<()> {
+    match value {
+        Value::Object(object) => match param_name(object) {
+            Some(name) => {
+                params.insert(name?.to_owned());
+            }
+            None => {
+                for value in object.values() {
+                    find_params(value, params)?;
+                }
+            }
+        },
+        Value::Array(values) => {
+            for value in values {
+                find_params(value, params)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replaces each parameter in `value` with a literal expression of the value `lookup` gives for it.
+fn replace_params(value: &mut Value, lookup: &dyn Fn(&str) -> Value) -> Result<()> {
+    match value {
+        Value::Object(object) => match param_name(object) {
+            Some(name) => {
+                let name = name?.to_owned();
+                let literal = lookup(&name);
+                anyhow::ensure!(
+                    !literal.is_array() && !literal.is_object(),
+                    "value of parameter '{}' of named query is not a string, number, boolean or null",
+                    name
+                );
+                *value = json!({"exprType": "Literal", "value": literal});
+            }
+            None => {
+                for value in object.values_mut() {
+                    replace_params(value, lookup)?;
+                }
+            }
+        },
+        Value::Array(values) => {
+            for value in values {
+                replace_params(value, lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::query::tests::{make_field, make_object, make_type_system, VERSION};
+    use crate::policies::Policies;
+    use crate::types::Type;
+
+    fn param(name: &str) -> Value {
+        json!({"exprType": "NamedParameter", "name": name})
+    }
+
+    fn age_between() -> NamedQuery {
+        let age = json!({
+            "exprType": "Property",
+            "property": "age",
+            "object": {"exprType": "Parameter", "position": 0},
+        });
+        NamedQuery::new(json!({
+            "typeName": "Person",
+            "filter": {
+                "exprType": "Binary",
+                "left": {"exprType": "Binary", "left": age.clone(), "op": "GtEq", "right": param("min")},
+                "op": "And",
+                "right": {"exprType": "Binary", "left": age, "op": "Lt", "right": param("max")},
+            },
+            "sort": [{"fieldName": "age", "ascending": true}],
+        }))
+        .unwrap()
+    }
+
+    fn params(value: Value) -> JsonObject {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn binds_parameters() {
+        let query = age_between();
+        assert_eq!(query.params.iter().collect::<Vec<_>>(), vec!["max", "min"]);
+        let spec = query
+            .bind(&params(json!({"min": 10, "max": 20.5})))
+            .unwrap();
+        let debug = format!("{:?}", spec);
+        assert!(debug.contains("U64(10)"), "{}", debug);
+        assert!(debug.contains("F64(20.5)"), "{}", debug);
+    }
+
+    #[test]
+    fn rejects_wrong_parameters() {
+        let query = age_between();
+        assert_eq!(
+            query
+                .bind(&params(json!({"min": 10})))
+                .unwrap_err()
+                .to_string(),
+            "missing value of parameter 'max' of named query"
+        );
+        assert_eq!(
+            query
+                .bind(&params(json!({"min": 10, "max": 20, "other": 1})))
+                .unwrap_err()
+                .to_string(),
+            "named query has no parameter 'other'"
+        );
+        assert!(query
+            .bind(&params(json!({"min": [10], "max": 20})))
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_specs() {
+        assert!(NamedQuery::new(json!({"filter": param("x")})).is_err());
+        assert!(NamedQuery::new(json!({"typeName": "Person", "limit": "ten"})).is_err());
+        assert!(NamedQuery::new(json!({
+            "typeName": "Person",
+            "filter": {"exprType": "NamedParameter"},
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn validates_against_types() {
+        let person = make_object("Person", vec![make_field("name", Type::String)]);
+        let ts = make_type_system(&[&person]);
+        let context = RequestContext {
+            policies: &Policies::default(),
+            ts: &ts,
+            api_version: VERSION.to_owned(),
+            user_id: None,
+            path: "".to_string(),
+            method: "GET".to_string(),
+        };
+        let err = age_between()
+            .validate(&context, &TargetDatabase::Sqlite)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expression error: entity 'Person' doesn't have field 'age'"
+        );
+
+        let person = make_object(
+            "Person",
+            vec![
+                make_field("name", Type::String),
+                make_field("age", Type::Float),
+            ],
+        );
+        let ts = make_type_system(&[&person]);
+        let context = RequestContext { ts: &ts, ..context };
+        assert!(age_between()
+            .validate(&context, &TargetDatabase::Sqlite)
+            .is_ok());
+    }
+
+    #[test]
+    fn looks_up_by_version() {
+        let mut queries = NamedQueries::default();
+        queries.insert("dev".into(), "between".into(), age_between());
+        assert!(queries.get("dev", "between").is_ok());
+        assert_eq!(
+            queries.get("prod", "between").unwrap_err().to_string(),
+            "no named query 'between' in version prod"
+        );
+    }
+}
@@ -1,9 +1,10 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::auth::AUTH_USER_NAME;
-use crate::datastore::expr::{BinaryExpr, Expr, Literal, PropertyAccess};
+use crate::datastore::expr::{BinaryExpr, BinaryOp, Expr, Literal, PropertyAccess};
+use crate::dates;
 use crate::policies::{FieldPolicies, Policies};
-use crate::types::{Field, ObjectType, Type, TypeSystem};
+use crate::types::{Field, ObjectType, Type, TypeSystem, SOFT_DELETE_FIELD};
 
 use anyhow::{anyhow, Context, Result};
 use enum_as_inner::EnumAsInner;
@@ -13,6 +14,7 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone, EnumAsInner)]
 pub(crate) enum SqlValue {
@@ -40,13 +42,15 @@ pub(crate) struct RequestContext<'a> {
     pub user_id: Option<String>,
     /// Current URL path from which this request originated.
     pub path: String,
+    /// HTTP method of the request.
+    pub method: String,
 }
 
 impl RequestContext<'_> {
     /// Calculates field policies for the request being processed.
     fn make_field_policies(&self, ty: &ObjectType) -> FieldPolicies {
         self.policies
-            .make_field_policies(&self.user_id, &self.path, ty)
+            .make_field_policies(&self.user_id, &self.path, &self.method, ty)
     }
 }
 
@@ -58,6 +62,7 @@ pub(crate) enum QueryField {
         /// Type of the field
         type_: Type,
         is_optional: bool,
+        is_nullable: bool,
         /// Index of a column containing this field in the resulting row we get from
         /// the database.
         column_idx: usize,
@@ -68,6 +73,7 @@ pub(crate) enum QueryField {
         /// Name of the original Type field
         name: String,
         is_optional: bool,
+        is_nullable: bool,
         /// Policy transformation to be applied on the resulting JSON value.
         transform: Option<fn(Value) -> Value>,
     },
@@ -85,11 +91,6 @@ pub(crate) struct Query {
     /// Entity that is being queried. Contains information necessary to reconstruct
     /// the JSON response.
     pub(crate) entity: QueriedEntity,
-    /// Entity fields selected by the user. This field is used to post-filter fields that
-    /// shall be returned to the user in JSON.
-    /// FIXME: The post-filtering is suboptimal solution and selection should happen when
-    /// we build the raw_sql query.
-    pub(crate) allowed_fields: Option<HashSet<String>>,
 }
 
 /// QueriedEntity represents queried Entity of type `ty` which is to be aliased as
@@ -105,6 +106,8 @@ pub(crate) struct QueriedEntity {
     /// Map from Entity field name to joined Entities which correspond to the entities
     /// stored under the field name.
     joins: HashMap<String, Join>,
+    /// Names of the fields whose values are stored encrypted, which SQL can't compare.
+    encrypted: HashSet<String>,
 }
 
 impl QueriedEntity {
@@ -112,9 +115,48 @@ impl QueriedEntity {
         self.joins.get(child_name).map(|c| &c.entity)
     }
 
-    fn has_field(&self, field_name: &str) -> bool {
-        self.ty.all_fields().any(|field| field.name == field_name)
+    pub(crate) fn type_name(&self) -> &str {
+        self.ty.name()
     }
+
+    fn shape(&self) -> EntityShape {
+        let mut shape = EntityShape {
+            type_name: self.type_name().to_owned(),
+            dates: vec![],
+            entities: HashMap::new(),
+        };
+        for field in &self.fields {
+            match field {
+                QueryField::Scalar {
+                    name,
+                    type_: Type::Date,
+                    transform: None,
+                    ..
+                } => shape.dates.push(name.clone()),
+                QueryField::Entity {
+                    name,
+                    transform: None,
+                    ..
+                } => {
+                    let child = self.get_child_entity(name).unwrap();
+                    shape.entities.insert(name.clone(), child.shape());
+                }
+                _ => {}
+            }
+        }
+        shape
+    }
+}
+
+/// How to rebuild the entities a query returns as typed objects, which JSON can't represent by
+/// itself: the fields holding dates, and the shapes of nested entities.  Fields whose value a
+/// policy transforms are left as they are.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EntityShape {
+    type_name: String,
+    dates: Vec<String>,
+    entities: HashMap<String, EntityShape>,
 }
 
 /// Represents JOIN operator joining `entity` to a previous QueriedEntity which holds the
@@ -214,7 +256,7 @@ pub(crate) struct QueryPlan {
     /// Entity object representing entity that is being retrieved along with necessary joins
     /// and nested entities
     entity: QueriedEntity,
-    /// List of fields to be returned to the user.
+    /// List of fields to be returned to the user, or None for all of them.
     allowed_fields: Option<HashSet<String>>,
     /// Counts the total number of joins the builder encountered. It's used to
     /// uniquely identify joined tables.
@@ -224,6 +266,15 @@ pub(crate) struct QueryPlan {
 }
 
 impl QueryPlan {
+    pub(crate) fn type_name(&self) -> &str {
+        self.entity.type_name()
+    }
+
+    /// The shape of the entities this plan queries.
+    pub(crate) fn entity_shape(&self) -> EntityShape {
+        self.entity.shape()
+    }
+
     fn new(base_type: Arc<ObjectType>) -> Self {
         Self {
             columns: vec![],
@@ -232,6 +283,7 @@ impl QueryPlan {
                 fields: vec![],
                 table_alias: base_type.backing_table().to_owned(),
                 joins: HashMap::default(),
+                encrypted: HashSet::default(),
             },
             allowed_fields: None,
             join_counter: 0,
@@ -260,18 +312,25 @@ impl QueryPlan {
         builder
     }
 
-    fn from_entity_name(c: &RequestContext, entity_name: &str) -> Result<Self> {
+    fn from_entity_name(
+        c: &RequestContext,
+        entity_name: &str,
+        include_deleted: bool,
+    ) -> Result<Self> {
         let ty =
             c.ts.lookup_object_type(entity_name, &c.api_version)
                 .context("unable to construct QueryPlan from unknown entity name")?;
 
         let mut builder = Self::new(ty.clone());
         builder.entity = builder.load_entity(c, &ty);
+        if !include_deleted {
+            builder.hide_deleted();
+        }
         Ok(builder)
     }
 
     /// Constructs QueryPlan from type `ty` and application of given
-    /// `operators.
+    /// `operators. Soft-deleted entities aren't part of the results.
     pub(crate) fn from_ops(
         c: &RequestContext,
         ty: &Arc<ObjectType>,
@@ -279,6 +338,7 @@ impl QueryPlan {
     ) -> Result<Self> {
         let mut query_plan = Self::new(ty.clone());
         query_plan.entity = query_plan.load_entity(c, ty);
+        query_plan.hide_deleted();
         query_plan.extend_operators(operators);
         Ok(query_plan)
     }
@@ -287,13 +347,34 @@ impl QueryPlan {
     /// additional helper data like `policies`, `api_version`,
     /// `userid` and `path` (url path used for policy evaluation).
     pub(crate) fn from_op_chain(context: &RequestContext, op_chain: QueryOpChain) -> Result<Self> {
+        let include_deleted = op_chain.includes_deleted();
         let (entity_name, operators) = convert_ops(op_chain)?;
-        let mut builder = Self::from_entity_name(context, &entity_name)?;
+        let mut builder = Self::from_entity_name(context, &entity_name, include_deleted)?;
 
         builder.extend_operators(operators);
         Ok(builder)
     }
 
+    /// Constructs a query plan from a whole query `spec`, which becomes a single SQL statement.
+    pub(crate) fn from_query_spec(context: &RequestContext, spec: QuerySpec) -> Result<Self> {
+        let mut builder = Self::from_entity_name(context, &spec.type_name, spec.include_deleted)?;
+        builder.extend_operators(spec.into_ops());
+        Ok(builder)
+    }
+
+    /// Filters out the entities that were soft-deleted, if the base type soft-deletes.
+    /// Like login filters, this has to come before any Take or Skip operator.
+    fn hide_deleted(&mut self) {
+        if self.base_type().soft_deletes() {
+            let deleted_at = PropertyAccess {
+                property: SOFT_DELETE_FIELD.to_owned(),
+                object: Expr::Parameter { position: 0 }.into(),
+            };
+            let expr = BinaryExpr::eq(deleted_at.into(), Literal::Null.into());
+            self.operators.push(QueryOp::Filter { expression: expr });
+        }
+    }
+
     fn extend_operators(&mut self, ops: Vec<QueryOp>) {
         let ops = self.process_projections(ops);
         self.operators.extend(ops);
@@ -322,6 +403,7 @@ impl QueryPlan {
             name: field.name.clone(),
             type_: field.type_.clone(),
             is_optional: field.is_optional,
+            is_nullable: field.is_nullable,
             column_idx,
             transform,
         };
@@ -379,6 +461,7 @@ impl QueryPlan {
                 QueryField::Entity {
                     name: field.name.clone(),
                     is_optional: field.is_optional,
+                    is_nullable: field.is_nullable,
                     transform: field_policy,
                 }
             } else {
@@ -391,6 +474,7 @@ impl QueryPlan {
             fields,
             table_alias: current_table.to_owned(),
             joins,
+            encrypted: field_policies.encrypted,
         }
     }
 
@@ -466,9 +550,9 @@ impl QueryPlan {
         gather_joins(&self.entity)
     }
 
-    fn make_filter_string(&self, expr: &Option<Expr>) -> Result<String> {
+    fn make_filter_string(&self, target: &TargetDatabase, expr: &Option<Expr>) -> Result<String> {
         let where_cond = if let Some(expr) = expr {
-            let condition = self.filter_expr_to_string(expr)?;
+            let condition = self.filter_expr_to_string(target, expr)?;
             format!("WHERE {}", condition)
         } else {
             "".to_owned()
@@ -476,7 +560,7 @@ impl QueryPlan {
         Ok(where_cond)
     }
 
-    fn filter_expr_to_string(&self, expr: &Expr) -> Result<String> {
+    fn filter_expr_to_string(&self, target: &TargetDatabase, expr: &Expr) -> Result<String> {
         let expr_str = match &expr {
             Expr::Literal { value } => match &value {
                 Literal::Bool(lit) => (if *lit { "true" } else { "false" }).to_string(),
@@ -487,20 +571,82 @@ impl QueryPlan {
                 Literal::Null => "NULL".to_string(),
             },
             Expr::Binary(binary_exp) => {
+                // Nothing is equal to NULL in SQL, not even NULL.
+                let is_null = matches!(
+                    *binary_exp.right,
+                    Expr::Literal {
+                        value: Literal::Null
+                    }
+                );
+                let op = match &binary_exp.op {
+                    BinaryOp::Eq if is_null => "IS",
+                    BinaryOp::NotEq if is_null => "IS NOT",
+                    op => op.to_sql_string(target),
+                };
+                // SQLite has no default escape character, so always name it.
+                let escape = if binary_exp.op.is_like() {
+                    format!(" ESCAPE '{}'", LIKE_ESCAPE)
+                } else {
+                    "".to_owned()
+                };
                 format!(
-                    "({} {} {})",
-                    self.filter_expr_to_string(&binary_exp.left)?,
-                    binary_exp.op.to_sql_string(),
-                    self.filter_expr_to_string(&binary_exp.right)?,
+                    "({} {} {}{})",
+                    self.operand_to_string(target, &binary_exp.left, &binary_exp.right)?,
+                    op,
+                    self.operand_to_string(target, &binary_exp.right, &binary_exp.left)?,
+                    escape,
                 )
             }
-            Expr::Property(property) => self.property_expr_to_string(property)?,
+            Expr::In(in_expr) => {
+                // `IN ()` is a syntax error, but nothing is in an empty list anyway.
+                if in_expr.list.is_empty() {
+                    "false".to_owned()
+                } else {
+                    let list = in_expr
+                        .list
+                        .iter()
+                        .map(|lit| {
+                            self.operand_to_string(target, &lit.clone().into(), &in_expr.value)
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    format!(
+                        "({} IN ({}))",
+                        self.filter_expr_to_string(target, &in_expr.value)?,
+                        list.join(", ")
+                    )
+                }
+            }
+            Expr::Property(property) => self.property_expr_to_string(property)?.0,
             Expr::Parameter { .. } => anyhow::bail!("unexpected standalone parameter usage"),
         };
         Ok(expr_str)
     }
 
-    fn property_expr_to_string(&self, prop_access: &PropertyAccess) -> Result<String> {
+    /// Converts `expr`, which is compared with `other`.  Dates are stored as numbers, so a literal
+    /// compared with a date field is converted to one.
+    fn operand_to_string(
+        &self,
+        target: &TargetDatabase,
+        expr: &Expr,
+        other: &Expr,
+    ) -> Result<String> {
+        if let (Expr::Literal { value }, Expr::Property(property)) = (expr, other) {
+            if self.property_expr_to_string(property)?.1 == Type::Date {
+                return match value {
+                    Literal::String(date) => Ok(dates::parse_str(date)?.to_string()),
+                    Literal::Bool(_) => anyhow::bail!(
+                        "cannot compare date field '{}' with a boolean",
+                        property.property
+                    ),
+                    _ => self.filter_expr_to_string(target, expr),
+                };
+            }
+        }
+        self.filter_expr_to_string(target, expr)
+    }
+
+    /// Returns the column `prop_access` refers to, and the type of its field.
+    fn property_expr_to_string(&self, prop_access: &PropertyAccess) -> Result<(String, Type)> {
         fn get_property_chain(prop_access: &PropertyAccess) -> Result<Vec<String>> {
             match &*prop_access.object {
                 Expr::Property(obj) => {
@@ -515,19 +661,23 @@ impl QueryPlan {
         let properties = get_property_chain(prop_access)?;
         assert!(!properties.is_empty());
 
-        let check_field = |entity: &QueriedEntity, field| {
-            anyhow::ensure!(
-                entity.has_field(field),
-                "expression error: entity '{}' doesn't have field '{}'",
-                entity.ty.name(),
-                field
-            );
-            Ok(())
+        let check_field = |entity: &QueriedEntity, field: &str| {
+            entity
+                .ty
+                .get_field(field)
+                .map(|f| f.type_.clone())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "expression error: entity '{}' doesn't have field '{}'",
+                        entity.ty.name(),
+                        field
+                    )
+                })
         };
 
         let mut field = &properties[0];
         let mut entity = &self.entity;
-        check_field(entity, field)?;
+        let mut field_type = check_field(entity, field)?;
 
         for next_field in &properties[1..] {
             entity = &entity
@@ -541,19 +691,36 @@ impl QueryPlan {
                 })?
                 .entity;
             field = next_field;
-            check_field(entity, field)?;
+            field_type = check_field(entity, field)?;
+        }
+        if entity.encrypted.contains(field) {
+            anyhow::bail!(
+                "expression error: field '{}' of entity '{}' is encrypted, so it can't be filtered on",
+                field,
+                entity.ty.name()
+            );
         }
         let c_alias = ColumnAlias {
             field_name: field.to_owned(),
             table_name: entity.table_alias.to_owned(),
         };
 
-        Ok(format!("\"{}\"", c_alias))
+        Ok((format!("\"{}\"", c_alias), field_type))
     }
 
+    /// Makes the ORDER BY clause of `sort`.  Rows with equal keys are ordered by id, so that the
+    /// order is the same every time the query runs.
     fn make_sort_string(&self, sort: Option<&SortBy>) -> Result<String> {
         let sort_str = if let Some(sort) = sort {
             let mut order_tokens = vec![];
+            let mut push_token = |field_name: &str, ascending: bool| {
+                let order = if ascending { "ASC" } else { "DESC" };
+                let c_alias = ColumnAlias {
+                    field_name: field_name.to_owned(),
+                    table_name: self.base_type().backing_table().to_owned(),
+                };
+                order_tokens.push(format!("\"{c_alias}\" {order}"));
+            };
             for sort_key in &sort.keys {
                 if !self.base_type().has_field(&sort_key.field_name) {
                     anyhow::bail!(
@@ -562,12 +729,17 @@ impl QueryPlan {
                         sort_key.field_name
                     );
                 }
-                let order = if sort_key.ascending { "ASC" } else { "DESC" };
-                let c_alias = ColumnAlias {
-                    field_name: sort_key.field_name.to_owned(),
-                    table_name: self.base_type().backing_table().to_owned(),
-                };
-                order_tokens.push(format!("\"{c_alias}\" {order}"));
+                if self.entity.encrypted.contains(&sort_key.field_name) {
+                    anyhow::bail!(
+                        "field '{}' of entity '{}' is encrypted, so it can't be sorted on",
+                        sort_key.field_name,
+                        self.base_type().name()
+                    );
+                }
+                push_token(&sort_key.field_name, sort_key.ascending);
+            }
+            if !sort.keys.iter().any(|key| key.field_name == "id") {
+                push_token("id", true);
             }
             format!("ORDER BY {}", order_tokens.join(", "))
         } else {
@@ -605,10 +777,14 @@ impl QueryPlan {
 
     /// Splits the operators' slice at a first occurrence of Take or Skip (break) operator into two slices
     /// first containing everything up to the Take|Skip (inclusive) and the second containing the
-    /// remainder. Idiomatically ops = [..., Take|Skip] + [...].
+    /// remainder. Idiomatically ops = [..., Take|Skip] + [...].  A Skip right before a Take stays with
+    /// it, as skipping and then taking is exactly what `LIMIT ... OFFSET ...` does.
     fn split_on_first_take<'a>(&self, ops: &'a [QueryOp]) -> (&'a [QueryOp], &'a [QueryOp]) {
         for (i, op) in ops.iter().enumerate() {
             match op {
+                QueryOp::Skip { .. } if matches!(ops.get(i + 1), Some(QueryOp::Take { .. })) => {
+                    return (&ops[..i + 2], &ops[i + 2..]);
+                }
                 QueryOp::Take { .. } | QueryOp::Skip { .. } => {
                     return (&ops[..i + 1], &ops[i + 1..]);
                 }
@@ -661,7 +837,7 @@ impl QueryPlan {
             remaining_ops = remainder;
 
             let filter_expr = self.gather_filters(ops);
-            let filter_string = self.make_filter_string(&filter_expr)?;
+            let filter_string = self.make_filter_string(target, &filter_expr)?;
 
             let sort = self.find_last_sort_by(ops);
             let sort_string = self.make_sort_string(sort)?;
@@ -679,12 +855,77 @@ impl QueryPlan {
         Ok(sql_query)
     }
 
+    /// Wraps `sql_query` in a SELECT retrieving only the columns of `fields`, returning it along
+    /// with the entity describing the narrowed rows. Filters and sorts of `sql_query` can still
+    /// use any column. Field policies stay attached to the selected fields.
+    fn make_projection(
+        &self,
+        target: &TargetDatabase,
+        sql_query: String,
+        fields: &HashSet<String>,
+    ) -> Result<(String, QueriedEntity)> {
+        for field_name in fields {
+            anyhow::ensure!(
+                self.base_type().has_field(field_name),
+                "entity '{}' has no field named '{}'",
+                self.base_type().name(),
+                field_name
+            );
+        }
+        anyhow::ensure!(!fields.is_empty(), "no fields selected");
+        let mut entity = self.entity.clone();
+        entity.fields.retain(|f| match f {
+            QueryField::Scalar { name, .. } | QueryField::Entity { name, .. } => {
+                fields.contains(name)
+            }
+        });
+        let mut selected = vec![];
+        select_columns(&mut entity, &mut selected);
+        let column_string = selected
+            .iter()
+            .map(|&idx| format!("\"{}\"", self.columns[idx].alias()))
+            .collect::<Vec<String>>()
+            .join(", ");
+        // Postgres doesn't keep the order of rows from a subquery, so sort them again. Limiting
+        // them again changes nothing, but lets the database stop early.
+        let sort_string = self.make_sort_string(self.find_last_sort_by(&self.operators))?;
+        let limit = self
+            .operators
+            .iter()
+            .rev()
+            .find_map(|op| op.as_take().copied());
+        let lo_string = self.make_limit_and_offset_string(target, limit, None);
+        let sql_query = format!(
+            "SELECT {} FROM ({}) AS projection {} {}",
+            column_string, sql_query, sort_string, lo_string
+        );
+        Ok((sql_query, entity))
+    }
+
     pub(crate) fn build_query(&self, target: &TargetDatabase) -> Result<Query> {
-        Ok(Query {
-            raw_sql: self.make_raw_query(target)?,
-            entity: self.entity.clone(),
-            allowed_fields: self.allowed_fields.clone(),
-        })
+        let raw_sql = self.make_raw_query(target)?;
+        let (raw_sql, entity) = match &self.allowed_fields {
+            Some(fields) => self.make_projection(target, raw_sql, fields)?,
+            None => (raw_sql, self.entity.clone()),
+        };
+        Ok(Query { raw_sql, entity })
+    }
+}
+
+/// Appends the indexes of the columns holding the fields of `entity`, and of the entities nested
+/// in it, to `selected`, pointing each field at the position its column gets there.
+fn select_columns(entity: &mut QueriedEntity, selected: &mut Vec<usize>) {
+    for field in &mut entity.fields {
+        match field {
+            QueryField::Scalar { column_idx, .. } => {
+                selected.push(*column_idx);
+                *column_idx = selected.len() - 1;
+            }
+            QueryField::Entity { name, .. } => {
+                let child = &mut entity.joins.get_mut(name.as_str()).unwrap().entity;
+                select_columns(child, selected);
+            }
+        }
     }
 }
 
@@ -693,6 +934,22 @@ fn escape_string(s: &str) -> String {
     format!("{}", format_sql_query::QuotedData(s))
 }
 
+/// Character that makes the next one in a LIKE pattern match itself, even if it's a wildcard.
+const LIKE_ESCAPE: char = '\\';
+
+/// Escapes `s` so that, as a LIKE pattern, it only matches itself: its `%` and `_`
+/// don't act as wildcards.
+pub(crate) fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '%' | '_' | LIKE_ESCAPE) {
+            escaped.push(LIKE_ESCAPE);
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 /// Returns the longest possible prefix of `s` that is at most `max_len`
 /// bytes long and ends at a character boundary so that we don't break
 /// multi-byte characters.
@@ -712,6 +969,9 @@ fn max_prefix(s: &str, max_len: usize) -> &str {
 pub(crate) enum QueryOpChain {
     BaseEntity {
         name: String,
+        /// Whether soft-deleted entities are part of the results.
+        #[serde(default, rename = "includeDeleted")]
+        include_deleted: bool,
     },
     #[serde(rename = "ExpressionFilter")]
     Filter {
@@ -738,6 +998,81 @@ pub(crate) enum QueryOpChain {
     },
 }
 
+impl QueryOpChain {
+    /// Whether the chain queries soft-deleted entities too.
+    pub(crate) fn includes_deleted(&self) -> bool {
+        self.base_entity().1
+    }
+
+    /// Name of the type the chain queries.
+    pub(crate) fn type_name(&self) -> &str {
+        self.base_entity().0
+    }
+
+    fn base_entity(&self) -> (&str, bool) {
+        let mut op = self;
+        loop {
+            op = match op {
+                QueryOpChain::BaseEntity {
+                    name,
+                    include_deleted,
+                } => return (name, *include_deleted),
+                QueryOpChain::Filter { inner, .. }
+                | QueryOpChain::Projection { inner, .. }
+                | QueryOpChain::Take { inner, .. }
+                | QueryOpChain::Skip { inner, .. }
+                | QueryOpChain::SortBy { inner, .. } => inner,
+            };
+        }
+    }
+}
+
+/// A whole query over the entities of `type_name`, as opposed to a `QueryOpChain` built up one
+/// operator at a time.  The entities matching `filter` are sorted by `sort`, and then `offset` of
+/// them are skipped and at most `limit` of the rest are returned.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct QuerySpec {
+    type_name: String,
+    #[serde(default)]
+    filter: Option<Expr>,
+    #[serde(default)]
+    sort: Vec<SortKey>,
+    #[serde(default)]
+    limit: Option<u64>,
+    #[serde(default)]
+    offset: Option<u64>,
+    /// Fields to return, or all of them if None.
+    #[serde(default)]
+    select: Option<Vec<String>>,
+    /// Whether soft-deleted entities are part of the results.
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+impl QuerySpec {
+    /// The operators applying this spec, in an order that builds a single statement.
+    fn into_ops(self) -> Vec<QueryOp> {
+        let mut ops = vec![];
+        if let Some(expression) = self.filter {
+            ops.push(QueryOp::Filter { expression });
+        }
+        if let Some(fields) = self.select {
+            ops.push(QueryOp::Projection { fields });
+        }
+        if !self.sort.is_empty() {
+            ops.push(QueryOp::SortBy(SortBy { keys: self.sort }));
+        }
+        if let Some(count) = self.offset {
+            ops.push(QueryOp::Skip { count });
+        }
+        if let Some(count) = self.limit {
+            ops.push(QueryOp::Take { count });
+        }
+        ops
+    }
+}
+
 /// Converts operator chain into a tuple `(entity_name, ops)`, where
 /// `entity_name` is the name taken from the BaseEntity which corresponds to
 /// Entity which is to be queried. `ops` are a Vector of Operators that
@@ -746,7 +1081,7 @@ pub(crate) enum QueryOpChain {
 fn convert_ops(op: QueryOpChain) -> Result<(String, Vec<QueryOp>)> {
     use QueryOpChain as Op;
     let (query_op, inner): (QueryOp, _) = match op {
-        Op::BaseEntity { name } => {
+        Op::BaseEntity { name, .. } => {
             return Ok((name, vec![]));
         }
         Op::Filter { expression, inner } => (QueryOp::Filter { expression }, inner),
@@ -768,7 +1103,8 @@ pub(crate) struct Mutation {
 }
 
 impl Mutation {
-    /// Constructs delete from filter expression.
+    /// Constructs delete from filter expression. Entities that were already
+    /// soft-deleted are left alone.
     pub(crate) fn delete_from_expr(
         c: &RequestContext,
         type_name: &str,
@@ -780,7 +1116,7 @@ impl Mutation {
             Err(_) => anyhow::bail!("Cannot delete from type `{type_name}`, type not found"),
         };
 
-        let mut query_plan = QueryPlan::from_entity_name(c, type_name)?;
+        let mut query_plan = QueryPlan::from_entity_name(c, type_name, false)?;
         if let Some(expr) = filter_expr {
             query_plan.extend_operators(vec![QueryOp::Filter {
                 expression: expr.clone(),
@@ -792,19 +1128,38 @@ impl Mutation {
         })
     }
 
+    /// Name of the type whose entities this mutation affects.
+    pub(crate) fn entity_name(&self) -> &str {
+        self.base_entity.name()
+    }
+
+    pub(crate) fn base_entity(&self) -> &ObjectType {
+        &self.base_entity
+    }
+
     pub(crate) fn build_sql(&self, target: TargetDatabase) -> Result<String> {
         let select_sql = self.filter_query_plan.build_query(&target)?.raw_sql;
         let id_column = ColumnAlias {
             field_name: "id".to_owned(),
             table_name: self.base_entity.backing_table().to_owned(),
         };
-        let raw_sql = format!(
-            r#"DELETE FROM "{base_table}"
-                WHERE "id" IN (
-                    SELECT "{id_column}" FROM ({select_sql}) as subquery
-                )"#,
-            base_table = &self.base_entity.backing_table(),
-        );
+        let base_table = self.base_entity.backing_table();
+        let raw_sql = if self.base_entity.soft_deletes() {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+            format!(
+                r#"UPDATE "{base_table}" SET "{SOFT_DELETE_FIELD}" = {now}
+                    WHERE "id" IN (
+                        SELECT "{id_column}" FROM ({select_sql}) as subquery
+                    )"#,
+            )
+        } else {
+            format!(
+                r#"DELETE FROM "{base_table}"
+                    WHERE "id" IN (
+                        SELECT "{id_column}" FROM ({select_sql}) as subquery
+                    )"#,
+            )
+        };
         Ok(raw_sql)
     }
 }
@@ -814,9 +1169,8 @@ pub(crate) mod tests {
     use super::*;
     use futures::StreamExt;
     use serde_json::json;
-    use tempfile::NamedTempFile;
 
-    use crate::datastore::expr::BinaryOp;
+    use crate::datastore::expr::{BinaryOp, InExpr};
     use crate::datastore::{DbConnection, QueryEngine};
     use crate::types;
     use crate::JsonObject;
@@ -851,14 +1205,13 @@ pub(crate) mod tests {
 
     pub(crate) fn make_field(name: &str, ty: Type) -> Field {
         let desc = types::NewField::new(name, ty, VERSION).unwrap();
-        Field::new(desc, vec![], None, false, false)
+        Field::new(desc, vec![], None, false, false, false)
     }
 
-    async fn init_query_engine(db_file: &NamedTempFile) -> QueryEngine {
-        let db_uri = format!("sqlite://{}?mode=rwc", db_file.path().to_string_lossy());
-        let data_db = DbConnection::connect(&db_uri, 1).await.unwrap();
-        let query_engine = QueryEngine::local_connection(&data_db, 1).await.unwrap();
-        query_engine
+    /// A query engine over an empty database of its own, held in memory.
+    pub(crate) async fn in_memory_engine() -> QueryEngine {
+        let data_db = DbConnection::connect("sqlite::memory:", 1).await.unwrap();
+        QueryEngine::local_connection(&data_db, 1).await.unwrap()
     }
 
     async fn init_database(query_engine: &QueryEngine, entities: &[&Arc<ObjectType>]) {
@@ -869,13 +1222,10 @@ pub(crate) mod tests {
         QueryEngine::commit_transaction(tr).await.unwrap();
     }
 
-    pub(crate) async fn setup_clear_db(
-        entities: &[&Arc<ObjectType>],
-    ) -> (QueryEngine, NamedTempFile) {
-        let db_file = NamedTempFile::new().unwrap();
-        let qe = init_query_engine(&db_file).await;
+    pub(crate) async fn setup_clear_db(entities: &[&Arc<ObjectType>]) -> QueryEngine {
+        let qe = in_memory_engine().await;
         init_database(&qe, entities).await;
-        (qe, db_file)
+        qe
     }
 
     pub(crate) async fn add_row(
@@ -902,7 +1252,10 @@ pub(crate) mod tests {
         fetch_rows_with_plan(qe, query_plan).await
     }
 
-    async fn fetch_rows_with_plan(qe: &QueryEngine, query_plan: QueryPlan) -> Vec<JsonObject> {
+    pub(crate) async fn fetch_rows_with_plan(
+        qe: &QueryEngine,
+        query_plan: QueryPlan,
+    ) -> Vec<JsonObject> {
         let qe = Arc::new(qe.clone());
         let tr = qe.clone().start_transaction_static().await.unwrap();
         let row_streams = qe.query(tr, query_plan).unwrap();
@@ -942,6 +1295,7 @@ pub(crate) mod tests {
                     api_version: VERSION.to_owned(),
                     user_id: None,
                     path: "".to_string(),
+                    method: "GET".to_string(),
                 },
                 op_chain,
             )
@@ -961,7 +1315,7 @@ pub(crate) mod tests {
             json!({"name": "Kek", "age": json!(40f32)}),
         ];
         {
-            let (qe, _db_file) = setup_clear_db(&*ENTITIES).await;
+            let qe = setup_clear_db(&*ENTITIES).await;
             for person in ppl {
                 add_row(&qe, &PERSON_TY, &person).await;
             }
@@ -977,6 +1331,7 @@ pub(crate) mod tests {
                     keys,
                     inner: QueryOpChain::BaseEntity {
                         name: "Person".to_owned(),
+                        include_deleted: false,
                     }
                     .into(),
                 }
@@ -999,6 +1354,66 @@ pub(crate) mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_in_filter() {
+        let context = RequestContext {
+            policies: &Policies::default(),
+            ts: &make_type_system(&*ENTITIES),
+            api_version: VERSION.to_owned(),
+            user_id: None,
+            path: "".to_string(),
+            method: "GET".to_string(),
+        };
+        let name_in = |names: &[&str]| QueryOpChain::Filter {
+            expression: InExpr {
+                value: Box::new(
+                    PropertyAccess {
+                        property: "name".to_owned(),
+                        object: Expr::Parameter { position: 0 }.into(),
+                    }
+                    .into(),
+                ),
+                list: names.iter().map(|&name| name.into()).collect(),
+            }
+            .into(),
+            inner: QueryOpChain::BaseEntity {
+                name: "Person".to_owned(),
+                include_deleted: false,
+            }
+            .into(),
+        };
+
+        let query_plan = QueryPlan::from_op_chain(&context, name_in(&["John", "O'Neil"])).unwrap();
+        let sql = query_plan
+            .build_query(&TargetDatabase::Sqlite)
+            .unwrap()
+            .raw_sql;
+        assert!(sql.contains(" IN ('John', 'O''Neil'))"), "{}", sql);
+
+        let qe = setup_clear_db(&*ENTITIES).await;
+        for name in ["John", "Alan", "Max"] {
+            add_row(&qe, &PERSON_TY, &json!({"name": name, "age": json!(20f32)})).await;
+        }
+        let fetch_names = |names: &[&str]| {
+            let query_plan = QueryPlan::from_op_chain(&context, name_in(names)).unwrap();
+            let qe = qe.clone();
+            async move {
+                let rows = fetch_rows_with_plan(&qe, query_plan).await;
+                let mut names: Vec<_> = rows
+                    .iter()
+                    .map(|r| r["name"].as_str().unwrap().to_owned())
+                    .collect();
+                names.sort();
+                names
+            }
+        };
+        assert_eq!(
+            fetch_names(&["John", "Max", "Kek"]).await,
+            vec!["John", "Max"]
+        );
+        assert!(fetch_names(&[]).await.is_empty());
+    }
+
     #[tokio::test]
     async fn test_delete_with_expr() {
         let delete_with_expr = |entity_name: &str, expr: Expr| {
@@ -1009,6 +1424,7 @@ pub(crate) mod tests {
                     api_version: VERSION.to_owned(),
                     user_id: None,
                     path: "".to_string(),
+                    method: "GET".to_string(),
                 },
                 entity_name,
                 &Some(expr),
@@ -1019,7 +1435,7 @@ pub(crate) mod tests {
         let john = json!({"name": "John", "age": json!(20f32)});
         let alan = json!({"name": "Alan", "age": json!(30f32)});
         {
-            let (qe, _db_file) = setup_clear_db(&*ENTITIES).await;
+            let qe = setup_clear_db(&*ENTITIES).await;
             add_row(&qe, &PERSON_TY, &john).await;
 
             let expr = binary(&["name"], BinaryOp::Eq, "John".into());
@@ -1029,13 +1445,13 @@ pub(crate) mod tests {
             assert_eq!(fetch_rows(&qe, &PERSON_TY).await.len(), 0);
         }
         {
-            let (qe, _db_file) = setup_clear_db(&*ENTITIES).await;
+            let qe = setup_clear_db(&*ENTITIES).await;
             add_row(&qe, &PERSON_TY, &john).await;
             add_row(&qe, &PERSON_TY, &alan).await;
 
             let expr = binary(&["age"], BinaryOp::Eq, (30.).into());
             let mutation = delete_with_expr("Person", expr);
-            qe.mutate(mutation).await.unwrap();
+            assert_eq!(qe.mutate(mutation).await.unwrap(), 1);
 
             let rows = fetch_rows(&qe, &PERSON_TY).await;
             assert_eq!(rows.len(), 1);
@@ -1044,7 +1460,7 @@ pub(crate) mod tests {
 
         let chiselstrike = json!({"name": "ChiselStrike", "ceo": john});
         {
-            let (qe, _db_file) = setup_clear_db(&*ENTITIES).await;
+            let qe = setup_clear_db(&*ENTITIES).await;
             add_row(&qe, &COMPANY_TY, &chiselstrike).await;
 
             let expr = binary(&["ceo", "name"], BinaryOp::Eq, "John".into());
@@ -1054,4 +1470,411 @@ pub(crate) mod tests {
             assert_eq!(fetch_rows(&qe, &COMPANY_TY).await.len(), 0);
         }
     }
+
+    #[tokio::test]
+    async fn test_soft_delete() {
+        let deleted_at = {
+            let desc = types::NewField::new(SOFT_DELETE_FIELD, Type::Float, VERSION).unwrap();
+            Field::new(desc, vec![], None, true, false, false)
+        };
+        let fields = vec![make_field("text", Type::String), deleted_at];
+        let desc = types::NewObject::new("Note", VERSION);
+        let note_ty = ObjectType::new(desc, fields, types::AuthOrNot::IsNotAuth)
+            .and_then(|ty| ty.with_soft_delete(true))
+            .map(Arc::new)
+            .unwrap();
+        let ts = make_type_system(&[&note_ty]);
+        let policies = Policies::default();
+        let context = RequestContext {
+            policies: &policies,
+            ts: &ts,
+            api_version: VERSION.to_owned(),
+            user_id: None,
+            path: "".to_string(),
+            method: "GET".to_string(),
+        };
+        let fetch_texts = |qe: QueryEngine, include_deleted: bool| {
+            let op_chain = QueryOpChain::BaseEntity {
+                name: "Note".to_owned(),
+                include_deleted,
+            };
+            let query_plan = QueryPlan::from_op_chain(&context, op_chain).unwrap();
+            async move {
+                let rows = fetch_rows_with_plan(&qe, query_plan).await;
+                let mut texts: Vec<_> = rows
+                    .iter()
+                    .map(|r| r["text"].as_str().unwrap().to_owned())
+                    .collect();
+                texts.sort();
+                texts
+            }
+        };
+
+        let qe = setup_clear_db(&[&note_ty]).await;
+        add_row(&qe, &note_ty, &json!({"text": "draft"})).await;
+        add_row(&qe, &note_ty, &json!({"text": "final"})).await;
+
+        let expr = binary(&["text"], BinaryOp::Eq, "draft".into());
+        let mutation = Mutation::delete_from_expr(&context, "Note", &Some(expr)).unwrap();
+        qe.mutate(mutation).await.unwrap();
+
+        // The row is still there, marked with the time it was deleted at.
+        let rows = fetch_rows(&qe, &note_ty).await;
+        assert_eq!(rows.len(), 2);
+        for row in rows {
+            let is_deleted = row.get(SOFT_DELETE_FIELD).map_or(false, |v| v.is_number());
+            assert_eq!(is_deleted, row["text"] == "draft", "{:?}", row);
+        }
+
+        assert_eq!(fetch_texts(qe.clone(), false).await, vec!["final"]);
+        assert_eq!(fetch_texts(qe.clone(), true).await, vec!["draft", "final"]);
+    }
+
+    #[tokio::test]
+    async fn test_null_comparison() {
+        let delete_with_expr = |expr: Expr| {
+            Mutation::delete_from_expr(
+                &RequestContext {
+                    policies: &Policies::default(),
+                    ts: &make_type_system(&*ENTITIES),
+                    api_version: VERSION.to_owned(),
+                    user_id: None,
+                    path: "".to_string(),
+                    method: "GET".to_string(),
+                },
+                "Person",
+                &Some(expr),
+            )
+            .unwrap()
+        };
+
+        let qe = setup_clear_db(&*ENTITIES).await;
+        add_row(&qe, &PERSON_TY, &json!({"name": "John", "age": 20.})).await;
+        add_row(&qe, &PERSON_TY, &json!({"name": "Alan", "age": 30.})).await;
+
+        let expr = binary(&["name"], BinaryOp::Eq, Literal::Null);
+        qe.mutate(delete_with_expr(expr)).await.unwrap();
+        assert_eq!(fetch_rows(&qe, &PERSON_TY).await.len(), 2);
+
+        let expr = binary(&["name"], BinaryOp::NotEq, Literal::Null);
+        qe.mutate(delete_with_expr(expr)).await.unwrap();
+        assert_eq!(fetch_rows(&qe, &PERSON_TY).await.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn optional_and_nullable_fields() {
+        let mut nickname = make_field("nickname", Type::String);
+        nickname.is_optional = true;
+        let partner = make_field("partner", Type::String).with_nullable(true);
+        let ty = make_object(
+            "Member",
+            vec![make_field("name", Type::String), nickname, partner],
+        );
+        let ts = make_type_system(&[&ty]);
+        let policies = Policies::default();
+        let context = RequestContext {
+            policies: &policies,
+            ts: &ts,
+            api_version: VERSION.to_owned(),
+            user_id: None,
+            path: "".to_string(),
+            method: "GET".to_string(),
+        };
+        let qe = setup_clear_db(&[&ty]).await;
+        let add = |value: serde_json::Value| {
+            let qe = qe.clone();
+            let ty = ty.clone();
+            async move { qe.add_row(&ty, value.as_object().unwrap(), None).await }
+        };
+
+        add(json!({"name": "set", "nickname": "n", "partner": "p"}))
+            .await
+            .unwrap();
+        add(json!({"name": "omitted", "partner": null}))
+            .await
+            .unwrap();
+        add(json!({"name": "null", "nickname": null, "partner": null}))
+            .await
+            .unwrap();
+        // Nullable fields may hold null, but they still have to be given.
+        add(json!({"name": "missing", "nickname": "n"}))
+            .await
+            .unwrap_err();
+
+        let mut rows = fetch_rows(&qe, &ty).await;
+        rows.sort_by_key(|r| r["name"].as_str().unwrap().to_owned());
+        let values = |row: &JsonObject| (row.get("nickname").cloned(), row.get("partner").cloned());
+        assert_eq!(rows.len(), 3);
+        assert_eq!(values(&rows[0]), (None, Some(Value::Null)));
+        assert_eq!(values(&rows[1]), (None, Some(Value::Null)));
+        assert_eq!(values(&rows[2]), (Some(json!("n")), Some(json!("p"))));
+
+        let names_where = |field: &'static str, op: BinaryOp| {
+            let op_chain = QueryOpChain::Filter {
+                expression: binary(&[field], op, Literal::Null),
+                inner: QueryOpChain::BaseEntity {
+                    name: "Member".to_owned(),
+                    include_deleted: false,
+                }
+                .into(),
+            };
+            let query_plan = QueryPlan::from_op_chain(&context, op_chain).unwrap();
+            let qe = qe.clone();
+            async move {
+                let rows = fetch_rows_with_plan(&qe, query_plan).await;
+                let mut names: Vec<_> = rows
+                    .iter()
+                    .map(|r| r["name"].as_str().unwrap().to_owned())
+                    .collect();
+                names.sort();
+                names
+            }
+        };
+        assert_eq!(
+            names_where("partner", BinaryOp::Eq).await,
+            vec!["null", "omitted"]
+        );
+        assert_eq!(names_where("partner", BinaryOp::NotEq).await, vec!["set"]);
+        assert_eq!(
+            names_where("nickname", BinaryOp::Eq).await,
+            vec!["null", "omitted"]
+        );
+    }
+
+    #[tokio::test]
+    async fn in_memory_database() {
+        let qe = setup_clear_db(&[&*PERSON_TY]).await;
+        add_row(&qe, &PERSON_TY, &json!({"name": "Alice", "age": 30.0})).await;
+        let rows = fetch_rows(&qe, &PERSON_TY).await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["name"], "Alice");
+        assert_eq!(rows[0]["age"], 30.0);
+
+        // Every engine gets a database of its own.
+        let other = setup_clear_db(&[&*PERSON_TY]).await;
+        assert!(fetch_rows(&other, &PERSON_TY).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_spec() {
+        let context = RequestContext {
+            policies: &Policies::default(),
+            ts: &TS,
+            api_version: VERSION.to_owned(),
+            user_id: None,
+            path: "".to_string(),
+            method: "GET".to_string(),
+        };
+        let qe = setup_clear_db(&*ENTITIES).await;
+        for (name, age) in [
+            ("a", 40.0),
+            ("b", 10.0),
+            ("c", 30.0),
+            ("d", 20.0),
+            ("e", 50.0),
+            ("f", 5.0),
+        ] {
+            add_row(&qe, &PERSON_TY, &json!({"name": name, "age": age})).await;
+        }
+
+        let spec: QuerySpec = serde_json::from_value(json!({
+            "typeName": "Person",
+            "filter": binary(&["age"], BinaryOp::GtEq, 10.0.into()),
+            "sort": [{"fieldName": "age", "ascending": false}],
+            "limit": 2,
+            "offset": 1,
+        }))
+        .unwrap();
+        let query_plan = QueryPlan::from_query_spec(&context, spec).unwrap();
+        let sql = query_plan
+            .build_query(&TargetDatabase::Sqlite)
+            .unwrap()
+            .raw_sql;
+        assert_eq!(sql.matches("subquery").count(), 1, "{}", sql);
+        assert!(sql.contains("LIMIT 2 OFFSET 1"), "{}", sql);
+
+        let rows = fetch_rows_with_plan(&qe, query_plan).await;
+        let names: Vec<_> = rows.iter().map(|r| r["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_select() {
+        let context = RequestContext {
+            policies: &Policies::default(),
+            ts: &TS,
+            api_version: VERSION.to_owned(),
+            user_id: None,
+            path: "".to_string(),
+            method: "GET".to_string(),
+        };
+        let qe = setup_clear_db(&*ENTITIES).await;
+        add_row(&qe, &PERSON_TY, &json!({"name": "a", "age": 40.0})).await;
+        add_row(&qe, &PERSON_TY, &json!({"name": "b", "age": 10.0})).await;
+
+        // Filtering and sorting can use fields that are not selected.
+        let spec: QuerySpec = serde_json::from_value(json!({
+            "typeName": "Person",
+            "filter": binary(&["age"], BinaryOp::Gt, 20.0.into()),
+            "sort": [{"fieldName": "age", "ascending": true}],
+            "select": ["name"],
+        }))
+        .unwrap();
+        let query_plan = QueryPlan::from_query_spec(&context, spec).unwrap();
+        let sql = query_plan
+            .build_query(&TargetDatabase::Sqlite)
+            .unwrap()
+            .raw_sql;
+        assert!(sql.contains("AS projection"), "{}", sql);
+        let rows = fetch_rows_with_plan(&qe, query_plan).await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].keys().collect::<Vec<_>>(), vec!["name"]);
+        assert_eq!(rows[0]["name"], "a");
+
+        let spec: QuerySpec = serde_json::from_value(json!({
+            "typeName": "Person",
+            "select": ["name", "height"],
+        }))
+        .unwrap();
+        let err = QueryPlan::from_query_spec(&context, spec)
+            .unwrap()
+            .build_query(&TargetDatabase::Sqlite)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "entity 'Person' has no field named 'height'"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sorted_select() {
+        let context = RequestContext {
+            policies: &Policies::default(),
+            ts: &TS,
+            api_version: VERSION.to_owned(),
+            user_id: None,
+            path: "".to_string(),
+            method: "GET".to_string(),
+        };
+        let qe = setup_clear_db(&*ENTITIES).await;
+        for (name, age) in [("a", 40.0), ("b", 10.0), ("c", 30.0), ("d", 20.0)] {
+            add_row(&qe, &PERSON_TY, &json!({"name": name, "age": age})).await;
+        }
+
+        let spec: QuerySpec = serde_json::from_value(json!({
+            "typeName": "Person",
+            "sort": [{"fieldName": "age", "ascending": false}],
+            "limit": 3,
+            "select": ["name"],
+        }))
+        .unwrap();
+        let query_plan = QueryPlan::from_query_spec(&context, spec).unwrap();
+        // The order of the sorted subquery isn't kept on its own, so the projection sorts again.
+        for target in [TargetDatabase::Sqlite, TargetDatabase::Postgres] {
+            let sql = query_plan.build_query(&target).unwrap().raw_sql;
+            let (_, outer) = sql.rsplit_once("AS projection").unwrap();
+            assert!(outer.contains("ORDER BY"), "{}", sql);
+            assert!(outer.contains("LIMIT 3"), "{}", sql);
+        }
+        let rows = fetch_rows_with_plan(&qe, query_plan).await;
+        let names: Vec<_> = rows.iter().map(|r| r["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["a", "c", "d"]);
+    }
+
+    #[test]
+    fn test_dialect_sql() {
+        let op_chain = QueryOpChain::Skip {
+            count: 2,
+            inner: QueryOpChain::Filter {
+                expression: binary(&["name"], BinaryOp::ILike, "al%".into()),
+                inner: QueryOpChain::BaseEntity {
+                    name: "Person".to_owned(),
+                    include_deleted: false,
+                }
+                .into(),
+            }
+            .into(),
+        };
+        let query_plan = QueryPlan::from_op_chain(
+            &RequestContext {
+                policies: &Policies::default(),
+                ts: &TS,
+                api_version: VERSION.to_owned(),
+                user_id: None,
+                path: "".to_string(),
+                method: "GET".to_string(),
+            },
+            op_chain,
+        )
+        .unwrap();
+
+        let postgres = query_plan.build_query(&TargetDatabase::Postgres).unwrap();
+        assert!(
+            postgres.raw_sql.contains(" ILIKE 'al%'"),
+            "{}",
+            postgres.raw_sql
+        );
+        assert!(
+            postgres.raw_sql.contains(" ILIKE 'al%' ESCAPE '\\')"),
+            "{}",
+            postgres.raw_sql
+        );
+        assert!(
+            postgres.raw_sql.contains("OFFSET 2"),
+            "{}",
+            postgres.raw_sql
+        );
+        assert!(!postgres.raw_sql.contains("LIMIT"), "{}", postgres.raw_sql);
+
+        let sqlite = query_plan.build_query(&TargetDatabase::Sqlite).unwrap();
+        assert!(sqlite.raw_sql.contains(" LIKE 'al%'"), "{}", sqlite.raw_sql);
+        assert!(!sqlite.raw_sql.contains("ILIKE"), "{}", sqlite.raw_sql);
+        assert!(sqlite.raw_sql.contains("LIMIT 2,-1"), "{}", sqlite.raw_sql);
+    }
+
+    #[tokio::test]
+    async fn test_dialect_rows() {
+        let qe = setup_clear_db(&*ENTITIES).await;
+        for name in ["Alan", "alfred", "ALBERT", "Bob", "Sal"] {
+            add_row(&qe, &PERSON_TY, &json!({"name": name, "age": 30.0})).await;
+        }
+        let fetch_names = |op: BinaryOp| {
+            let qe = qe.clone();
+            async move {
+                let op_chain = QueryOpChain::Filter {
+                    expression: binary(&["name"], op, "al%".into()),
+                    inner: QueryOpChain::BaseEntity {
+                        name: "Person".to_owned(),
+                        include_deleted: false,
+                    }
+                    .into(),
+                };
+                let query_plan = QueryPlan::from_op_chain(
+                    &RequestContext {
+                        policies: &Policies::default(),
+                        ts: &TS,
+                        api_version: VERSION.to_owned(),
+                        user_id: None,
+                        path: "".to_string(),
+                        method: "GET".to_string(),
+                    },
+                    op_chain,
+                )
+                .unwrap();
+                let rows = fetch_rows_with_plan(&qe, query_plan).await;
+                let mut names: Vec<_> = rows
+                    .iter()
+                    .map(|r| r["name"].as_str().unwrap().to_owned())
+                    .collect();
+                names.sort();
+                names
+            }
+        };
+
+        assert_eq!(
+            fetch_names(BinaryOp::ILike).await,
+            vec!["ALBERT", "Alan", "alfred"]
+        );
+        assert_eq!(fetch_names(BinaryOp::NotILike).await, vec!["Bob", "Sal"]);
+    }
 }
@@ -0,0 +1,80 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Conversions of `Date` field values.
+//!
+//! Dates are stored as milliseconds since the Unix epoch, so that they sort and compare as numbers.  They come in
+//! either as such a number or as an ISO 8601 string, and go out as ISO 8601 strings in UTC.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, SecondsFormat, TimeZone, Utc};
+use serde_json::Value;
+
+/// Converts a date given as a JSON value to milliseconds since the epoch.
+pub(crate) fn parse(value: &Value) -> Result<f64> {
+    match value {
+        Value::Number(n) => n
+            .as_f64()
+            .ok_or_else(|| anyhow!("date {} is out of range", n)),
+        Value::String(s) => parse_str(s),
+        v => anyhow::bail!("expected a date, got {}", v),
+    }
+}
+
+/// Converts an ISO 8601 date, with or without a time, to milliseconds since the epoch.  A date
+/// without a time is midnight UTC.
+pub(crate) fn parse_str(s: &str) -> Result<f64> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(s) {
+        return Ok(date.timestamp_millis() as f64);
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(date.and_hms(0, 0, 0).timestamp_millis() as f64);
+    }
+    if let Ok(ms) = s.parse::<f64>() {
+        return Ok(ms);
+    }
+    anyhow::bail!("'{}' is not an ISO 8601 date", s)
+}
+
+/// Formats milliseconds since the epoch as an ISO 8601 date in UTC, like JavaScript's
+/// `Date.prototype.toISOString()`.
+pub(crate) fn format(ms: f64) -> Result<String> {
+    let date = Utc
+        .timestamp_millis_opt(ms as i64)
+        .single()
+        .ok_or_else(|| anyhow!("date {} is out of range", ms))?;
+    Ok(date.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+/// The current time, in milliseconds since the epoch.
+pub(crate) fn now() -> f64 {
+    Utc::now().timestamp_millis() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_formats() {
+        assert_eq!(parse(&json!(1641092645000.0)).unwrap(), 1641092645000.0);
+        assert_eq!(
+            parse(&json!("2022-01-02T03:04:05Z")).unwrap(),
+            1641092645000.0
+        );
+        assert_eq!(
+            parse(&json!("2022-01-02T04:04:05.000+01:00")).unwrap(),
+            1641092645000.0
+        );
+        assert_eq!(parse(&json!("2022-01-02")).unwrap(), 1641081600000.0);
+        assert!(parse(&json!("yesterday")).is_err());
+        assert!(parse(&json!(true)).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let formatted = format(1641092645123.0).unwrap();
+        assert_eq!(formatted, "2022-01-02T03:04:05.123Z");
+        assert_eq!(parse_str(&formatted).unwrap(), 1641092645123.0);
+    }
+}
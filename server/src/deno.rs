@@ -1,23 +1,47 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::api::ApiService;
-use crate::api::{response_template, Body, RequestPath};
+use crate::api::{
+    parse_range, response_template, Body, ByteRange, ClientAddr, DeniedHeaders, RequestPath,
+    ResponseHeaderLimits,
+};
 use crate::auth::get_username_from_id;
+use crate::cookies;
+use crate::csv::CsvWriter;
 use crate::datastore::crud;
 use crate::datastore::engine::extract_transaction;
 use crate::datastore::engine::IdTree;
 use crate::datastore::engine::TransactionStatic;
+use crate::datastore::engine::{DisallowedValue, UniqueViolation};
 use crate::datastore::engine::{QueryResults, ResultRow};
 use crate::datastore::expr::Expr;
-use crate::datastore::query::{Mutation, QueryOpChain, QueryPlan, RequestContext};
+use crate::datastore::named_query::NamedQueries;
+use crate::datastore::query::{
+    EntityShape, Mutation, QueryOpChain, QueryPlan, QuerySpec, RequestContext,
+};
 use crate::datastore::MetaService;
 use crate::datastore::QueryEngine;
+use crate::dates;
+use crate::encoding;
+use crate::encryption;
+use crate::introspect;
+use crate::jobs::{JobStatus, JobStore};
+use crate::json_array::JsonArrayWriter;
+use crate::json_schema;
+use crate::multipart;
+use crate::passwords;
 use crate::policies::Policies;
+use crate::prefetch::prefetch;
 use crate::rcmut::RcMut;
-use crate::types::Type;
+use crate::runtime;
+use crate::signing;
+use crate::telemetry::{self, SpanGuard};
+use crate::text_body;
 use crate::types::TypeSystem;
 use crate::types::TypeSystemError;
+use crate::types::{ObjectType, Type};
 use crate::vecmap::VecMap;
+use crate::websocket::{self, WebSocketEvent, WebSocketResource};
 use crate::JsonObject;
 use anyhow::{anyhow, Context as AnyhowContext, Result};
 use api::chisel_js;
@@ -49,16 +73,23 @@ use deno_runtime::web_worker::WebWorker;
 use deno_runtime::web_worker::WebWorkerOptions;
 use deno_runtime::worker::{MainWorker, WorkerOptions};
 use deno_runtime::BootstrapOptions;
-use futures::stream::{try_unfold, Stream};
+use futures::stream::{try_unfold, Stream, StreamExt};
 use futures::task::LocalFutureObj;
 use futures::{future, FutureExt};
 use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderName, HeaderValue, ACCEPT_RANGES, ACCESS_CONTROL_REQUEST_METHOD, CONTENT_LENGTH,
+    CONTENT_RANGE, IF_RANGE, RANGE,
+};
 use hyper::Method;
 use hyper::Uri;
 use hyper::{Request, Response, StatusCode};
 use log::debug;
 use once_cell::unsync::OnceCell;
+use opentelemetry::KeyValue;
 use pin_project::pin_project;
+use rand::rngs::OsRng;
+use rand::RngCore;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 use std::cell::Cell;
@@ -70,7 +101,7 @@ use std::fmt::Debug;
 use std::future::Future;
 use std::io::Read;
 use std::io::Write;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::DerefMut;
 use std::pin::Pin;
 use std::rc::Rc;
@@ -78,7 +109,9 @@ use std::rc::Weak;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tempfile::Builder;
+use tokio::time::Instant;
 
 // FIXME: This should not be here. The client should download and
 // compile modules, the server should not get code out of the
@@ -93,15 +126,18 @@ struct VersionedCode {
     version: u64,
 }
 
+/// Updates to the state of the workers, which both the endpoint and the task worker get.
+#[derive(Clone)]
 enum WorkerMsg {
     SetMeta(MetaService),
-    HandleRequest(Request<hyper::Body>),
     SetTypeSystem(TypeSystem),
     RemoveTypeVersion(String),
     SetQueryEngine(Arc<QueryEngine>),
     SetPolicies(Policies),
-    MutatePolicies(Box<dyn FnOnce(&mut Policies) + Send>),
     SetCurrentSecrets(JsonObject),
+    SetConfig(JsonObject),
+    SetNamedQueries(NamedQueries),
+    SetJobs(JobStore),
 }
 
 /// A v8 isolate doesn't want to be moved between or used from
@@ -125,19 +161,99 @@ struct DenoService {
     module_loader: Arc<std::sync::Mutex<ModuleLoaderInner>>,
 
     import_endpoint: v8::Global<v8::Function>,
-    activate_endpoint: v8::Global<v8::Function>,
+    activate_endpoints: v8::Global<v8::Function>,
     call_handler: v8::Global<v8::Function>,
     read_worker_channel: v8::Global<v8::Function>,
     end_of_request: v8::Global<v8::Function>,
+    set_middleware: v8::Global<v8::Function>,
 
     to_worker: Sender<WorkerMsg>,
+    to_task_worker: Sender<WorkerMsg>,
+    requests: Sender<Request<hyper::Body>>,
     worker_channel_id: u32,
+    // Background tasks queued with enqueue() run on a worker of their own, so that they don't hold up requests.
+    task_worker_channel_id: u32,
+
+    // How many requests run_js may work on at once, and how many it currently works on.
+    max_concurrent_requests: usize,
+    requests_in_flight: Rc<Cell<usize>>,
+
+    // Response headers that endpoints may not set.
+    denied_headers: DeniedHeaders,
+    // How many response headers endpoints may set, and how large they may be.
+    header_limits: ResponseHeaderLimits,
+
+    // How many chunks of a response body to read ahead of the client.
+    response_prefetch_chunks: usize,
 }
 
 #[derive(thiserror::Error, Debug)]
 enum Error {
     #[error["Endpoint didn't produce a response"]]
     NotAResponse,
+    #[error["Endpoint returned status code {0}, which is not between 100 and 599"]]
+    InvalidStatus(f64),
+    #[error["Bad Request: {0}"]]
+    BadRequest(String),
+    #[error["Request Timeout: the request body took too long to arrive"]]
+    BodyReadTimeout,
+    #[error["Gateway Timeout: the request ran out of time for database queries"]]
+    QueryDeadline,
+}
+
+/// Names the class of the JavaScript error that an op failing with `err` throws. The worker
+/// answers errors of the `Chisel*` classes with the status they name, and others with a 500.
+fn get_error_class(err: &AnyError) -> &'static str {
+    for cause in err.chain() {
+        if cause.is::<UniqueViolation>() {
+            return "ChiselConflict";
+        }
+        if cause.is::<DisallowedValue>() {
+            return "ChiselBadRequest";
+        }
+        if let Some(err) = cause.downcast_ref::<Error>() {
+            match err {
+                Error::BadRequest(_) => return "ChiselBadRequest",
+                Error::BodyReadTimeout => return "ChiselRequestTimeout",
+                Error::QueryDeadline => return "ChiselGatewayTimeout",
+                Error::NotAResponse | Error::InvalidStatus(_) => (),
+            }
+        }
+    }
+    deno_runtime::errors::get_error_class_name(err).unwrap_or("Error")
+}
+
+/// An exception thrown by JavaScript code, or a value a promise was rejected with.
+#[derive(thiserror::Error, Debug)]
+#[error("{message}")]
+pub(crate) struct JsException {
+    message: String,
+    /// The stack frames of the exception, if it is an Error.
+    trace: Option<String>,
+}
+
+impl JsException {
+    fn new(scope: &mut v8::HandleScope, exception: v8::Local<v8::Value>) -> Self {
+        let message = exception.to_rust_string_lossy(scope);
+        // The stack of an Error starts with the same line as its string form.
+        let trace = exception
+            .to_object(scope)
+            .and_then(|obj| get_member::<v8::Local<v8::String>>(obj, scope, "stack").ok())
+            .map(|stack| stack.to_rust_string_lossy(scope))
+            .map(|stack| match stack.strip_prefix(&message) {
+                Some(frames) => frames.trim_start_matches('\n').to_string(),
+                None => stack,
+            })
+            .filter(|trace| !trace.is_empty());
+        Self { message, trace }
+    }
+
+    /// The stack trace of the JavaScript exception that caused `err`, if any.
+    pub(crate) fn trace_of(err: &anyhow::Error) -> Option<&str> {
+        err.chain()
+            .find_map(|e| e.downcast_ref::<JsException>())
+            .and_then(|e| e.trace.as_deref())
+    }
 }
 
 struct ModuleLoaderInner {
@@ -225,17 +341,62 @@ fn build_extensions() -> Vec<Extension> {
         .ops(vec![
             op_format_file_name::decl(),
             op_chisel_read_body::decl(),
+            op_chisel_read_text::decl(),
+            op_chisel_buffer_body::decl(),
+            op_chisel_read_multipart::decl(),
+            op_chisel_upgrade_websocket::decl(),
+            op_chisel_websocket_accept::decl(),
+            op_chisel_websocket_next::decl(),
+            op_chisel_websocket_send_text::decl(),
+            op_chisel_websocket_send_binary::decl(),
+            op_chisel_websocket_close::decl(),
             op_chisel_store::decl(),
+            op_chisel_store_many::decl(),
+            op_chisel_update::decl(),
+            op_chisel_increment::decl(),
+            op_chisel_upsert::decl(),
             op_chisel_entity_delete::decl(),
             op_chisel_crud_delete::decl(),
+            op_chisel_delete_many::decl(),
             op_chisel_get_secret::decl(),
+            op_chisel_config::decl(),
+            op_chisel_sign::decl(),
+            op_chisel_verify::decl(),
+            op_chisel_hash_password::decl(),
+            op_chisel_verify_password::decl(),
+            op_chisel_random_bytes::decl(),
+            op_chisel_random_token::decl(),
+            op_chisel_base64_encode::decl(),
+            op_chisel_base64_decode::decl(),
+            op_chisel_hex_encode::decl(),
+            op_chisel_hex_decode::decl(),
+            op_chisel_now::decl(),
+            op_chisel_cookies::decl(),
+            op_chisel_request_route::decl(),
+            op_chisel_remote_addr::decl(),
+            op_chisel_set_route::decl(),
+            op_chisel_validate::decl(),
+            op_chisel_check_schema::decl(),
             op_chisel_crud_query::decl(),
+            op_chisel_crud_query_csv::decl(),
+            op_chisel_crud_query_json::decl(),
             op_chisel_relational_query_create::decl(),
+            op_chisel_query_create::decl(),
+            op_chisel_run_named_query::decl(),
             op_chisel_query_next::decl(),
+            op_chisel_query_next_csv::decl(),
+            op_chisel_query_next_json::decl(),
+            op_chisel_relational_query_page::decl(),
+            op_chisel_raw_query::decl(),
+            op_chisel_last_modified::decl(),
+            op_chisel_type_info::decl(),
+            op_chisel_is_allowed::decl(),
             op_chisel_commit_transaction::decl(),
             op_chisel_rollback_transaction::decl(),
             op_chisel_create_transaction::decl(),
             op_chisel_init_worker::decl(),
+            op_chisel_create_job::decl(),
+            op_chisel_set_job_status::decl(),
             op_chisel_read_worker_channel::decl(),
             op_chisel_start_request::decl(),
         ])
@@ -277,7 +438,7 @@ fn create_web_worker(
             use_deno_namespace: args.use_deno_namespace,
             worker_type: args.worker_type,
             maybe_inspector_server: maybe_inspector_server.clone(),
-            get_error_class_fn: None,
+            get_error_class_fn: Some(&get_error_class),
             blob_store: Default::default(),
             broadcast_channel: Default::default(),
             shared_array_buffer_store: None,
@@ -294,7 +455,11 @@ fn create_web_worker(
     })
 }
 
-type Channel = Receiver<WorkerMsg>;
+/// What a worker receives: updates to its state and, for the endpoint worker, the requests to handle.
+struct Channel {
+    state: Receiver<WorkerMsg>,
+    requests: Receiver<Request<hyper::Body>>,
+}
 type GlobalChannels = VecMap<Channel>;
 
 lazy_static! {
@@ -306,7 +471,13 @@ thread_local! {
 }
 
 impl DenoService {
-    pub(crate) async fn new(inspect_brk: bool) -> (Self, v8::Global<v8::Function>) {
+    pub(crate) async fn new(
+        inspect_brk: bool,
+        max_concurrent_requests: usize,
+        response_prefetch_chunks: usize,
+        denied_headers: DeniedHeaders,
+        header_limits: ResponseHeaderLimits,
+    ) -> (Self, v8::Global<v8::Function>) {
         let web_worker_preload_module_cb =
             Arc::new(|worker| LocalFutureObj::new(Box::new(future::ready(Ok(worker)))));
         let inner = Arc::new(std::sync::Mutex::new(ModuleLoaderInner {
@@ -421,11 +592,12 @@ impl DenoService {
 
         let (
             import_endpoint,
-            activate_endpoint,
+            activate_endpoints,
             call_handler,
             init_worker,
             read_worker_channel,
             end_of_request,
+            set_middleware,
         ) = {
             let runtime = &mut worker.js_runtime;
             let promise = runtime
@@ -437,9 +609,9 @@ impl DenoService {
             let import_endpoint: v8::Local<v8::Function> =
                 get_member(module, scope, "importEndpoint").unwrap();
             let import_endpoint = v8::Global::new(scope, import_endpoint);
-            let activate_endpoint: v8::Local<v8::Function> =
-                get_member(module, scope, "activateEndpoint").unwrap();
-            let activate_endpoint = v8::Global::new(scope, activate_endpoint);
+            let activate_endpoints: v8::Local<v8::Function> =
+                get_member(module, scope, "activateEndpoints").unwrap();
+            let activate_endpoints = v8::Global::new(scope, activate_endpoints);
             let call_handler: v8::Local<v8::Function> =
                 get_member(module, scope, "callHandler").unwrap();
             let call_handler = v8::Global::new(scope, call_handler);
@@ -452,20 +624,34 @@ impl DenoService {
             let end_of_request: v8::Local<v8::Function> =
                 get_member(module, scope, "endOfRequest").unwrap();
             let end_of_request = v8::Global::new(scope, end_of_request);
+            let set_middleware: v8::Local<v8::Function> =
+                get_member(module, scope, "setMiddleware").unwrap();
+            let set_middleware = v8::Global::new(scope, set_middleware);
 
             (
                 import_endpoint,
-                activate_endpoint,
+                activate_endpoints,
                 call_handler,
                 init_worker,
                 read_worker_channel,
                 end_of_request,
+                set_middleware,
             )
         };
 
         let (to_worker_sender, to_worker_receiver) = async_channel::bounded(1);
+        let (to_task_worker_sender, to_task_worker_receiver) = async_channel::bounded(1);
+        let (requests_sender, requests_receiver) = async_channel::bounded(1);
         let mut map = GLOBAL_WORKER_CHANNELS.lock().unwrap();
-        let worker_channel_id = map.push(to_worker_receiver) as u32;
+        let worker_channel_id = map.push(Channel {
+            state: to_worker_receiver,
+            requests: requests_receiver,
+        }) as u32;
+        // The task worker handles no requests, so nothing is ever sent on its channel for them.
+        let task_worker_channel_id = map.push(Channel {
+            state: to_task_worker_receiver,
+            requests: async_channel::bounded(1).1,
+        }) as u32;
 
         (
             Self {
@@ -473,12 +659,21 @@ impl DenoService {
                 inspector,
                 module_loader: inner,
                 import_endpoint,
-                activate_endpoint,
+                activate_endpoints,
                 call_handler,
                 to_worker: to_worker_sender,
+                to_task_worker: to_task_worker_sender,
+                requests: requests_sender,
                 worker_channel_id,
+                task_worker_channel_id,
                 read_worker_channel,
                 end_of_request,
+                set_middleware,
+                max_concurrent_requests,
+                requests_in_flight: Default::default(),
+                denied_headers,
+                header_limits,
+                response_prefetch_chunks,
             },
             init_worker,
         )
@@ -499,18 +694,192 @@ impl Future for ReadFuture {
     }
 }
 
+/// Reads the next chunk of a request body. If the body has a deadline and the chunk doesn't
+/// arrive by then, the body is cancelled and the read fails with `Error::BodyReadTimeout`.
+async fn read_body_chunk(resource: &Rc<BodyResource>) -> Result<Option<hyper::body::Bytes>> {
+    let cancel = RcRef::map(resource, |r| &r.cancel);
+    let fut = ReadFuture {
+        resource: resource.clone(),
+    };
+    let fut = fut.or_cancel(cancel);
+    let chunk = match resource.deadline {
+        Some(deadline) => match tokio::time::timeout_at(deadline, fut).await {
+            Ok(chunk) => chunk,
+            Err(_) => {
+                resource.cancel.cancel();
+                return Err(Error::BodyReadTimeout.into());
+            }
+        },
+        None => fut.await,
+    };
+    Ok(chunk?.transpose()?)
+}
+
 #[op]
 async fn op_chisel_read_body(
     state: Rc<RefCell<OpState>>,
     body_rid: ResourceId,
 ) -> Result<Option<ZeroCopyBuf>> {
     let resource: Rc<BodyResource> = state.borrow().resource_table.get(body_rid)?;
-    let cancel = RcRef::map(&resource, |r| &r.cancel);
-    let fut = ReadFuture {
-        resource: resource.clone(),
-    };
-    let fut = fut.or_cancel(cancel);
-    Ok(fut.await?.transpose()?.map(|x| x.to_vec().into()))
+    let chunk = read_body_chunk(&resource).await?;
+    Ok(chunk.map(|x| x.to_vec().into()))
+}
+
+/// Longest request body that is read whole, as the whole of it is held in memory.
+const MAX_WHOLE_BODY_LEN: usize = 16 * 1024 * 1024;
+
+async fn read_whole_body(resource: &Rc<BodyResource>) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    while let Some(chunk) = read_body_chunk(resource).await? {
+        if body.len() + chunk.len() > MAX_WHOLE_BODY_LEN {
+            let msg = format!("request body is longer than {} bytes", MAX_WHOLE_BODY_LEN);
+            return Err(Error::BadRequest(msg).into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Reads the whole of a request body and decodes it in the charset of `content_type`.
+#[op]
+async fn op_chisel_read_text(
+    state: Rc<RefCell<OpState>>,
+    body_rid: ResourceId,
+    content_type: String,
+) -> Result<String> {
+    let resource: Rc<BodyResource> = state.borrow().resource_table.get(body_rid)?;
+    let body = read_whole_body(&resource).await?;
+    text_body::decode(&body, &content_type)
+}
+
+/// Reads the whole of a request body and returns it, keeping a copy that is read in its place
+/// afterwards.  The body can then be looked at before the endpoint reads it, in whichever way.
+#[op]
+async fn op_chisel_buffer_body(
+    state: Rc<RefCell<OpState>>,
+    body_rid: ResourceId,
+) -> Result<ZeroCopyBuf> {
+    let resource: Rc<BodyResource> = state.borrow().resource_table.get(body_rid)?;
+    let body = read_whole_body(&resource).await?;
+    *resource.body.borrow_mut() = hyper::Body::from(body.clone());
+    Ok(body.into())
+}
+
+/// Accepts the WebSocket upgrade that the current request asks for.  Returns the resource of
+/// the connection, and the `Sec-WebSocket-Accept` header of the response to send.
+#[op]
+fn op_chisel_upgrade_websocket(state: &mut OpState) -> Result<(ResourceId, String)> {
+    let upgrade = state
+        .try_take::<websocket::Upgrade>()
+        .context("the request is not a WebSocket upgrade")?;
+    let (resource, accept_key) = upgrade.accept();
+    let rid = state.resource_table.add(resource);
+    Ok((rid, accept_key))
+}
+
+fn websocket_resource(
+    state: &Rc<RefCell<OpState>>,
+    rid: ResourceId,
+) -> Result<Rc<WebSocketResource>> {
+    Ok(state.borrow().resource_table.get(rid)?)
+}
+
+#[op]
+async fn op_chisel_websocket_accept(state: Rc<RefCell<OpState>>, rid: ResourceId) -> Result<()> {
+    websocket_resource(&state, rid)?.accept().await
+}
+
+#[op]
+async fn op_chisel_websocket_next(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+) -> Result<WebSocketEvent> {
+    websocket_resource(&state, rid)?.next().await
+}
+
+#[op]
+async fn op_chisel_websocket_send_text(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+    data: String,
+) -> Result<()> {
+    let message = websocket::Message::Text(data);
+    websocket_resource(&state, rid)?.send(message).await
+}
+
+#[op]
+async fn op_chisel_websocket_send_binary(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+    data: ZeroCopyBuf,
+) -> Result<()> {
+    let message = websocket::Message::Binary(data.to_vec());
+    websocket_resource(&state, rid)?.send(message).await
+}
+
+#[op]
+async fn op_chisel_websocket_close(
+    state: Rc<RefCell<OpState>>,
+    rid: ResourceId,
+    code: u16,
+    reason: String,
+) -> Result<()> {
+    websocket_resource(&state, rid)?.close(code, reason).await
+}
+
+#[derive(Serialize)]
+enum MultipartEvent {
+    Part(multipart::PartInfo),
+    Data(ZeroCopyBuf),
+}
+
+/// A body that isn't valid multipart/form-data is the client's fault.
+fn bad_multipart(err: anyhow::Error) -> Error {
+    Error::BadRequest(err.to_string())
+}
+
+/// Reads a multipart/form-data body up to the start of the next part or the next piece of the
+/// current part's contents. Returns None at the end of the body.
+#[op]
+async fn op_chisel_read_multipart(
+    state: Rc<RefCell<OpState>>,
+    body_rid: ResourceId,
+    content_type: String,
+) -> Result<Option<MultipartEvent>> {
+    let resource: Rc<BodyResource> = state.borrow().resource_table.get(body_rid)?;
+    loop {
+        let event = {
+            let mut parser = resource.multipart.borrow_mut();
+            if parser.is_none() {
+                let boundary = multipart::boundary(&content_type).map_err(bad_multipart)?;
+                *parser = Some(multipart::Parser::new(&boundary));
+            }
+            parser
+                .as_mut()
+                .unwrap()
+                .next_event()
+                .map_err(bad_multipart)?
+        };
+        match event {
+            Some(multipart::Event::Part(info)) => return Ok(Some(MultipartEvent::Part(info))),
+            Some(multipart::Event::Data(data)) => {
+                return Ok(Some(MultipartEvent::Data(data.into())))
+            }
+            Some(multipart::Event::End) => return Ok(None),
+            None => (),
+        }
+
+        let chunk = read_body_chunk(&resource).await?;
+        let mut parser = resource.multipart.borrow_mut();
+        let parser = parser.as_mut().unwrap();
+        match chunk {
+            Some(chunk) => parser.feed(&chunk),
+            None => {
+                parser.finish().map_err(bad_multipart)?;
+                return Ok(None);
+            }
+        }
+    }
 }
 
 /// RequestContext corresponds to `requestContext` structure used in chisel.ts.
@@ -519,8 +888,7 @@ struct ChiselRequestContext {
     /// Current URL path.
     path: String,
     /// Current HTTP method.
-    #[serde(rename = "method")]
-    _method: String,
+    method: String,
     /// Schema version to be used with the request.
     #[serde(rename = "apiVersion")]
     api_version: String,
@@ -545,30 +913,228 @@ async fn op_chisel_store(
     content: StoreContent,
     c: ChiselRequestContext,
 ) -> Result<IdTree> {
-    let type_name = &content.name;
     let value = &content.value;
 
-    let (query_engine, ty) = {
+    let (query_engine, ty, value) = {
+        let mut state = state.borrow_mut();
+        let ty = writable_type(&state, &content.name, &c)?;
+        note_write(&mut state, &ty);
+        let value = current_policies(&state)
+            .enforce_write_policies(&c.user_id, &c.path, &c.method, &ty, value)?;
+        let query_engine = query_engine_arc(&state);
+        (query_engine, ty, value)
+    };
+    let transaction = {
+        let state = state.borrow();
+        current_transaction(&state)
+    };
+    let mut transaction = transaction.lock().await;
+    let write = query_engine.add_row(&ty, &value, Some(transaction.deref_mut()));
+    traced(&state, "store", &content.name, write).await
+}
+
+#[derive(Deserialize)]
+struct StoreManyContent {
+    name: String,
+    values: Vec<JsonObject>,
+}
+
+#[op]
+async fn op_chisel_store_many(
+    state: Rc<RefCell<OpState>>,
+    content: StoreManyContent,
+    c: ChiselRequestContext,
+) -> Result<Vec<IdTree>> {
+    let (query_engine, ty, values) = {
+        let mut state = state.borrow_mut();
+        let ty = writable_type(&state, &content.name, &c)?;
+        note_write(&mut state, &ty);
+        let policies = current_policies(&state);
+        let values = content
+            .values
+            .iter()
+            .map(|value| {
+                policies.enforce_write_policies(&c.user_id, &c.path, &c.method, &ty, value)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let query_engine = query_engine_arc(&state);
+        (query_engine, ty, values)
+    };
+    let transaction = {
         let state = state.borrow();
-        let ty = match current_type_system(&state).lookup_type(type_name, &c.api_version) {
-            Ok(Type::Object(ty)) => ty,
-            _ => anyhow::bail!("Cannot save into type {}.", type_name),
+        current_transaction(&state)
+    };
+    let mut transaction = transaction.lock().await;
+    let write = query_engine.add_rows(&ty, &values, Some(transaction.deref_mut()));
+    traced(&state, "store many", &content.name, write).await
+}
+
+#[derive(Deserialize)]
+struct UpdateContent {
+    name: String,
+    id: String,
+    value: JsonObject,
+}
+
+/// Writes only the fields present in `content.value` to an existing object.  Returns false if
+/// there is no object with that id.
+#[op]
+async fn op_chisel_update(
+    state: Rc<RefCell<OpState>>,
+    content: UpdateContent,
+    c: ChiselRequestContext,
+) -> Result<bool> {
+    let (query_engine, ty, value) = {
+        let mut state = state.borrow_mut();
+        let ty = writable_type(&state, &content.name, &c)?;
+        note_write(&mut state, &ty);
+        let value = current_policies(&state).enforce_write_policies(
+            &c.user_id,
+            &c.path,
+            &c.method,
+            &ty,
+            &content.value,
+        )?;
+        let query_engine = query_engine_arc(&state);
+        (query_engine, ty, value)
+    };
+    let transaction = {
+        let state = state.borrow();
+        current_transaction(&state)
+    };
+    let mut transaction = transaction.lock().await;
+    let write = query_engine.update_row(&ty, &content.id, &value, Some(transaction.deref_mut()));
+    traced(&state, "update", &content.name, write).await
+}
+
+#[derive(Deserialize)]
+struct IncrementContent {
+    name: String,
+    id: String,
+    field: String,
+    delta: f64,
+}
+
+/// Adds `content.delta` to a number field of an existing object, atomically.  Returns the new
+/// value, or None if there is no object with that id.
+#[op]
+async fn op_chisel_increment(
+    state: Rc<RefCell<OpState>>,
+    content: IncrementContent,
+    c: ChiselRequestContext,
+) -> Result<Option<f64>> {
+    anyhow::ensure!(
+        content.delta.is_finite(),
+        "cannot increment field `{}` by {}",
+        content.field,
+        content.delta
+    );
+    let (query_engine, ty, delta) = {
+        let mut state = state.borrow_mut();
+        let ty = writable_type(&state, &content.name, &c)?;
+        note_write(&mut state, &ty);
+        let mut value = JsonObject::new();
+        value.insert(content.field.clone(), content.delta.into());
+        let value = current_policies(&state)
+            .enforce_write_policies(&c.user_id, &c.path, &c.method, &ty, &value)?;
+        // Like any other write, a stripped field is left as it is.
+        let delta = match value.get(&content.field) {
+            None => 0.0,
+            Some(delta) => delta
+                .as_f64()
+                .with_context(|| format!("cannot increment encrypted field `{}`", content.field))?,
         };
-        if ty.is_auth() && !is_auth_path(&c.api_version, &c.path) {
-            anyhow::bail!("Cannot save into type {}.", type_name);
-        }
+        let query_engine = query_engine_arc(&state);
+        (query_engine, ty, delta)
+    };
+    let transaction = {
+        let state = state.borrow();
+        current_transaction(&state)
+    };
+    let mut transaction = transaction.lock().await;
+    let write = query_engine.increment(
+        &ty,
+        &content.id,
+        &content.field,
+        delta,
+        Some(transaction.deref_mut()),
+    );
+    traced(&state, "increment", &content.name, write).await
+}
+
+#[derive(Deserialize)]
+struct UpsertContent {
+    name: String,
+    #[serde(rename = "conflictFields")]
+    conflict_fields: Vec<String>,
+    value: JsonObject,
+}
 
+/// Inserts `content.value`, or updates the object that has the same values for its conflict
+/// fields.  Returns the id of the written object.
+#[op]
+async fn op_chisel_upsert(
+    state: Rc<RefCell<OpState>>,
+    content: UpsertContent,
+    c: ChiselRequestContext,
+) -> Result<String> {
+    let (query_engine, ty, value) = {
+        let mut state = state.borrow_mut();
+        let ty = writable_type(&state, &content.name, &c)?;
+        note_write(&mut state, &ty);
+        let value = current_policies(&state).enforce_write_policies(
+            &c.user_id,
+            &c.path,
+            &c.method,
+            &ty,
+            &content.value,
+        )?;
         let query_engine = query_engine_arc(&state);
-        (query_engine, ty)
+        (query_engine, ty, value)
     };
     let transaction = {
         let state = state.borrow();
         current_transaction(&state)
     };
     let mut transaction = transaction.lock().await;
-    query_engine
-        .add_row(&ty, value, Some(transaction.deref_mut()))
-        .await
+    let write = query_engine.upsert_row(
+        &ty,
+        &content.conflict_fields,
+        &value,
+        Some(transaction.deref_mut()),
+    );
+    traced(&state, "upsert", &content.name, write).await
+}
+
+/// The types written in the current transaction, which are recorded as modified once it commits.
+#[derive(Default)]
+struct TransactionWrites {
+    types: Vec<Arc<ObjectType>>,
+    /// Whether a raw query, which could have written to any type, ran in the transaction.
+    raw: bool,
+}
+
+fn note_write(state: &mut OpState, ty: &Arc<ObjectType>) {
+    if let Some(writes) = state.try_borrow_mut::<TransactionWrites>() {
+        writes.types.push(ty.clone());
+    }
+}
+
+/// Looks up the type that `type_name` names for the endpoint making the request `c`, failing if
+/// it can't write into it.
+fn writable_type(
+    state: &OpState,
+    type_name: &str,
+    c: &ChiselRequestContext,
+) -> Result<Arc<ObjectType>> {
+    let ty = match current_type_system(state).lookup_type(type_name, &c.api_version) {
+        Ok(Type::Object(ty)) => ty,
+        _ => anyhow::bail!("Cannot save into type {}.", type_name),
+    };
+    if ty.is_auth() && !is_auth_path(&c.api_version, &c.path) {
+        anyhow::bail!("Cannot save into type {}.", type_name);
+    }
+    Ok(ty)
 }
 
 #[derive(Deserialize)]
@@ -594,6 +1160,7 @@ async fn op_chisel_entity_delete(
                 api_version: context.api_version,
                 user_id: context.user_id,
                 path: context.path,
+                method: context.method,
             },
             &params.type_name,
             &params.filter_expr,
@@ -603,7 +1170,68 @@ async fn op_chisel_entity_delete(
         )?
     };
     let query_engine = query_engine_arc(&state.borrow());
-    query_engine.mutate(mutation).await
+    traced(
+        &state,
+        "delete",
+        &params.type_name,
+        query_engine.mutate(mutation),
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct DeleteManyParams {
+    #[serde(rename = "typeName")]
+    type_name: String,
+    #[serde(rename = "filterExpr")]
+    filter_expr: Option<Expr>,
+    /// Whether to delete every entity of the type when there is no filter.
+    #[serde(default)]
+    all: bool,
+}
+
+/// Deletes the entities matching a filter, returning how many there were. Deleting without a filter is refused
+/// unless `all` is set, so that a filter that was lost on the way doesn't empty the whole table.
+#[op]
+async fn op_chisel_delete_many(
+    state: Rc<RefCell<OpState>>,
+    params: DeleteManyParams,
+    context: ChiselRequestContext,
+) -> Result<u64> {
+    if params.filter_expr.is_none() && !params.all {
+        return Err(Error::BadRequest(format!(
+            "deleteMany on type {} requires a filter, or `all: true` to delete every entity",
+            params.type_name
+        ))
+        .into());
+    }
+    let mutation = {
+        let state = state.borrow_mut();
+        Mutation::delete_from_expr(
+            &RequestContext {
+                policies: current_policies(&state),
+                ts: current_type_system(&state),
+                api_version: context.api_version,
+                user_id: context.user_id,
+                path: context.path,
+                method: context.method,
+            },
+            &params.type_name,
+            &params.filter_expr,
+        )
+        .context(
+            "failed to construct delete expression from JSON passed to `op_chisel_delete_many`",
+        )?
+    };
+    let query_engine = query_engine_arc(&state.borrow());
+    traced(
+        &state,
+        "delete",
+        &params.type_name,
+        query_engine.mutate(mutation),
+    )
+    .await
 }
 
 #[derive(Deserialize)]
@@ -628,6 +1256,7 @@ async fn op_chisel_crud_delete(
                 api_version: context.api_version,
                 user_id: context.user_id,
                 path: context.path,
+                method: context.method,
             },
             &params.type_name,
             &params.url,
@@ -640,89 +1269,516 @@ async fn op_chisel_crud_delete(
         let state = state.borrow();
         query_engine_arc(&state).clone()
     };
-    query_engine.mutate(mutation).await
+    traced(
+        &state,
+        "delete",
+        &params.type_name,
+        query_engine.mutate(mutation),
+    )
+    .await?;
+    Ok(())
+}
+
+type DbStream = RefCell<QueryResults>;
+
+struct QueryStreamResource {
+    stream: DbStream,
+    cancel: CancelHandle,
+    /// How to write the rows, for exported queries.
+    writer: Option<Rc<RowWriter>>,
+}
+
+/// How the rows of an exported query are written.
+enum RowWriter {
+    Csv(CsvWriter),
+    JsonArray(JsonArrayWriter),
+}
+
+impl Resource for QueryStreamResource {
+    fn close(self: Rc<Self>) {
+        self.cancel.cancel();
+    }
+}
+
+#[op]
+fn op_chisel_get_secret(op_state: &mut OpState, key: String) -> Result<Option<serde_json::Value>> {
+    let ret = if let Some(secrets) = current_secrets(op_state) {
+        secrets.get(&key).cloned()
+    } else {
+        None
+    };
+    Ok(ret)
+}
+
+#[op]
+fn op_chisel_config(op_state: &mut OpState, key: String) -> Option<serde_json::Value> {
+    op_state
+        .try_borrow::<Config>()
+        .and_then(|config| config.0.get(&key).cloned())
+}
+
+#[op]
+fn op_chisel_sign(op_state: &mut OpState, value: String) -> Result<String> {
+    let keys = signing::signing_keys(current_secrets(op_state));
+    signing::sign(&keys, &value)
+}
+
+#[op]
+fn op_chisel_verify(op_state: &mut OpState, signed: String) -> Option<String> {
+    let keys = signing::signing_keys(current_secrets(op_state));
+    signing::verify(&keys, &signed)
+}
+
+// Hashing is deliberately slow, so keep it off the thread running JavaScript.
+#[op]
+async fn op_chisel_hash_password(password: String) -> Result<String> {
+    tokio::task::spawn_blocking(move || passwords::hash(&password)).await?
+}
+
+#[op]
+async fn op_chisel_verify_password(password: String, hash: String) -> Result<bool> {
+    Ok(tokio::task::spawn_blocking(move || passwords::verify(&password, &hash)).await?)
+}
+
+/// Most random bytes that `op_chisel_random_bytes` returns at once, like `crypto.getRandomValues()`.
+const MAX_RANDOM_BYTES: usize = 65536;
+
+/// Random bytes from the operating system's generator, fit for keys and nonces.
+#[op]
+fn op_chisel_random_bytes(len: usize) -> Result<ZeroCopyBuf> {
+    anyhow::ensure!(
+        len <= MAX_RANDOM_BYTES,
+        "Cannot generate more than {} random bytes at once",
+        MAX_RANDOM_BYTES
+    );
+    let mut bytes = vec![0; len];
+    OsRng.fill_bytes(&mut bytes);
+    Ok(bytes.into())
+}
+
+/// 256 random bits from the operating system's generator, encoded as URL-safe base64 without padding.
+#[op]
+fn op_chisel_random_token() -> String {
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+#[op]
+fn op_chisel_base64_encode(data: ZeroCopyBuf, url_safe: bool) -> String {
+    encoding::base64_encode(&data, url_safe)
+}
+
+#[op]
+fn op_chisel_base64_decode(text: String, url_safe: bool) -> Result<ZeroCopyBuf> {
+    Ok(encoding::base64_decode(&text, url_safe)?.into())
+}
+
+#[op]
+fn op_chisel_hex_encode(data: ZeroCopyBuf) -> String {
+    encoding::hex_encode(&data)
+}
+
+#[op]
+fn op_chisel_hex_decode(text: String) -> Result<ZeroCopyBuf> {
+    Ok(encoding::hex_decode(&text)?.into())
+}
+
+/// The server time, in milliseconds since the epoch like stored dates.
+#[op]
+fn op_chisel_now() -> f64 {
+    dates::now()
+}
+
+#[op]
+fn op_chisel_cookies(op_state: &mut OpState) -> HashMap<String, String> {
+    op_state
+        .try_borrow::<RequestCookies>()
+        .map(|cookies| cookies.0.clone())
+        .unwrap_or_default()
+}
+
+#[op]
+fn op_chisel_request_route(op_state: &mut OpState) -> Option<RequestRoute> {
+    op_state.try_borrow::<RequestRoute>().cloned()
+}
+
+#[op]
+fn op_chisel_remote_addr(op_state: &mut OpState) -> Option<String> {
+    let addr = op_state.try_borrow::<RequestRemoteAddr>()?.0?;
+    Some(addr.to_string())
+}
+
+#[op]
+fn op_chisel_set_route(op_state: &mut OpState, route: String) {
+    if let Some(current) = op_state.try_borrow_mut::<RequestRoute>() {
+        current.route = route;
+    }
+}
+
+#[op]
+fn op_chisel_validate(
+    schema: serde_json::Value,
+    value: serde_json::Value,
+) -> Result<Vec<json_schema::ValidationError>> {
+    Ok(json_schema::compiled(&schema)?.validate(&value))
+}
+
+#[op]
+fn op_chisel_check_schema(schema: serde_json::Value) -> Result<()> {
+    json_schema::compiled(&schema)?;
+    Ok(())
+}
+
+#[op]
+async fn op_chisel_crud_query(
+    state: Rc<RefCell<OpState>>,
+    params: crud::QueryParams,
+    context: ChiselRequestContext,
+) -> Result<crud::QueryPage> {
+    let type_name = params.type_name().to_owned();
+    // Contextualize stream creation to prevent state RC borrow living across await
+    let query = {
+        let op_state = &state.borrow();
+        let transaction = current_transaction(op_state);
+        let query_engine = query_engine_arc(op_state);
+
+        crud::run_query(
+            &RequestContext {
+                policies: current_policies(op_state),
+                ts: current_type_system(op_state),
+                api_version: context.api_version,
+                user_id: context.user_id,
+                path: context.path,
+                method: context.method,
+            },
+            params,
+            query_engine,
+            transaction,
+        )
+    };
+    traced(&state, "query", &type_name, query).await
+}
+
+#[op]
+fn op_chisel_relational_query_create(
+    op_state: &mut OpState,
+    op_chain: QueryOpChain,
+    context: ChiselRequestContext,
+) -> Result<CreatedQuery> {
+    let query_plan = QueryPlan::from_op_chain(
+        &RequestContext {
+            policies: current_policies(op_state),
+            ts: current_type_system(op_state),
+            api_version: context.api_version,
+            user_id: context.user_id,
+            path: context.path,
+            method: context.method,
+        },
+        op_chain,
+    )?;
+    create_query(op_state, query_plan)
+}
+
+/// Like op_chisel_relational_query_create, but takes the whole query at once.
+#[op]
+fn op_chisel_query_create(
+    op_state: &mut OpState,
+    spec: QuerySpec,
+    context: ChiselRequestContext,
+) -> Result<CreatedQuery> {
+    let query_plan = QueryPlan::from_query_spec(
+        &RequestContext {
+            policies: current_policies(op_state),
+            ts: current_type_system(op_state),
+            api_version: context.api_version,
+            user_id: context.user_id,
+            path: context.path,
+            method: context.method,
+        },
+        spec,
+    )?;
+    create_query(op_state, query_plan)
+}
+
+/// Like op_chisel_query_create, but runs the named query `name` of the current version, with
+/// `params` as the values of its parameters.
+#[op]
+fn op_chisel_run_named_query(
+    op_state: &mut OpState,
+    name: String,
+    params: JsonObject,
+    context: ChiselRequestContext,
+) -> Result<CreatedQuery> {
+    let spec = op_state
+        .borrow::<NamedQueries>()
+        .get(&context.api_version, &name)?
+        .bind(&params)
+        .with_context(|| format!("running named query '{}'", name))?;
+    let query_plan = QueryPlan::from_query_spec(
+        &RequestContext {
+            policies: current_policies(op_state),
+            ts: current_type_system(op_state),
+            api_version: context.api_version,
+            user_id: context.user_id,
+            path: context.path,
+            method: context.method,
+        },
+        spec,
+    )?;
+    create_query(op_state, query_plan)
+}
+
+/// A query stream, along with the shape of the entities it returns.
+#[derive(Serialize)]
+struct CreatedQuery {
+    rid: ResourceId,
+    shape: EntityShape,
+}
+
+fn create_query(op_state: &mut OpState, query_plan: QueryPlan) -> Result<CreatedQuery> {
+    let shape = query_plan.entity_shape();
+    let transaction = current_transaction(op_state);
+    let query_engine = query_engine_arc(op_state);
+    let span = start_data_span(op_state, "query", query_plan.type_name());
+    let stream = query_engine.query(transaction, query_plan)?;
+    let rid = add_query_stream(op_state, span, Box::pin(stream), None);
+    Ok(CreatedQuery { rid, shape })
+}
+
+fn add_query_stream(
+    op_state: &mut OpState,
+    span: SpanGuard,
+    stream: QueryResults,
+    writer: Option<RowWriter>,
+) -> ResourceId {
+    // The query goes on for as long as its results are being read.
+    let stream = Box::pin(stream.map(move |row| {
+        let _ = &span;
+        row
+    }));
+    let resource = QueryStreamResource {
+        stream: RefCell::new(stream),
+        cancel: Default::default(),
+        writer: writer.map(Rc::new),
+    };
+    op_state.resource_table.add(resource)
 }
 
-type DbStream = RefCell<QueryResults>;
-
-struct QueryStreamResource {
-    stream: DbStream,
-    cancel: CancelHandle,
+/// A CRUD query exported as CSV: the stream of its rows, along with the header line.
+#[derive(Serialize)]
+struct CreatedCsvQuery {
+    rid: ResourceId,
+    header: String,
 }
 
-impl Resource for QueryStreamResource {
-    fn close(self: Rc<Self>) {
-        self.cancel.cancel();
-    }
+/// Like op_chisel_crud_query, but streams all the results, to be read as CSV lines with
+/// op_chisel_query_next_csv.
+#[op]
+fn op_chisel_crud_query_csv(
+    op_state: &mut OpState,
+    params: crud::QueryParams,
+    context: ChiselRequestContext,
+) -> Result<CreatedCsvQuery> {
+    let ty = current_type_system(op_state)
+        .lookup_object_type(params.type_name(), &context.api_version)
+        .context("unexpected type name as crud query base type")?;
+    let csv = CsvWriter::new(&ty);
+    let header = csv.header();
+    let rid = export_crud_query(op_state, params, context, RowWriter::Csv(csv))?;
+    Ok(CreatedCsvQuery { rid, header })
+}
+
+/// Like op_chisel_crud_query_csv, but the results are read as the pieces of a JSON array with
+/// op_chisel_query_next_json.
+#[op]
+fn op_chisel_crud_query_json(
+    op_state: &mut OpState,
+    params: crud::QueryParams,
+    context: ChiselRequestContext,
+) -> Result<ResourceId> {
+    let writer = RowWriter::JsonArray(JsonArrayWriter::new());
+    export_crud_query(op_state, params, context, writer)
 }
 
-#[op]
-fn op_chisel_get_secret(op_state: &mut OpState, key: String) -> Result<Option<serde_json::Value>> {
-    let ret = if let Some(secrets) = current_secrets(op_state) {
-        secrets.get(&key).cloned()
-    } else {
-        None
-    };
-    Ok(ret)
+/// Starts the CRUD query of `params`, streaming all of its results, which are written by `writer`.
+fn export_crud_query(
+    op_state: &mut OpState,
+    params: crud::QueryParams,
+    context: ChiselRequestContext,
+    writer: RowWriter,
+) -> Result<ResourceId> {
+    let transaction = current_transaction(op_state);
+    let query_engine = query_engine_arc(op_state);
+    let span = start_data_span(op_state, "query", params.type_name());
+    let stream = crud::run_query_stream(
+        &RequestContext {
+            policies: current_policies(op_state),
+            ts: current_type_system(op_state),
+            api_version: context.api_version,
+            user_id: context.user_id,
+            path: context.path,
+            method: context.method,
+        },
+        params,
+        query_engine,
+        transaction,
+    )?;
+    Ok(add_query_stream(op_state, span, stream, Some(writer)))
 }
 
 #[op]
-async fn op_chisel_crud_query(
+async fn op_chisel_relational_query_page(
     state: Rc<RefCell<OpState>>,
-    params: crud::QueryParams,
+    op_chain: QueryOpChain,
     context: ChiselRequestContext,
-) -> Result<Vec<JsonObject>> {
+    size: u64,
+    token: Option<String>,
+) -> Result<crud::QueryPage> {
+    let type_name = op_chain.type_name().to_owned();
     // Contextualize stream creation to prevent state RC borrow living across await
-    {
+    let query = {
         let op_state = &state.borrow();
         let transaction = current_transaction(op_state);
         let query_engine = query_engine_arc(op_state);
 
-        crud::run_query(
+        crud::run_op_chain_page(
             &RequestContext {
                 policies: current_policies(op_state),
                 ts: current_type_system(op_state),
                 api_version: context.api_version,
                 user_id: context.user_id,
                 path: context.path,
+                method: context.method,
             },
-            params,
+            op_chain,
+            size,
+            token,
             query_engine,
             transaction,
         )
+    };
+    traced(&state, "query", &type_name, query).await
+}
+
+lazy_static! {
+    /// A type name in braces, like `{Person}`, which raw queries use to refer to the table of a type.
+    static ref RAW_QUERY_TYPE: regex::Regex = regex::Regex::new(r"\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+}
+
+/// Replaces the type names in the SQL of a raw query with the backing tables of those types.  Returns the SQL
+/// and the names of the types it refers to.
+fn resolve_raw_query_types(
+    ts: &TypeSystem,
+    api_version: &str,
+    sql: &str,
+) -> Result<(String, Vec<String>)> {
+    let mut types = vec![];
+    let mut resolved = String::with_capacity(sql.len());
+    let mut last = 0;
+    for caps in RAW_QUERY_TYPE.captures_iter(sql) {
+        let (whole, name) = (caps.get(0).unwrap(), &caps[1]);
+        let ty = ts.lookup_object_type(name, api_version)?;
+        resolved.push_str(&sql[last..whole.start()]);
+        resolved.push_str(&format!("\"{}\"", ty.backing_table()));
+        last = whole.end();
+        types.push(name.to_owned());
     }
-    .await
+    resolved.push_str(&sql[last..]);
+    Ok((resolved, types))
 }
 
+/// When the objects that reading `type_name` returns last changed, in milliseconds since the epoch, truncated to
+/// whole seconds like HTTP dates are.  None if they changed within the current second, since a later change in
+/// the same second would look no newer.
 #[op]
-fn op_chisel_relational_query_create(
+fn op_chisel_last_modified(
     op_state: &mut OpState,
-    op_chain: QueryOpChain,
-    context: ChiselRequestContext,
-) -> Result<ResourceId> {
-    let query_plan = QueryPlan::from_op_chain(
-        &RequestContext {
-            policies: current_policies(op_state),
-            ts: current_type_system(op_state),
-            api_version: context.api_version,
-            user_id: context.user_id,
-            path: context.path,
-        },
-        op_chain,
-    )?;
-    create_query(op_state, query_plan)
+    type_name: String,
+    c: ChiselRequestContext,
+) -> Result<Option<f64>> {
+    let ty = current_type_system(op_state).lookup_object_type(&type_name, &c.api_version)?;
+    let last_modified = query_engine_arc(op_state)
+        .modification_log()
+        .last_modified(&ty);
+    let seconds = |ms: f64| (ms / 1000.0).floor();
+    if seconds(last_modified) >= seconds(dates::now()) {
+        return Ok(None);
+    }
+    Ok(Some(seconds(last_modified) * 1000.0))
 }
 
-fn create_query(op_state: &mut OpState, query_plan: QueryPlan) -> Result<ResourceId> {
-    let transaction = current_transaction(op_state);
-    let query_engine = query_engine_arc(op_state);
-    let stream = query_engine.query(transaction, query_plan)?;
-    let resource = QueryStreamResource {
-        stream: RefCell::new(stream),
-        cancel: Default::default(),
+/// Describes the type `type_name` of the endpoint's API version, like `/__schema` does.
+#[op]
+fn op_chisel_type_info(
+    op_state: &mut OpState,
+    type_name: String,
+    c: ChiselRequestContext,
+) -> Result<serde_json::Value> {
+    let ty = current_type_system(op_state).lookup_object_type(&type_name, &c.api_version)?;
+    Ok(introspect::type_json(&ty))
+}
+
+/// Whether `username`, or an anonymous user if None, may execute the endpoint at `path` of the
+/// endpoint's API version, as decided for real requests.
+#[op]
+fn op_chisel_is_allowed(
+    op_state: &mut OpState,
+    path: String,
+    username: Option<String>,
+    c: ChiselRequestContext,
+) -> Result<bool> {
+    anyhow::ensure!(path.starts_with('/'), "path '{}' must start with /", path);
+    is_allowed_by_policy(
+        op_state,
+        &c.api_version,
+        username,
+        std::path::Path::new(&path),
+    )
+}
+
+#[derive(Deserialize)]
+struct RawQueryContent {
+    sql: String,
+    params: Vec<serde_json::Value>,
+}
+
+/// Runs SQL written by the endpoint, for what the query API can't express.  Only endpoints whose policy allows
+/// raw queries may run them, as field policies can't be applied to their results.
+#[op]
+async fn op_chisel_raw_query(
+    state: Rc<RefCell<OpState>>,
+    content: RawQueryContent,
+    c: ChiselRequestContext,
+) -> Result<Vec<JsonObject>> {
+    let (query_engine, transaction, sql, types) = {
+        let mut state = state.borrow_mut();
+        let allowed = current_policies(&state)
+            .versions
+            .get(&c.api_version)
+            .map_or(false, |v| {
+                v.allows_raw_queries(std::path::Path::new(&c.path))
+            });
+        if !allowed {
+            anyhow::bail!(
+                "Endpoint {} may not run raw queries: its policy doesn't allow them",
+                c.path
+            );
+        }
+        let (sql, types) =
+            resolve_raw_query_types(current_type_system(&state), &c.api_version, &content.sql)?;
+        if let Some(writes) = state.try_borrow_mut::<TransactionWrites>() {
+            writes.raw = true;
+        }
+        (
+            query_engine_arc(&state),
+            current_transaction(&state),
+            sql,
+            types,
+        )
     };
-    let rid = op_state.resource_table.add(resource);
-    Ok(rid)
+    let query = query_engine.raw_query(transaction, sql, content.params);
+    traced(&state, "raw query", &types.join(","), query).await
 }
 
 // A future that resolves when this stream next element is available.
@@ -748,6 +1804,53 @@ impl Future for QueryNextFuture {
 async fn op_chisel_query_next(
     state: Rc<RefCell<OpState>>,
     query_stream_rid: ResourceId,
+) -> Result<Option<ResultRow>> {
+    query_next(&state, query_stream_rid).await
+}
+
+/// Like op_chisel_query_next, but returns the row as a CSV line, for queries created by
+/// op_chisel_crud_query_csv.
+#[op]
+async fn op_chisel_query_next_csv(
+    state: Rc<RefCell<OpState>>,
+    query_stream_rid: ResourceId,
+) -> Result<Option<String>> {
+    let writer = export_writer(&state, query_stream_rid)?;
+    let csv = match &*writer {
+        RowWriter::Csv(csv) => csv,
+        _ => anyhow::bail!("query is not a CSV export"),
+    };
+    let row = query_next(&state, query_stream_rid).await?;
+    Ok(row.map(|row| csv.row(&row)))
+}
+
+/// Like op_chisel_query_next, but returns the row as the next piece of a JSON array, for queries
+/// created by op_chisel_crud_query_json. Once the rows run out, it returns the end of the array,
+/// and then None. A query that fails leaves the array unfinished.
+#[op]
+async fn op_chisel_query_next_json(
+    state: Rc<RefCell<OpState>>,
+    query_stream_rid: ResourceId,
+) -> Result<Option<String>> {
+    let writer = export_writer(&state, query_stream_rid)?;
+    let json = match &*writer {
+        RowWriter::JsonArray(json) => json,
+        _ => anyhow::bail!("query is not a JSON export"),
+    };
+    match query_next(&state, query_stream_rid).await? {
+        Some(row) => Ok(Some(json.row(&row)?)),
+        None => Ok(json.end()),
+    }
+}
+
+fn export_writer(state: &RefCell<OpState>, query_stream_rid: ResourceId) -> Result<Rc<RowWriter>> {
+    let rc: Rc<QueryStreamResource> = state.borrow().resource_table.get(query_stream_rid)?;
+    rc.writer.clone().context("query is not an export")
+}
+
+async fn query_next(
+    state: &RefCell<OpState>,
+    query_stream_rid: ResourceId,
 ) -> Result<Option<ResultRow>> {
     let (resource, cancel) = {
         let rc: Rc<QueryStreamResource> = state.borrow().resource_table.get(query_stream_rid)?;
@@ -755,11 +1858,20 @@ async fn op_chisel_query_next(
         (Rc::downgrade(&rc), cancel)
     };
     let fut = QueryNextFuture { resource };
-    let fut = fut.or_cancel(cancel);
-    if let Some(row) = fut.await? {
-        Ok(Some(row?))
-    } else {
-        Ok(None)
+    let fut = async move { Ok::<_, anyhow::Error>(fut.or_cancel(cancel).await?) };
+    match before_deadline(state, fut).await {
+        Ok(Some(row)) => Ok(Some(row?)),
+        Ok(None) => Ok(None),
+        Err(err) => {
+            // Dropping the stream cancels its query, rather than leaving it to run until the
+            // endpoint closes the stream.
+            state
+                .borrow_mut()
+                .resource_table
+                .close(query_stream_rid)
+                .ok();
+            Err(err)
+        }
     }
 }
 
@@ -770,32 +1882,88 @@ fn op_format_file_name(file_name: String) -> Result<String> {
 }
 
 #[op]
-fn op_chisel_init_worker(id: u32) {
+fn op_chisel_init_worker(
+    op_state: &mut OpState,
+    id: u32,
+    body_read_timeout_ms: Option<u64>,
+    query_timeout_ms: Option<u64>,
+) {
     let mut map = GLOBAL_WORKER_CHANNELS.lock().unwrap();
     let channel = map.remove(id as usize).unwrap();
     WORKER_CHANNEL.with(|d| {
         d.set(channel).unwrap();
     });
+    op_state.put(BodyReadTimeout(
+        body_read_timeout_ms.map(Duration::from_millis),
+    ));
+    op_state.put(QueryTimeout(query_timeout_ms.map(Duration::from_millis)));
+}
+
+/// How long request bodies may take to arrive, counting from the start of the request. This is
+/// separate from how long endpoints may run, as it only bounds the wait on slow clients.
+struct BodyReadTimeout(Option<Duration>);
+
+/// How long the database queries of a request, or of a background task, may run for, counting
+/// from the start of its transaction.
+struct QueryTimeout(Option<Duration>);
+
+/// When the queries of the current transaction must be done by, if ever.
+struct QueryDeadline(Option<Instant>);
+
+/// Runs `fut`, which queries the database for the current transaction, failing it with
+/// `Error::QueryDeadline` if it's still running when the deadline of the transaction passes.
+/// Dropping the future cancels the query, and frees its connection for other requests.
+async fn before_deadline<T>(
+    state: &RefCell<OpState>,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let deadline = state
+        .borrow()
+        .try_borrow::<QueryDeadline>()
+        .and_then(|d| d.0);
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return fut.await,
+    };
+    match tokio::time::timeout_at(deadline, fut).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(err)) if Instant::now() < deadline => Err(err),
+        // Postgres cancels statements at the deadline too, failing them with an error of its own.
+        _ => Err(Error::QueryDeadline.into()),
+    }
+}
+
+/// Adds a queued job for a task queued with enqueueJob() by `owner`, returning its id.
+#[op]
+fn op_chisel_create_job(op_state: &mut OpState, owner: Option<String>) -> Result<String> {
+    Ok(op_state.borrow::<JobStore>().create(owner))
+}
+
+#[op]
+fn op_chisel_set_job_status(op_state: &mut OpState, id: String, status: JobStatus) -> Result<()> {
+    op_state.borrow::<JobStore>().set(&id, status)
 }
 
 #[op]
 async fn op_chisel_read_worker_channel(state: Rc<RefCell<OpState>>) -> Result<()> {
-    let receiver = WORKER_CHANNEL.with(|d| d.get().unwrap().clone());
+    let receiver = WORKER_CHANNEL.with(|d| d.get().unwrap().state.clone());
     let msg = receiver.recv().await.unwrap();
 
     let mut state = state.borrow_mut();
     let state = &mut state;
     match msg {
         WorkerMsg::SetMeta(meta) => state.put::<Rc<MetaService>>(Rc::new(meta)),
-        WorkerMsg::HandleRequest(_req) => unreachable!("Wrong message"),
         WorkerMsg::SetTypeSystem(type_system) => state.put(type_system),
         WorkerMsg::RemoveTypeVersion(version) => {
             state.borrow_mut::<TypeSystem>().versions.remove(&version);
         }
         WorkerMsg::SetQueryEngine(query_engine) => state.put(query_engine),
-        WorkerMsg::SetPolicies(policies) => state.put(policies),
-        WorkerMsg::MutatePolicies(func) => func(state.borrow_mut()),
+        // Requests that hold a snapshot of the policies keep it unchanged.
+        WorkerMsg::SetPolicies(policies) => state.put(Rc::new(policies)),
         WorkerMsg::SetCurrentSecrets(secretes) => state.put(secretes),
+        WorkerMsg::SetConfig(config) => state.put(Config(config)),
+        WorkerMsg::SetNamedQueries(queries) => state.put(queries),
+        WorkerMsg::SetJobs(jobs) => state.put(jobs),
     }
 
     Ok(())
@@ -833,8 +2001,35 @@ extern "C" fn promise_reject_callback(message: v8::PromiseRejectMessage) {
     }
 }
 
-pub(crate) async fn init_deno(inspect_brk: bool) -> Result<()> {
-    let (service, init_worker) = DenoService::new(inspect_brk).await;
+/// `timeout` in milliseconds as a JavaScript number, or undefined if there is none.
+fn millis_value<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    timeout: Option<Duration>,
+) -> v8::Local<'s, v8::Value> {
+    match timeout {
+        Some(t) => v8::Number::new(scope, t.as_millis() as f64).into(),
+        None => v8::undefined(scope).into(),
+    }
+}
+
+pub(crate) async fn init_deno(
+    inspect_brk: bool,
+    max_concurrent_requests: usize,
+    coerce_responses: bool,
+    body_read_timeout: Option<Duration>,
+    query_timeout: Option<Duration>,
+    response_prefetch_chunks: usize,
+    denied_headers: DeniedHeaders,
+    header_limits: ResponseHeaderLimits,
+) -> Result<()> {
+    let (service, init_worker) = DenoService::new(
+        inspect_brk,
+        max_concurrent_requests,
+        response_prefetch_chunks,
+        denied_headers,
+        header_limits,
+    )
+    .await;
     DENO.with(|d| {
         d.set(Rc::new(RefCell::new(service)))
             .map_err(|_| ())
@@ -848,9 +2043,23 @@ pub(crate) async fn init_deno(inspect_brk: bool) -> Result<()> {
     scope.set_promise_reject_callback(promise_reject_callback);
     let undefined = v8::undefined(scope).into();
     let id = v8::Number::new(scope, service.worker_channel_id as f64).into();
+    let task_worker_id = v8::Number::new(scope, service.task_worker_channel_id as f64).into();
+    let coerce_responses = v8::Boolean::new(scope, coerce_responses).into();
+    let body_read_timeout = millis_value(scope, body_read_timeout);
+    let query_timeout = millis_value(scope, query_timeout);
     init_worker
         .open(scope)
-        .call(scope, undefined, &[id])
+        .call(
+            scope,
+            undefined,
+            &[
+                id,
+                task_worker_id,
+                coerce_responses,
+                body_read_timeout,
+                query_timeout,
+            ],
+        )
         .unwrap();
     Ok(())
 }
@@ -962,7 +2171,8 @@ async fn resolve_promise(js_promise: v8::Global<v8::Value>) -> Result<v8::Global
     }
     let key = v8::String::new(scope, "error").unwrap().into();
     assert!(obj.has(scope, key).unwrap());
-    anyhow::bail!(obj.get(scope, key).unwrap().to_rust_string_lossy(scope));
+    let exception = obj.get(scope, key).unwrap();
+    Err(JsException::new(scope, exception).into())
 }
 
 type ReadFutureState = v8::Global<v8::Function>;
@@ -1014,6 +2224,10 @@ fn get_read_stream(
 struct BodyResource {
     body: RefCell<hyper::Body>,
     cancel: CancelHandle,
+    /// When the whole body must have arrived by, if there is a limit.
+    deadline: Option<Instant>,
+    // Set once the body starts being read as multipart/form-data.
+    multipart: RefCell<Option<multipart::Parser>>,
 }
 
 impl Resource for BodyResource {
@@ -1022,8 +2236,15 @@ impl Resource for BodyResource {
     }
 }
 
+/// The policies that the current request started with, which it keeps even if they are replaced
+/// before it finishes.  Outside of requests, the latest policies.
+struct RequestPolicies(Rc<Policies>);
+
 fn current_policies(st: &OpState) -> &Policies {
-    st.borrow()
+    match st.try_borrow::<RequestPolicies>() {
+        Some(snapshot) => &snapshot.0,
+        None => st.borrow::<Rc<Policies>>(),
+    }
 }
 
 fn is_allowed_by_policy(
@@ -1032,29 +2253,23 @@ fn is_allowed_by_policy(
     username: Option<String>,
     path: &std::path::Path,
 ) -> Result<bool> {
-    let policies = current_policies(state);
-    match policies.versions.get(api_version) {
-        None => Err(anyhow!(
-            "found a route, but no version object for {}/{}",
-            api_version,
-            path.display()
-        )),
-        Some(x) => Ok(x.user_authorization.is_allowed(username, path)),
-    }
-}
-
-async fn mutate_policies_impl(func: Box<dyn FnOnce(&mut Policies) + Send>) {
-    to_worker(WorkerMsg::MutatePolicies(func)).await;
+    current_policies(state).is_allowed(api_version, username, path)
 }
 
 pub(crate) async fn mutate_policies<F>(func: F)
 where
     F: FnOnce(&mut Policies) + Send + 'static,
 {
-    mutate_policies_impl(Box::new(func)).await;
+    let policies = {
+        let mut runtime = runtime::get();
+        func(&mut runtime.policies);
+        runtime.policies.clone()
+    };
+    to_worker(WorkerMsg::SetPolicies(policies)).await;
 }
 
 pub(crate) async fn set_policies(policies: Policies) {
+    runtime::get().policies = policies.clone();
     to_worker(WorkerMsg::SetPolicies(policies)).await;
 }
 
@@ -1069,12 +2284,62 @@ fn current_transaction(st: &OpState) -> TransactionStatic {
 fn set_current_transaction(st: &mut OpState, transaction: TransactionStatic) {
     assert!(!st.has::<TransactionStatic>());
     st.put(transaction);
+    st.put(TransactionWrites::default());
 }
 
 fn current_secrets(st: &OpState) -> Option<&JsonObject> {
     st.try_borrow()
 }
 
+/// Runtime configuration, which unlike secrets is not sensitive and can be changed at any time.
+struct Config(JsonObject);
+
+/// Cookies sent with the request being handled.
+struct RequestCookies(HashMap<String, String>);
+
+/// Trace context of the request being handled, which its data accesses are traced in.
+struct RequestTrace(opentelemetry::Context);
+
+/// Address of the client of the request being handled, if known.
+struct RequestRemoteAddr(Option<IpAddr>);
+
+/// Starts a span of the request being handled, for an access to the data of `type_name`.
+fn start_data_span(st: &OpState, name: &'static str, type_name: &str) -> SpanGuard {
+    let trace = st
+        .try_borrow::<RequestTrace>()
+        .map(|trace| trace.0.clone())
+        .unwrap_or_default();
+    telemetry::start_child(
+        &trace,
+        name,
+        vec![KeyValue::new("chisel.type", type_name.to_owned())],
+    )
+}
+
+/// Runs `fut` in a span started with `start_data_span`, recording whether it failed.
+async fn traced<T>(
+    state: &RefCell<OpState>,
+    name: &'static str,
+    type_name: &str,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let span = start_data_span(&state.borrow(), name, type_name);
+    let result = before_deadline(state, fut).await;
+    if let Err(err) = &result {
+        span.set_error(err);
+    }
+    result
+}
+
+/// Path of the request being handled, both as requested and as the route template that matched it, like
+/// `/dev/posts/123` and `/dev/posts/:id`.  Templates have a fixed number of values, so they are what to label
+/// aggregated data with.
+#[derive(Clone, Serialize)]
+struct RequestRoute {
+    path: String,
+    route: String,
+}
+
 fn current_type_system(st: &OpState) -> &TypeSystem {
     st.borrow()
 }
@@ -1100,12 +2365,17 @@ pub(crate) fn lookup_builtin_type(
 }
 
 pub(crate) async fn remove_type_version(version: &str) {
+    runtime::get().type_system.versions.remove(version);
     to_worker(WorkerMsg::RemoveTypeVersion(version.to_string())).await;
 }
 
 async fn to_worker(msg: WorkerMsg) {
     let promise = {
-        let sender = get().to_worker.clone();
+        let (sender, task_sender) = {
+            let service = get();
+            (service.to_worker.clone(), service.to_task_worker.clone())
+        };
+        task_sender.send(msg.clone()).await.unwrap();
         sender.send(msg).await.unwrap();
         let mut service = get();
         let service: &mut DenoService = &mut service;
@@ -1123,26 +2393,54 @@ async fn to_worker(msg: WorkerMsg) {
 }
 
 pub(crate) async fn set_type_system(type_system: TypeSystem) {
+    runtime::get().type_system = type_system.clone();
     to_worker(WorkerMsg::SetTypeSystem(type_system)).await;
 }
 
 pub(crate) async fn update_secrets(secrets: JsonObject) {
+    encryption::set_keys(&secrets);
+    runtime::get().secrets = Some(secrets.clone());
     to_worker(WorkerMsg::SetCurrentSecrets(secrets.clone())).await;
 }
 
+pub(crate) async fn set_config(config: JsonObject) {
+    to_worker(WorkerMsg::SetConfig(config)).await;
+}
+
+pub(crate) async fn set_named_queries(queries: NamedQueries) {
+    to_worker(WorkerMsg::SetNamedQueries(queries)).await;
+}
+
+pub(crate) async fn set_jobs(jobs: JobStore) {
+    to_worker(WorkerMsg::SetJobs(jobs)).await;
+}
+
 #[op]
 async fn op_chisel_commit_transaction(state: Rc<RefCell<OpState>>) -> Result<()> {
     let transaction = {
         let mut state = state.borrow_mut();
+        state.try_take::<RequestPolicies>();
         take_current_transaction(&mut state)
     };
     crate::datastore::QueryEngine::commit_transaction_static(transaction).await?;
+    let mut state = state.borrow_mut();
+    if let Some(writes) = state.try_take::<TransactionWrites>() {
+        let log = query_engine_arc(&state).modification_log().clone();
+        if writes.raw {
+            log.record_all();
+        }
+        for ty in &writes.types {
+            log.record(ty);
+        }
+    }
     Ok(())
 }
 
 #[op]
 fn op_chisel_rollback_transaction(state: &mut OpState) -> Result<()> {
     let transaction = take_current_transaction(state);
+    state.try_take::<TransactionWrites>();
+    state.try_take::<RequestPolicies>();
     // Check that this is the last reference to the transaction.
     let transaction = extract_transaction(transaction);
     // Drop the transaction, causing it to rollback.
@@ -1153,8 +2451,14 @@ fn op_chisel_rollback_transaction(state: &mut OpState) -> Result<()> {
 #[op]
 async fn op_chisel_create_transaction(state: Rc<RefCell<OpState>>) -> Result<()> {
     let qe = query_engine_arc(&state.borrow());
-    let transaction = qe.start_transaction_static().await?;
-    set_current_transaction(&mut state.borrow_mut(), transaction);
+    let timeout = state.borrow().borrow::<QueryTimeout>().0;
+    let transaction = qe.clone().start_transaction_static().await?;
+    if let Some(timeout) = timeout {
+        qe.set_statement_timeout(&transaction, timeout).await?;
+    }
+    let mut state = state.borrow_mut();
+    state.put(QueryDeadline(timeout.map(|t| Instant::now() + t)));
+    set_current_transaction(&mut state, transaction);
     Ok(())
 }
 
@@ -1182,11 +2486,36 @@ impl Drop for RequestHandler {
     }
 }
 
+/// What clients that are turned away because of too many concurrent requests are told to wait.
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Counts a request as in flight for as long as it lives.
+struct RequestPermit(Rc<Cell<usize>>);
+
+impl RequestPermit {
+    fn try_acquire() -> Option<Self> {
+        let service = get();
+        let in_flight = &service.requests_in_flight;
+        if in_flight.get() >= service.max_concurrent_requests {
+            return None;
+        }
+        in_flight.set(in_flight.get() + 1);
+        Some(RequestPermit(in_flight.clone()))
+    }
+}
+
+impl Drop for RequestPermit {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
 #[pin_project]
 struct EndReqStream<S> {
     #[pin]
     inner: S,
     req: RequestHandler,
+    _permit: RequestPermit,
 }
 
 impl<S> Stream for EndReqStream<S>
@@ -1200,6 +2529,23 @@ where
     }
 }
 
+/// Checks the ChiselAuth header of a request for an internal route against the CHISELD_AUTH_SECRET secret.
+/// Returns the response rejecting the request, or None if it may proceed.
+pub(crate) fn check_chisel_auth(
+    secrets: Option<&JsonObject>,
+    req: &Request<hyper::Body>,
+) -> Result<Option<Response<Body>>> {
+    let auth_header = req.headers().get("ChiselAuth");
+    let expected_secret = secrets.and_then(|sec| sec.get("CHISELD_AUTH_SECRET"));
+    match (expected_secret, auth_header) {
+        (Some(_), None) => Ok(Some(ApiService::forbidden("ChiselAuth")?)),
+        (Some(serde_json::Value::String(s)), Some(h)) if *s != *h => {
+            Ok(Some(ApiService::forbidden("Fundamental auth")?))
+        }
+        _ => Ok(None),
+    }
+}
+
 // FIXME: It would probably be cleaner to move more of this to
 // javascript.
 async fn special_response(
@@ -1209,20 +2555,14 @@ async fn special_response(
 ) -> Result<Option<Response<Body>>> {
     let req_path = req.uri().path();
     // TODO: Make this optional, for users who want to reject some OPTIONS requests.
-    if req.method() == "OPTIONS" {
-        // Makes CORS preflights pass.
+    if req.method() == "OPTIONS" && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD) {
+        // Makes CORS preflights pass. Other OPTIONS requests go to the endpoint, which can tell
+        // what methods it allows.
         return Ok(Some(Response::builder().body("ok".to_string().into())?));
     }
     if req_path.starts_with("/__chiselstrike/auth/") {
-        let auth_header = req.headers().get("ChiselAuth");
-        let expected_secret = current_secrets(&state.borrow())
-            .and_then(|sec| sec.get("CHISELD_AUTH_SECRET").cloned());
-        match (expected_secret, auth_header) {
-            (Some(_), None) => return Ok(Some(ApiService::forbidden("ChiselAuth")?)),
-            (Some(serde_json::Value::String(s)), Some(h)) if s != *h => {
-                return Ok(Some(ApiService::forbidden("Fundamental auth")?))
-            }
-            _ => (),
+        if let Some(rejection) = check_chisel_auth(current_secrets(&state.borrow()), req)? {
+            return Ok(Some(rejection));
         }
     } else {
         let username = get_username_from_id(state.clone(), userid.clone()).await;
@@ -1266,6 +2606,12 @@ async fn convert_response(mut res: Response<Body>) -> Result<ResponseParts> {
 }
 
 pub(crate) async fn run_js(path: String, req: Request<hyper::Body>) -> Result<Response<Body>> {
+    // Shed load instead of queueing requests that we would take too long to get to.
+    let permit = match RequestPermit::try_acquire() {
+        Some(permit) => permit,
+        None => return ApiService::service_unavailable(RETRY_AFTER_SECS),
+    };
+
     thread_local! {
         static NEXT_REQUEST_ID: Cell<u32> = Cell::new(0);
     }
@@ -1276,6 +2622,16 @@ pub(crate) async fn run_js(path: String, req: Request<hyper::Body>) -> Result<Re
         v
     });
     let request_handler = RequestHandler { id };
+    let is_head = req.method() == Method::HEAD;
+    let is_get = req.method() == Method::GET;
+    // Whether the response still matches what the client has can't be told, so with an If-Range
+    // the whole response is sent, as if there were no Range.
+    let range = req
+        .headers()
+        .get(RANGE)
+        .filter(|_| !req.headers().contains_key(IF_RANGE))
+        .and_then(|range| range.to_str().ok())
+        .map(str::to_owned);
 
     {
         let mut service = get();
@@ -1287,8 +2643,8 @@ pub(crate) async fn run_js(path: String, req: Request<hyper::Body>) -> Result<Re
         }
     }
 
-    let sender = get().to_worker.clone();
-    sender.send(WorkerMsg::HandleRequest(req)).await.unwrap();
+    let sender = get().requests.clone();
+    sender.send(req).await.unwrap();
 
     let result = {
         let mut service = get();
@@ -1309,7 +2665,7 @@ pub(crate) async fn run_js(path: String, req: Request<hyper::Body>) -> Result<Re
     };
     let result = resolve_promise(result).await?;
 
-    let body = {
+    let (builder, mut stream, has_body, prefetch_chunks) = {
         // The rust borrow checker can track fields independently, but
         // only in very simple cases. For example,
         //
@@ -1336,10 +2692,11 @@ pub(crate) async fn run_js(path: String, req: Request<hyper::Body>) -> Result<Re
 
         let runtime = &mut service.worker.js_runtime;
         let stream = get_read_stream(runtime, result.clone())?;
-        let stream = EndReqStream {
+        let stream = Box::pin(EndReqStream {
             inner: stream,
             req: request_handler,
-        };
+            _permit: permit,
+        });
 
         let scope = &mut runtime.handle_scope();
         let response = result
@@ -1349,30 +2706,128 @@ pub(crate) async fn run_js(path: String, req: Request<hyper::Body>) -> Result<Re
 
         let headers: v8::Local<v8::Array> = get_member(response, scope, "headers")?;
         let num_headers = headers.length();
+        let header_limits = service.header_limits;
+        header_limits.check_count(num_headers as usize)?;
+        let mut header_bytes = 0;
+
+        // Responses whose body is null, like most 204 No Content ones, have no body at all.
+        let has_body: v8::Local<v8::Value> = get_member(response, scope, "hasBody")?;
+        let has_body = !has_body.is_false();
 
         let status: v8::Local<v8::Number> = get_member(response, scope, "status")?;
-        let status = status.value() as u16;
+        let status = status.value();
+        if status.fract() != 0.0 || !(100.0..=599.0).contains(&status) {
+            return Err(Error::InvalidStatus(status).into());
+        }
 
-        let mut builder = response_template().status(StatusCode::from_u16(status)?);
+        // Hyper always sends the standard reason phrase of the status code, so a custom
+        // statusText can't be passed on.
+        let status = StatusCode::from_u16(status as u16)?;
+        let mut builder = response_template().status(status);
 
         for i in 0..num_headers {
             let value: v8::Local<v8::Array> = try_into_or(headers.get_index(scope, i))?;
             let key: v8::Local<v8::String> = try_into_or(value.get_index(scope, 0))?;
             let value: v8::Local<v8::String> = try_into_or(value.get_index(scope, 1))?;
-
+            // Measured before anything is copied out of the isolate.
+            let len = key.utf8_length(scope) + value.utf8_length(scope);
+            header_limits.add_bytes(&mut header_bytes, len)?;
+
+            let key = HeaderName::from_bytes(key.to_rust_string_lossy(scope).as_bytes())?;
+            if !service.denied_headers.allows(&key, status) {
+                warn!("Dropped header {} from the response of {}", key, path);
+                continue;
+            }
             // FIXME: Do we have to handle non utf-8 values?
-            builder = builder.header(
-                key.to_rust_string_lossy(scope),
-                value.to_rust_string_lossy(scope),
-            );
+            builder = builder.header(key, value.to_rust_string_lossy(scope));
         }
 
-        builder.body(Body::Stream(Box::pin(stream)))?
+        (builder, stream, has_body, service.response_prefetch_chunks)
     };
 
+    let body = if has_body {
+        // Hyper writes the status and headers as soon as we return, without waiting for
+        // the first chunk of the body.
+        builder.body(Body::Stream(prefetch(stream, prefetch_chunks)))?
+    } else {
+        // There is nothing to send, but the request only ends once its transaction is
+        // committed, and a failure to commit must still be reported.
+        while let Some(chunk) = stream.next().await {
+            chunk?;
+        }
+        builder.body(Body::Const(None))?
+    };
+
+    if is_head {
+        return Ok(strip_body(body));
+    }
+    if is_get {
+        return serve_range(body, range.as_deref()).await;
+    }
     Ok(body)
 }
 
+/// Serves the part of `res` that a `Range` header asks for, with `206 Partial Content`, or answers
+/// `416 Range Not Satisfiable` if that part is past its end.
+///
+/// Only `200 OK` responses with a `Content-Length` can be split, and they advertise it with
+/// `Accept-Ranges`. Any other response is sent whole, as is any response to a request without a
+/// single range of bytes.
+async fn serve_range(res: Response<Body>, range: Option<&str>) -> Result<Response<Body>> {
+    let length = res
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse().ok());
+    let length = match length {
+        Some(length) if res.status() == StatusCode::OK => length,
+        _ => return Ok(res),
+    };
+    let (mut parts, mut body) = res.into_parts();
+    parts
+        .headers
+        .insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    match range.and_then(|range| parse_range(range, length)) {
+        None => Ok(Response::from_parts(parts, body)),
+        Some(ByteRange::Unsatisfiable) => {
+            // Like a response without a body, this one only ends once its transaction does.
+            while let Some(chunk) = body.data().await {
+                chunk?;
+            }
+            parts.status = StatusCode::RANGE_NOT_SATISFIABLE;
+            let content_range = format!("bytes */{}", length);
+            parts.headers.insert(CONTENT_RANGE, content_range.parse()?);
+            parts.headers.insert(CONTENT_LENGTH, 0.into());
+            Ok(Response::from_parts(parts, Body::Const(None)))
+        }
+        Some(ByteRange::Bytes { start, end }) => {
+            parts.status = StatusCode::PARTIAL_CONTENT;
+            let content_range = format!("bytes {}-{}/{}", start, end, length);
+            parts.headers.insert(CONTENT_RANGE, content_range.parse()?);
+            parts
+                .headers
+                .insert(CONTENT_LENGTH, (end - start + 1).into());
+            Ok(Response::from_parts(parts, body.slice(start, end)))
+        }
+    }
+}
+
+/// Turns the response to a HEAD request into one with the same headers but no body.
+///
+/// The body is dropped without being read, which ends the request as if the client had gone away
+/// after the headers. The `Content-Length` the response would have had is only added when the
+/// endpoint didn't set one and it is known without reading the body.
+fn strip_body(res: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = res.into_parts();
+    if let Some(length) = body.size_hint().exact() {
+        parts
+            .headers
+            .entry(CONTENT_LENGTH)
+            .or_insert_with(|| length.into());
+    }
+    Response::from_parts(parts, Body::Const(None))
+}
+
 #[derive(Serialize)]
 struct StartRequest {
     body_rid: Option<u32>,
@@ -1385,10 +2840,18 @@ struct StartRequest {
 async fn handle_request(
     state: Rc<RefCell<OpState>>,
     userid: Option<String>,
-    req: Request<hyper::Body>,
+    mut req: Request<hyper::Body>,
 ) -> Result<StartRequest> {
     // FIXME: this request conversion is probably simplistic. Check deno/ext/http/lib.rs
 
+    // Kept until the endpoint accepts it with op_chisel_upgrade_websocket, or the next request.
+    match websocket::Upgrade::from_request(&mut req) {
+        Some(upgrade) => state.borrow_mut().put(upgrade),
+        None => {
+            state.borrow_mut().try_take::<websocket::Upgrade>();
+        }
+    }
+
     // Hyper gives us a URL with just the path, make it a full URL
     // before passing it to deno.
     // FIXME: Use the real values for this server.
@@ -1402,19 +2865,40 @@ async fn handle_request(
     let method = req.method();
 
     let mut headers: HashMap<String, String> = HashMap::new();
+    let mut cookies = HashMap::new();
     for (k, v) in req.headers().iter() {
         let k = k.as_str();
         let v = v.to_str()?;
+        if k == "cookie" {
+            cookies::parse(v, &mut cookies);
+        }
         headers.insert(k.to_string(), v.to_string());
     }
+    state.borrow_mut().put(RequestCookies(cookies));
+    let trace = req.extensions().get().cloned().unwrap_or_default();
+    state.borrow_mut().put(RequestTrace(trace));
+    let remote_addr = req.extensions().get().map(|&ClientAddr(addr)| addr);
+    state.borrow_mut().put(RequestRemoteAddr(remote_addr));
+    // Until the endpoint narrows it down, the best route we know is the path itself.
+    let path = req.uri().path().to_string();
+    state.borrow_mut().put(RequestRoute {
+        route: path.clone(),
+        path,
+    });
+    // Kept until the transaction of the request ends.
+    let policies = state.borrow().borrow::<Rc<Policies>>().clone();
+    state.borrow_mut().put(RequestPolicies(policies));
 
     let has_body = method != Method::GET && method != Method::HEAD;
     let method = method.as_str().to_string();
     let body_rid = if has_body {
         let body = req.into_body();
+        let timeout = state.borrow().borrow::<BodyReadTimeout>().0;
         let resource = BodyResource {
             body: RefCell::new(body),
             cancel: Default::default(),
+            deadline: timeout.map(|t| Instant::now() + t),
+            multipart: Default::default(),
         };
         let rid = state.borrow_mut().resource_table.add(resource);
         Some(rid)
@@ -1439,11 +2923,8 @@ enum StartRequestRes {
 
 #[op]
 async fn op_chisel_start_request(state: Rc<RefCell<OpState>>) -> Result<StartRequestRes> {
-    let receiver = WORKER_CHANNEL.with(|d| d.get().unwrap().clone());
-    let req = match receiver.recv().await {
-        Ok(WorkerMsg::HandleRequest(req)) => req,
-        _ => unreachable!("Wrong message"),
-    };
+    let receiver = WORKER_CHANNEL.with(|d| d.get().unwrap().requests.clone());
+    let req = receiver.recv().await.unwrap();
     let userid = match req.headers().get("ChiselUID").map(|v| v.to_str()) {
         Some(Ok(str)) => Some(str.to_string()),
         Some(Err(e)) => {
@@ -1505,16 +2986,90 @@ pub(crate) async fn compile_endpoint(path: String, code: String) -> Result<()> {
     Ok(())
 }
 
-pub(crate) async fn activate_endpoint(path: &str) -> Result<()> {
+/// The version and code of the endpoint at `path` most recently loaded in this executor, if any.
+pub(crate) fn endpoint_code(path: &str) -> Option<(u64, String)> {
+    let service = get();
+    let handle = service.module_loader.lock().unwrap();
+    handle
+        .code_map
+        .get(&format!("{}.js", path))
+        .map(|entry| (entry.version, entry.code.clone()))
+}
+
+/// Makes the endpoints at `paths`, compiled with `compile_endpoint`, serve requests.  If `prefix`
+/// is given, the endpoints under it stop serving at the same time, so no request sees a mix of
+/// old and new endpoints.
+pub(crate) async fn activate_endpoints<S: AsRef<str>>(
+    prefix: Option<&str>,
+    paths: &[S],
+) -> Result<()> {
+    let promise = {
+        let mut service = get();
+        let service: &mut DenoService = &mut service;
+        let runtime = &mut service.worker.js_runtime;
+        let scope = &mut runtime.handle_scope();
+        let activate_endpoints = service.activate_endpoints.open(scope);
+        let undefined = v8::undefined(scope).into();
+        let prefix = match prefix {
+            Some(prefix) => v8::String::new(scope, prefix).unwrap().into(),
+            None => undefined,
+        };
+        let paths: Vec<v8::Local<v8::Value>> = paths
+            .iter()
+            .map(|p| v8::String::new(scope, p.as_ref()).unwrap().into())
+            .collect();
+        let paths = v8::Array::new_with_elements(scope, &paths).into();
+        let promise = activate_endpoints
+            .call(scope, undefined, &[prefix, paths])
+            .unwrap();
+        v8::Global::new(scope, promise)
+    };
+    resolve_promise(promise).await?;
+    Ok(())
+}
+
+/// Path under which the middleware module of each API version is kept, under the reserved
+/// __chiselstrike version so that it never clashes with an endpoint.
+const MIDDLEWARE_PATH: &str = "/__chiselstrike/middleware";
+
+/// Makes `code` the middleware that wraps every endpoint of `api_version`, or removes the
+/// middleware of that version if None.
+pub(crate) async fn set_middleware(api_version: &str, code: Option<String>) -> Result<()> {
+    let path = format!("{}/{}", MIDDLEWARE_PATH, api_version);
     let promise = {
         let mut service = get();
         let service: &mut DenoService = &mut service;
+
+        let version = match code {
+            Some(code) => {
+                let mut handle = service.module_loader.lock().unwrap();
+                let entry = handle
+                    .code_map
+                    .entry(format!("{}.js", path))
+                    .and_modify(|v| v.version += 1)
+                    .or_insert(VersionedCode {
+                        code: "".to_string(),
+                        version: 0,
+                    });
+                entry.code = code;
+                Some(entry.version)
+            }
+            None => None,
+        };
+
         let runtime = &mut service.worker.js_runtime;
         let scope = &mut runtime.handle_scope();
-        let activate_endpoint = service.activate_endpoint.open(scope);
+        let set_middleware = service.set_middleware.open(scope);
         let undefined = v8::undefined(scope).into();
-        let path = v8::String::new(scope, path).unwrap().into();
-        let promise = activate_endpoint.call(scope, undefined, &[path]).unwrap();
+        let api_version = v8::String::new(scope, api_version).unwrap().into();
+        let path = v8::String::new(scope, &path).unwrap().into();
+        let version = match version {
+            Some(version) => v8::Number::new(scope, version as f64).into(),
+            None => undefined,
+        };
+        let promise = set_middleware
+            .call(scope, undefined, &[api_version, path, version])
+            .unwrap();
         v8::Global::new(scope, promise)
     };
     resolve_promise(promise).await?;
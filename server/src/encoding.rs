@@ -0,0 +1,136 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Base64 and hex encodings of binary data, for tokens and interchange with other services.
+//!
+//! Decoding is strict: anything that isn't exactly what the encoder could have produced is an error, instead of
+//! being skipped or decoded to something else.
+
+use anyhow::{anyhow, Result};
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Encodes `data` as base64, with `+` and `/` and padding, or as URL-safe base64 with `-` and `_` and no padding.
+pub(crate) fn base64_encode(data: &[u8], url_safe: bool) -> String {
+    base64::encode_config(data, base64_config(url_safe))
+}
+
+/// Decodes base64 as encoded by `base64_encode`. URL-safe base64 may be padded too, since some encoders pad it.
+pub(crate) fn base64_decode(text: &str, url_safe: bool) -> Result<Vec<u8>> {
+    let text = if url_safe {
+        strip_padding(text)?
+    } else {
+        anyhow::ensure!(text.len() % 4 == 0, "Invalid base64: missing padding");
+        text
+    };
+    base64::decode_config(text, base64_config(url_safe))
+        .map_err(|e| anyhow!("Invalid base64: {}", e))
+}
+
+fn base64_config(url_safe: bool) -> base64::Config {
+    if url_safe {
+        base64::URL_SAFE_NO_PAD
+    } else {
+        base64::STANDARD
+    }
+}
+
+/// Removes the padding of URL-safe base64, if it has the padding its length calls for.
+fn strip_padding(text: &str) -> Result<&str> {
+    let stripped = text.trim_end_matches('=');
+    if stripped.len() != text.len() {
+        let padding = (4 - stripped.len() % 4) % 4;
+        anyhow::ensure!(
+            text.len() - stripped.len() == padding && text.len() % 4 == 0,
+            "Invalid base64: wrong padding"
+        );
+    }
+    Ok(stripped)
+}
+
+/// Encodes `data` as lowercase hex.
+pub(crate) fn hex_encode(data: &[u8]) -> String {
+    let mut text = String::with_capacity(data.len() * 2);
+    for byte in data {
+        text.push(HEX_DIGITS[(byte >> 4) as usize] as char);
+        text.push(HEX_DIGITS[(byte & 0xf) as usize] as char);
+    }
+    text
+}
+
+/// Decodes hex in either case.
+pub(crate) fn hex_decode(text: &str) -> Result<Vec<u8>> {
+    let digits = text.as_bytes();
+    anyhow::ensure!(digits.len() % 2 == 0, "Invalid hex: odd number of digits");
+    digits
+        .chunks(2)
+        .enumerate()
+        .map(|(i, pair)| Ok(hex_digit(pair[0], 2 * i)? << 4 | hex_digit(pair[1], 2 * i + 1)?))
+        .collect()
+}
+
+fn hex_digit(digit: u8, offset: usize) -> Result<u8> {
+    match digit {
+        b'0'..=b'9' => Ok(digit - b'0'),
+        b'a'..=b'f' => Ok(digit - b'a' + 10),
+        b'A'..=b'F' => Ok(digit - b'A' + 10),
+        _ => anyhow::bail!("Invalid hex: unexpected character at offset {}", offset),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = &[0x00, 0xfb, 0xff, 0x10, 0x7e, 0x3f];
+
+    #[test]
+    fn base64_round_trips() {
+        for len in 0..DATA.len() {
+            let data = &DATA[..len];
+            for url_safe in [false, true] {
+                let text = base64_encode(data, url_safe);
+                assert_eq!(base64_decode(&text, url_safe).unwrap(), data);
+            }
+        }
+        assert_eq!(base64_encode(DATA, false), "APv/EH4/");
+        assert_eq!(base64_encode(DATA, true), "APv_EH4_");
+        assert_eq!(base64_encode(b"ab", false), "YWI=");
+        assert_eq!(base64_encode(b"ab", true), "YWI");
+        assert_eq!(base64_decode("YWI=", true).unwrap(), b"ab");
+    }
+
+    #[test]
+    fn base64_rejects_malformed_input() {
+        // Missing or extra padding.
+        assert!(base64_decode("YWI", false).is_err());
+        assert!(base64_decode("YWI==", false).is_err());
+        assert!(base64_decode("YWI==", true).is_err());
+        assert!(base64_decode("YW=I", true).is_err());
+        // The other alphabet.
+        assert!(base64_decode("APv_EH4_", false).is_err());
+        assert!(base64_decode("APv/EH4/", true).is_err());
+        // Bits that no encoder would have set.
+        assert!(base64_decode("YWJ=", false).is_err());
+        assert!(base64_decode("Y", true).is_err());
+        assert!(base64_decode("YW I=", false).is_err());
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        assert_eq!(hex_encode(DATA), "00fbff107e3f");
+        assert_eq!(hex_decode("00fbff107e3f").unwrap(), DATA);
+        assert_eq!(hex_decode("00FBFF107E3F").unwrap(), DATA);
+        assert_eq!(hex_decode("").unwrap(), b"");
+    }
+
+    #[test]
+    fn hex_rejects_malformed_input() {
+        assert!(hex_decode("abc").is_err());
+        assert!(hex_decode("0g").is_err());
+        assert!(hex_decode(" 00").is_err());
+        assert_eq!(
+            hex_decode("00zz").unwrap_err().to_string(),
+            "Invalid hex: unexpected character at offset 2"
+        );
+    }
+}
@@ -0,0 +1,170 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Encryption at rest of fields whose label has the `encrypt` policy.
+//!
+//! An encrypted value is stored as `enc1:` followed by the base64 of a random nonce and the AES-256-GCM ciphertext of
+//! the value.  Keys come from the `CHISELD_ENCRYPTION_KEY` secret, which like `CHISELD_SIGNING_KEY` is either a single
+//! string or an array of strings.  Values are encrypted with the first key and decrypted with any of them, so a key can
+//! be rotated by prepending the new one, as long as the old one stays until nothing is stored with it.
+
+use crate::policies::anonymize;
+use crate::JsonObject;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::sync::RwLock;
+
+pub(crate) const ENCRYPTION_KEY_SECRET: &str = "CHISELD_ENCRYPTION_KEY";
+
+const PREFIX: &str = "enc1:";
+const NONCE_LEN: usize = 12;
+
+lazy_static! {
+    // Ciphers for the keys in the secrets, newest first.  Policy transforms are plain functions, so
+    // they find the keys here rather than in the state of the request.
+    static ref CIPHERS: RwLock<Vec<Aes256Gcm>> = RwLock::new(vec![]);
+}
+
+/// Takes the keys from `secrets`, replacing the previous ones.
+pub(crate) fn set_keys(secrets: &JsonObject) {
+    let keys = match secrets.get(ENCRYPTION_KEY_SECRET) {
+        Some(Value::String(key)) => vec![key.as_str()],
+        Some(Value::Array(keys)) => keys.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    };
+    *CIPHERS.write().unwrap() = keys.into_iter().map(cipher).collect();
+}
+
+fn cipher(key: &str) -> Aes256Gcm {
+    // Keys can be of any length, like signing keys, so they are hashed into AES keys.
+    let key = Sha256::digest(key.as_bytes());
+    Aes256Gcm::new(Key::from_slice(&key))
+}
+
+/// Encrypts a string `value` with the newest key.  Null values are stored as they are.
+pub(crate) fn encrypt(field: &str, value: &Value) -> Result<Value> {
+    let plaintext = match value {
+        Value::Null => return Ok(Value::Null),
+        Value::String(s) => s,
+        v => anyhow::bail!(
+            "Cannot encrypt field {}: only strings can be encrypted, got {}",
+            field,
+            v
+        ),
+    };
+    let ciphers = CIPHERS.read().unwrap();
+    let cipher = ciphers.first().ok_or_else(|| {
+        anyhow!(
+            "Cannot encrypt field {}: secret {} is not set",
+            field,
+            ENCRYPTION_KEY_SECRET
+        )
+    })?;
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+        cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+            .map_err(|_| anyhow!("Cannot encrypt field {}", field))?,
+    );
+    Ok(Value::String(format!(
+        "{}{}",
+        PREFIX,
+        base64::encode(sealed)
+    )))
+}
+
+/// Decrypts a value stored by `encrypt`.  Values stored before the field was encrypted are returned as they are, and
+/// values that no key decrypts are anonymized.
+pub(crate) fn decrypt(value: Value) -> Value {
+    let sealed = match value.as_str().and_then(|s| s.strip_prefix(PREFIX)) {
+        Some(sealed) => sealed,
+        None => return value,
+    };
+    match try_decrypt(sealed) {
+        Some(plaintext) => Value::String(plaintext),
+        None => {
+            warn!(
+                "Could not decrypt a field value with any of the keys in {}",
+                ENCRYPTION_KEY_SECRET
+            );
+            anonymize(value)
+        }
+    }
+}
+
+fn try_decrypt(sealed: &str) -> Option<String> {
+    let sealed = base64::decode(sealed).ok()?;
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let ciphers = CIPHERS.read().unwrap();
+    let plaintext = ciphers
+        .iter()
+        .find_map(|c| c.decrypt(Nonce::from_slice(nonce), ciphertext).ok())?;
+    String::from_utf8(plaintext).ok()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::{Mutex, MutexGuard};
+
+    lazy_static! {
+        static ref KEYS_LOCK: Mutex<()> = Mutex::new(());
+    }
+
+    /// Keeps other tests from changing the keys while the guard is held.
+    pub(crate) fn lock_keys() -> MutexGuard<'static, ()> {
+        KEYS_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn use_keys(keys: Value) {
+        set_keys(json!({ ENCRYPTION_KEY_SECRET: keys }).as_object().unwrap());
+    }
+
+    #[test]
+    fn round_trip() {
+        let _lock = lock_keys();
+        use_keys(json!("secret"));
+        let encrypted = encrypt("ssn", &json!("123-45-6789")).unwrap();
+        let stored = encrypted.as_str().unwrap();
+        assert!(stored.starts_with(PREFIX));
+        assert!(!stored.contains("123-45-6789"));
+        // Nonces are random, so equal values don't look equal when stored.
+        assert_ne!(encrypt("ssn", &json!("123-45-6789")).unwrap(), encrypted);
+        assert_eq!(decrypt(encrypted), json!("123-45-6789"));
+
+        assert_eq!(encrypt("ssn", &Value::Null).unwrap(), Value::Null);
+        assert!(encrypt("ssn", &json!(12)).is_err());
+        assert_eq!(decrypt(json!("stored before")), json!("stored before"));
+    }
+
+    #[test]
+    fn rotation() {
+        let _lock = lock_keys();
+        use_keys(json!("old"));
+        let old = encrypt("ssn", &json!("a")).unwrap();
+
+        use_keys(json!(["new", "old"]));
+        let new = encrypt("ssn", &json!("b")).unwrap();
+        assert_eq!(decrypt(old.clone()), json!("a"));
+        assert_eq!(decrypt(new.clone()), json!("b"));
+
+        // Values that no key decrypts don't come out as ciphertext.
+        use_keys(json!("new"));
+        assert_eq!(decrypt(old), json!("xxxxx"));
+        assert_eq!(decrypt(new), json!("b"));
+
+        use_keys(Value::Null);
+        let err = encrypt("ssn", &json!("c")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot encrypt field ssn: secret CHISELD_ENCRYPTION_KEY is not set"
+        );
+    }
+}
@@ -7,13 +7,21 @@
 //! metadata of the ChiselStrike server endpoints as OpenAPI 2.0 format:
 //!
 //! https://swagger.io/specification/v2/
+//!
+//! It also registers `/__schema`, which lists the types defined in every
+//! API version along with their fields.
 
 use crate::api::{response_template, ApiService, Body};
+use crate::deno::check_chisel_auth;
 use crate::runtime;
+use crate::types::{ObjectType, TypeSystem};
+use crate::JsonObject;
 use anyhow::Result;
 use futures::FutureExt;
 use hyper::{Request, Response};
+use itertools::Itertools;
 use openapi::{Info, Operations, Spec};
+use serde_json::{json, Value};
 use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -74,6 +82,65 @@ async fn introspect(req: Request<hyper::Body>) -> Result<Response<Body>> {
         .unwrap())
 }
 
+/// Describes the fields of `ty` and its constraints, as `/__schema` lists them and endpoints get them from
+/// `typeInfo()`.
+pub(crate) fn type_json(ty: &ObjectType) -> Value {
+    let fields = ty
+        .user_fields()
+        .map(|field| {
+            json!({
+                "name": field.name,
+                "type": field.type_.name(),
+                "labels": field.labels,
+                "default": field.user_provided_default(),
+                "optional": field.is_optional,
+                "unique": field.is_unique,
+                "indexed": field.is_indexed,
+                "allowedValues": field.allowed_values,
+                "nullable": field.is_nullable,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "name": ty.name(),
+        "fields": fields,
+        "uniqueTogether": ty.unique_constraints(),
+        "softDelete": ty.soft_deletes(),
+    })
+}
+
+/// Describes the types of every API version, keyed by version, in the same terms as `chisel describe`.
+fn schema_json(type_system: &TypeSystem) -> Value {
+    let versions = type_system
+        .versions
+        .iter()
+        .sorted_by(|x, y| x.0.cmp(y.0))
+        .map(|(api_version, version_types)| {
+            let types = version_types
+                .custom_types
+                .values()
+                .sorted_by(|x, y| x.name().cmp(y.name()))
+                .map(|ty| type_json(ty))
+                .collect::<Vec<_>>();
+            (api_version.clone(), Value::from(types))
+        })
+        .collect::<JsonObject>();
+    Value::Object(versions)
+}
+
+async fn schema(req: Request<hyper::Body>) -> Result<Response<Body>> {
+    let runtime = runtime::get();
+    if !runtime.public_schema {
+        if let Some(rejection) = check_chisel_auth(runtime.secrets.as_ref(), &req)? {
+            return Ok(rejection);
+        }
+    }
+    let body = serde_json::to_string(&schema_json(&runtime.type_system))?;
+    Ok(response_template()
+        .header("Content-Type", "application/json")
+        .body(body.into())?)
+}
+
 pub(crate) fn add_introspection<P: AsRef<Path>>(api: &ApiService, path: P) {
     let mut introspect_route = PathBuf::from("/");
     introspect_route.push(&path);
@@ -86,4 +153,8 @@ pub(crate) fn add_introspection<P: AsRef<Path>>(api: &ApiService, path: P) {
 pub(crate) fn init(api: &ApiService) {
     add_introspection(api, "/");
     add_introspection(api, "__chiselstrike");
+    api.add_route(
+        PathBuf::from("/__schema"),
+        Arc::new(move |req| { schema(req) }.boxed_local()),
+    );
 }
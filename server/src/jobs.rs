@@ -0,0 +1,200 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Jobs: background tasks queued with `enqueueJob()`, whose progress clients can follow.
+//!
+//! An endpoint that queues a job answers with `202 Accepted` and a `Location` of
+//! `/__chiselstrike/jobs/<id>`, which tells whether the job is still queued, running, or done.
+//! Jobs are kept in memory, shared by all executor threads, so the status can be read from any of
+//! them but is gone after a restart. A job queued by a logged-in user can only be read by that user.
+
+use crate::api::{response_template, ApiService, Body};
+use anyhow::Result;
+use futures::FutureExt;
+use hyper::{Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Path under which the status of each job is served.
+pub(crate) const JOBS_PATH: &str = "/__chiselstrike/jobs";
+
+/// How many finished jobs to remember; the status of older ones is forgotten.
+const MAX_FINISHED_JOBS: usize = 10_000;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase", tag = "status")]
+pub(crate) enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobStatus {
+    fn is_finished(&self) -> bool {
+        matches!(self, Self::Succeeded | Self::Failed)
+    }
+}
+
+/// What the status route returns for a job.
+#[derive(Serialize)]
+struct JobInfo<'a> {
+    id: &'a str,
+    #[serde(flatten)]
+    status: JobStatus,
+}
+
+struct Job {
+    /// The user whose request queued the job, if one was logged in.
+    owner: Option<String>,
+    status: JobStatus,
+}
+
+#[derive(Default)]
+struct Jobs {
+    statuses: HashMap<String, Job>,
+    /// Ids of the finished jobs, oldest first.
+    finished: VecDeque<String>,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct JobStore {
+    jobs: Arc<Mutex<Jobs>>,
+}
+
+impl JobStore {
+    /// Adds a queued job on behalf of `owner`, returning its id.
+    pub(crate) fn create(&self, owner: Option<String>) -> String {
+        let id = Uuid::new_v4().to_string();
+        let mut jobs = self.jobs.lock().unwrap();
+        let status = JobStatus::Queued;
+        jobs.statuses.insert(id.clone(), Job { owner, status });
+        id
+    }
+
+    /// Moves job `id` to `status`. A finished job stays finished.
+    pub(crate) fn set(&self, id: &str, status: JobStatus) -> Result<()> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let current = jobs
+            .statuses
+            .get_mut(id)
+            .ok_or_else(|| anyhow::anyhow!("no job with id {}", id))?;
+        anyhow::ensure!(
+            !current.status.is_finished(),
+            "job {} has already finished",
+            id
+        );
+        let finished = status.is_finished();
+        current.status = status;
+        if finished {
+            jobs.finished.push_back(id.to_owned());
+            if jobs.finished.len() > MAX_FINISHED_JOBS {
+                let oldest = jobs.finished.pop_front().unwrap();
+                jobs.statuses.remove(&oldest);
+            }
+        }
+        Ok(())
+    }
+
+    /// The status of job `id`, as seen by `user`: a job that belongs to someone else reads as
+    /// missing.
+    pub(crate) fn get(&self, id: &str, user: Option<&str>) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.statuses.get(id)?;
+        match &job.owner {
+            Some(owner) if Some(owner.as_str()) != user => None,
+            _ => Some(job.status.clone()),
+        }
+    }
+}
+
+async fn job_status(jobs: JobStore, req: Request<hyper::Body>) -> Result<Response<Body>> {
+    if req.method() != Method::GET {
+        return Ok(response_template()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header("Allow", "GET")
+            .body(Body::default())?);
+    }
+    let id = req.uri().path()[JOBS_PATH.len()..].trim_matches('/');
+    let user = req.headers().get("ChiselUID").and_then(|v| v.to_str().ok());
+    let status = match jobs.get(id, user) {
+        Some(status) => status,
+        None => return ApiService::not_found(),
+    };
+    let body = serde_json::to_string(&JobInfo { id, status })?;
+    Ok(response_template()
+        .header("Content-Type", "application/json")
+        .body(body.into())?)
+}
+
+pub(crate) fn init(api: &ApiService, jobs: JobStore) {
+    api.add_route(
+        PathBuf::from(JOBS_PATH),
+        Arc::new(move |req| job_status(jobs.clone(), req).boxed_local()),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn job_lifecycle() {
+        let jobs = JobStore::default();
+        let id = jobs.create(None);
+        assert_eq!(jobs.get(&id, None), Some(JobStatus::Queued));
+        jobs.set(&id, JobStatus::Running).unwrap();
+        jobs.set(&id, JobStatus::Succeeded).unwrap();
+        assert_eq!(jobs.get(&id, None), Some(JobStatus::Succeeded));
+        assert!(jobs.set(&id, JobStatus::Running).is_err());
+        assert!(jobs.set("unknown", JobStatus::Running).is_err());
+        assert_eq!(jobs.get("unknown", None), None);
+    }
+
+    #[test]
+    fn only_owner_reads_job() {
+        let jobs = JobStore::default();
+        let id = jobs.create(Some("alice".into()));
+        assert_eq!(jobs.get(&id, Some("alice")), Some(JobStatus::Queued));
+        assert_eq!(jobs.get(&id, Some("bob")), None);
+        assert_eq!(jobs.get(&id, None), None);
+        let anonymous = jobs.create(None);
+        assert_eq!(jobs.get(&anonymous, Some("bob")), Some(JobStatus::Queued));
+    }
+
+    #[test]
+    fn forgets_oldest_finished_jobs() {
+        let jobs = JobStore::default();
+        let first = jobs.create(None);
+        jobs.set(&first, JobStatus::Succeeded).unwrap();
+        let running = jobs.create(None);
+        jobs.set(&running, JobStatus::Running).unwrap();
+        for _ in 0..MAX_FINISHED_JOBS {
+            let id = jobs.create(None);
+            jobs.set(&id, JobStatus::Failed).unwrap();
+        }
+        assert_eq!(jobs.get(&first, None), None);
+        assert_eq!(jobs.get(&running, None), Some(JobStatus::Running));
+    }
+
+    #[test]
+    fn serializes_status() {
+        let info = JobInfo {
+            id: "1",
+            status: JobStatus::Failed,
+        };
+        assert_eq!(
+            serde_json::to_string(&info).unwrap(),
+            r#"{"id":"1","status":"failed"}"#
+        );
+        assert_eq!(
+            serde_json::to_value(JobStatus::Queued).unwrap(),
+            json!({"status": "queued"})
+        );
+        let running: JobStatus = serde_json::from_value(json!({"status": "running"})).unwrap();
+        assert_eq!(running, JobStatus::Running);
+    }
+}
@@ -0,0 +1,95 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Serialization of query results as a JSON array that is written as the rows come, so that large results don't have
+//! to be held in memory and can still be read by any JSON parser, unlike newline-delimited JSON.
+
+use crate::JsonObject;
+use anyhow::Result;
+use std::cell::Cell;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// Nothing was written yet.
+    Empty,
+    /// The array was opened, and holds at least one entity.
+    Open,
+    /// The array was closed.
+    Closed,
+}
+
+/// Writes the entities of a query as the pieces of a JSON array: the first one opens the array, the following ones
+/// are preceded by a comma, and `end` closes it.
+///
+/// A query that fails midway never gets to `end`, so its array stays open and parsers reject it, instead of
+/// taking the entities written so far for the whole result.
+pub(crate) struct JsonArrayWriter {
+    state: Cell<State>,
+}
+
+impl JsonArrayWriter {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Cell::new(State::Empty),
+        }
+    }
+
+    pub(crate) fn row(&self, entity: &JsonObject) -> Result<String> {
+        let separator = match self.state.get() {
+            State::Empty => "[\n",
+            State::Open => ",\n",
+            State::Closed => anyhow::bail!("JSON array is already closed"),
+        };
+        self.state.set(State::Open);
+        Ok(format!("{}{}", separator, serde_json::to_string(entity)?))
+    }
+
+    /// Closes the array, or returns None if it was closed already.
+    pub(crate) fn end(&self) -> Option<String> {
+        let end = match self.state.get() {
+            State::Empty => "[]\n",
+            State::Open => "\n]\n",
+            State::Closed => return None,
+        };
+        self.state.set(State::Closed);
+        Some(end.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn entity(value: Value) -> JsonObject {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn writes_a_json_array() {
+        let writer = JsonArrayWriter::new();
+        let mut text = String::new();
+        text.push_str(&writer.row(&entity(json!({"name": "a"}))).unwrap());
+        text.push_str(&writer.row(&entity(json!({"name": "b,]"}))).unwrap());
+        text.push_str(&writer.end().unwrap());
+        assert_eq!(text, "[\n{\"name\":\"a\"},\n{\"name\":\"b,]\"}\n]\n");
+        let parsed: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(parsed, json!([{"name": "a"}, {"name": "b,]"}]));
+
+        assert_eq!(writer.end(), None);
+        assert!(writer.row(&entity(json!({}))).is_err());
+    }
+
+    #[test]
+    fn empty_array() {
+        let writer = JsonArrayWriter::new();
+        assert_eq!(writer.end().unwrap(), "[]\n");
+        assert_eq!(writer.end(), None);
+    }
+
+    #[test]
+    fn unfinished_array_is_invalid() {
+        let writer = JsonArrayWriter::new();
+        let text = writer.row(&entity(json!({"name": "a"}))).unwrap();
+        assert!(serde_json::from_str::<Value>(&text).is_err());
+    }
+}
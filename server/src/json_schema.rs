@@ -0,0 +1,520 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Validation of JSON values against a JSON Schema.
+//!
+//! Only the commonly used subset of the specification is supported: `type`, `enum`, `const`, `properties`,
+//! `required`, `additionalProperties`, `items`, `minItems`, `maxItems`, `minLength`, `maxLength`, `pattern`,
+//! `minimum`, `maximum`, `exclusiveMinimum` and `exclusiveMaximum`, along with annotations like `title` and
+//! `description`, which don't affect validation.  Schemas with any other keyword, such as `$ref`, `anyOf` or
+//! `format`, are rejected rather than have the keyword ignored, which would let through values the schema is meant
+//! to reject.
+//!
+//! Schemas are compiled before use, which checks them and compiles their patterns.
+
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A place where a value doesn't conform to the schema.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct ValidationError {
+    /// JSON pointer to the offending part of the value; empty for the value itself.
+    pub(crate) path: String,
+    pub(crate) message: String,
+}
+
+/// Keywords that only describe values, which validation ignores.
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "deprecated",
+    "readOnly",
+    "writeOnly",
+];
+
+const KEYWORDS: &[&str] = &[
+    "type",
+    "enum",
+    "const",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+];
+
+/// How many compiled schemas each thread keeps for `compiled`.
+const MAX_CACHED_SCHEMAS: usize = 1024;
+
+/// A checked schema, ready to validate values.
+#[derive(Debug)]
+pub(crate) enum Schema {
+    /// `true` allows any value, `false` none.
+    Bool(bool),
+    Object(Box<Keywords>),
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Keywords {
+    types: Option<Vec<String>>,
+    allowed: Option<Vec<Value>>,
+    expected: Option<Value>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    exclusive_minimum: Option<f64>,
+    exclusive_maximum: Option<f64>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    pattern: Option<Regex>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+    items: Option<Schema>,
+    required: Vec<String>,
+    properties: HashMap<String, Schema>,
+    additional_properties: Option<Schema>,
+}
+
+/// Checks `value` against `schema`, returning every violation found.  Fails if the schema itself is malformed.
+pub(crate) fn validate(schema: &Value, value: &Value) -> Result<Vec<ValidationError>> {
+    Ok(Schema::compile(schema)?.validate(value))
+}
+
+thread_local! {
+    static CACHE: RefCell<HashMap<String, Rc<Schema>>> = RefCell::new(HashMap::new());
+}
+
+/// `schema` compiled, compiling it only the first time it is seen by this thread.
+pub(crate) fn compiled(schema: &Value) -> Result<Rc<Schema>> {
+    let key = schema.to_string();
+    if let Some(compiled) = CACHE.with(|c| c.borrow().get(&key).cloned()) {
+        return Ok(compiled);
+    }
+    let compiled = Rc::new(Schema::compile(schema)?);
+    CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        if cache.len() >= MAX_CACHED_SCHEMAS {
+            cache.clear();
+        }
+        cache.insert(key, compiled.clone());
+    });
+    Ok(compiled)
+}
+
+fn check_type(name: &str) -> Result<()> {
+    match name {
+        "null" | "boolean" | "object" | "array" | "number" | "integer" | "string" => Ok(()),
+        _ => bail!("unknown type '{}' in schema", name),
+    }
+}
+
+fn type_matches(name: &str, value: &Value) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "number" => value.is_number(),
+        "integer" => value.as_f64().map_or(false, |n| n.fract() == 0.0),
+        "string" => value.is_string(),
+        _ => unreachable!("types are checked when compiling"),
+    }
+}
+
+fn number(schema: &Map<String, Value>, keyword: &str) -> Result<Option<f64>> {
+    schema
+        .get(keyword)
+        .map(|n| {
+            n.as_f64()
+                .ok_or_else(|| anyhow!("'{}' in schema must be a number", keyword))
+        })
+        .transpose()
+}
+
+fn count(schema: &Map<String, Value>, keyword: &str) -> Result<Option<usize>> {
+    schema
+        .get(keyword)
+        .map(|n| {
+            n.as_u64()
+                .map(|n| n as usize)
+                .ok_or_else(|| anyhow!("'{}' in schema must be a non-negative integer", keyword))
+        })
+        .transpose()
+}
+
+/// Escapes a property name for use in a JSON pointer.
+fn pointer_token(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+impl Schema {
+    /// Checks `schema`, failing if it is malformed or uses a keyword that isn't supported.
+    pub(crate) fn compile(schema: &Value) -> Result<Self> {
+        Self::compile_at(schema, "")
+    }
+
+    fn compile_at(schema: &Value, path: &str) -> Result<Self> {
+        let schema = match schema {
+            Value::Bool(b) => return Ok(Self::Bool(*b)),
+            Value::Object(schema) => schema,
+            _ => bail!("schema at '{}' must be an object or a boolean", path),
+        };
+        for keyword in schema.keys() {
+            if !KEYWORDS.contains(&keyword.as_str()) && !ANNOTATIONS.contains(&keyword.as_str()) {
+                bail!("unsupported keyword '{}' in schema at '{}'", keyword, path);
+            }
+        }
+
+        let mut keywords = Keywords::default();
+        if let Some(ty) = schema.get("type") {
+            let names: Vec<String> = match ty {
+                Value::String(name) => vec![name.clone()],
+                Value::Array(names) => names
+                    .iter()
+                    .map(|n| {
+                        n.as_str()
+                            .map(str::to_owned)
+                            .context("'type' in schema must name types")
+                    })
+                    .collect::<Result<_>>()?,
+                _ => bail!("'type' in schema must be a string or an array"),
+            };
+            for name in &names {
+                check_type(name)?;
+            }
+            keywords.types = Some(names);
+        }
+        if let Some(allowed) = schema.get("enum") {
+            let allowed = allowed
+                .as_array()
+                .context("'enum' in schema must be an array")?;
+            keywords.allowed = Some(allowed.clone());
+        }
+        keywords.expected = schema.get("const").cloned();
+        keywords.minimum = number(schema, "minimum")?;
+        keywords.maximum = number(schema, "maximum")?;
+        keywords.exclusive_minimum = number(schema, "exclusiveMinimum")?;
+        keywords.exclusive_maximum = number(schema, "exclusiveMaximum")?;
+        keywords.min_length = count(schema, "minLength")?;
+        keywords.max_length = count(schema, "maxLength")?;
+        if let Some(pattern) = schema.get("pattern") {
+            let pattern = pattern
+                .as_str()
+                .context("'pattern' in schema must be a string")?;
+            let re = Regex::new(pattern)
+                .with_context(|| format!("invalid 'pattern' in schema: {}", pattern))?;
+            keywords.pattern = Some(re);
+        }
+        keywords.min_items = count(schema, "minItems")?;
+        keywords.max_items = count(schema, "maxItems")?;
+        if let Some(items) = schema.get("items") {
+            let items_path = format!("{}/items", path);
+            keywords.items = Some(Self::compile_at(items, &items_path)?);
+        }
+        if let Some(required) = schema.get("required") {
+            let required = required
+                .as_array()
+                .context("'required' in schema must be an array")?;
+            for name in required {
+                let name = name
+                    .as_str()
+                    .context("'required' in schema must list property names")?;
+                keywords.required.push(name.to_owned());
+            }
+        }
+        if let Some(properties) = schema.get("properties") {
+            let properties = properties
+                .as_object()
+                .context("'properties' in schema must be an object")?;
+            for (name, property) in properties {
+                let property_path = format!("{}/properties/{}", path, pointer_token(name));
+                let property = Self::compile_at(property, &property_path)?;
+                keywords.properties.insert(name.clone(), property);
+            }
+        }
+        if let Some(additional) = schema.get("additionalProperties") {
+            let additional_path = format!("{}/additionalProperties", path);
+            keywords.additional_properties = Some(Self::compile_at(additional, &additional_path)?);
+        }
+        Ok(Self::Object(Box::new(keywords)))
+    }
+
+    /// Checks `value` against the schema, returning every violation found.
+    pub(crate) fn validate(&self, value: &Value) -> Vec<ValidationError> {
+        let mut errors = vec![];
+        self.validate_at(value, "", &mut errors);
+        errors
+    }
+
+    fn validate_at(&self, value: &Value, path: &str, errors: &mut Vec<ValidationError>) {
+        let mut error = |message: String| {
+            errors.push(ValidationError {
+                path: path.to_owned(),
+                message,
+            })
+        };
+        let schema = match self {
+            Self::Bool(true) => return,
+            Self::Bool(false) => {
+                error("no value is allowed here".to_owned());
+                return;
+            }
+            Self::Object(schema) => schema,
+        };
+
+        if let Some(names) = &schema.types {
+            if !names.iter().any(|name| type_matches(name, value)) {
+                error(format!("expected {}", names.join(" or ")));
+                // The other keywords assume the right type.
+                return;
+            }
+        }
+        if let Some(allowed) = &schema.allowed {
+            if !allowed.contains(value) {
+                error(format!("must be one of {}", Value::from(allowed.clone())));
+            }
+        }
+        if let Some(expected) = &schema.expected {
+            if expected != value {
+                error(format!("must be {}", expected));
+            }
+        }
+
+        match value {
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or_default();
+                if let Some(min) = schema.minimum {
+                    if n < min {
+                        error(format!("must be at least {}", min));
+                    }
+                }
+                if let Some(max) = schema.maximum {
+                    if n > max {
+                        error(format!("must be at most {}", max));
+                    }
+                }
+                if let Some(min) = schema.exclusive_minimum {
+                    if n <= min {
+                        error(format!("must be greater than {}", min));
+                    }
+                }
+                if let Some(max) = schema.exclusive_maximum {
+                    if n >= max {
+                        error(format!("must be less than {}", max));
+                    }
+                }
+            }
+            Value::String(s) => {
+                let len = s.chars().count();
+                if let Some(min) = schema.min_length {
+                    if len < min {
+                        error(format!("must be at least {} characters long", min));
+                    }
+                }
+                if let Some(max) = schema.max_length {
+                    if len > max {
+                        error(format!("must be at most {} characters long", max));
+                    }
+                }
+                if let Some(re) = &schema.pattern {
+                    if !re.is_match(s) {
+                        error(format!("must match the pattern {}", re.as_str()));
+                    }
+                }
+            }
+            Value::Array(items) => {
+                if let Some(min) = schema.min_items {
+                    if items.len() < min {
+                        error(format!("must have at least {} items", min));
+                    }
+                }
+                if let Some(max) = schema.max_items {
+                    if items.len() > max {
+                        error(format!("must have at most {} items", max));
+                    }
+                }
+                if let Some(item_schema) = &schema.items {
+                    for (i, item) in items.iter().enumerate() {
+                        item_schema.validate_at(item, &format!("{}/{}", path, i), errors);
+                    }
+                }
+            }
+            Value::Object(fields) => {
+                for name in &schema.required {
+                    if !fields.contains_key(name) {
+                        errors.push(ValidationError {
+                            path: format!("{}/{}", path, pointer_token(name)),
+                            message: "is required".to_owned(),
+                        });
+                    }
+                }
+                for (name, field) in fields {
+                    let field_schema = schema
+                        .properties
+                        .get(name)
+                        .or(schema.additional_properties.as_ref());
+                    if let Some(field_schema) = field_schema {
+                        let field_path = format!("{}/{}", path, pointer_token(name));
+                        field_schema.validate_at(field, &field_path, errors);
+                    }
+                }
+            }
+            Value::Null | Value::Bool(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn paths(schema: Value, value: Value) -> Vec<(String, String)> {
+        validate(&schema, &value)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.path, e.message))
+            .collect()
+    }
+
+    fn person_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["name", "email"],
+            "properties": {
+                "name": {"type": "string", "minLength": 1},
+                "email": {"type": "string", "pattern": "^[^@]+@[^@]+$"},
+                "age": {"type": "integer", "minimum": 0},
+                "tags": {"type": "array", "items": {"type": "string"}, "maxItems": 2},
+                "address": {
+                    "type": "object",
+                    "properties": {"zip": {"type": "string", "maxLength": 5}},
+                    "additionalProperties": false
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn valid() {
+        let person = json!({
+            "name": "Alice",
+            "email": "alice@example.com",
+            "age": 30,
+            "tags": ["admin"],
+            "address": {"zip": "12345"},
+            "unknown": "anything goes"
+        });
+        assert!(paths(person_schema(), person).is_empty());
+    }
+
+    #[test]
+    fn error_paths() {
+        let person = json!({
+            "name": "",
+            "age": 2.5,
+            "tags": ["a", 7, "c"],
+            "address": {"zip": "123456", "city": "Nowhere"}
+        });
+        let mut errors = paths(person_schema(), person);
+        errors.sort();
+        let expected: Vec<(String, String)> = vec![
+            ("/address/city", "no value is allowed here"),
+            ("/address/zip", "must be at most 5 characters long"),
+            ("/age", "expected integer"),
+            ("/email", "is required"),
+            ("/name", "must be at least 1 characters long"),
+            ("/tags", "must have at most 2 items"),
+            ("/tags/1", "expected string"),
+        ]
+        .into_iter()
+        .map(|(p, m)| (p.to_owned(), m.to_owned()))
+        .collect();
+        assert_eq!(errors, expected);
+    }
+
+    #[test]
+    fn keywords() {
+        let schema = json!({"type": ["number", "null"], "exclusiveMaximum": 10});
+        assert!(paths(schema.clone(), json!(null)).is_empty());
+        assert_eq!(
+            paths(schema.clone(), json!(10)),
+            vec![("".to_owned(), "must be less than 10".to_owned())]
+        );
+        assert_eq!(
+            paths(schema, json!("10")),
+            vec![("".to_owned(), "expected number or null".to_owned())]
+        );
+        assert_eq!(
+            paths(json!({"enum": ["red", "green"]}), json!("blue")),
+            vec![(
+                "".to_owned(),
+                r#"must be one of ["red","green"]"#.to_owned()
+            )]
+        );
+        assert_eq!(
+            paths(
+                json!({"properties": {"a/b": {"const": 1}}}),
+                json!({"a/b": 2})
+            ),
+            vec![("/a~1b".to_owned(), "must be 1".to_owned())]
+        );
+    }
+
+    #[test]
+    fn malformed_schema() {
+        assert!(validate(&json!({"type": "text"}), &json!("x")).is_err());
+        assert!(validate(&json!({"pattern": "("}), &json!("x")).is_err());
+        assert!(validate(&json!("string"), &json!("x")).is_err());
+        // Nested schemas are checked even if no value reaches them.
+        assert!(Schema::compile(&json!({"items": {"type": "text"}})).is_err());
+    }
+
+    #[test]
+    fn unsupported_keywords() {
+        for keyword in ["$ref", "anyOf", "oneOf", "allOf", "not", "format"] {
+            let schema = json!({"properties": {"a": {keyword: "x"}}});
+            let err = Schema::compile(&schema).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "unsupported keyword '{}' in schema at '/properties/a'",
+                    keyword
+                )
+            );
+        }
+        let annotated = json!({"title": "Person", "description": "someone", "type": "object"});
+        assert!(paths(annotated, json!({})).is_empty());
+    }
+
+    #[test]
+    fn compiles_once() {
+        let schema = json!({"pattern": "^a"});
+        let first = compiled(&schema).unwrap();
+        let second = compiled(&schema).unwrap();
+        assert!(Rc::ptr_eq(&first, &second));
+        assert_eq!(
+            second.validate(&json!("b")),
+            vec![ValidationError {
+                path: "".to_owned(),
+                message: "must match the pattern ^a".to_owned()
+            }]
+        );
+    }
+}
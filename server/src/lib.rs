@@ -18,19 +18,36 @@ extern crate lazy_static;
 
 pub(crate) mod api;
 pub(crate) mod auth;
+pub(crate) mod cookies;
+pub(crate) mod csv;
 pub(crate) mod datastore;
+pub(crate) mod dates;
 pub(crate) mod deno;
+pub(crate) mod encoding;
+pub(crate) mod encryption;
 pub(crate) mod internal;
 pub(crate) mod introspect;
+pub(crate) mod jobs;
+pub(crate) mod json_array;
+pub(crate) mod json_schema;
+pub(crate) mod multipart;
+pub(crate) mod passwords;
 pub(crate) mod policies;
+pub(crate) mod prefetch;
 pub(crate) mod prefix_map;
 pub(crate) mod rcmut;
 pub(crate) mod rpc;
 pub(crate) mod runtime;
 pub(crate) mod secrets;
 pub mod server;
+pub(crate) mod signing;
+pub(crate) mod telemetry;
+pub(crate) mod text_body;
+pub(crate) mod tls;
 pub(crate) mod types;
 pub(crate) mod vecmap;
+pub(crate) mod watch;
+pub(crate) mod websocket;
 
 pub(crate) mod chisel {
     tonic::include_proto!("chisel");
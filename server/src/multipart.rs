@@ -0,0 +1,316 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Incremental parsing of `multipart/form-data` bodies.
+//!
+//! The body is fed to a `Parser` in chunks as they arrive, and the parser hands out the parts' headers and
+//! contents as soon as it has them, so that big uploads never have to be held in memory at once.
+
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+
+/// Longest header section of a part that we accept.
+const MAX_HEADERS_LEN: usize = 16 * 1024;
+/// Longest text before the first boundary that we accept.
+const MAX_PREAMBLE_LEN: usize = 16 * 1024;
+
+/// Extracts the boundary from the `Content-Type` of a `multipart/form-data` body.
+pub(crate) fn boundary(content_type: &str) -> Result<String> {
+    let mut params = content_type.split(';');
+    let mime = params.next().unwrap_or_default().trim();
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        bail!("expected a multipart/form-data body, got '{}'", mime);
+    }
+    params
+        .filter_map(|p| p.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_owned())
+        .filter(|b| !b.is_empty())
+        .ok_or_else(|| anyhow!("multipart/form-data body without a boundary"))
+}
+
+/// What the headers of a part say about it.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub(crate) struct PartInfo {
+    pub(crate) name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) filename: Option<String>,
+    #[serde(rename = "contentType", skip_serializing_if = "Option::is_none")]
+    pub(crate) content_type: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Event {
+    /// The start of a part. Its contents follow as `Data` events.
+    Part(PartInfo),
+    Data(Vec<u8>),
+    /// The end of the body.
+    End,
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    /// Before the first boundary.
+    Preamble,
+    /// Right after a boundary, which is followed by either a line break or `--` for the last one.
+    AfterBoundary,
+    Headers,
+    Contents,
+    Done,
+}
+
+pub(crate) struct Parser {
+    /// The delimiter that precedes each part: a line break, two dashes and the boundary.
+    delimiter: Vec<u8>,
+    state: State,
+    /// Input that hasn't been turned into events yet.
+    buf: Vec<u8>,
+    /// Bytes of the preamble skipped so far.
+    preamble_len: usize,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+impl Parser {
+    pub(crate) fn new(boundary: &str) -> Self {
+        Self {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            state: State::Preamble,
+            buf: vec![],
+            preamble_len: 0,
+        }
+    }
+
+    pub(crate) fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the next event, or None if more input has to be fed first.
+    pub(crate) fn next_event(&mut self) -> Result<Option<Event>> {
+        loop {
+            match self.state {
+                State::Preamble => {
+                    // The first boundary may start the body, without a line break before it.
+                    if self.preamble_len == 0 && self.buf.starts_with(&self.delimiter[2..]) {
+                        self.buf.drain(..self.delimiter.len() - 2);
+                        self.state = State::AfterBoundary;
+                        continue;
+                    }
+                    if let Some(i) = find(&self.buf, &self.delimiter) {
+                        self.buf.drain(..i + self.delimiter.len());
+                        self.state = State::AfterBoundary;
+                        continue;
+                    }
+                    // Only the end of the input could be the start of a delimiter.
+                    let skipped = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+                    self.buf.drain(..skipped);
+                    self.preamble_len += skipped;
+                    if self.preamble_len > MAX_PREAMBLE_LEN {
+                        bail!(
+                            "preamble of multipart body longer than {} bytes",
+                            MAX_PREAMBLE_LEN
+                        );
+                    }
+                    return Ok(None);
+                }
+                State::AfterBoundary => {
+                    if self.buf.len() < 2 {
+                        return Ok(None);
+                    }
+                    if self.buf.starts_with(b"--") {
+                        self.buf.clear();
+                        self.state = State::Done;
+                    } else if self.buf.starts_with(b"\r\n") {
+                        self.buf.drain(..2);
+                        self.state = State::Headers;
+                    } else {
+                        bail!("malformed multipart body: boundary not followed by a line break");
+                    }
+                }
+                State::Headers => {
+                    // An empty header section is just the line break that ends it.
+                    let end = if self.buf.starts_with(b"\r\n") {
+                        Some((0, 2))
+                    } else {
+                        find(&self.buf, b"\r\n\r\n").map(|i| (i, i + 4))
+                    };
+                    let (end, next) = match end {
+                        Some(end) => end,
+                        None if self.buf.len() > MAX_HEADERS_LEN => {
+                            bail!(
+                                "headers of multipart part longer than {} bytes",
+                                MAX_HEADERS_LEN
+                            )
+                        }
+                        None => return Ok(None),
+                    };
+                    let info = parse_headers(&self.buf[..end])?;
+                    self.buf.drain(..next);
+                    self.state = State::Contents;
+                    return Ok(Some(Event::Part(info)));
+                }
+                State::Contents => {
+                    if let Some(i) = find(&self.buf, &self.delimiter) {
+                        let data = self.buf[..i].to_vec();
+                        self.buf.drain(..i + self.delimiter.len());
+                        self.state = State::AfterBoundary;
+                        if !data.is_empty() {
+                            return Ok(Some(Event::Data(data)));
+                        }
+                        continue;
+                    }
+                    // The end of the input could be the start of a delimiter, so hold on to it.
+                    let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+                    if safe == 0 {
+                        return Ok(None);
+                    }
+                    let data = self.buf.drain(..safe).collect();
+                    return Ok(Some(Event::Data(data)));
+                }
+                State::Done => return Ok(Some(Event::End)),
+            }
+        }
+    }
+
+    /// Checks that the body, which has all been fed, ended where it should.
+    pub(crate) fn finish(&self) -> Result<()> {
+        if self.state != State::Done {
+            bail!("multipart body ended unexpectedly");
+        }
+        Ok(())
+    }
+}
+
+fn parse_headers(headers: &[u8]) -> Result<PartInfo> {
+    let headers = std::str::from_utf8(headers)?;
+    let mut info = PartInfo::default();
+    let mut has_disposition = false;
+    for line in headers.split("\r\n").filter(|l| !l.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("malformed header in multipart part: {}", line))?;
+        let value = value.trim();
+        if name.trim().eq_ignore_ascii_case("content-type") {
+            info.content_type = Some(value.to_owned());
+        } else if name.trim().eq_ignore_ascii_case("content-disposition") {
+            has_disposition = true;
+            for param in value.split(';').skip(1) {
+                if let Some((key, value)) = param.split_once('=') {
+                    let value = value.trim().trim_matches('"').to_owned();
+                    match key.trim() {
+                        "name" => info.name = value,
+                        "filename" => info.filename = Some(value),
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+    if !has_disposition {
+        bail!("multipart part without a Content-Disposition header");
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &str = "preamble\r\n\
+                        --XyZ\r\n\
+                        Content-Disposition: form-data; name=\"title\"\r\n\
+                        \r\n\
+                        Hello\r\n\
+                        --XyZ\r\n\
+                        Content-Disposition: form-data; name=\"upload\"; filename=\"notes.txt\"\r\n\
+                        Content-Type: text/plain\r\n\
+                        \r\n\
+                        line one\r\n--Xy not yet\r\n\
+                        --XyZ--\r\n";
+
+    /// Parses `body` fed in chunks of `chunk_len` bytes, merging consecutive data events.
+    fn parse(body: &[u8], chunk_len: usize) -> Result<Vec<Event>> {
+        let mut parser = Parser::new("XyZ");
+        let mut events = vec![];
+        let mut chunks = body.chunks(chunk_len);
+        loop {
+            match parser.next_event()? {
+                Some(Event::End) => break,
+                Some(Event::Data(data)) => match events.last_mut() {
+                    Some(Event::Data(prev)) => prev.extend(data),
+                    _ => events.push(Event::Data(data)),
+                },
+                Some(event) => events.push(event),
+                None => match chunks.next() {
+                    Some(chunk) => parser.feed(chunk),
+                    None => break,
+                },
+            }
+        }
+        parser.finish()?;
+        Ok(events)
+    }
+
+    #[test]
+    fn parts() {
+        let expected = vec![
+            Event::Part(PartInfo {
+                name: "title".into(),
+                ..Default::default()
+            }),
+            Event::Data(b"Hello".to_vec()),
+            Event::Part(PartInfo {
+                name: "upload".into(),
+                filename: Some("notes.txt".into()),
+                content_type: Some("text/plain".into()),
+            }),
+            Event::Data(b"line one\r\n--Xy not yet".to_vec()),
+        ];
+        // However the body is split, the events are the same.
+        for chunk_len in [1, 2, 5, 7, BODY.len()] {
+            assert_eq!(parse(BODY.as_bytes(), chunk_len).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn preamble() {
+        // The body may start right with a boundary.
+        let body = &BODY["preamble\r\n".len()..];
+        assert_eq!(
+            parse(body.as_bytes(), 3).unwrap(),
+            parse(BODY.as_bytes(), 3).unwrap()
+        );
+
+        // What is skipped of the preamble isn't kept around, and there is only so much of it.
+        let mut parser = Parser::new("XyZ");
+        let chunk = [b'x'; 1000];
+        let mut result = Ok(None);
+        for _ in 0..MAX_PREAMBLE_LEN / chunk.len() + 1 {
+            parser.feed(&chunk);
+            result = parser.next_event();
+            assert!(parser.buf.len() < parser.delimiter.len());
+        }
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let body = &BODY.as_bytes()[..BODY.len() - 10];
+        assert!(parse(body, 4).is_err());
+    }
+
+    #[test]
+    fn content_type_boundary() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=\"a b\"").unwrap(),
+            "a b"
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data;charset=utf-8;boundary=--x").unwrap(),
+            "--x"
+        );
+        assert!(boundary("application/json").is_err());
+        assert!(boundary("multipart/form-data").is_err());
+    }
+}
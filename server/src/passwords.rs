@@ -0,0 +1,90 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Password hashing for applications that do their own authentication.
+//!
+//! Passwords are hashed with Argon2id and a random salt.  The result is a PHC string (`$argon2id$v=19$...`) that
+//! carries the algorithm, its parameters and the salt, so hashes stay verifiable if the defaults change later.
+//! Hashes whose cost parameters exceed the defaults are rejected without being computed, as verifying a crafted
+//! hash could otherwise take as much memory and time as it asks for.
+
+use anyhow::{anyhow, Result};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Argon2, Params};
+use rand::rngs::OsRng;
+
+/// Hashes `password` with a fresh salt, returning the encoded hash.
+pub(crate) fn hash(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Cannot hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+/// Checks `password` against a hash produced by `hash()`.  The comparison takes the same time wherever the
+/// hashes differ.  A malformed `hash`, or one costlier than `hash()` makes, matches no password.
+pub(crate) fn verify(password: &str, hash: &str) -> bool {
+    let hash = match PasswordHash::new(hash) {
+        Ok(hash) => hash,
+        Err(_) => return false,
+    };
+    match Params::try_from(&hash) {
+        Ok(params) if within_default_cost(&params) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+fn within_default_cost(params: &Params) -> bool {
+    params.m_cost() <= Params::DEFAULT_M_COST
+        && params.t_cost() <= Params::DEFAULT_T_COST
+        && params.p_cost() <= Params::DEFAULT_P_COST
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let hashed = hash("correct horse battery staple").unwrap();
+        assert!(hashed.starts_with("$argon2id$"));
+        assert!(!hashed.contains("correct horse"));
+        assert!(verify("correct horse battery staple", &hashed));
+    }
+
+    #[test]
+    fn wrong_password() {
+        let hashed = hash("hunter2").unwrap();
+        assert!(!verify("hunter3", &hashed));
+        assert!(!verify("", &hashed));
+        assert!(!verify("hunter2", "not a hash"));
+        assert!(!verify("hunter2", ""));
+    }
+
+    #[test]
+    fn cost_limits() {
+        let hashed = hash("hunter2").unwrap();
+        let m_cost = format!("m={}", Params::DEFAULT_M_COST);
+        assert!(hashed.contains(&m_cost));
+        let costly = hashed.replace(&m_cost, "m=4194304");
+        assert!(!verify("hunter2", &costly));
+
+        let params = Params::new(Params::MIN_M_COST, 1, 1, None).unwrap();
+        let cheap = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password(b"hunter2", &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string();
+        assert!(verify("hunter2", &cheap));
+    }
+
+    #[test]
+    fn salted() {
+        let first = hash("hunter2").unwrap();
+        let second = hash("hunter2").unwrap();
+        assert_ne!(first, second);
+        assert!(verify("hunter2", &first));
+        assert!(verify("hunter2", &second));
+    }
+}
@@ -1,20 +1,52 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::prefix_map::PrefixMap;
-use crate::types::ObjectType;
-use anyhow::Result;
+use crate::types::{Field, ObjectType, Type};
+use crate::JsonObject;
+use anyhow::{Context, Result};
 use serde_json::{json, Value};
 use std::collections::{HashMap, HashSet};
 use std::path::Path;
-use yaml_rust::YamlLoader;
+use yaml_rust::{Yaml, YamlLoader};
 
 /// Different kinds of policies.
 #[derive(Clone)]
 pub(crate) enum Kind {
     /// How this policy transforms values read from storage.
-    Transform(fn(Value) -> Value),
+    Transform {
+        /// Name of the transformation, as given in the policy YAML.
+        name: &'static str,
+        f: fn(Value) -> Value,
+    },
     /// Field is of AuthUser type and must match the user currently logged in.
     MatchLogin,
+    /// Values are stored encrypted, and only endpoints excepted from the policy read them decrypted.  Others read
+    /// them anonymized.
+    Encrypt,
+    /// Clients may not write this field.
+    DenyWrite(WriteMode),
+}
+
+impl Kind {
+    /// Describes this kind the way it is spelled in the policy YAML.
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            Kind::Transform { name, .. } => format!("transform: {}", name),
+            Kind::MatchLogin => "transform: match_login".to_owned(),
+            Kind::Encrypt => "transform: encrypt".to_owned(),
+            Kind::DenyWrite(WriteMode::Strip) => "write: deny (strip)".to_owned(),
+            Kind::DenyWrite(WriteMode::Reject) => "write: deny (reject)".to_owned(),
+        }
+    }
+}
+
+/// What to do with a write to a field whose label denies writes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum WriteMode {
+    /// Silently drop the field from the stored value.
+    Strip,
+    /// Fail the whole store operation.
+    Reject,
 }
 
 #[derive(Clone)]
@@ -23,10 +55,21 @@ pub(crate) struct Policy {
 
     /// This policy doesn't apply when the request URI matches.
     pub(crate) except_uri: regex::Regex,
+
+    /// HTTP methods, in upper case, of the requests this policy applies to.  Empty if it applies to all of them.
+    pub(crate) methods: Vec<String>,
+}
+
+impl Policy {
+    /// Whether the request at `path` with `method` is exempt from this policy.
+    pub(crate) fn is_excepted(&self, path: &str, method: &str) -> bool {
+        self.except_uri.is_match(path)
+            || !(self.methods.is_empty() || self.methods.iter().any(|m| m == method))
+    }
 }
 
 /// Maps labels to their applicable policies.
-pub(crate) type LabelPolicies = HashMap<String, Policy>;
+pub(crate) type LabelPolicies = HashMap<String, Vec<Policy>>;
 
 #[derive(Clone, Default, Debug)]
 pub(crate) struct FieldPolicies {
@@ -34,15 +77,27 @@ pub(crate) struct FieldPolicies {
     pub(crate) transforms: HashMap<String, fn(Value) -> Value>,
     /// Names of fields that must equal the currently logged-in user.
     pub(crate) match_login: HashSet<String>,
+    /// Maps names of fields that clients may not write to what happens when they try.
+    pub(crate) write_denied: HashMap<String, WriteMode>,
+    /// Names of fields whose values are stored encrypted, so that they can't be filtered or sorted on.
+    pub(crate) encrypted: HashSet<String>,
     /// ID of the currently logged-in user.
     pub(crate) current_userid: Option<String>,
 }
 
+/// Who may access the endpoints under a path.
+#[derive(Clone, Debug)]
+pub(crate) enum PathRule {
+    /// Only logged-in users whose username matches the regex.
+    Users(regex::Regex),
+    /// Anyone, including anonymous users.
+    Public,
+}
+
 #[derive(Clone, Default, Debug)]
 pub(crate) struct UserAuthorization {
-    /// A user is authorized to access a path if the username matches the regex for the longest path prefix present
-    /// here.
-    paths: PrefixMap<regex::Regex>,
+    /// A user is authorized to access a path if it satisfies the rule for the longest path prefix present here.
+    paths: PrefixMap<PathRule>,
 }
 
 impl UserAuthorization {
@@ -50,7 +105,8 @@ impl UserAuthorization {
     pub fn is_allowed(&self, username: Option<String>, path: &Path) -> bool {
         match self.paths.longest_prefix(path) {
             None => true,
-            Some((_, u)) => match username {
+            Some((_, PathRule::Public)) => true,
+            Some((_, PathRule::Users(u))) => match username {
                 None => false, // Must be logged in if path specified a regex.
                 Some(username) => u.is_match(&username),
             },
@@ -60,17 +116,37 @@ impl UserAuthorization {
     /// Authorizes users matching a regex to execute any endpoint under this path.  Longer paths override existing
     /// prefixes.  Error if this same path has already been added.
     pub fn add(&mut self, path: &str, users: regex::Regex) -> Result<()> {
-        if self.paths.insert(path.into(), users).is_some() {
+        self.add_rule(path, PathRule::Users(users))
+    }
+
+    /// Lets anyone, logged in or not, execute any endpoint under this path.  Like `add`, this overrides rules for
+    /// shorter prefixes, so a public path can carve an exception out of a restricted parent.
+    pub fn add_public(&mut self, path: &str) -> Result<()> {
+        self.add_rule(path, PathRule::Public)
+    }
+
+    fn add_rule(&mut self, path: &str, rule: PathRule) -> Result<()> {
+        if self.paths.insert(path.into(), rule).is_some() {
             anyhow::bail!("Repeated path in user authorization: {:?}", path);
         }
         Ok(())
     }
+
+    /// Iterates over paths and the rules governing access to them.
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &PathRule)> {
+        self.paths.iter()
+    }
 }
 
 #[derive(Clone, Default)]
 pub(crate) struct VersionPolicy {
     pub(crate) labels: LabelPolicies,
+    /// Labels implicitly attached to every field whose name matches the regex.
+    pub(crate) field_patterns: Vec<(regex::Regex, String)>,
     pub(crate) user_authorization: UserAuthorization,
+    /// Whether endpoints under a path may run raw SQL queries.  Like user authorization, the longest prefix of
+    /// an endpoint's path decides.
+    pub(crate) raw_query_paths: PrefixMap<bool>,
 }
 
 #[derive(Clone, Default)]
@@ -89,11 +165,30 @@ impl Policies {
         Ok(())
     }
 
+    /// Is this username allowed to execute the endpoint at this path of `version`?  The path doesn't include the
+    /// version.
+    pub(crate) fn is_allowed(
+        &self,
+        version: &str,
+        username: Option<String>,
+        path: &Path,
+    ) -> Result<bool> {
+        match self.versions.get(version) {
+            None => anyhow::bail!(
+                "found a route, but no version object for {}/{}",
+                version,
+                path.display()
+            ),
+            Some(x) => Ok(x.user_authorization.is_allowed(username, path)),
+        }
+    }
+
     /// For field of type `ty` creates field policies.
     pub(crate) fn make_field_policies(
         &self,
         user_id: &Option<String>,
         current_path: &str,
+        current_method: &str,
         ty: &ObjectType,
     ) -> FieldPolicies {
         let mut field_policies = FieldPolicies {
@@ -103,15 +198,43 @@ impl Policies {
 
         if let Some(version) = self.versions.get(&ty.api_version) {
             for fld in ty.user_fields() {
-                for lbl in &fld.labels {
-                    if let Some(p) = version.labels.get(lbl) {
-                        if !p.except_uri.is_match(current_path) {
-                            match p.kind {
-                                Kind::Transform(f) => {
-                                    field_policies.transforms.insert(fld.name.clone(), f);
+                let pattern_labels = version
+                    .field_patterns
+                    .iter()
+                    .filter(|(re, _)| re.is_match(&fld.name))
+                    .map(|(_, lbl)| lbl);
+                for lbl in fld.labels.iter().chain(pattern_labels) {
+                    for p in version.labels.get(lbl).into_iter().flatten() {
+                        let excepted = p.is_excepted(current_path, current_method);
+                        match p.kind {
+                            // Values are encrypted no matter who writes them.
+                            Kind::Encrypt => {
+                                field_policies.encrypted.insert(fld.name.clone());
+                                let transforms = &mut field_policies.transforms;
+                                if excepted {
+                                    // Other labels still get to anonymize the value.
+                                    transforms
+                                        .entry(fld.name.clone())
+                                        .or_insert(crate::encryption::decrypt);
+                                } else {
+                                    transforms.insert(fld.name.clone(), anonymize);
                                 }
-                                Kind::MatchLogin => {
-                                    field_policies.match_login.insert(fld.name.clone());
+                            }
+                            _ if excepted => {}
+                            Kind::Transform { f, .. } => {
+                                field_policies.transforms.insert(fld.name.clone(), f);
+                            }
+                            Kind::MatchLogin => {
+                                field_policies.match_login.insert(fld.name.clone());
+                            }
+                            Kind::DenyWrite(mode) => {
+                                let entry = field_policies
+                                    .write_denied
+                                    .entry(fld.name.clone())
+                                    .or_insert(mode);
+                                // Rejecting is stricter than stripping, so it wins when labels disagree.
+                                if mode == WriteMode::Reject {
+                                    *entry = mode;
                                 }
                             }
                         }
@@ -121,9 +244,92 @@ impl Policies {
         }
         field_policies
     }
+
+    /// Applies write policies to `value`, which is about to be stored as type `ty`.  Returns the value with
+    /// write-denied fields stripped and encrypted fields encrypted, or an error if any write-denied field is in
+    /// reject mode.  Nested entities are checked against their own type's policies.
+    pub(crate) fn enforce_write_policies(
+        &self,
+        user_id: &Option<String>,
+        current_path: &str,
+        current_method: &str,
+        ty: &ObjectType,
+        value: &JsonObject,
+    ) -> Result<JsonObject> {
+        let field_policies = self.make_field_policies(user_id, current_path, current_method, ty);
+        let mut ret = JsonObject::new();
+        for (name, field_value) in value.iter() {
+            match field_policies.write_denied.get(name) {
+                Some(WriteMode::Reject) => {
+                    anyhow::bail!(
+                        "Cannot write field {} of type {}: denied by policy",
+                        name,
+                        ty.name()
+                    );
+                }
+                Some(WriteMode::Strip) => continue,
+                None => {}
+            }
+            let field_value = match (ty.get_field(name).map(|f| &f.type_), field_value) {
+                (Some(Type::Object(nested_ty)), Value::Object(nested_value)) => {
+                    Value::Object(self.enforce_write_policies(
+                        user_id,
+                        current_path,
+                        current_method,
+                        nested_ty,
+                        nested_value,
+                    )?)
+                }
+                _ if field_policies.encrypted.contains(name) => {
+                    crate::encryption::encrypt(name, field_value)?
+                }
+                _ => field_value.clone(),
+            };
+            ret.insert(name.clone(), field_value);
+        }
+        Ok(ret)
+    }
 }
 
 impl VersionPolicy {
+    /// Are the values of `field` stored encrypted?  They are for every endpoint, excepted or not.
+    pub(crate) fn is_encrypted(&self, field: &Field) -> bool {
+        let pattern_labels = self
+            .field_patterns
+            .iter()
+            .filter(|(re, _)| re.is_match(&field.name))
+            .map(|(_, lbl)| lbl);
+        field
+            .labels
+            .iter()
+            .chain(pattern_labels)
+            .flat_map(|lbl| self.labels.get(lbl).into_iter().flatten())
+            .any(|p| matches!(p.kind, Kind::Encrypt))
+    }
+
+    /// Fails if `ty` has a unique encrypted field.  Equal values are stored differently each time, so
+    /// the database can't tell whether they repeat.
+    pub(crate) fn check_encrypted_fields(&self, ty: &ObjectType) -> Result<()> {
+        for field in ty.user_fields().filter(|f| self.is_encrypted(f)) {
+            let in_constraint = ty
+                .unique_constraints()
+                .iter()
+                .any(|c| c.contains(&field.name));
+            anyhow::ensure!(
+                !field.is_unique && !in_constraint,
+                "field `{}` of type {} is encrypted, so it can't be unique",
+                field.name,
+                ty.name()
+            );
+        }
+        Ok(())
+    }
+
+    /// May the endpoint at this path run raw SQL queries?  None may, unless the policy allows it.
+    pub(crate) fn allows_raw_queries(&self, path: &Path) -> bool {
+        matches!(self.raw_query_paths.longest_prefix(path), Some((_, true)))
+    }
+
     pub(crate) fn from_yaml<S: AsRef<str>>(config: S) -> Result<Self> {
         let mut policies = Self::default();
         let mut labels = vec![];
@@ -138,31 +344,68 @@ impl VersionPolicy {
                 labels.push(name.to_owned());
                 debug!("Applying policy for label {:?}", name);
                 let pattern = label["except_uri"].as_str().unwrap_or("^$"); // ^$ never matches; each path has at least a '/' in it.
+                let except_uri = compile_regex(pattern, "except_uri", "label", name)?;
+                let methods = parse_methods(&label["methods"], name)?;
+                if let Some(field_pattern) = label["field_pattern"].as_str() {
+                    let field_pattern =
+                        compile_regex(field_pattern, "field_pattern", "label", name)?;
+                    policies
+                        .field_patterns
+                        .push((field_pattern, name.to_owned()));
+                }
+                let label_policies = policies.labels.entry(name.to_owned()).or_default();
 
                 match label["transform"].as_str() {
                     Some("anonymize") => {
-                        policies.labels.insert(
-                            name.to_owned(),
-                            Policy {
-                                kind: Kind::Transform(crate::policies::anonymize),
-                                except_uri: regex::Regex::new(pattern)?,
+                        label_policies.push(Policy {
+                            kind: Kind::Transform {
+                                name: "anonymize",
+                                f: crate::policies::anonymize,
                             },
-                        );
+                            except_uri: except_uri.clone(),
+                            methods: methods.clone(),
+                        });
                     }
                     Some("match_login") => {
-                        policies.labels.insert(
-                            name.to_owned(),
-                            Policy {
-                                kind: Kind::MatchLogin,
-                                except_uri: regex::Regex::new(pattern)?,
-                            },
-                        );
+                        label_policies.push(Policy {
+                            kind: Kind::MatchLogin,
+                            except_uri: except_uri.clone(),
+                            methods: methods.clone(),
+                        });
+                    }
+                    Some("encrypt") => {
+                        label_policies.push(Policy {
+                            kind: Kind::Encrypt,
+                            except_uri: except_uri.clone(),
+                            methods: methods.clone(),
+                        });
                     }
                     Some(x) => {
                         anyhow::bail!("unknown transform: {} for label {}", x, name);
                     }
                     None => {}
                 };
+
+                match label["write"].as_str() {
+                    Some("deny") => {
+                        let mode = match label["write_mode"].as_str() {
+                            Some("reject") | None => WriteMode::Reject,
+                            Some("strip") => WriteMode::Strip,
+                            Some(x) => {
+                                anyhow::bail!("unknown write_mode: {} for label {}", x, name);
+                            }
+                        };
+                        label_policies.push(Policy {
+                            kind: Kind::DenyWrite(mode),
+                            except_uri: except_uri.clone(),
+                            methods: methods.clone(),
+                        });
+                    }
+                    Some("allow") | None => {}
+                    Some(x) => {
+                        anyhow::bail!("unknown write policy: {} for label {}", x, name);
+                    }
+                };
             }
             for endpoint in config["endpoints"]
                 .as_vec()
@@ -171,18 +414,576 @@ impl VersionPolicy {
             {
                 if let Some(path) = endpoint["path"].as_str() {
                     if let Some(users) = endpoint["users"].as_str() {
-                        policies
-                            .user_authorization
-                            .add(path, regex::Regex::new(users)?)?;
+                        let users = compile_regex(users, "users", "endpoint", path)?;
+                        policies.user_authorization.add(path, users)?;
                     }
+                    let allow = match endpoint["raw_queries"].as_str() {
+                        Some("allow") => true,
+                        Some("deny") => false,
+                        None => continue,
+                        Some(x) => {
+                            anyhow::bail!(
+                                "unknown raw_queries policy: {} for endpoint {}",
+                                x,
+                                path
+                            );
+                        }
+                    };
+                    policies.raw_query_paths.insert(path.into(), allow);
                 }
             }
+            for path in config["public_paths"]
+                .as_vec()
+                .get_or_insert(&[].into())
+                .iter()
+            {
+                let path = path.as_str().ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "couldn't parse yaml: public path is not a string: {:?}",
+                        path
+                    )
+                })?;
+                policies.user_authorization.add_public(path)?;
+            }
         }
         Ok(policies)
     }
 }
 
+/// Reads the `methods` of a label, which may be a single method or a list of them.
+fn parse_methods(methods: &Yaml, label: &str) -> Result<Vec<String>> {
+    let methods = match methods {
+        Yaml::BadValue => return Ok(vec![]),
+        Yaml::Array(methods) => methods.iter().collect(),
+        method => vec![method],
+    };
+    methods
+        .into_iter()
+        .map(|method| match method.as_str() {
+            Some(method) if !method.is_empty() => Ok(method.to_uppercase()),
+            _ => anyhow::bail!(
+                "couldn't parse yaml: invalid method {:?} in `methods` of label {}",
+                method,
+                label
+            ),
+        })
+        .collect()
+}
+
+/// Compiles a regex from the policy YAML, pointing at where it came from if it's invalid.
+fn compile_regex(pattern: &str, key: &str, entry_kind: &str, entry: &str) -> Result<regex::Regex> {
+    regex::Regex::new(pattern).with_context(|| {
+        format!(
+            "invalid regex {:?} in `{}` of {} {}",
+            pattern, key, entry_kind, entry
+        )
+    })
+}
+
 pub(crate) fn anonymize(_: Value) -> Value {
     // TODO: use type-specific anonymization.
     json!("xxxxx")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::expr::BinaryOp;
+    use crate::datastore::query::tests::{
+        binary, fetch_rows, fetch_rows_with_plan, make_field, make_object, make_type_system,
+        setup_clear_db, VERSION,
+    };
+    use crate::datastore::query::{QueryPlan, RequestContext, TargetDatabase};
+    use crate::encryption::tests::{lock_keys, use_keys};
+    use crate::types::{Field, NewField};
+    use std::sync::Arc;
+
+    fn labeled_field(name: &str, label: &str) -> Field {
+        let desc = NewField::new(name, Type::String, VERSION).unwrap();
+        Field::new(desc, vec![label.to_owned()], None, true, false, false)
+    }
+
+    fn make_policies(yaml: &str) -> Policies {
+        let mut policies = Policies::default();
+        policies.add_from_yaml(VERSION, yaml).unwrap();
+        policies
+    }
+
+    fn person() -> Arc<ObjectType> {
+        make_object(
+            "Person",
+            vec![
+                make_field("name", Type::String),
+                labeled_field("internalScore", "internal"),
+            ],
+        )
+    }
+
+    fn input() -> JsonObject {
+        json!({"name": "Alan", "internalScore": "100"})
+            .as_object()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn user_authorization_segments() {
+        let policy = VersionPolicy::from_yaml(
+            r#"
+endpoints:
+  - path: /admin
+    users: ^admin@example.com$
+"#,
+        )
+        .unwrap();
+        let auth = &policy.user_authorization;
+        let admin = Some("admin@example.com".to_owned());
+        let other = Some("other@example.com".to_owned());
+        assert!(auth.is_allowed(admin.clone(), Path::new("/admin")));
+        assert!(auth.is_allowed(admin, Path::new("/admin/users")));
+        assert!(!auth.is_allowed(other.clone(), Path::new("/admin")));
+        assert!(!auth.is_allowed(other.clone(), Path::new("/admin/users")));
+        assert!(auth.is_allowed(other, Path::new("/administrators")));
+        assert!(auth.is_allowed(None, Path::new("/administrators")));
+    }
+
+    #[test]
+    fn public_paths() {
+        let policy = VersionPolicy::from_yaml(
+            r#"
+endpoints:
+  - path: /
+    users: .*
+  - path: /admin
+    users: ^admin@example.com$
+public_paths:
+  - /auth
+  - /health
+  - /admin/status
+"#,
+        )
+        .unwrap();
+        let auth = &policy.user_authorization;
+        let admin = Some("admin@example.com".to_owned());
+        let other = Some("other@example.com".to_owned());
+
+        // Everything not explicitly public requires a login.
+        assert!(!auth.is_allowed(None, Path::new("/comments")));
+        assert!(auth.is_allowed(other.clone(), Path::new("/comments")));
+        assert!(!auth.is_allowed(None, Path::new("/authors")));
+
+        // Public paths are open to anyone.
+        assert!(auth.is_allowed(None, Path::new("/auth")));
+        assert!(auth.is_allowed(None, Path::new("/auth/signup")));
+        assert!(auth.is_allowed(None, Path::new("/health")));
+        assert!(auth.is_allowed(other.clone(), Path::new("/health")));
+
+        // A public subpath overrides its restricted parent, but only for that subpath.
+        assert!(auth.is_allowed(None, Path::new("/admin/status")));
+        assert!(auth.is_allowed(other.clone(), Path::new("/admin/status")));
+        assert!(!auth.is_allowed(None, Path::new("/admin/users")));
+        assert!(!auth.is_allowed(other, Path::new("/admin/users")));
+        assert!(auth.is_allowed(admin, Path::new("/admin/users")));
+    }
+
+    #[test]
+    fn decisions_of_a_version() {
+        let yaml = r#"
+endpoints:
+  - path: /
+    users: .*
+  - path: /admin
+    users: ^admin@example.com$
+public_paths:
+  - /health
+"#;
+        let mut policies = make_policies(yaml);
+        policies.add_from_yaml("open", "").unwrap();
+        let auth = &policies.versions[VERSION].user_authorization;
+        let admin = Some("admin@example.com".to_owned());
+        let other = Some("other@example.com".to_owned());
+        for user in [None, other, admin] {
+            for path in ["/", "/comments", "/health", "/admin", "/admin/users"] {
+                let path = Path::new(path);
+                assert_eq!(
+                    policies.is_allowed(VERSION, user.clone(), path).unwrap(),
+                    auth.is_allowed(user.clone(), path),
+                    "{:?} at {:?}",
+                    user,
+                    path
+                );
+                // Without rules, anyone is allowed anywhere.
+                assert!(policies.is_allowed("open", user.clone(), path).unwrap());
+            }
+        }
+        // The root rule denies anonymous users by default.
+        assert!(!policies
+            .is_allowed(VERSION, None, Path::new("/comments"))
+            .unwrap());
+        assert!(policies.is_allowed("nope", None, Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn raw_queries() {
+        let policy = VersionPolicy::from_yaml(
+            r#"
+endpoints:
+  - path: /reports
+    raw_queries: allow
+  - path: /reports/public
+    raw_queries: deny
+  - path: /admin
+    users: ^admin@example.com$
+"#,
+        )
+        .unwrap();
+        assert!(policy.allows_raw_queries(Path::new("/reports")));
+        assert!(policy.allows_raw_queries(Path::new("/reports/monthly")));
+        assert!(!policy.allows_raw_queries(Path::new("/reports/public/totals")));
+        assert!(!policy.allows_raw_queries(Path::new("/reportsx")));
+        assert!(!policy.allows_raw_queries(Path::new("/admin")));
+
+        assert!(VersionPolicy::from_yaml(
+            r#"
+endpoints:
+  - path: /reports
+    raw_queries: always
+"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn public_path_repeated() {
+        assert!(VersionPolicy::from_yaml(
+            r#"
+endpoints:
+  - path: /auth
+    users: .*
+public_paths:
+  - /auth
+"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn invalid_regex() {
+        let err = VersionPolicy::from_yaml(
+            r#"
+labels:
+  - name: pii
+    transform: anonymize
+    except_uri: /comments(
+"#,
+        )
+        .err()
+        .unwrap();
+        let msg = format!("{:#}", err);
+        assert!(msg.contains("except_uri"), "{}", msg);
+        assert!(msg.contains("pii"), "{}", msg);
+
+        let err = VersionPolicy::from_yaml(
+            r#"
+endpoints:
+  - path: /comments
+    users: "[admin"
+"#,
+        )
+        .err()
+        .unwrap();
+        let msg = format!("{:#}", err);
+        assert!(msg.contains("users"), "{}", msg);
+        assert!(msg.contains("/comments"), "{}", msg);
+    }
+
+    #[test]
+    fn field_pattern() {
+        let policies = make_policies(
+            r#"
+labels:
+  - name: pii
+    transform: anonymize
+    field_pattern: _email$
+"#,
+        );
+        let user = make_object(
+            "User",
+            vec![
+                make_field("name", Type::String),
+                make_field("work_email", Type::String),
+                make_field("home_email", Type::String),
+                make_field("email_verified", Type::Boolean),
+            ],
+        );
+        let field_policies = policies.make_field_policies(&None, "/users", "GET", &user);
+        let mut transformed: Vec<_> = field_policies.transforms.keys().cloned().collect();
+        transformed.sort();
+        assert_eq!(transformed, vec!["home_email", "work_email"]);
+    }
+
+    #[test]
+    fn write_deny_strip() {
+        let policies = make_policies(
+            r#"
+labels:
+  - name: internal
+    write: deny
+    write_mode: strip
+"#,
+        );
+        let stored = policies
+            .enforce_write_policies(&None, "/people", "POST", &person(), &input())
+            .unwrap();
+        assert_eq!(stored.get("name"), Some(&json!("Alan")));
+        assert_eq!(stored.get("internalScore"), None);
+    }
+
+    #[test]
+    fn write_deny_reject() {
+        let policies = make_policies(
+            r#"
+labels:
+  - name: internal
+    write: deny
+    write_mode: reject
+"#,
+        );
+        let err = policies
+            .enforce_write_policies(&None, "/people", "POST", &person(), &input())
+            .unwrap_err();
+        assert!(err.to_string().contains("internalScore"));
+
+        let mut allowed = input();
+        allowed.remove("internalScore");
+        let stored = policies
+            .enforce_write_policies(&None, "/people", "POST", &person(), &allowed)
+            .unwrap();
+        assert_eq!(stored, allowed);
+    }
+
+    #[test]
+    fn write_deny_except_uri() {
+        let policies = make_policies(
+            r#"
+labels:
+  - name: internal
+    write: deny
+    except_uri: ^/admin
+"#,
+        );
+        let stored = policies
+            .enforce_write_policies(&None, "/admin/people", "POST", &person(), &input())
+            .unwrap();
+        assert_eq!(stored, input());
+        assert!(policies
+            .enforce_write_policies(&None, "/people", "POST", &person(), &input())
+            .is_err());
+    }
+
+    #[test]
+    fn write_deny_nested() {
+        let company = make_object(
+            "Company",
+            vec![
+                make_field("name", Type::String),
+                make_field("ceo", Type::Object(person())),
+            ],
+        );
+        let policies = make_policies(
+            r#"
+labels:
+  - name: internal
+    write: deny
+    write_mode: strip
+"#,
+        );
+        let value = json!({"name": "ChiselStrike", "ceo": input()});
+        let stored = policies
+            .enforce_write_policies(
+                &None,
+                "/companies",
+                "POST",
+                &company,
+                value.as_object().unwrap(),
+            )
+            .unwrap();
+        assert_eq!(stored["ceo"], json!({"name": "Alan"}));
+    }
+
+    #[test]
+    fn methods_scope_writes() {
+        let policies = make_policies(
+            r#"
+labels:
+  - name: internal
+    write: deny
+    methods: [post, PUT]
+"#,
+        );
+        // The field is visible to reads, whatever the method.
+        let field_policies = policies.make_field_policies(&None, "/people", "GET", &person());
+        assert!(field_policies.transforms.is_empty());
+        let field_policies = policies.make_field_policies(&None, "/people", "POST", &person());
+        assert!(field_policies.transforms.is_empty());
+
+        // But it can only be written by requests with other methods.
+        let err = policies
+            .enforce_write_policies(&None, "/people", "POST", &person(), &input())
+            .unwrap_err();
+        assert!(err.to_string().contains("internalScore"));
+        assert!(policies
+            .enforce_write_policies(&None, "/people", "PUT", &person(), &input())
+            .is_err());
+        let stored = policies
+            .enforce_write_policies(&None, "/people", "PATCH", &person(), &input())
+            .unwrap();
+        assert_eq!(stored, input());
+    }
+
+    #[test]
+    fn methods_scope_transforms() {
+        let policies = make_policies(
+            r#"
+labels:
+  - name: internal
+    transform: anonymize
+    methods: GET
+  - name: internal
+    write: deny
+    methods: POST
+"#,
+        );
+        let get = policies.make_field_policies(&None, "/people", "GET", &person());
+        assert!(get.transforms.contains_key("internalScore"));
+        assert!(get.write_denied.is_empty());
+        let post = policies.make_field_policies(&None, "/people", "POST", &person());
+        assert!(post.transforms.is_empty());
+        assert_eq!(
+            post.write_denied.get("internalScore"),
+            Some(&WriteMode::Reject)
+        );
+    }
+
+    #[test]
+    fn invalid_methods() {
+        let err = VersionPolicy::from_yaml(
+            r#"
+labels:
+  - name: internal
+    write: deny
+    methods: [POST, 3]
+"#,
+        )
+        .err()
+        .unwrap();
+        let msg = err.to_string();
+        assert!(msg.contains("methods"), "{}", msg);
+        assert!(msg.contains("internal"), "{}", msg);
+    }
+
+    #[tokio::test]
+    async fn encrypt() {
+        let _lock = lock_keys();
+        use_keys(json!("secret"));
+        let policies = make_policies(
+            r#"
+labels:
+  - name: internal
+    transform: encrypt
+    except_uri: ^/admin
+"#,
+        );
+        let ty = person();
+        let value = policies
+            .enforce_write_policies(&None, "/people", "POST", &ty, &input())
+            .unwrap();
+        let qe = setup_clear_db(&[&ty]).await;
+        qe.add_row(&ty, &value, None).await.unwrap();
+
+        // The column holds ciphertext.
+        let stored = fetch_rows(&qe, &ty).await;
+        let score = stored[0]["internalScore"].as_str().unwrap();
+        assert!(score.starts_with("enc1:"), "{}", score);
+        assert_eq!(stored[0]["name"], "Alan");
+
+        let ts = make_type_system(&[&ty]);
+        let read = |path: &str| {
+            let context = RequestContext {
+                policies: &policies,
+                ts: &ts,
+                api_version: VERSION.to_owned(),
+                user_id: None,
+                path: path.to_owned(),
+                method: "GET".to_owned(),
+            };
+            let spec = serde_json::from_value(json!({"typeName": "Person"})).unwrap();
+            QueryPlan::from_query_spec(&context, spec).unwrap()
+        };
+        let rows = fetch_rows_with_plan(&qe, read("/admin/people")).await;
+        assert_eq!(rows[0]["internalScore"], "100");
+        let rows = fetch_rows_with_plan(&qe, read("/people")).await;
+        assert_eq!(rows[0]["internalScore"], "xxxxx");
+    }
+
+    #[test]
+    fn encrypted_fields_cant_be_compared() {
+        let yaml = r#"
+labels:
+  - name: internal
+    transform: encrypt
+    except_uri: ^/admin
+"#;
+        let policies = make_policies(yaml);
+        let ty = person();
+        let ts = make_type_system(&[&ty]);
+        // Not even where the values are read decrypted.
+        let context = RequestContext {
+            policies: &policies,
+            ts: &ts,
+            api_version: VERSION.to_owned(),
+            user_id: None,
+            path: "/admin/people".to_owned(),
+            method: "GET".to_owned(),
+        };
+        let build = |spec: Value| {
+            let spec = serde_json::from_value(spec).unwrap();
+            QueryPlan::from_query_spec(&context, spec)
+                .unwrap()
+                .build_query(&TargetDatabase::Sqlite)
+        };
+        let err = build(json!({
+            "typeName": "Person",
+            "filter": binary(&["internalScore"], BinaryOp::Eq, "100".into()),
+        }))
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expression error: field 'internalScore' of entity 'Person' is encrypted, so it can't be filtered on"
+        );
+        let err = build(json!({
+            "typeName": "Person",
+            "sort": [{"fieldName": "internalScore", "ascending": true}],
+        }))
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "field 'internalScore' of entity 'Person' is encrypted, so it can't be sorted on"
+        );
+        assert!(build(
+            json!({"typeName": "Person", "sort": [{"fieldName": "name", "ascending": true}]})
+        )
+        .is_ok());
+
+        let version = VersionPolicy::from_yaml(yaml).unwrap();
+        version.check_encrypted_fields(&ty).unwrap();
+        let mut score = labeled_field("internalScore", "internal");
+        score.is_unique = true;
+        let unique = make_object("Person", vec![score]);
+        let err = version.check_encrypted_fields(&unique).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "field `internalScore` of type Person is encrypted, so it can't be unique"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Reading a stream ahead of whoever consumes it.
+
+use futures::{Stream, StreamExt};
+use std::pin::Pin;
+
+/// Reads up to `high_water_mark` items of `stream` ahead of the consumer of the returned stream, so that a slow
+/// producer and a slow consumer work at the same time instead of taking turns.  A `high_water_mark` of 0 reads
+/// nothing ahead, returning `stream` as it is.
+///
+/// The items are read by a task of the current `LocalSet`, which stops and drops `stream` when the returned stream
+/// is dropped, once the item it is reading at that time arrives.
+pub(crate) fn prefetch<S>(stream: S, high_water_mark: usize) -> Pin<Box<dyn Stream<Item = S::Item>>>
+where
+    S: Stream + 'static,
+{
+    if high_water_mark == 0 {
+        return Box::pin(stream);
+    }
+    let (sender, receiver) = async_channel::bounded(high_water_mark);
+    tokio::task::spawn_local(async move {
+        futures::pin_mut!(stream);
+        while let Some(item) = stream.next().await {
+            if sender.send(item).await.is_err() {
+                break;
+            }
+        }
+    });
+    Box::pin(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use tokio::task::LocalSet;
+    use tokio::time::{sleep, Instant};
+
+    /// A stream of `count` chunks that takes `delay` to produce each, and counts how many it produced.
+    fn producer(
+        count: usize,
+        delay: Duration,
+        produced: Rc<Cell<usize>>,
+    ) -> impl Stream<Item = Box<[u8]>> {
+        futures::stream::unfold(0, move |i| {
+            let produced = produced.clone();
+            async move {
+                if i == count {
+                    return None;
+                }
+                sleep(delay).await;
+                produced.set(produced.get() + 1);
+                Some((vec![0u8; 16 * 1024].into_boxed_slice(), i + 1))
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn reads_ahead_up_to_the_high_water_mark() {
+        LocalSet::new()
+            .run_until(async {
+                let produced = Rc::new(Cell::new(0));
+                let mut stream = prefetch(producer(10, Duration::ZERO, produced.clone()), 3);
+                sleep(Duration::from_millis(50)).await;
+                // Three in the buffer, and one waiting for room.
+                assert_eq!(produced.get(), 4);
+
+                let mut received = 0;
+                while stream.next().await.is_some() {
+                    received += 1;
+                }
+                assert_eq!(received, 10);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn stops_reading_when_dropped() {
+        LocalSet::new()
+            .run_until(async {
+                let produced = Rc::new(Cell::new(0));
+                let mut stream = prefetch(producer(100, Duration::ZERO, produced.clone()), 2);
+                stream.next().await.unwrap();
+                drop(stream);
+                sleep(Duration::from_millis(50)).await;
+                assert!(produced.get() <= 4, "produced {}", produced.get());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn no_high_water_mark_reads_on_demand() {
+        LocalSet::new()
+            .run_until(async {
+                let produced = Rc::new(Cell::new(0));
+                let mut stream = prefetch(producer(10, Duration::ZERO, produced.clone()), 0);
+                sleep(Duration::from_millis(50)).await;
+                assert_eq!(produced.get(), 0);
+                stream.next().await.unwrap();
+                assert_eq!(produced.get(), 1);
+            })
+            .await;
+    }
+
+    /// A slow consumer, like a client on a slow network, reads a response from a producer that is fast but not
+    /// instant.  Reading ahead lets the two overlap, so the whole response takes about half as long.  The clock is
+    /// paused and only moves on when every task waits for it, so the times are exact.
+    #[tokio::test(start_paused = true)]
+    async fn reading_ahead_overlaps_with_consumer() {
+        const CHUNKS: usize = 50;
+        const DELAY: Duration = Duration::from_millis(4);
+        async fn read_all(high_water_mark: usize) -> Duration {
+            LocalSet::new()
+                .run_until(async {
+                    let produced = Rc::new(Cell::new(0));
+                    let stream = producer(CHUNKS, DELAY, produced);
+                    let mut stream = prefetch(stream, high_water_mark);
+                    let start = Instant::now();
+                    let mut bytes = 0;
+                    while let Some(chunk) = stream.next().await {
+                        bytes += chunk.len();
+                        sleep(DELAY).await;
+                    }
+                    assert_eq!(bytes, CHUNKS * 16 * 1024);
+                    start.elapsed()
+                })
+                .await
+        }
+        // Producing and consuming take turns.
+        assert_eq!(read_all(0).await, DELAY * (2 * CHUNKS) as u32);
+        // Each chunk is consumed while the next one is produced.
+        assert_eq!(read_all(4).await, DELAY * (CHUNKS + 1) as u32);
+    }
+}
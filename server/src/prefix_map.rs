@@ -18,7 +18,8 @@ impl<T> Default for PrefixMap<T> {
 }
 
 impl<T> PrefixMap<T> {
-    /// Returns the longest map entry whose key is a prefix of path, if one exists.
+    /// Returns the longest map entry whose key is a prefix of path, if one exists.  Prefixes are matched on whole
+    /// path segments, so `/admin` is a prefix of `/admin/users` but not of `/administrators`.
     pub(crate) fn longest_prefix(&self, path: &Path) -> Option<(&Path, &T)> {
         let path_range = (Bound::Unbounded, Bound::Included(path));
         let tree_range = self.map.range::<Path, _>(path_range);
@@ -95,4 +96,18 @@ mod tests {
         assert_longest_prefix!(tt, "/a/bb/c/d", "/a/bb/c");
         assert_longest_prefix!(tt, "/a/b/d", "/a/b");
     }
+
+    #[test]
+    fn segment_boundaries() {
+        let map = BTreeMap::from([entry("/admin"), entry("/admin/users")]);
+        let tt = PrefixMap { map };
+        assert_longest_prefix!(tt, "/admin", "/admin");
+        assert_longest_prefix!(tt, "/admin/", "/admin");
+        assert_longest_prefix!(tt, "/admin/settings", "/admin");
+        assert_longest_prefix!(tt, "/admin/users", "/admin/users");
+        assert_longest_prefix!(tt, "/admin/users/1", "/admin/users");
+        assert_longest_prefix!(tt, "/admin/usersx", "/admin");
+        assert_eq!(lp("/administrators", &tt), None);
+        assert_eq!(lp("/adm", &tt), None);
+    }
 }
@@ -2,42 +2,97 @@
 
 use crate::api::{ApiInfo, RequestPath};
 use crate::chisel;
+use crate::datastore::named_query::NamedQuery;
+use crate::datastore::query::RequestContext;
 use crate::datastore::{MetaService, QueryEngine};
 use crate::deno;
 use crate::deno::mutate_policies;
 use crate::deno::remove_type_version;
 use crate::deno::set_type_system;
-use crate::policies::{Policies, VersionPolicy};
+use crate::policies::{Kind, PathRule, Policies, VersionPolicy};
 use crate::prefix_map::PrefixMap;
 use crate::runtime;
 use crate::server::CommandTrait;
 use crate::server::CoordinatorChannel;
 use crate::types::AuthOrNot::IsNotAuth;
 use crate::types::{Field, NewField, NewObject, ObjectType, Type, TypeSystem, TypeSystemError};
+use crate::JsonObject;
 use anyhow::{Context, Result};
 use async_lock::Mutex;
 use chisel::chisel_rpc_server::{ChiselRpc, ChiselRpcServer};
 use chisel::{
     ChiselApplyRequest, ChiselApplyResponse, ChiselDeleteRequest, ChiselDeleteResponse,
-    DescribeRequest, DescribeResponse, PopulateRequest, PopulateResponse, RestartRequest,
-    RestartResponse, StatusRequest, StatusResponse,
+    DescribeRequest, DescribeResponse, ExportPoliciesRequest, ExportPoliciesResponse,
+    GetEndpointRequest, GetEndpointResponse, ListPolicyVersionsRequest, ListPolicyVersionsResponse,
+    PopulateRequest, PopulateResponse, ReloadPoliciesRequest, ReloadPoliciesResponse,
+    RestartRequest, RestartResponse, SetConfigRequest, SetConfigResponse, SetMiddlewareRequest,
+    SetMiddlewareResponse, SetNamedQueryRequest, SetNamedQueryResponse, StatusRequest,
+    StatusResponse,
 };
 use futures::FutureExt;
 use std::collections::{BTreeSet, HashMap};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tonic::{transport::Server, Request, Response, Status};
+use tonic::{transport::Server, Code, Request, Response, Status};
+
+/// An error caused by the request rather than by the server, which is reported to the client with
+/// a gRPC code other than `Internal`.
+#[derive(Debug, thiserror::Error)]
+enum RequestError {
+    /// The request is malformed, or asks for something that can't be done.
+    #[error("{0}")]
+    InvalidArgument(String),
+    /// The request defines the same thing more than once.
+    #[error("{0}")]
+    AlreadyExists(String),
+    /// The request refers to something that doesn't exist.
+    #[error("{0}")]
+    NotFound(String),
+}
+
+/// Converts the error of an RPC method into a `Status` with the code of the `RequestError` in its
+/// chain, if any, or `Internal` otherwise.
+fn to_status(e: anyhow::Error) -> Status {
+    let code = match e.downcast_ref::<RequestError>() {
+        Some(RequestError::InvalidArgument(_)) => Code::InvalidArgument,
+        Some(RequestError::AlreadyExists(_)) => Code::AlreadyExists,
+        Some(RequestError::NotFound(_)) => Code::NotFound,
+        None => Code::Internal,
+    };
+    Status::new(code, format!("{:?}", e))
+}
+
+fn invalid(msg: impl Into<String>) -> RequestError {
+    RequestError::InvalidArgument(msg.into())
+}
+
+/// Marks `e` as caused by the request, keeping its message.
+fn as_invalid(e: impl Into<anyhow::Error>) -> RequestError {
+    invalid(format!("{:?}", e.into()))
+}
+
+/// The policy file of a request, which has at most one.  No file means no policies.
+fn single_policy(policies: &[chisel::PolicyUpdateRequest]) -> Result<&str> {
+    anyhow::ensure!(
+        policies.len() <= 1,
+        invalid("Currently only one policy file supported")
+    );
+    Ok(policies
+        .get(0)
+        .map(|x| x.policy_config.as_ref())
+        .unwrap_or(""))
+}
 
 fn validate_api_version(version: &str) -> Result<()> {
     anyhow::ensure!(
         version.is_ascii(),
-        "api version cannot have non-ascii characters"
+        invalid("api version cannot have non-ascii characters")
     );
     let v = regex::Regex::new(r"^[-_[[:alnum:]]]+$").unwrap();
     anyhow::ensure!(
         v.is_match(version),
-        "api version can only be alphanumeric, _ or -"
+        invalid("api version can only be alphanumeric, _ or -")
     );
     Ok(())
 }
@@ -126,7 +181,7 @@ impl RpcService {
 
         anyhow::ensure!(
             "__chiselstrike" != &api_version,
-            "__chiselstrike is a reserved version name"
+            invalid("__chiselstrike is a reserved version name")
         );
         state.versions.remove(&api_version);
 
@@ -139,12 +194,17 @@ impl RpcService {
 
         meta.delete_policy_version(&mut transaction, &api_version)
             .await?;
+        meta.delete_named_queries(&mut transaction, &api_version)
+            .await?;
+        meta.delete_middleware(&mut transaction, &api_version)
+            .await?;
 
         for ty in to_remove.iter() {
             meta.remove_type(&mut transaction, ty).await?;
         }
 
         MetaService::commit_transaction(transaction).await?;
+        let named_queries = meta.load_named_queries().await?;
 
         let query_engine = &state.query_engine;
         let mut transaction = query_engine.start_transaction().await?;
@@ -161,6 +221,7 @@ impl RpcService {
         let version = api_version.clone();
 
         let cmd = send_command!({
+            deno::set_middleware(&version, None).await?;
             remove_type_version(&version).await;
 
             mutate_policies(move |policies| {
@@ -168,6 +229,8 @@ impl RpcService {
             })
             .await;
 
+            deno::set_named_queries(named_queries).await;
+
             let runtime = runtime::get();
             runtime.api.remove_routes(&prefix);
             Ok(())
@@ -179,6 +242,209 @@ impl RpcService {
         }))
     }
 
+    /// Set or remove the middleware that wraps every endpoint of a version
+    async fn set_middleware_aux(
+        &self,
+        request: Request<SetMiddlewareRequest>,
+    ) -> Result<Response<SetMiddlewareResponse>> {
+        let SetMiddlewareRequest { code, version } = request.into_inner();
+        validate_api_version(&version)?;
+        let state = self.state.lock().await;
+
+        // Load the code before persisting it, so that a broken middleware doesn't keep chiseld
+        // from starting.
+        let middleware = code.clone();
+        let api_version = version.clone();
+        let cmd = send_command!({
+            deno::set_middleware(&api_version, middleware).await?;
+            Ok(())
+        });
+        state.send_command(cmd).await.map_err(|e| {
+            // The code failing to compile or to export a middleware is the request's fault,
+            // anything else is ours.
+            if e.chain().any(|cause| cause.is::<deno::JsException>()) {
+                e.context(invalid("loading middleware"))
+            } else {
+                e
+            }
+        })?;
+        state
+            .meta
+            .persist_middleware(&version, code.as_deref())
+            .await?;
+
+        Ok(Response::new(SetMiddlewareResponse {}))
+    }
+
+    /// Set or remove a runtime configuration value
+    async fn set_config_aux(
+        &self,
+        request: Request<SetConfigRequest>,
+    ) -> Result<Response<SetConfigResponse>> {
+        let SetConfigRequest { name, value } = request.into_inner();
+        anyhow::ensure!(!name.is_empty(), invalid("config name can't be empty"));
+        let value: Option<serde_json::Value> = value
+            .map(|v| serde_json::from_str(&v))
+            .transpose()
+            .with_context(|| invalid(format!("value of config {} is not valid JSON", name)))?;
+        let state = self.state.lock().await;
+
+        state.meta.persist_config(&name, value.as_ref()).await?;
+        // Config is not secret, so changes are worth logging.
+        match &value {
+            Some(value) => info!("Config {} set to {}", name, value),
+            None => info!("Config {} unset", name),
+        }
+        let config = state.meta.load_config().await?;
+        let cmd = send_command!({
+            deno::set_config(config).await;
+            Ok(())
+        });
+        state.send_command(cmd).await?;
+
+        Ok(Response::new(SetConfigResponse {}))
+    }
+
+    /// Set or remove a named query, which endpoints run with `runNamedQuery()`
+    async fn set_named_query_aux(
+        &self,
+        request: Request<SetNamedQueryRequest>,
+    ) -> Result<Response<SetNamedQueryResponse>> {
+        let SetNamedQueryRequest {
+            version,
+            name,
+            spec,
+        } = request.into_inner();
+        anyhow::ensure!(!name.is_empty(), invalid("query name can't be empty"));
+        let spec: Option<serde_json::Value> = spec
+            .map(|s| serde_json::from_str(&s))
+            .transpose()
+            .with_context(|| invalid(format!("spec of query {} is not valid JSON", name)))?;
+        let state = self.state.lock().await;
+
+        if let Some(spec) = &spec {
+            anyhow::ensure!(
+                state.versions.contains(&version),
+                RequestError::NotFound(format!("no version {}", version))
+            );
+            // Checked against the types as they are now, so that mistakes show up before any
+            // endpoint runs the query.
+            let context = RequestContext {
+                policies: &state.policies,
+                ts: &state.type_system,
+                api_version: version.clone(),
+                user_id: None,
+                path: "".to_string(),
+                method: "GET".to_string(),
+            };
+            NamedQuery::new(spec.clone())
+                .and_then(|query| query.validate(&context, &state.query_engine.target_db()))
+                .with_context(|| invalid(format!("invalid query {}", name)))?;
+        }
+
+        state
+            .meta
+            .persist_named_query(&version, &name, spec.as_ref())
+            .await?;
+        match &spec {
+            Some(_) => info!("Named query {} of version {} set", name, version),
+            None => info!("Named query {} of version {} unset", name, version),
+        }
+        let named_queries = state.meta.load_named_queries().await?;
+        let cmd = send_command!({
+            deno::set_named_queries(named_queries).await;
+            Ok(())
+        });
+        state.send_command(cmd).await?;
+
+        Ok(Response::new(SetNamedQueryResponse {}))
+    }
+
+    /// Replace the policies of a version, leaving its types and endpoints as they are.  Requests
+    /// that are already running finish under the policies they started with.
+    async fn reload_policies_aux(
+        &self,
+        request: Request<ReloadPoliciesRequest>,
+    ) -> Result<Response<ReloadPoliciesResponse>> {
+        let ReloadPoliciesRequest { version, policies } = request.into_inner();
+        let policy_str = single_policy(&policies)?;
+        let policy = VersionPolicy::from_yaml(policy_str).map_err(as_invalid)?;
+        let mut state = self.state.lock().await;
+        anyhow::ensure!(
+            state.versions.contains(&version),
+            RequestError::NotFound(format!("no version {}", version))
+        );
+        if let Ok(version_types) = state.type_system.get_version(&version) {
+            for ty in version_types.custom_types.values() {
+                policy.check_encrypted_fields(ty).map_err(as_invalid)?;
+            }
+        }
+
+        let mut transaction = state.meta.start_transaction().await?;
+        state
+            .meta
+            .persist_policy_version(&mut transaction, &version, policy_str)
+            .await?;
+        MetaService::commit_transaction(transaction).await?;
+
+        use itertools::Itertools;
+        let labels = policy.labels.keys().cloned().sorted().collect();
+        state
+            .policies
+            .versions
+            .insert(version.clone(), policy.clone());
+        let pol_version = version.clone();
+        let cmd = send_command!({
+            mutate_policies(move |policies| {
+                policies.versions.insert(pol_version, policy);
+            })
+            .await;
+            Ok(())
+        });
+        state.send_command(cmd).await?;
+        // Policies change what reads return, even for data that didn't change.
+        state.query_engine.modification_log().record_all();
+        info!("Policies of version {} reloaded", version);
+
+        Ok(Response::new(ReloadPoliciesResponse { labels }))
+    }
+
+    /// Get the version and code of an endpoint
+    async fn get_endpoint_aux(
+        &self,
+        request: Request<GetEndpointRequest>,
+    ) -> Result<Response<GetEndpointResponse>> {
+        let path = request.into_inner().path;
+        let state = self.state.lock().await;
+        anyhow::ensure!(
+            state
+                .routes
+                .iter()
+                .any(|(p, _)| p.to_str() == Some(path.as_str())),
+            RequestError::NotFound(format!("no endpoint at {}", path))
+        );
+
+        // All executors load the same code, so asking one of them is enough.
+        let found = Arc::new(std::sync::Mutex::new(None));
+        let cmd = {
+            let found = found.clone();
+            let path = path.clone();
+            send_command!({
+                *found.lock().unwrap() = deno::endpoint_code(&path);
+                Ok(())
+            })
+        };
+        if let Some(executor) = state.commands.first() {
+            executor.send(cmd).await?;
+        }
+        let (version, code) = found
+            .lock()
+            .unwrap()
+            .take()
+            .with_context(|| format!("endpoint {} is not loaded", path))?;
+        Ok(Response::new(GetEndpointResponse { version, code }))
+    }
+
     async fn populate_aux(
         &self,
         request: Request<PopulateRequest>,
@@ -217,8 +483,13 @@ impl RpcService {
         let api_info = ApiInfo::new(app_name, api_version_tag);
 
         let mut endpoint_routes = vec![];
+        let mut paths = BTreeSet::new();
         for endpoint in apply_request.endpoints {
             let path = format!("/{}/{}", api_version, endpoint.path);
+            anyhow::ensure!(
+                paths.insert(path.clone()),
+                RequestError::AlreadyExists(format!("endpoint {} is defined more than once", path))
+            );
             endpoint_routes.push((path, endpoint.code));
         }
 
@@ -235,12 +506,12 @@ impl RpcService {
             state
                 .send_command(cmd)
                 .await
-                .with_context(|| format!("parsing endpoint {}", path))?;
+                .with_context(|| invalid(format!("parsing endpoint {}", path)))?;
         }
 
         anyhow::ensure!(
             "__chiselstrike" != &api_version,
-            "__chiselstrike is a reserved version name"
+            invalid("__chiselstrike is a reserved version name")
         );
 
         // so that an empty apply removes the version.
@@ -251,7 +522,13 @@ impl RpcService {
         let mut type_names_user_order = vec![];
 
         for tdef in apply_request.types.iter() {
-            type_names.insert(tdef.name.clone());
+            anyhow::ensure!(
+                type_names.insert(tdef.name.clone()),
+                RequestError::AlreadyExists(format!(
+                    "type {} is defined more than once",
+                    tdef.name
+                ))
+            );
             type_names_user_order.push(tdef.name.clone());
         }
 
@@ -268,21 +545,11 @@ impl RpcService {
             }
         }
 
-        anyhow::ensure!(
-            apply_request.policies.len() <= 1,
-            "Currently only one policy file supported"
-        );
-
-        let policy_str = apply_request
-            .policies
-            .get(0)
-            .map(|x| x.policy_config.as_ref())
-            .unwrap_or("");
-
-        let policy = VersionPolicy::from_yaml(policy_str)?;
+        let policy_str = single_policy(&apply_request.policies)?;
+        let policy = VersionPolicy::from_yaml(policy_str).map_err(as_invalid)?;
 
         if !to_remove.is_empty() && !apply_request.allow_type_deletion {
-            anyhow::bail!(
+            anyhow::bail!(invalid(
                 r"Trying to remove types from type file. This will delete the underlying data associated with this type.
 To proceed, try:
 
@@ -291,11 +558,12 @@ To proceed, try:
 or
 
    'chisel apply --allow-type-deletion' (otherwise)"
-            );
+            ));
         }
 
         let mut decorators = BTreeSet::default();
         let mut new_types = HashMap::<String, Arc<ObjectType>>::default();
+        let mut seeds = HashMap::<String, Vec<JsonObject>>::default();
 
         // No changes are made to the type system in this loop. We re-read the database after we
         // apply the changes, and this way we don't have to deal with the case of succeding to
@@ -303,7 +571,10 @@ or
         for type_def in apply_request.types {
             let name = type_def.name;
             if state.type_system.lookup_builtin_type(&name).is_ok() {
-                anyhow::bail!("custom type expected, got `{}` instead", name);
+                anyhow::bail!(invalid(format!(
+                    "custom type expected, got `{}` instead",
+                    name
+                )));
             }
 
             let mut fields = Vec::new();
@@ -316,33 +587,89 @@ or
                     Ok(ty) => ty,
                     Err(_) => match new_types.get(&field.field_type) {
                         Some(ty) => Type::Object(ty.clone()),
-                        None => anyhow::bail!(
+                        None => anyhow::bail!(invalid(format!(
                             "field type `{}` is neither a built-in nor a custom type",
                             &field.field_type
-                        ),
+                        ))),
                     },
                 };
 
-                fields.push(Field::new(
-                    NewField::new(&field.name, field_ty, &api_version)?,
-                    field.labels,
-                    field.default_value,
-                    field.is_optional,
-                    field.is_unique,
-                ));
+                if !field.allowed_values.is_empty() {
+                    anyhow::ensure!(
+                        field_ty == Type::String,
+                        invalid(format!(
+                            "field `{}` lists allowed values, but only string fields can",
+                            &field.name
+                        ))
+                    );
+                    if let Some(default) = &field.default_value {
+                        anyhow::ensure!(
+                            field.allowed_values.contains(default),
+                            invalid(format!(
+                                "default value `{}` of field `{}` is not one of its allowed values",
+                                default, &field.name
+                            ))
+                        );
+                    }
+                }
+
+                if let (Type::Date, Some(default)) = (&field_ty, &field.default_value) {
+                    crate::dates::parse_str(default).map_err(|e| {
+                        invalid(format!(
+                            "default value of field `{}` is not a date: {}",
+                            &field.name, e
+                        ))
+                    })?;
+                }
+
+                fields.push(
+                    Field::new(
+                        NewField::new(&field.name, field_ty, &api_version).map_err(as_invalid)?,
+                        field.labels,
+                        field.default_value,
+                        field.is_optional,
+                        field.is_unique,
+                        field.is_indexed,
+                    )
+                    .with_allowed_values(field.allowed_values)
+                    .with_nullable(field.is_nullable),
+                );
             }
 
-            let ty = Arc::new(ObjectType::new(
-                NewObject::new(&name, &api_version),
-                fields,
-                IsNotAuth,
-            )?);
+            let unique_constraints = type_def
+                .unique_constraints
+                .into_iter()
+                .map(|c| c.fields)
+                .collect();
+            let ty = Arc::new(
+                ObjectType::new(NewObject::new(&name, &api_version), fields, IsNotAuth)
+                    .and_then(|ty| ty.with_unique_constraints(unique_constraints))
+                    .and_then(|ty| ty.with_soft_delete(type_def.soft_delete))
+                    .map_err(as_invalid)?,
+            );
+            policy.check_encrypted_fields(&ty).map_err(as_invalid)?;
             new_types.insert(name.to_owned(), ty.clone());
 
+            if !type_def.seeds.is_empty() {
+                let rows: Vec<JsonObject> = serde_json::from_str(&type_def.seeds)
+                    .map_err(|e| invalid(format!("seeds for type {}: {}", name, e)))?;
+                for (i, row) in rows.iter().enumerate() {
+                    state
+                        .query_engine
+                        .check_row(&ty, row)
+                        .with_context(|| invalid(format!("seed {} of type {}", i, name)))?;
+                }
+                seeds.insert(name.to_owned(), rows);
+            }
+
             match version_types.lookup_custom_type(&name) {
                 Ok(old_type) => {
-                    let delta = TypeSystem::generate_type_delta(&old_type, ty)?;
-                    to_update.push((old_type.clone(), delta));
+                    let delta =
+                        TypeSystem::generate_type_delta(&old_type, ty).map_err(as_invalid)?;
+                    // Applying an unchanged type again leaves it alone.
+                    if !delta.is_empty() {
+                        to_update.push((old_type.clone(), delta));
+                    }
                 }
                 Err(TypeSystemError::NoSuchType(_) | TypeSystemError::NoSuchVersion(_)) => {
                     to_insert.push(ty.clone());
@@ -405,6 +732,26 @@ or
                 .alter_table(&mut transaction, &old, delta)
                 .await?;
         }
+        // Only empty types are seeded, so that applying again doesn't store the seeds twice. Seeds
+        // go through the write policies, like any other store, so that labeled fields get
+        // encrypted or rejected.
+        for (name, rows) in seeds.into_iter() {
+            let ty = state.type_system.lookup_custom_type(&name, &api_version)?;
+            if !query_engine.is_empty(&mut transaction, &ty).await? {
+                continue;
+            }
+            for (i, row) in rows.iter().enumerate() {
+                let context = || format!("storing seed {} of type {}", i, name);
+                let row = state
+                    .policies
+                    .enforce_write_policies(&None, "", "POST", &ty, row)
+                    .with_context(context)?;
+                query_engine
+                    .add_row(&ty, &row, Some(&mut transaction))
+                    .await
+                    .with_context(context)?;
+            }
+        }
         QueryEngine::commit_transaction(transaction).await?;
 
         let prefix: PathBuf = format!("/{}/", api_version).into();
@@ -444,12 +791,15 @@ or
                 }
                 runtime.api.update_api_info(&api_version, api_info);
             }
-            for (path, _) in endpoints {
-                deno::activate_endpoint(&path).await?;
-            }
+            // Swap all handlers of this version at once, right after the routes, so requests
+            // never see half of an apply.
+            let paths: Vec<&String> = endpoints.iter().map(|(path, _)| path).collect();
+            deno::activate_endpoints(Some(&prefix.to_string_lossy()), &paths).await?;
             Ok(())
         });
         state.send_command(cmd).await?;
+        // New types, policies and endpoints change what reads return, even for data that didn't change.
+        state.query_engine.modification_log().record_all();
 
         // FIXME: return number of effective changes? Probably depends on how we implement
         // terraform-like workflow (x added, y removed, z modified)
@@ -479,9 +829,7 @@ impl ChiselRpc for RpcService {
         &self,
         request: Request<ChiselApplyRequest>,
     ) -> Result<Response<ChiselApplyResponse>, Status> {
-        self.apply_aux(request)
-            .await
-            .map_err(|e| Status::internal(format!("{:?}", e)))
+        self.apply_aux(request).await.map_err(to_status)
     }
 
     /// Delete a version of ChiselStrike
@@ -489,18 +837,54 @@ impl ChiselRpc for RpcService {
         &self,
         request: Request<ChiselDeleteRequest>,
     ) -> Result<Response<ChiselDeleteResponse>, Status> {
-        self.delete_aux(request)
-            .await
-            .map_err(|e| Status::internal(format!("{:?}", e)))
+        self.delete_aux(request).await.map_err(to_status)
+    }
+
+    /// Set or remove the middleware that wraps every endpoint
+    async fn set_middleware(
+        &self,
+        request: Request<SetMiddlewareRequest>,
+    ) -> Result<Response<SetMiddlewareResponse>, Status> {
+        self.set_middleware_aux(request).await.map_err(to_status)
+    }
+
+    /// Set or remove a runtime configuration value
+    async fn set_config(
+        &self,
+        request: Request<SetConfigRequest>,
+    ) -> Result<Response<SetConfigResponse>, Status> {
+        self.set_config_aux(request).await.map_err(to_status)
+    }
+
+    /// Set or remove a named query
+    async fn set_named_query(
+        &self,
+        request: Request<SetNamedQueryRequest>,
+    ) -> Result<Response<SetNamedQueryResponse>, Status> {
+        self.set_named_query_aux(request).await.map_err(to_status)
+    }
+
+    /// Replace the policies of a version, leaving its types and endpoints as they are
+    async fn reload_policies(
+        &self,
+        request: Request<ReloadPoliciesRequest>,
+    ) -> Result<Response<ReloadPoliciesResponse>, Status> {
+        self.reload_policies_aux(request).await.map_err(to_status)
+    }
+
+    /// Get the version and code of an endpoint
+    async fn get_endpoint(
+        &self,
+        request: Request<GetEndpointRequest>,
+    ) -> Result<Response<GetEndpointResponse>, Status> {
+        self.get_endpoint_aux(request).await.map_err(to_status)
     }
 
     async fn populate(
         &self,
         request: Request<PopulateRequest>,
     ) -> Result<Response<PopulateResponse>, Status> {
-        self.populate_aux(request)
-            .await
-            .map_err(|e| Status::internal(format!("{:?}", e)))
+        self.populate_aux(request).await.map_err(to_status)
     }
 
     async fn describe(
@@ -528,11 +912,23 @@ impl ChiselRpc for RpcService {
                             default_value: field.user_provided_default().clone(),
                             is_optional: field.is_optional,
                             is_unique: field.is_unique,
+                            is_indexed: field.is_indexed,
+                            allowed_values: field.allowed_values.clone(),
+                            is_nullable: field.is_nullable,
                         });
                     }
+                    let unique_constraints = ty
+                        .unique_constraints()
+                        .iter()
+                        .map(|fields| chisel::UniqueConstraint {
+                            fields: fields.clone(),
+                        })
+                        .collect();
                     let type_def = chisel::TypeDefinition {
                         name: ty.name().to_string(),
                         field_defs,
+                        unique_constraints,
+                        soft_delete: ty.soft_deletes(),
                     };
                     type_defs.push(type_def);
                 }
@@ -566,6 +962,38 @@ impl ChiselRpc for RpcService {
         Ok(Response::new(response))
     }
 
+    async fn export_policies(
+        &self,
+        _request: tonic::Request<ExportPoliciesRequest>,
+    ) -> Result<tonic::Response<ExportPoliciesResponse>, tonic::Status> {
+        let state = self.state.lock().await;
+        use itertools::Itertools;
+        let versions = state
+            .policies
+            .versions
+            .iter()
+            .sorted_by(|x, y| x.0.cmp(y.0))
+            .map(|(version, policy)| export_version_policies(version, policy))
+            .collect();
+        Ok(Response::new(ExportPoliciesResponse { versions }))
+    }
+
+    async fn list_policy_versions(
+        &self,
+        _request: tonic::Request<ListPolicyVersionsRequest>,
+    ) -> Result<tonic::Response<ListPolicyVersionsResponse>, tonic::Status> {
+        let state = self.state.lock().await;
+        use itertools::Itertools;
+        let versions = state
+            .policies
+            .versions
+            .iter()
+            .sorted_by(|x, y| x.0.cmp(y.0))
+            .map(|(version, policy)| summarize_version_policies(version, policy))
+            .collect();
+        Ok(Response::new(ListPolicyVersionsResponse { versions }))
+    }
+
     async fn restart(
         &self,
         _request: tonic::Request<RestartRequest>,
@@ -575,6 +1003,84 @@ impl ChiselRpc for RpcService {
     }
 }
 
+/// Converts the policies of a version into their RPC representation.  Regexes are exported as their source
+/// patterns.
+fn export_version_policies(version: &str, policy: &VersionPolicy) -> chisel::VersionPoliciesExport {
+    use itertools::Itertools;
+    let labels = policy
+        .labels
+        .iter()
+        .sorted_by(|x, y| x.0.cmp(y.0))
+        .map(|(label, rules)| chisel::LabelPolicyExport {
+            label: label.clone(),
+            rules: rules
+                .iter()
+                .map(|rule| chisel::PolicyRuleDefinition {
+                    kind: rule.kind.describe(),
+                    except_uri: rule.except_uri.as_str().to_owned(),
+                    methods: rule.methods.clone(),
+                })
+                .collect(),
+            field_patterns: policy
+                .field_patterns
+                .iter()
+                .filter(|(_, l)| l == label)
+                .map(|(re, _)| re.as_str().to_owned())
+                .collect(),
+        })
+        .collect();
+    let user_authorization = policy
+        .user_authorization
+        .iter()
+        .map(|(path, rule)| match rule {
+            PathRule::Users(users) => chisel::UserAuthorizationExport {
+                path: path.display().to_string(),
+                users: users.as_str().to_owned(),
+                public: false,
+            },
+            PathRule::Public => chisel::UserAuthorizationExport {
+                path: path.display().to_string(),
+                users: String::new(),
+                public: true,
+            },
+        })
+        .collect();
+    chisel::VersionPoliciesExport {
+        version: version.to_owned(),
+        labels,
+        user_authorization,
+    }
+}
+
+/// Summarizes the policies of a version: how many labels and authorization rules it has, and the names of the
+/// transforms its labels apply, without repeats.
+fn summarize_version_policies(
+    version: &str,
+    policy: &VersionPolicy,
+) -> chisel::PolicyVersionSummary {
+    use itertools::Itertools;
+    let transforms = policy
+        .labels
+        .values()
+        .flatten()
+        .filter_map(|rule| match rule.kind {
+            Kind::Transform { name, .. } => Some(name),
+            Kind::MatchLogin => Some("match_login"),
+            Kind::Encrypt => Some("encrypt"),
+            Kind::DenyWrite(_) => None,
+        })
+        .unique()
+        .sorted()
+        .map(str::to_owned)
+        .collect();
+    chisel::PolicyVersionSummary {
+        version: version.to_owned(),
+        label_count: policy.labels.len() as u32,
+        authorization_rule_count: policy.user_authorization.iter().count() as u32,
+        transforms,
+    }
+}
+
 pub(crate) fn spawn(
     rpc: RpcService,
     addr: SocketAddr,
@@ -593,3 +1099,324 @@ pub(crate) fn spawn(
         Ok(())
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chisel::{AddTypeRequest, EndPointCreationRequest, FieldDefinition};
+    use crate::datastore::DbConnection;
+
+    /// An RPC service over a fresh database, with no executors to send commands to.
+    async fn rpc_service() -> RpcService {
+        let conn = DbConnection::connect("sqlite::memory:", 1).await.unwrap();
+        let meta = MetaService::local_connection(&conn, 1).await.unwrap();
+        meta.create_schema().await.unwrap();
+        let query_engine = QueryEngine::local_connection(&conn, 1).await.unwrap();
+        let state = GlobalRpcState::new(meta, query_engine, vec![])
+            .await
+            .unwrap();
+        RpcService::new(Arc::new(Mutex::new(state)))
+    }
+
+    fn apply_request(
+        types: Vec<AddTypeRequest>,
+        endpoints: Vec<EndPointCreationRequest>,
+    ) -> Request<ChiselApplyRequest> {
+        Request::new(ChiselApplyRequest {
+            types,
+            endpoints,
+            policies: vec![],
+            allow_type_deletion: false,
+            version: "dev".into(),
+            version_tag: String::new(),
+            app_name: String::new(),
+        })
+    }
+
+    fn person_type(field_type: &str) -> AddTypeRequest {
+        AddTypeRequest {
+            name: "Person".into(),
+            field_defs: vec![FieldDefinition {
+                name: "name".into(),
+                field_type: field_type.into(),
+                labels: vec![],
+                is_optional: false,
+                default_value: None,
+                is_unique: false,
+                is_indexed: false,
+                allowed_values: vec![],
+                is_nullable: false,
+            }],
+            unique_constraints: vec![],
+            soft_delete: false,
+            seeds: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn apply_status_codes() {
+        let rpc = rpc_service().await;
+
+        let endpoint = || EndPointCreationRequest {
+            path: "hello".into(),
+            code: "export default () => 'hi';".into(),
+        };
+        let status = rpc
+            .apply(apply_request(vec![], vec![endpoint(), endpoint()]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+        assert_eq!(
+            status.message(),
+            "endpoint /dev/hello is defined more than once"
+        );
+
+        let status = rpc
+            .apply(apply_request(vec![person_type("Strnig")], vec![]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "field type `Strnig` is neither a built-in nor a custom type"
+        );
+
+        let status = rpc
+            .apply(apply_request(
+                vec![person_type("string"), person_type("string")],
+                vec![],
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+
+        rpc.apply(apply_request(vec![person_type("string")], vec![]))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_date_defaults() {
+        let rpc = rpc_service().await;
+        let with_default = |default: &str| {
+            let mut ty = person_type("Date");
+            ty.field_defs[0].default_value = Some(default.into());
+            ty
+        };
+
+        let status = rpc
+            .apply(apply_request(vec![with_default("yesterday")], vec![]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "default value of field `name` is not a date: 'yesterday' is not an ISO 8601 date"
+        );
+
+        rpc.apply(apply_request(vec![with_default("2022-05-01")], vec![]))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn seeds() {
+        let rpc = rpc_service().await;
+        let seeded_person = || AddTypeRequest {
+            seeds: r#"[{"name": "alice"}, {"name": "bob"}]"#.into(),
+            ..person_type("string")
+        };
+        let names = || async {
+            let state = rpc.state.lock().await;
+            let ty = state
+                .type_system
+                .lookup_custom_type("Person", "dev")
+                .unwrap();
+            let sql = format!("SELECT name FROM \"{}\" ORDER BY name", ty.backing_table());
+            let qe = state.query_engine.clone();
+            let tr = qe.clone().start_transaction_static().await.unwrap();
+            let rows = qe.raw_query(tr, sql, vec![]).await.unwrap();
+            rows.into_iter()
+                .map(|row| row["name"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let status = rpc
+            .apply(apply_request(
+                vec![AddTypeRequest {
+                    seeds: r#"[{"nmae": "alice"}]"#.into(),
+                    ..person_type("string")
+                }],
+                vec![],
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert!(status.message().starts_with("seed 0 of type Person"));
+
+        rpc.apply(apply_request(vec![person_type("string")], vec![]))
+            .await
+            .unwrap();
+        assert!(names().await.is_empty());
+
+        // An existing type is seeded while it is empty.
+        rpc.apply(apply_request(vec![seeded_person()], vec![]))
+            .await
+            .unwrap();
+        assert_eq!(names().await, vec!["alice", "bob"]);
+
+        // The type isn't empty anymore, so it isn't seeded again.
+        rpc.apply(apply_request(vec![seeded_person()], vec![]))
+            .await
+            .unwrap();
+        assert_eq!(names().await, vec!["alice", "bob"]);
+    }
+
+    #[tokio::test]
+    async fn reload_policies() {
+        let rpc = rpc_service().await;
+        let reload = |version: &str, policy: &str| {
+            rpc.reload_policies(Request::new(ReloadPoliciesRequest {
+                version: version.into(),
+                policies: vec![chisel::PolicyUpdateRequest {
+                    policy_config: policy.into(),
+                }],
+            }))
+        };
+        let anonymize_pii = "labels:\n  - name: pii\n    transform: anonymize\n";
+
+        let status = reload("dev", anonymize_pii).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.message(), "no version dev");
+
+        rpc.apply(apply_request(vec![person_type("string")], vec![]))
+            .await
+            .unwrap();
+        let status = reload("dev", "labels: [").await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let labels = reload("dev", anonymize_pii)
+            .await
+            .unwrap()
+            .into_inner()
+            .labels;
+        assert_eq!(labels, vec!["pii"]);
+        let state = rpc.state.lock().await;
+        let policy = &state.policies.versions["dev"];
+        assert!(policy.labels.contains_key("pii"));
+        // The reloaded policies are the ones chiseld starts with from now on.
+        let persisted = state.meta.load_policies().await.unwrap();
+        assert!(persisted.versions["dev"].labels.contains_key("pii"));
+    }
+
+    #[tokio::test]
+    async fn list_policy_versions() {
+        let rpc = rpc_service().await;
+        let apply = |version: &str, policy: &str| {
+            rpc.apply(Request::new(ChiselApplyRequest {
+                types: vec![],
+                endpoints: vec![],
+                policies: vec![chisel::PolicyUpdateRequest {
+                    policy_config: policy.into(),
+                }],
+                allow_type_deletion: false,
+                version: version.into(),
+                version_tag: String::new(),
+                app_name: String::new(),
+            }))
+        };
+        apply(
+            "dev",
+            r#"
+labels:
+  - name: pii
+    transform: anonymize
+  - name: mine
+    transform: match_login
+  - name: internal
+    write: deny
+endpoints:
+  - path: /admin
+    users: ^admin@example.com$
+public_paths:
+  - /public
+"#,
+        )
+        .await
+        .unwrap();
+        apply(
+            "v2",
+            "labels:\n  - name: pii\n    transform: anonymize\n  - name: secret\n    transform: anonymize\n",
+        )
+        .await
+        .unwrap();
+
+        let versions = rpc
+            .list_policy_versions(Request::new(ListPolicyVersionsRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .versions;
+        assert_eq!(versions.len(), 2);
+
+        let dev = &versions[0];
+        assert_eq!(dev.version, "dev");
+        assert_eq!(dev.label_count, 3);
+        assert_eq!(dev.authorization_rule_count, 2);
+        assert_eq!(dev.transforms, vec!["anonymize", "match_login"]);
+
+        let v2 = &versions[1];
+        assert_eq!(v2.version, "v2");
+        assert_eq!(v2.label_count, 2);
+        assert_eq!(v2.authorization_rule_count, 0);
+        assert_eq!(v2.transforms, vec!["anonymize"]);
+    }
+
+    #[test]
+    fn export_policies_round_trip() {
+        let yaml = r#"
+labels:
+  - name: pii
+    transform: anonymize
+    except_uri: ^/admin
+    field_pattern: _email$
+  - name: protect
+    transform: match_login
+  - name: internal
+    write: deny
+    write_mode: strip
+    methods: [POST, PUT]
+endpoints:
+  - path: /comments
+    users: ^admin@example.com$
+"#;
+        let policy = VersionPolicy::from_yaml(yaml).unwrap();
+        let exported = export_version_policies("dev", &policy);
+        assert_eq!(exported.version, "dev");
+
+        let labels: Vec<_> = exported.labels.iter().map(|l| l.label.as_str()).collect();
+        assert_eq!(labels, vec!["internal", "pii", "protect"]);
+
+        let internal = &exported.labels[0];
+        assert_eq!(internal.rules.len(), 1);
+        assert_eq!(internal.rules[0].kind, "write: deny (strip)");
+        assert_eq!(internal.rules[0].methods, vec!["POST", "PUT"]);
+        assert!(internal.field_patterns.is_empty());
+
+        let pii = &exported.labels[1];
+        assert_eq!(pii.rules.len(), 1);
+        assert_eq!(pii.rules[0].kind, "transform: anonymize");
+        assert_eq!(pii.rules[0].except_uri, "^/admin");
+        assert!(pii.rules[0].methods.is_empty());
+        assert_eq!(pii.field_patterns, vec!["_email$"]);
+
+        let protect = &exported.labels[2];
+        assert_eq!(protect.rules[0].kind, "transform: match_login");
+        assert_eq!(protect.rules[0].except_uri, "^$");
+
+        assert_eq!(exported.user_authorization.len(), 1);
+        assert_eq!(exported.user_authorization[0].path, "/comments");
+        assert_eq!(exported.user_authorization[0].users, "^admin@example.com$");
+        assert!(!exported.user_authorization[0].public);
+    }
+}
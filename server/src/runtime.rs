@@ -1,7 +1,10 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
 use crate::api::ApiService;
+use crate::policies::Policies;
 use crate::rcmut::RcMut;
+use crate::types::TypeSystem;
+use crate::JsonObject;
 use derive_new::new;
 use once_cell::sync::OnceCell;
 use std::cell::RefCell;
@@ -10,6 +13,16 @@ use std::rc::Rc;
 #[derive(new)]
 pub(crate) struct Runtime {
     pub(crate) api: Rc<ApiService>,
+    /// Whether `/__schema` is served without checking the ChiselAuth header.
+    pub(crate) public_schema: bool,
+    /// Copies of the type system, policies and secrets held by the JavaScript workers, for the routes served
+    /// without them and for the updates sent to them.
+    #[new(default)]
+    pub(crate) type_system: TypeSystem,
+    #[new(default)]
+    pub(crate) policies: Policies,
+    #[new(default)]
+    pub(crate) secrets: Option<JsonObject>,
 }
 
 thread_local!(static RUNTIME: OnceCell<Rc<RefCell<Runtime>>> = OnceCell::new());
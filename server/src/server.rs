@@ -1,6 +1,8 @@
 // SPDX-FileCopyrightText: © 2021 ChiselStrike <info@chiselstrike.com>
 
-use crate::api::ApiService;
+use crate::api::{ApiService, DeniedHeaders, ResponseHeaderLimits};
+use crate::datastore::crud::PageLimits;
+use crate::datastore::engine::{ModificationLog, RetryPolicy};
 use crate::datastore::{DbConnection, MetaService, QueryEngine};
 use crate::deno;
 use crate::deno::init_deno;
@@ -9,18 +11,23 @@ use crate::deno::set_policies;
 use crate::deno::set_query_engine;
 use crate::deno::set_type_system;
 use crate::deno::update_secrets;
-use crate::deno::{activate_endpoint, compile_endpoint};
+use crate::deno::{
+    activate_endpoints, compile_endpoint, set_config, set_jobs, set_middleware, set_named_queries,
+};
+use crate::jobs::JobStore;
 use crate::rpc::{GlobalRpcState, RpcService};
 use crate::runtime;
 use crate::runtime::Runtime;
 use crate::secrets::get_secrets;
+use crate::tls::TlsConfig;
 use crate::JsonObject;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use async_lock::Mutex;
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
 use futures::StreamExt;
-use std::net::SocketAddr;
+use hyper::header::HeaderName;
+use std::net::{IpAddr, SocketAddr};
 use std::panic;
 use std::path::PathBuf;
 use std::rc::Rc;
@@ -48,7 +55,7 @@ pub struct Opt {
     /// Data database URI. [deprecated: use --db-uri instead]
     #[structopt(short, long, default_value = "sqlite://chiseld-data.db?mode=rwc")]
     _data_db_uri: String,
-    /// Database URI.
+    /// Database URI. With `sqlite::memory:`, the database is kept in memory and lost on exit.
     #[structopt(long, default_value = "sqlite://.chiseld.db?mode=rwc")]
     db_uri: String,
     /// Should we wait for a debugger before executing any JS?
@@ -63,6 +70,93 @@ pub struct Opt {
     /// If on, serve a web UI on an internal route.
     #[structopt(long)]
     webui: bool,
+    /// How many times to retry a database operation that fails with a transient error.
+    #[structopt(long, default_value = "3")]
+    db_retries: u32,
+    /// How long to wait before the first retry of a database operation, doubled before each following one.
+    #[structopt(long, default_value = "50")]
+    db_retry_delay_ms: u64,
+    /// Log a warning for database queries that take longer than this many milliseconds.
+    #[structopt(long)]
+    slow_query_threshold_ms: Option<u64>,
+    /// Reject stored entities that have fields their type doesn't declare, instead of dropping
+    /// those fields.
+    #[structopt(long)]
+    strict_fields: bool,
+    /// Number of entities that CRUD endpoints return when a request sets no `limit`.
+    #[structopt(long)]
+    default_page_size: Option<u64>,
+    /// Largest number of entities that CRUD endpoints return. Requests with a larger `limit` get
+    /// this many.
+    #[structopt(long)]
+    max_page_size: Option<u64>,
+    /// How many requests each executor thread works on at once. Requests beyond that are
+    /// rejected with 503 Service Unavailable.
+    #[structopt(long, default_value = "1000")]
+    max_concurrent_requests: usize,
+    /// Answer 408 Request Timeout when a request body takes longer than this many milliseconds
+    /// to arrive, counting from the start of the request.
+    #[structopt(long)]
+    body_read_timeout_ms: Option<u64>,
+    /// Cancel the database queries of a request once this many milliseconds passed since it
+    /// started, answering 504 Gateway Timeout unless the endpoint handles the error. Tasks
+    /// queued with enqueue() get as long from when they start.
+    #[structopt(long)]
+    query_timeout_ms: Option<u64>,
+    /// How many chunks of a streamed response body to read from the endpoint ahead of the
+    /// client. Reading ahead keeps endpoints producing while slow clients receive, at the cost
+    /// of holding up to that many chunks in memory per response.
+    #[structopt(long, default_value = "0")]
+    response_prefetch_chunks: usize,
+    /// Serve the `/__schema` type listing without requiring the ChiselAuth header.
+    #[structopt(long)]
+    public_schema: bool,
+    /// API version of requests that name none, either in their path or in the ChiselStrike-Version header.
+    #[structopt(long)]
+    default_api_version: Option<String>,
+    /// Turn strings and objects returned by endpoints instead of a Response into 200 responses,
+    /// with a text and a JSON body respectively.
+    #[structopt(long)]
+    coerce_responses: bool,
+    /// Header with the id of each request, which is echoed in its response. Requests without
+    /// one get a generated id.
+    #[structopt(long, default_value = "X-Request-Id")]
+    request_id_header: String,
+    /// Response header that endpoints may not set, on top of the hop-by-hop ones like
+    /// Transfer-Encoding. Such headers are dropped from responses. Can be given multiple times.
+    #[structopt(long)]
+    deny_response_header: Vec<String>,
+    /// Most headers an endpoint's response may have. Requests whose endpoint returns more fail
+    /// with a 500.
+    #[structopt(long, default_value = "100")]
+    max_response_headers: usize,
+    /// Most bytes the names and values of the headers of an endpoint's response may add up to.
+    /// Requests whose endpoint returns more fail with a 500.
+    #[structopt(long, default_value = "65536")]
+    max_response_header_bytes: usize,
+    /// Address of a proxy in front of chiseld, whose X-Forwarded-For header is trusted to name
+    /// the client it forwards requests for. Can be given multiple times.
+    #[structopt(long)]
+    trusted_proxy: Vec<IpAddr>,
+    /// Show the stack trace of exceptions thrown by endpoints in their error responses. Without
+    /// this, traces are only logged.
+    #[structopt(long)]
+    dev_mode: bool,
+    /// Directory of JavaScript endpoints to serve under the `dev` version, which are reloaded
+    /// whenever they change. Compile errors are logged and leave the previous handler in place.
+    #[structopt(long, requires = "dev-mode")]
+    watch_endpoints: Option<PathBuf>,
+    /// OTLP collector to export request traces to, like `http://localhost:4317`. Without it,
+    /// requests are not traced.
+    #[structopt(long)]
+    otlp_endpoint: Option<String>,
+    /// PEM file with the certificate chain to serve the API over HTTPS with. The certificate is
+    /// reloaded when the file changes.
+    #[structopt(long, requires = "api-tls-key")]
+    api_tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of --api-tls-cert.
+    #[structopt(long, requires = "api-tls-cert")]
+    api_tls_key: Option<PathBuf>,
 }
 
 /// Whether an action should be repeated.
@@ -91,6 +185,27 @@ pub struct SharedState {
     executor_threads: usize,
     db: DbConnection,
     nr_connections: usize,
+    slow_query_threshold: Option<Duration>,
+    retry_policy: RetryPolicy,
+    strict_fields: bool,
+    page_limits: PageLimits,
+    /// Shared by the query engines of all threads, so that reads see changes made through any of them.
+    modification_log: ModificationLog,
+    /// Jobs queued with enqueueJob(), whose status may be asked of any thread.
+    jobs: JobStore,
+    max_concurrent_requests: usize,
+    body_read_timeout: Option<Duration>,
+    query_timeout: Option<Duration>,
+    response_prefetch_chunks: usize,
+    public_schema: bool,
+    default_api_version: Option<String>,
+    coerce_responses: bool,
+    request_id_header: HeaderName,
+    denied_headers: DeniedHeaders,
+    header_limits: ResponseHeaderLimits,
+    trusted_proxies: Vec<IpAddr>,
+    dev_mode: bool,
+    tls: Option<Arc<TlsConfig>>,
 }
 
 impl SharedState {
@@ -107,7 +222,10 @@ pub struct SharedTasks {
 impl SharedTasks {
     pub async fn join(self) -> Result<DoRepeat> {
         self.rpc_task.await??;
-        self.sig_task.await?
+        let repeat = self.sig_task.await??;
+        // Flushing spans blocks until they are exported.
+        tokio::task::spawn_blocking(crate::telemetry::shutdown).await?;
+        Ok(repeat)
     }
 }
 
@@ -119,7 +237,7 @@ pub(crate) async fn add_endpoint<S: AsRef<str>>(
     let path = path.as_ref();
 
     compile_endpoint(path.to_string(), code).await?;
-    activate_endpoint(path).await?;
+    activate_endpoints(None, &[path]).await?;
 
     let func = Arc::new({
         let path = path.to_string();
@@ -130,29 +248,47 @@ pub(crate) async fn add_endpoint<S: AsRef<str>>(
 }
 
 async fn run(state: SharedState, mut cmd: ExecutorChannel) -> Result<()> {
-    init_deno(state.inspect_brk).await?;
-
-    // Ensure we read the secrets before spawning an ApiService; secrets may dictate API authorization.
-    if let Ok(secrets) = get_secrets().await {
-        match serde_json::from_str(&secrets) {
-            Err(e) => warn!("Could not read secrets: {:?}", e),
-            Ok(json) => update_secrets(json).await,
-        }
-    }
+    init_deno(
+        state.inspect_brk,
+        state.max_concurrent_requests,
+        state.coerce_responses,
+        state.body_read_timeout,
+        state.query_timeout,
+        state.response_prefetch_chunks,
+        state.denied_headers.clone(),
+        state.header_limits,
+    )
+    .await?;
 
     let meta = MetaService::local_connection(&state.db, state.nr_connections).await?;
     let ts = meta.load_type_system().await?;
 
     let routes = meta.load_endpoints().await?;
+    let middleware = meta.load_middleware().await?;
+    let config = meta.load_config().await?;
+    let named_queries = meta.load_named_queries().await?;
     let policies = meta.load_policies().await?;
     let api_info = meta.load_api_info().await?;
 
-    let mut api_service = ApiService::new(api_info);
+    let mut api_service = ApiService::new(
+        api_info,
+        state.default_api_version.clone(),
+        state.request_id_header.clone(),
+        state.trusted_proxies.clone(),
+        state.dev_mode,
+    );
     crate::auth::init(&mut api_service).await?;
     crate::introspect::init(&api_service);
-
-    let query_engine =
-        Arc::new(QueryEngine::local_connection(&state.db, state.nr_connections).await?);
+    crate::jobs::init(&api_service, state.jobs.clone());
+
+    let query_engine = QueryEngine::local_connection(&state.db, state.nr_connections)
+        .await?
+        .with_slow_query_threshold(state.slow_query_threshold)
+        .with_retry_policy(state.retry_policy)
+        .with_strict_fields(state.strict_fields)
+        .with_page_limits(state.page_limits)
+        .with_modification_log(state.modification_log.clone());
+    let query_engine = Arc::new(query_engine);
     ts.create_builtin_backing_tables(query_engine.as_ref())
         .await?;
     let api_service = Rc::new(api_service);
@@ -162,16 +298,31 @@ async fn run(state: SharedState, mut cmd: ExecutorChannel) -> Result<()> {
         crate::introspect::add_introspection(&api_service, v);
     }
 
-    let rt = Runtime::new(api_service.clone());
+    let rt = Runtime::new(api_service.clone(), state.public_schema);
     runtime::set(rt);
+
+    // Ensure we read the secrets before spawning an ApiService; secrets may dictate API authorization.
+    if let Ok(secrets) = get_secrets().await {
+        match serde_json::from_str(&secrets) {
+            Err(e) => warn!("Could not read secrets: {:?}", e),
+            Ok(json) => update_secrets(json).await,
+        }
+    }
+
     set_type_system(ts).await;
     set_query_engine(query_engine).await;
     set_policies(policies).await;
+    set_config(config).await;
+    set_named_queries(named_queries).await;
+    set_jobs(state.jobs.clone()).await;
     set_meta(meta).await;
 
     for (path, code) in routes.iter() {
         add_endpoint(path.to_str().unwrap(), code.to_string(), &api_service).await?;
     }
+    for (version, code) in middleware {
+        set_middleware(&version, Some(code)).await?;
+    }
 
     let command_task = tokio::task::spawn_local(async move {
         while let Some(item) = cmd.rx.next().await {
@@ -183,13 +334,15 @@ async fn run(state: SharedState, mut cmd: ExecutorChannel) -> Result<()> {
     let api_tasks = crate::api::spawn(
         api_service,
         state.api_listen_addr.clone(),
+        state.tls.clone(),
         state.signal_rx.clone(),
     )?;
     state.readiness_tx.send(()).await?;
 
+    let scheme = if state.tls.is_some() { "https" } else { "http" };
     info!(
-        "ChiselStrike is ready 🚀 - URL: http://{} ",
-        state.api_listen_addr
+        "ChiselStrike is ready 🚀 - URL: {}://{} ",
+        scheme, state.api_listen_addr
     );
 
     for api_task in api_tasks {
@@ -242,6 +395,7 @@ fn find_legacy_sqlite_dbs(opt: &Opt) -> Vec<PathBuf> {
 pub async fn run_shared_state(
     opt: Opt,
 ) -> Result<(SharedTasks, SharedState, Vec<ExecutorChannel>)> {
+    crate::telemetry::init(opt.otlp_endpoint.as_deref())?;
     let db_conn = DbConnection::connect(&opt.db_uri, opt.nr_connections).await?;
     let meta = MetaService::local_connection(&db_conn, opt.nr_connections).await?;
 
@@ -251,7 +405,34 @@ pub async fn run_shared_state(
             .await?;
     }
 
-    let query_engine = QueryEngine::local_connection(&db_conn, opt.nr_connections).await?;
+    let slow_query_threshold = opt.slow_query_threshold_ms.map(Duration::from_millis);
+    let retry_policy = RetryPolicy {
+        retries: opt.db_retries,
+        base_delay: Duration::from_millis(opt.db_retry_delay_ms),
+    };
+    let request_id_header = HeaderName::from_bytes(opt.request_id_header.as_bytes())
+        .with_context(|| format!("invalid request id header '{}'", opt.request_id_header))?;
+    let denied_headers = opt
+        .deny_response_header
+        .iter()
+        .map(|name| {
+            HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid denied response header '{}'", name))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let denied_headers = DeniedHeaders::new(denied_headers);
+    let page_limits = PageLimits {
+        default: opt.default_page_size,
+        max: opt.max_page_size,
+    };
+    let modification_log = ModificationLog::default();
+    let query_engine = QueryEngine::local_connection(&db_conn, opt.nr_connections)
+        .await?
+        .with_slow_query_threshold(slow_query_threshold)
+        .with_retry_policy(retry_policy)
+        .with_strict_fields(opt.strict_fields)
+        .with_page_limits(page_limits)
+        .with_modification_log(modification_log.clone());
 
     meta.create_schema().await?;
 
@@ -352,6 +533,40 @@ pub async fn run_shared_state(
         }
     });
 
+    let tls = match (opt.api_tls_cert, opt.api_tls_key) {
+        (Some(cert), Some(key)) => Some(Arc::new(TlsConfig::load(cert, key)?)),
+        _ => None,
+    };
+    if let Some(tls) = tls.clone() {
+        let tls_shutdown = signal_rx.clone();
+        // Like secrets, renewed certificates are picked up without a restart.
+        tokio::task::spawn(async move {
+            let mut last_modified = tls.modified();
+            loop {
+                futures::select! {
+                    _ = sleep(Duration::from_millis(1000)).fuse() => {},
+                    _ = tls_shutdown.recv().fuse() => {
+                        break;
+                    }
+                };
+                let modified = tls.modified();
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                match tls.reload() {
+                    Ok(()) => info!("Reloaded the TLS certificate"),
+                    Err(e) => warn!("Could not reload the TLS certificate: {:?}", e),
+                }
+            }
+        });
+    }
+
+    if let Some(dir) = opt.watch_endpoints {
+        // Executors only pick up commands once they are ready, so the first reload waits for them.
+        crate::watch::spawn(dir, commands2.clone(), signal_rx.clone());
+    }
+
     // rpc server should start listening only when all threads start
     let (readiness_tx, readiness_rx) = async_channel::bounded(opt.executor_threads);
 
@@ -387,6 +602,28 @@ pub async fn run_shared_state(
         executor_threads: opt.executor_threads,
         db: db_conn,
         nr_connections: opt.nr_connections,
+        slow_query_threshold,
+        retry_policy,
+        strict_fields: opt.strict_fields,
+        page_limits,
+        modification_log,
+        jobs: JobStore::default(),
+        max_concurrent_requests: opt.max_concurrent_requests,
+        body_read_timeout: opt.body_read_timeout_ms.map(Duration::from_millis),
+        query_timeout: opt.query_timeout_ms.map(Duration::from_millis),
+        response_prefetch_chunks: opt.response_prefetch_chunks,
+        public_schema: opt.public_schema,
+        default_api_version: opt.default_api_version,
+        coerce_responses: opt.coerce_responses,
+        request_id_header,
+        denied_headers,
+        header_limits: ResponseHeaderLimits {
+            count: opt.max_response_headers,
+            bytes: opt.max_response_header_bytes,
+        },
+        trusted_proxies: opt.trusted_proxy,
+        dev_mode: opt.dev_mode,
+        tls,
     };
 
     let tasks = SharedTasks { rpc_task, sig_task };
@@ -0,0 +1,116 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Tamper-proof values for cookies and sessions.
+//!
+//! A signed value is the original string followed by a `.` and the base64url-encoded HMAC-SHA256 of that string.
+//! Keys come from the `CHISELD_SIGNING_KEY` secret, which is either a single string or an array of strings.  Values
+//! are signed with the first key and verified against all of them, so a key can be rotated by prepending the new
+//! key and dropping the old one once values signed with it have expired.
+
+use crate::JsonObject;
+use anyhow::Result;
+use hmac::{Hmac, Mac, NewMac};
+use serde_json::Value;
+use sha2::Sha256;
+
+pub(crate) const SIGNING_KEY_SECRET: &str = "CHISELD_SIGNING_KEY";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Extracts the signing keys from the secrets, newest first.
+pub(crate) fn signing_keys(secrets: Option<&JsonObject>) -> Vec<String> {
+    match secrets.and_then(|s| s.get(SIGNING_KEY_SECRET)) {
+        Some(Value::String(key)) => vec![key.clone()],
+        Some(Value::Array(keys)) => keys
+            .iter()
+            .filter_map(|k| k.as_str().map(str::to_owned))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn mac(key: &str, value: &str) -> HmacSha256 {
+    // HMAC accepts keys of any length.
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).unwrap();
+    mac.update(value.as_bytes());
+    mac
+}
+
+/// Signs `value` with the newest key.
+pub(crate) fn sign(keys: &[String], value: &str) -> Result<String> {
+    let key = keys.first().ok_or_else(|| {
+        anyhow::anyhow!(
+            "Cannot sign values: secret {} is not set",
+            SIGNING_KEY_SECRET
+        )
+    })?;
+    let tag = mac(key, value).finalize().into_bytes();
+    Ok(format!(
+        "{}.{}",
+        value,
+        base64::encode_config(tag, base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+/// Returns the original value if `signed` carries a valid signature from any of the keys, None otherwise.
+pub(crate) fn verify(keys: &[String], signed: &str) -> Option<String> {
+    let (value, tag) = signed.rsplit_once('.')?;
+    let tag = base64::decode_config(tag, base64::URL_SAFE_NO_PAD).ok()?;
+    keys.iter()
+        .any(|key| mac(key, value).verify(&tag).is_ok())
+        .then(|| value.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn keys(v: Value) -> Vec<String> {
+        let secrets = json!({ SIGNING_KEY_SECRET: v });
+        signing_keys(secrets.as_object())
+    }
+
+    #[test]
+    fn round_trip() {
+        let keys = keys(json!("s3cr3t"));
+        let signed = sign(&keys, "user=alice.smith").unwrap();
+        assert_ne!(signed, "user=alice.smith");
+        assert_eq!(verify(&keys, &signed).as_deref(), Some("user=alice.smith"));
+    }
+
+    #[test]
+    fn tampered() {
+        let keys = keys(json!("s3cr3t"));
+        let signed = sign(&keys, "user=alice").unwrap();
+        let tampered = signed.replacen("alice", "admin", 1);
+        assert_eq!(verify(&keys, &tampered), None);
+        assert_eq!(verify(&keys, "user=alice"), None);
+        assert_eq!(verify(&keys, "user=alice.not-base64!"), None);
+
+        let other_keys = vec!["other".to_owned()];
+        assert_eq!(verify(&other_keys, &signed), None);
+    }
+
+    #[test]
+    fn rotation() {
+        let old = keys(json!("old"));
+        let signed = sign(&old, "session").unwrap();
+
+        let rotated = keys(json!(["new", "old"]));
+        assert_eq!(verify(&rotated, &signed).as_deref(), Some("session"));
+        let resigned = sign(&rotated, "session").unwrap();
+        assert_ne!(resigned, signed);
+        assert_eq!(
+            verify(&keys(json!("new")), &resigned).as_deref(),
+            Some("session")
+        );
+    }
+
+    #[test]
+    fn no_key() {
+        assert!(sign(&[], "value").is_err());
+        assert_eq!(verify(&[], "value.AAAA"), None);
+        assert!(signing_keys(None).is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Request tracing with OpenTelemetry.
+//!
+//! Every request gets a server span, which continues the trace of the `traceparent` header if the request has one.
+//! Data accesses made while handling the request get spans of their own under it.  Spans are exported over OTLP
+//! when the server is given an endpoint, and are not recorded at all otherwise.
+
+use anyhow::Result;
+use hyper::header::HeaderMap;
+use hyper::Request;
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::config;
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::{SpanKind, StatusCode, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+
+const TRACER_NAME: &str = "chiseld";
+
+/// Sets up the propagation of trace context and, given an `otlp_endpoint`, the export of spans to it.
+pub(crate) fn init(otlp_endpoint: Option<&str>) -> Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    if let Some(endpoint) = otlp_endpoint {
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                TRACER_NAME,
+            )])))
+            .install_batch(opentelemetry::runtime::Tokio)?;
+    }
+    Ok(())
+}
+
+/// Exports the spans that are still buffered.
+pub(crate) fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// A span, which ends when this is dropped.
+pub(crate) struct SpanGuard(Context);
+
+impl SpanGuard {
+    /// The context for spans nested in this one.
+    pub(crate) fn context(&self) -> &Context {
+        &self.0
+    }
+
+    pub(crate) fn set_status(&self, status: u16) {
+        let span = self.0.span();
+        span.set_attribute(KeyValue::new("http.status_code", status as i64));
+        if status >= 500 {
+            span.set_status(StatusCode::Error, String::new());
+        }
+    }
+
+    pub(crate) fn set_error(&self, err: &anyhow::Error) {
+        self.0.span().set_status(StatusCode::Error, err.to_string());
+    }
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        self.0.span().end();
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Starts the span of handling `req`, and makes its context available to whoever handles it.
+pub(crate) fn start_request(req: &mut Request<hyper::Body>, request_id: &str) -> SpanGuard {
+    let parent = global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(req.headers())));
+    let path = req.uri().path().to_owned();
+    let attributes = vec![
+        KeyValue::new("http.method", req.method().to_string()),
+        KeyValue::new("http.target", path.clone()),
+        KeyValue::new("chisel.request_id", request_id.to_owned()),
+    ];
+    let span = start_span(
+        &parent,
+        format!("{} {}", req.method(), path),
+        SpanKind::Server,
+        attributes,
+    );
+    req.extensions_mut().insert(span.context().clone());
+    span
+}
+
+/// Starts a span nested in `parent`.
+pub(crate) fn start_child(
+    parent: &Context,
+    name: &'static str,
+    attributes: Vec<KeyValue>,
+) -> SpanGuard {
+    start_span(parent, name.to_owned(), SpanKind::Internal, attributes)
+}
+
+fn start_span(
+    parent: &Context,
+    name: String,
+    kind: SpanKind,
+    attributes: Vec<KeyValue>,
+) -> SpanGuard {
+    let tracer = global::tracer(TRACER_NAME);
+    // Spans are started in the current context, which is only the parent for the duration of this call.
+    let _attached = parent.clone().attach();
+    let span = tracer
+        .span_builder(name)
+        .with_kind(kind)
+        .with_attributes(attributes)
+        .start(&tracer);
+    SpanGuard(parent.with_span(span))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::sdk::export::trace::SpanData;
+    use opentelemetry::sdk::trace::{Span, SpanProcessor, TracerProvider};
+    use opentelemetry::trace::TraceResult;
+    use opentelemetry::{Key, Value};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps the spans that end, instead of exporting them.
+    #[derive(Debug, Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanProcessor for Recorder {
+        fn on_start(&self, _span: &Span, _cx: &Context) {}
+
+        fn on_end(&self, span: SpanData) {
+            self.0.lock().unwrap().push(span);
+        }
+
+        fn force_flush(&self) -> TraceResult<()> {
+            Ok(())
+        }
+
+        fn shutdown(&mut self) -> TraceResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn request_span() {
+        init(None).unwrap();
+        let recorder = Recorder::default();
+        let provider = TracerProvider::builder()
+            .with_span_processor(recorder.clone())
+            .build();
+        global::set_tracer_provider(provider);
+
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let mut req = Request::get("/dev/hello")
+            .header(
+                "traceparent",
+                format!("00-{}-00f067aa0ba902b7-01", trace_id),
+            )
+            .body(hyper::Body::empty())
+            .unwrap();
+        let span = start_request(&mut req, "42");
+        start_child(span.context(), "query", vec![]);
+        span.set_status(200);
+
+        // The trace of the request continues in the headers of requests it makes.
+        let mut headers = HashMap::new();
+        global::get_text_map_propagator(|p| p.inject_context(span.context(), &mut headers));
+        assert!(headers["traceparent"].starts_with(&format!("00-{}-", trace_id)));
+        drop(span);
+
+        let spans = recorder.0.lock().unwrap();
+        let names: Vec<_> = spans.iter().map(|s| s.name.to_string()).collect();
+        assert_eq!(names, vec!["query", "GET /dev/hello"]);
+        let request = &spans[1];
+        assert_eq!(spans[0].parent_span_id, request.span_context.span_id());
+        let attribute = |key: &'static str| request.attributes.get(&Key::new(key)).cloned();
+        assert_eq!(attribute("http.method"), Some(Value::from("GET")));
+        assert_eq!(attribute("http.target"), Some(Value::from("/dev/hello")));
+        assert_eq!(attribute("http.status_code"), Some(Value::I64(200)));
+        assert_eq!(attribute("chisel.request_id"), Some(Value::from("42")));
+    }
+}
@@ -0,0 +1,74 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Decoding of text bodies in the character set that their `Content-Type` names.
+
+use anyhow::{anyhow, Result};
+use encoding_rs::{Encoding, UTF_8};
+
+/// Finds the encoding named by the `charset` parameter of `content_type`, or UTF-8 if there is none.
+pub(crate) fn encoding(content_type: &str) -> Result<&'static Encoding> {
+    let charset = content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|p| p.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim().trim_matches('"'));
+    match charset {
+        None => Ok(UTF_8),
+        Some(label) => Encoding::for_label(label.as_bytes())
+            .ok_or_else(|| anyhow!("unsupported charset '{}'", label)),
+    }
+}
+
+/// Decodes `bytes` in the character set of `content_type`.  Bytes that aren't valid in it are an error rather than
+/// being replaced.
+pub(crate) fn decode(bytes: &[u8], content_type: &str) -> Result<String> {
+    let encoding = encoding(content_type)?;
+    encoding
+        .decode_without_bom_handling_and_without_replacement(bytes)
+        .map(|text| text.into_owned())
+        .ok_or_else(|| anyhow!("request body is not valid {} text", encoding.name()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charsets() {
+        assert_eq!(encoding("").unwrap(), UTF_8);
+        assert_eq!(encoding("text/plain").unwrap(), UTF_8);
+        assert_eq!(
+            encoding("text/plain; Charset=\"ISO-8859-1\"")
+                .unwrap()
+                .name(),
+            "windows-1252"
+        );
+        assert_eq!(
+            encoding("application/x-www-form-urlencoded;charset=shift_jis")
+                .unwrap()
+                .name(),
+            "Shift_JIS"
+        );
+        assert_eq!(
+            encoding("text/plain; charset=klingon")
+                .unwrap_err()
+                .to_string(),
+            "unsupported charset 'klingon'"
+        );
+    }
+
+    #[test]
+    fn latin1() {
+        let body = b"caf\xe9 cr\xe8me br\xfbl\xe9e, \xa35";
+        let text = decode(body, "text/plain; charset=iso-8859-1").unwrap();
+        assert_eq!(text, "café crème brûlée, £5");
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        assert_eq!(decode("café".as_bytes(), "text/plain").unwrap(), "café");
+        let err = decode(b"caf\xe9", "text/plain; charset=utf-8").unwrap_err();
+        assert_eq!(err.to_string(), "request body is not valid UTF-8 text");
+    }
+}
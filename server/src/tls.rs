@@ -0,0 +1,298 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! HTTPS for the API server.
+//!
+//! The certificate and key are read from PEM files at startup, and read again whenever the files change, so that
+//! renewed certificates are picked up without a restart.  Connections that don't start with a TLS handshake get a
+//! plain-text 400 response instead of a dropped connection.
+
+use anyhow::{anyhow, Context, Result};
+use futures::channel::mpsc;
+use futures::stream::Stream;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::Poll;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// First byte of a TLS handshake record.
+const HANDSHAKE_RECORD: u8 = 0x16;
+
+/// How long a client has to complete its handshake before the connection is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const PLAINTEXT_RESPONSE: &[u8] = b"HTTP/1.1 400 Bad Request\r\n\
+    Content-Type: text/plain\r\n\
+    Content-Length: 40\r\n\
+    Connection: close\r\n\
+    \r\n\
+    This server only accepts HTTPS requests\n";
+
+pub(crate) struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    server_config: RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsConfig {
+    pub(crate) fn load(cert_path: PathBuf, key_path: PathBuf) -> Result<Self> {
+        let server_config = server_config(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            server_config: RwLock::new(Arc::new(server_config)),
+        })
+    }
+
+    /// Reads the certificate and key again.  Connections keep using the old ones if that fails.
+    pub(crate) fn reload(&self) -> Result<()> {
+        let server_config = server_config(&self.cert_path, &self.key_path)?;
+        *self.server_config.write().unwrap() = Arc::new(server_config);
+        Ok(())
+    }
+
+    /// When the certificate or key file last changed, if they can be looked at.
+    pub(crate) fn modified(&self) -> Option<SystemTime> {
+        let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+        modified(&self.cert_path).max(modified(&self.key_path))
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.server_config.read().unwrap().clone())
+    }
+}
+
+fn server_config(cert_path: &Path, key_path: &Path) -> Result<ServerConfig> {
+    let certs = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        anyhow::bail!("no certificate found in {}", cert_path.display());
+    }
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("no private key found in {}", key_path.display()))?;
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("invalid TLS certificate or key")?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("could not read PEM file {}", path.display()))
+}
+
+/// The connections accepted on a listener, once their TLS handshakes complete.
+pub(crate) struct TlsIncoming {
+    connections: mpsc::UnboundedReceiver<io::Result<TlsStream<TcpStream>>>,
+    accept_task: JoinHandle<()>,
+}
+
+impl TlsIncoming {
+    pub(crate) fn new(listener: TcpListener, tls: Arc<TlsConfig>) -> Self {
+        let (tx, connections) = mpsc::unbounded();
+        let accept_task = tokio::task::spawn_local(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        if tx.unbounded_send(Err(e)).is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                let acceptor = tls.acceptor();
+                let tx = tx.clone();
+                // A slow handshake doesn't hold up the connections behind it.
+                tokio::task::spawn_local(async move {
+                    match handshake(stream, acceptor).await {
+                        Ok(Some(stream)) => tx.unbounded_send(Ok(stream)).ok(),
+                        Ok(None) => None,
+                        Err(e) => {
+                            debug!("TLS handshake failed: {}", e);
+                            None
+                        }
+                    };
+                });
+            }
+        });
+        Self {
+            connections,
+            accept_task,
+        }
+    }
+}
+
+/// Completes the TLS handshake of `stream`, or answers with an error if the client talks plain HTTP.
+/// Fails if the client takes longer than `HANDSHAKE_TIMEOUT`, so that idle connections don't pile up.
+async fn handshake(
+    stream: TcpStream,
+    acceptor: TlsAcceptor,
+) -> io::Result<Option<TlsStream<TcpStream>>> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake_in_time(stream, acceptor))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "client took too long"))?
+}
+
+async fn handshake_in_time(
+    mut stream: TcpStream,
+    acceptor: TlsAcceptor,
+) -> io::Result<Option<TlsStream<TcpStream>>> {
+    let mut first = [0u8; 1];
+    if stream.peek(&mut first).await? == 0 {
+        return Ok(None);
+    }
+    if first[0] != HANDSHAKE_RECORD {
+        stream.write_all(PLAINTEXT_RESPONSE).await?;
+        stream.shutdown().await?;
+        return Ok(None);
+    }
+    Ok(Some(acceptor.accept(stream).await?))
+}
+
+impl Stream for TlsIncoming {
+    type Item = io::Result<TlsStream<TcpStream>>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.connections).poll_next(cx)
+    }
+}
+
+impl Drop for TlsIncoming {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+    use rustls::{ClientConfig, RootCertStore, ServerName};
+    use std::convert::{Infallible, TryFrom};
+    use std::net::SocketAddr;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+    use tokio_rustls::TlsConnector;
+
+    /// Writes a new self-signed certificate for localhost into `dir`, returning it in DER.
+    fn write_certificate(dir: &TempDir) -> Vec<u8> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        std::fs::write(dir.path().join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(dir.path().join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+        cert.serialize_der().unwrap()
+    }
+
+    async fn get(stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> String {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    async fn get_https(addr: SocketAddr, cert: &[u8]) -> io::Result<String> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(cert.to_vec())).unwrap();
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await?;
+        Ok(get(&mut stream).await)
+    }
+
+    #[tokio::test]
+    async fn serve_https() {
+        let dir = TempDir::new().unwrap();
+        let cert = write_certificate(&dir);
+        let tls = TlsConfig::load(dir.path().join("cert.pem"), dir.path().join("key.pem")).unwrap();
+        let tls = Arc::new(tls);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async move {
+                let make_svc = make_service_fn(|_| async {
+                    Ok::<_, Infallible>(service_fn(|_| async {
+                        Ok::<_, Infallible>(Response::new(Body::from("hello")))
+                    }))
+                });
+                let incoming = TlsIncoming::new(listener, tls.clone());
+                let server =
+                    Server::builder(hyper::server::accept::from_stream(incoming)).serve(make_svc);
+                tokio::task::spawn_local(server);
+
+                let response = get_https(addr, &cert).await.unwrap();
+                assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+                assert!(response.ends_with("hello"), "{}", response);
+
+                let mut plain = TcpStream::connect(addr).await.unwrap();
+                let response = get(&mut plain).await;
+                assert!(
+                    response.starts_with("HTTP/1.1 400 Bad Request"),
+                    "{}",
+                    response
+                );
+                assert!(response.ends_with("This server only accepts HTTPS requests\n"));
+
+                // A renewed certificate is used once reloaded.
+                let renewed = write_certificate(&dir);
+                assert!(get_https(addr, &renewed).await.is_err());
+                tls.reload().unwrap();
+                assert!(get_https(addr, &renewed).await.is_ok());
+                assert!(get_https(addr, &cert).await.is_err());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn silent_client_times_out() {
+        let dir = TempDir::new().unwrap();
+        write_certificate(&dir);
+        let tls = TlsConfig::load(dir.path().join("cert.pem"), dir.path().join("key.pem")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        // The clock jumps ahead once nothing but the client can make progress.
+        tokio::time::pause();
+        let err = handshake(stream, tls.acceptor()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -11,6 +11,12 @@ use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use uuid::Uuid;
 
+/// Field that deleting an entity of a type declared with `@softDelete` sets to the time of
+/// deletion, in milliseconds since the epoch, instead of removing the entity. The name is part
+/// of the documented API (see the soft deletes section of data-access.md), so it can't change
+/// without breaking existing models.
+pub(crate) const SOFT_DELETE_FIELD: &str = "deletedAt";
+
 #[derive(thiserror::Error, Debug)]
 pub(crate) enum TypeSystemError {
     #[error["type already exists"]]
@@ -72,6 +78,9 @@ fn string_field(name: &str) -> Field {
         is_optional: false,
         api_version: "__chiselstrike".into(),
         is_unique: false,
+        is_indexed: false,
+        allowed_values: vec![],
+        is_nullable: false,
     }
 }
 
@@ -92,6 +101,9 @@ fn optional_number_field(name: &str) -> Field {
         is_optional: true,
         api_version: "__chiselstrike".into(),
         is_unique: false,
+        is_indexed: false,
+        allowed_values: vec![],
+        is_nullable: false,
     }
 }
 
@@ -104,6 +116,8 @@ impl Default for TypeSystem {
         ts.builtin_types.insert("string".into(), Type::String);
         ts.builtin_types.insert("number".into(), Type::Float);
         ts.builtin_types.insert("boolean".into(), Type::Boolean);
+        ts.builtin_types.insert("Date".into(), Type::Date);
+        ts.builtin_types.insert("JSONValue".into(), Type::Json);
         ts.add_builtin_object_type(
             AUTH_USER_NAME,
             vec![
@@ -232,7 +246,8 @@ impl TypeSystem {
         for (name, field) in new_fields.map.iter() {
             match old_fields.map.remove(name) {
                 None => {
-                    if field.default.is_none() && !field.is_optional {
+                    // Existing rows get null for the new field, which only optional and nullable fields can hold.
+                    if field.default.is_none() && !field.accepts_null() {
                         return Err(TypeSystemError::UnsafeReplacement(new_type.name.clone(), format!("Trying to add a new non-optional field ({}) without a default value. Consider adding a default value or making it optional to make the types compatible", field.name)));
                     }
                     added_fields.push(field.to_owned().clone());
@@ -252,6 +267,16 @@ impl TypeSystem {
                         ));
                     }
 
+                    if !field.accepts_null() && old.accepts_null() && field.default.is_none() {
+                        return Err(TypeSystemError::UnsafeReplacement(
+                            new_type.name.clone(),
+                            format!(
+                                "making field {} required without a default value, while existing rows may lack it. Incompatible change",
+                                field.name,
+                            ),
+                        ));
+                    }
+
                     if field.is_unique && !old.is_unique {
                         // FIXME: it should be possible to do it by issuing a select count() and
                         // then a select count distinct and comparing both results. But to do this
@@ -270,12 +295,18 @@ impl TypeSystem {
                         || field.type_ != old.type_
                         || field.is_optional != old.is_optional
                         || field.is_unique != old.is_unique
+                        || field.is_indexed != old.is_indexed
+                        || field.allowed_values != old.allowed_values
+                        || field.is_nullable != old.is_nullable
                     {
                         Some(FieldAttrDelta {
                             type_: field.type_.clone(),
                             default: field.default.clone(),
                             is_optional: field.is_optional,
                             is_unique: field.is_unique,
+                            is_indexed: field.is_indexed,
+                            allowed_values: field.allowed_values.clone(),
+                            is_nullable: field.is_nullable,
                         })
                     } else {
                         None
@@ -298,11 +329,44 @@ impl TypeSystem {
                             "logical error! updating field without id".to_string(),
                         )
                     })?;
-                    updated_fields.push(FieldDelta { id, attrs, labels });
+                    if attrs.is_some() || labels.is_some() {
+                        updated_fields.push(FieldDelta { id, attrs, labels });
+                    }
                 }
             }
         }
 
+        let same_constraint = |a: &Vec<String>, b: &Vec<String>| {
+            a.len() == b.len() && a.iter().all(|f| b.contains(f))
+        };
+        for constraint in new_type.unique_constraints() {
+            if !old_type
+                .unique_constraints()
+                .iter()
+                .any(|old| same_constraint(old, constraint))
+            {
+                // Like making a field unique, this would need checking the existing rows first.
+                return Err(TypeSystemError::UnsafeReplacement(
+                    new_type.name.clone(),
+                    format!(
+                        "adding a unique constraint on ({}). Incompatible change",
+                        constraint.join(", ")
+                    ),
+                ));
+            }
+        }
+        let removed_unique_constraints = old_type
+            .unique_constraints()
+            .iter()
+            .filter(|&old| {
+                !new_type
+                    .unique_constraints()
+                    .iter()
+                    .any(|new| same_constraint(old, new))
+            })
+            .cloned()
+            .collect();
+
         // only allow the removal of fields that previously had a default value or was optional
         for (_, field) in old_fields.map.into_iter() {
             if field.default.is_none() && !field.is_optional {
@@ -317,10 +381,18 @@ impl TypeSystem {
             removed_fields.push(field.to_owned().clone());
         }
 
+        let soft_delete = if old_type.soft_delete != new_type.soft_delete {
+            Some(new_type.soft_delete)
+        } else {
+            None
+        };
+
         Ok(ObjectDelta {
             added_fields,
             removed_fields,
             updated_fields,
+            removed_unique_constraints,
+            soft_delete,
         })
     }
 
@@ -467,6 +539,10 @@ pub(crate) enum Type {
     String,
     Float,
     Boolean,
+    /// Stored as milliseconds since the epoch, see crate::dates.
+    Date,
+    /// Any JSON value, stored serialized.
+    Json,
     Id,
     Object(Arc<ObjectType>),
 }
@@ -478,6 +554,8 @@ impl Type {
             Type::Id => "string",
             Type::String => "string",
             Type::Boolean => "boolean",
+            Type::Date => "Date",
+            Type::Json => "JSONValue",
             Type::Object(ty) => &ty.name,
         }
     }
@@ -643,6 +721,10 @@ pub(crate) struct ObjectType {
     chisel_id: Field,
     /// Name of the backing table for this type.
     backing_table: String,
+    /// Sets of fields whose values, taken together, no two objects may share.
+    unique_constraints: Vec<Vec<String>>,
+    /// Whether deleting objects only sets their SOFT_DELETE_FIELD.
+    soft_delete: bool,
     is_auth: AuthOrNot,
 
     pub(crate) api_version: String,
@@ -675,6 +757,9 @@ impl ObjectType {
             is_optional: false,
             api_version: "__chiselstrike".into(),
             is_unique: true,
+            is_indexed: false,
+            allowed_values: vec![],
+            is_nullable: false,
         };
         Ok(Self {
             meta_id: desc.id(),
@@ -683,10 +768,78 @@ impl ObjectType {
             backing_table,
             fields,
             chisel_id,
+            unique_constraints: vec![],
+            soft_delete: false,
             is_auth,
         })
     }
 
+    /// Makes the combined values of each of the `constraints` unique among objects of this type.
+    pub(crate) fn with_unique_constraints(
+        mut self,
+        constraints: Vec<Vec<String>>,
+    ) -> anyhow::Result<Self> {
+        for fields in &constraints {
+            anyhow::ensure!(
+                !fields.is_empty(),
+                "unique constraint of type {} names no fields",
+                self.name
+            );
+            for (i, name) in fields.iter().enumerate() {
+                anyhow::ensure!(
+                    self.user_fields().any(|f| &f.name == name),
+                    "unique constraint of type {} names unknown field {}",
+                    self.name,
+                    name
+                );
+                anyhow::ensure!(
+                    !fields[..i].contains(name),
+                    "unique constraint of type {} names field {} twice",
+                    self.name,
+                    name
+                );
+            }
+        }
+        self.unique_constraints = constraints;
+        Ok(self)
+    }
+
+    pub(crate) fn unique_constraints(&self) -> &[Vec<String>] {
+        &self.unique_constraints
+    }
+
+    /// Makes deleting objects of this type only mark them as deleted, if `soft_delete` is set. The
+    /// type has to declare an optional SOFT_DELETE_FIELD to hold the time of deletion.
+    pub(crate) fn with_soft_delete(mut self, soft_delete: bool) -> anyhow::Result<Self> {
+        if soft_delete {
+            let declared = self.user_fields().any(|f| {
+                f.name == SOFT_DELETE_FIELD
+                    && f.is_optional
+                    && matches!(f.type_, Type::Float | Type::Date)
+            });
+            anyhow::ensure!(
+                declared,
+                "type {} is declared with @softDelete, so it needs an optional `{}` field of type number or Date",
+                self.name,
+                SOFT_DELETE_FIELD
+            );
+        }
+        self.soft_delete = soft_delete;
+        Ok(self)
+    }
+
+    /// Whether no two objects of this type can have the same values for all of `fields`, as
+    /// required to resolve conflicts on them.
+    pub(crate) fn is_unique_key(&self, fields: &[String]) -> bool {
+        let same_fields = |constraint: &Vec<String>| {
+            constraint.len() == fields.len() && constraint.iter().all(|f| fields.contains(f))
+        };
+        match fields {
+            [name] if self.get_field(name).map_or(false, |f| f.is_unique) => true,
+            _ => self.unique_constraints.iter().any(same_fields),
+        }
+    }
+
     pub(crate) fn user_fields(&self) -> impl Iterator<Item = &Field> {
         self.fields.iter()
     }
@@ -727,6 +880,12 @@ impl ObjectType {
             AuthOrNot::IsNotAuth => false,
         }
     }
+
+    /// Whether deleting entities of this type keeps their rows, setting `SOFT_DELETE_FIELD`
+    /// instead. Types opt in with the `@softDelete` decorator.
+    pub(crate) fn soft_deletes(&self) -> bool {
+        self.soft_delete
+    }
 }
 
 impl PartialEq for ObjectType {
@@ -863,6 +1022,14 @@ pub(crate) struct Field {
     pub(crate) labels: Vec<String>,
     pub(crate) is_optional: bool,
     pub(crate) is_unique: bool,
+    /// Whether the backing table has an index on this field, to speed up filtering on it.
+    pub(crate) is_indexed: bool,
+    /// The values a string field is limited to, declared as a union of string literals.  Any value is allowed if
+    /// this is empty.
+    pub(crate) allowed_values: Vec<String>,
+    /// Whether the field is declared as `T | null`, so that null is a value it holds and returns rather than the
+    /// absence of one, which is what an optional field that isn't set reads as.
+    pub(crate) is_nullable: bool,
     // We want to keep the default the user gave us so we can
     // return it in `chisel describe`. That's the default that is
     // valid in typescriptland.
@@ -883,14 +1050,19 @@ impl Field {
         default: Option<String>,
         is_optional: bool,
         is_unique: bool,
+        is_indexed: bool,
     ) -> Self {
-        let effective_default = if let Type::Boolean = &desc.ty() {
-            default
+        let effective_default = match &desc.ty() {
+            Type::Boolean => default
                 .clone()
                 .map(|x| if x == "false" { "false" } else { "true" })
-                .map(|x| x.to_string())
-        } else {
-            default.clone()
+                .map(|x| x.to_string()),
+            // Date defaults are checked when the type is applied.
+            Type::Date => default.clone().map(|x| match crate::dates::parse_str(&x) {
+                Ok(ms) => ms.to_string(),
+                Err(_) => x,
+            }),
+            _ => default.clone(),
         };
 
         Self {
@@ -903,9 +1075,32 @@ impl Field {
             effective_default,
             is_optional,
             is_unique,
+            is_indexed,
+            allowed_values: vec![],
+            is_nullable: false,
         }
     }
 
+    pub(crate) fn with_allowed_values(mut self, allowed_values: Vec<String>) -> Self {
+        self.allowed_values = allowed_values;
+        self
+    }
+
+    pub(crate) fn with_nullable(mut self, is_nullable: bool) -> Self {
+        self.is_nullable = is_nullable;
+        self
+    }
+
+    /// Whether null may be stored in this field, either as a value or as the absence of one.
+    pub(crate) fn accepts_null(&self) -> bool {
+        self.is_optional || self.is_nullable
+    }
+
+    /// Whether `value` may be stored in this field.
+    pub(crate) fn allows(&self, value: &str) -> bool {
+        self.allowed_values.is_empty() || self.allowed_values.iter().any(|v| v == value)
+    }
+
     pub(crate) fn user_provided_default(&self) -> &Option<String> {
         &self.default
     }
@@ -914,6 +1109,12 @@ impl Field {
         &self.effective_default
     }
 
+    /// Whether an insertion that has no value for this field leaves it out rather than
+    /// storing a generated or default value.
+    pub(crate) fn can_be_omitted(&self) -> bool {
+        self.is_optional && self.default.is_none()
+    }
+
     pub(crate) fn generate_value(&self) -> Option<String> {
         match self.type_ {
             Type::Id => Some(Uuid::new_v4().to_string()),
@@ -937,6 +1138,9 @@ pub(crate) struct FieldAttrDelta {
     pub(crate) default: Option<String>,
     pub(crate) is_optional: bool,
     pub(crate) is_unique: bool,
+    pub(crate) is_indexed: bool,
+    pub(crate) allowed_values: Vec<String>,
+    pub(crate) is_nullable: bool,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -951,4 +1155,125 @@ pub(crate) struct ObjectDelta {
     pub(crate) added_fields: Vec<Field>,
     pub(crate) removed_fields: Vec<Field>,
     pub(crate) updated_fields: Vec<FieldDelta>,
+    pub(crate) removed_unique_constraints: Vec<Vec<String>>,
+    /// The new value of the type's soft delete setting, if it changed.
+    pub(crate) soft_delete: Option<bool>,
+}
+
+impl ObjectDelta {
+    /// Whether the new definition is the same as the old one, so there is nothing to evolve.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.added_fields.is_empty()
+            && self.removed_fields.is_empty()
+            && self.updated_fields.is_empty()
+            && self.removed_unique_constraints.is_empty()
+            && self.soft_delete.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field<D: FieldDescriptor>(desc: D, default: Option<&str>, is_optional: bool) -> Field {
+        Field::new(
+            desc,
+            vec![],
+            default.map(str::to_owned),
+            is_optional,
+            false,
+            false,
+        )
+    }
+
+    fn person(fields: Vec<Field>) -> Arc<ObjectType> {
+        let desc = NewObject::new("Person", "dev");
+        Arc::new(ObjectType::new(desc, fields, AuthOrNot::IsNotAuth).unwrap())
+    }
+
+    fn existing_person() -> Arc<ObjectType> {
+        person(vec![
+            field(
+                ExistingField::new("name", Type::String, 1, "dev"),
+                None,
+                false,
+            ),
+            field(ExistingField::new("age", Type::Float, 2, "dev"), None, true),
+        ])
+    }
+
+    fn new_field(name: &str, ty: Type, default: Option<&str>, is_optional: bool) -> Field {
+        field(
+            NewField::new(name, ty, "dev").unwrap(),
+            default,
+            is_optional,
+        )
+    }
+
+    #[test]
+    fn identical_redefinition() {
+        let redefined = person(vec![
+            new_field("age", Type::Float, None, true),
+            new_field("name", Type::String, None, false),
+        ]);
+        let delta = TypeSystem::generate_type_delta(&existing_person(), redefined).unwrap();
+        assert!(delta.is_empty());
+
+        let changed = person(vec![
+            new_field("name", Type::String, Some("anonymous"), false),
+            new_field("age", Type::Float, None, true),
+        ]);
+        let delta = TypeSystem::generate_type_delta(&existing_person(), changed).unwrap();
+        assert!(!delta.is_empty());
+        assert_eq!(delta.updated_fields.len(), 1);
+        assert_eq!(delta.updated_fields[0].id, 1);
+    }
+
+    #[test]
+    fn incompatible_redefinition() {
+        let unsafe_reason =
+            |fields| match TypeSystem::generate_type_delta(&existing_person(), person(fields)) {
+                Err(TypeSystemError::UnsafeReplacement(_, reason)) => reason,
+                other => panic!("expected an unsafe replacement, got {:?}", other),
+            };
+
+        let reason = unsafe_reason(vec![
+            new_field("name", Type::Float, None, false),
+            new_field("age", Type::Float, None, true),
+        ]);
+        assert!(reason.contains("changing types from string into number"));
+
+        let reason = unsafe_reason(vec![
+            new_field("name", Type::String, None, false),
+            new_field("age", Type::Float, None, false),
+        ]);
+        assert!(reason.contains("making field age required"));
+
+        // With a default, existing rows have a value to fall back on.
+        let required_age = person(vec![
+            new_field("name", Type::String, None, false),
+            new_field("age", Type::Float, Some("0"), false),
+        ]);
+        TypeSystem::generate_type_delta(&existing_person(), required_age).unwrap();
+    }
+
+    #[test]
+    fn soft_delete() {
+        let note = |ty, is_optional| {
+            let deleted_at = new_field(SOFT_DELETE_FIELD, ty, None, is_optional);
+            let desc = NewObject::new("Note", "dev");
+            ObjectType::new(desc, vec![deleted_at], AuthOrNot::IsNotAuth).unwrap()
+        };
+        assert!(note(Type::Float, true).with_soft_delete(true).is_ok());
+        assert!(note(Type::Date, true).with_soft_delete(true).is_ok());
+        assert!(note(Type::Float, false).with_soft_delete(true).is_err());
+        assert!(note(Type::String, true).with_soft_delete(true).is_err());
+
+        // Declaring the field alone doesn't turn soft deletes on.
+        let plain = note(Type::Float, true);
+        assert!(!plain.soft_deletes());
+        let soft = note(Type::Float, true).with_soft_delete(true).unwrap();
+        let delta = TypeSystem::generate_type_delta(&plain, Arc::new(soft)).unwrap();
+        assert_eq!(delta.soft_delete, Some(true));
+    }
 }
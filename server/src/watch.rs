@@ -0,0 +1,256 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Reloading of endpoints from a source directory, for development.
+//!
+//! With `--watch-endpoints`, chiseld looks at a directory every half a second and serves each JavaScript file in it
+//! as an endpoint of the `dev` version, at the file's path relative to the directory without the `.js` extension.
+//! Files that were added or changed since the last look go through the same path as endpoints loaded at startup. A
+//! file that fails to compile is reported, and whatever handler its path had before keeps serving.
+
+use crate::runtime;
+use crate::server::{add_endpoint, CoordinatorChannel};
+use anyhow::Result;
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+
+/// API version the endpoints of the watched directory are served under.
+pub(crate) const WATCH_VERSION: &str = "dev";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What a file looked like the last time it was read, to tell whether it changed since. The size is there because
+/// file systems with a coarse modification time can miss two writes in a row.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Stamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+/// Changes to the endpoints of a directory since it was last scanned.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Changes {
+    /// Route and file of the endpoints that were added or modified.
+    pub(crate) changed: Vec<(String, PathBuf)>,
+    /// Routes whose file was removed.
+    pub(crate) removed: Vec<String>,
+}
+
+pub(crate) struct EndpointWatcher {
+    dir: PathBuf,
+    stamps: HashMap<PathBuf, Stamp>,
+}
+
+impl EndpointWatcher {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            stamps: HashMap::new(),
+        }
+    }
+
+    /// Looks at the directory again. The first scan reports every endpoint as changed.
+    pub(crate) fn scan(&mut self) -> Result<Changes> {
+        let mut files = vec![];
+        find_sources(&self.dir, &mut files)?;
+        files.sort();
+
+        let mut changes = Changes::default();
+        let mut stamps = HashMap::new();
+        for file in files {
+            // The file may be gone already, in which case the next scan reports it as removed.
+            let meta = match fs::metadata(&file) {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            let stamp = Stamp {
+                modified: meta.modified()?,
+                len: meta.len(),
+            };
+            if self.stamps.get(&file) != Some(&stamp) {
+                changes.changed.push((self.route(&file), file.clone()));
+            }
+            stamps.insert(file, stamp);
+        }
+        let mut removed: Vec<&PathBuf> = self
+            .stamps
+            .keys()
+            .filter(|file| !stamps.contains_key(*file))
+            .collect();
+        removed.sort();
+        changes.removed = removed.into_iter().map(|file| self.route(file)).collect();
+
+        self.stamps = stamps;
+        Ok(changes)
+    }
+
+    /// Forgets what `file` looked like, so that the next scan reports it as changed even if it isn't.
+    fn forget(&mut self, file: &Path) {
+        self.stamps.remove(file);
+    }
+
+    fn route(&self, file: &Path) -> String {
+        let relative = file.strip_prefix(&self.dir).unwrap().with_extension("");
+        let segments: Vec<_> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect();
+        format!("/{}/{}", WATCH_VERSION, segments.join("/"))
+    }
+}
+
+fn find_sources(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_sources(&path, files)?;
+        } else if path.extension().map_or(false, |ext| ext == "js") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Loads the endpoint in every executor. Compilation fails the same way in all of them, so an error leaves every
+/// one of them with the previous handler.
+async fn reload(commands: &[CoordinatorChannel], route: &str, code: String) -> Result<()> {
+    for cmd in commands {
+        let route = route.to_owned();
+        let code = code.clone();
+        let payload = send_command!({
+            let api = runtime::get().api.clone();
+            add_endpoint(route, code, &api).await
+        });
+        cmd.send(payload).await?;
+    }
+    Ok(())
+}
+
+async fn remove(commands: &[CoordinatorChannel], route: &str) -> Result<()> {
+    for cmd in commands {
+        let route = PathBuf::from(route);
+        let payload = send_command!({
+            runtime::get().api.remove_routes(&route);
+            Ok(())
+        });
+        cmd.send(payload).await?;
+    }
+    Ok(())
+}
+
+/// Watches `dir` until `shutdown` fires, applying its changes to the endpoints of every executor.
+pub(crate) fn spawn(
+    dir: PathBuf,
+    commands: Vec<CoordinatorChannel>,
+    shutdown: async_channel::Receiver<()>,
+) {
+    tokio::task::spawn(async move {
+        let mut watcher = EndpointWatcher::new(dir);
+        let mut last_try_was_failure = false;
+        loop {
+            match watcher.scan() {
+                Ok(changes) => {
+                    last_try_was_failure = false;
+                    for (route, file) in changes.changed {
+                        let code = match fs::read_to_string(&file) {
+                            Ok(code) => code,
+                            Err(e) => {
+                                warn!("Could not read {}: {:?}", file.display(), e);
+                                watcher.forget(&file);
+                                continue;
+                            }
+                        };
+                        match reload(&commands, &route, code).await {
+                            Ok(()) => info!("Reloaded endpoint {}", route),
+                            Err(e) => error!("Could not reload endpoint {}: {:?}", route, e),
+                        }
+                    }
+                    for route in changes.removed {
+                        match remove(&commands, &route).await {
+                            Ok(()) => info!("Removed endpoint {}", route),
+                            Err(e) => error!("Could not remove endpoint {}: {:?}", route, e),
+                        }
+                    }
+                }
+                Err(e) => {
+                    if !last_try_was_failure {
+                        warn!("Could not watch endpoints: {:?}", e);
+                    }
+                    last_try_was_failure = true;
+                }
+            }
+            futures::select! {
+                _ = sleep(POLL_INTERVAL).fuse() => {},
+                _ = shutdown.recv().fuse() => {
+                    break;
+                }
+            };
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn changed(routes: &[&str], dir: &Path) -> Vec<(String, PathBuf)> {
+        routes
+            .iter()
+            .map(|r| {
+                let file = dir.join(format!("{}.js", r.strip_prefix("/dev/").unwrap()));
+                (r.to_string(), file)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn first_scan_reports_every_endpoint() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("hello.js"), "a").unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        fs::write(dir.path().join("nested/deep.js"), "b").unwrap();
+        fs::write(dir.path().join("notes.txt"), "not an endpoint").unwrap();
+
+        let mut watcher = EndpointWatcher::new(dir.path().to_owned());
+        let changes = watcher.scan().unwrap();
+        assert_eq!(
+            changes.changed,
+            changed(&["/dev/hello", "/dev/nested/deep"], dir.path())
+        );
+        assert!(changes.removed.is_empty());
+        assert_eq!(watcher.scan().unwrap(), Changes::default());
+    }
+
+    #[test]
+    fn modified_and_removed_files() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("a.js"), "a").unwrap();
+        fs::write(dir.path().join("b.js"), "b").unwrap();
+        let mut watcher = EndpointWatcher::new(dir.path().to_owned());
+        watcher.scan().unwrap();
+
+        fs::write(dir.path().join("a.js"), "a, modified").unwrap();
+        fs::remove_file(dir.path().join("b.js")).unwrap();
+        fs::write(dir.path().join("c.js"), "c").unwrap();
+        let changes = watcher.scan().unwrap();
+        assert_eq!(changes.changed, changed(&["/dev/a", "/dev/c"], dir.path()));
+        assert_eq!(changes.removed, vec!["/dev/b".to_string()]);
+    }
+
+    #[test]
+    fn forgotten_files_are_reported_again() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("a.js"), "a").unwrap();
+        let mut watcher = EndpointWatcher::new(dir.path().to_owned());
+        watcher.scan().unwrap();
+
+        watcher.forget(&dir.path().join("a.js"));
+        let changes = watcher.scan().unwrap();
+        assert_eq!(changes.changed, changed(&["/dev/a"], dir.path()));
+    }
+}
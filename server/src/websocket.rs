@@ -0,0 +1,180 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! WebSocket connections that endpoints accept.
+//!
+//! An endpoint accepts a connection by answering the upgrade request with `101 Switching Protocols`.  Hyper
+//! only hands the connection over once that response is sent, so a `WebSocketResource` starts out holding the
+//! pending upgrade, and can send and receive messages once `accept()` has waited for it.
+
+use anyhow::{anyhow, Result};
+use deno_core::{AsyncRefCell, CancelFuture, CancelHandle, RcRef, Resource, ZeroCopyBuf};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use hyper::header::{SEC_WEBSOCKET_KEY, UPGRADE};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper::{Method, Request};
+use serde_derive::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::rc::Rc;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+pub(crate) use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+type Socket = WebSocketStream<Upgraded>;
+
+/// Close code for a connection that ended without a close frame.
+const ABNORMAL_CLOSURE: u16 = 1006;
+
+/// The switch to WebSocket that a request asks for, which its endpoint may accept.
+pub(crate) struct Upgrade {
+    on_upgrade: OnUpgrade,
+    /// Value of the `Sec-WebSocket-Accept` header of the response that accepts the upgrade.
+    accept_key: String,
+}
+
+impl Upgrade {
+    /// Takes the upgrade out of `req`, if it is a WebSocket handshake.
+    pub(crate) fn from_request<B>(req: &mut Request<B>) -> Option<Self> {
+        let headers = req.headers();
+        let is_websocket = headers
+            .get(UPGRADE)
+            .and_then(|v| v.to_str().ok())
+            .map_or(false, |v| v.eq_ignore_ascii_case("websocket"));
+        if req.method() != Method::GET || !is_websocket {
+            return None;
+        }
+        let accept_key = derive_accept_key(headers.get(SEC_WEBSOCKET_KEY)?.as_bytes());
+        Some(Self {
+            on_upgrade: hyper::upgrade::on(req),
+            accept_key,
+        })
+    }
+
+    /// Accepts the upgrade.  Returns the connection, and the `Sec-WebSocket-Accept` header to
+    /// respond with.
+    pub(crate) fn accept(self) -> (WebSocketResource, String) {
+        let resource = WebSocketResource {
+            upgrade: RefCell::new(Some(self.on_upgrade)),
+            sink: AsyncRefCell::new(None),
+            stream: AsyncRefCell::new(None),
+            cancel: Default::default(),
+        };
+        (resource, self.accept_key)
+    }
+}
+
+/// Something that happened on a WebSocket connection.
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub(crate) enum WebSocketEvent {
+    Text {
+        data: String,
+    },
+    Binary {
+        data: ZeroCopyBuf,
+    },
+    /// The connection is closed, and no more events follow.
+    Close {
+        code: u16,
+        reason: String,
+    },
+}
+
+pub(crate) struct WebSocketResource {
+    upgrade: RefCell<Option<OnUpgrade>>,
+    sink: AsyncRefCell<Option<SplitSink<Socket, Message>>>,
+    stream: AsyncRefCell<Option<SplitStream<Socket>>>,
+    /// Cancelled when either side closes the connection, which stops all pending operations.
+    cancel: CancelHandle,
+}
+
+impl Resource for WebSocketResource {
+    // Unlike other resources, a WebSocket outlives the request that opened it.  The worker tells
+    // them apart by this name.
+    fn name(&self) -> Cow<str> {
+        "webSocket".into()
+    }
+
+    fn close(self: Rc<Self>) {
+        self.cancel.cancel();
+    }
+}
+
+impl WebSocketResource {
+    /// Waits for the connection to be handed over, which happens once the response accepting it
+    /// is sent.
+    pub(crate) async fn accept(self: &Rc<Self>) -> Result<()> {
+        let upgrade = self
+            .upgrade
+            .borrow_mut()
+            .take()
+            .ok_or_else(|| anyhow!("WebSocket is already accepted"))?;
+        let cancel = RcRef::map(self, |r| &r.cancel);
+        let upgraded = upgrade.or_cancel(cancel).await??;
+        let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+        let (sink, stream) = socket.split();
+        *RcRef::map(self, |r| &r.sink).borrow_mut().await = Some(sink);
+        *RcRef::map(self, |r| &r.stream).borrow_mut().await = Some(stream);
+        Ok(())
+    }
+
+    /// Waits for the next message.  Pings are answered without showing up here.
+    pub(crate) async fn next(self: &Rc<Self>) -> Result<WebSocketEvent> {
+        let mut stream = RcRef::map(self, |r| &r.stream).borrow_mut().await;
+        let stream = stream
+            .as_mut()
+            .ok_or_else(|| anyhow!("WebSocket is not connected"))?;
+        loop {
+            let cancel = RcRef::map(self, |r| &r.cancel);
+            let event = match stream.next().or_cancel(cancel).await {
+                Ok(Some(Ok(Message::Text(data)))) => WebSocketEvent::Text { data },
+                Ok(Some(Ok(Message::Binary(data)))) => WebSocketEvent::Binary { data: data.into() },
+                Ok(Some(Ok(Message::Close(frame)))) => {
+                    // Reading on sends the answer to a close frame from the peer, and then ends
+                    // right away.
+                    let cancel = RcRef::map(self, |r| &r.cancel);
+                    let _ = stream.next().or_cancel(cancel).await;
+                    let (code, reason) = match frame {
+                        Some(frame) => (frame.code.into(), frame.reason.into_owned()),
+                        None => (ABNORMAL_CLOSURE, String::new()),
+                    };
+                    WebSocketEvent::Close { code, reason }
+                }
+                Ok(Some(Ok(_))) => continue,
+                // The peer went away, or we closed the resource.
+                Ok(Some(Err(_)) | None) | Err(_) => WebSocketEvent::Close {
+                    code: ABNORMAL_CLOSURE,
+                    reason: String::new(),
+                },
+            };
+            if let WebSocketEvent::Close { .. } = event {
+                self.cancel.cancel();
+            }
+            return Ok(event);
+        }
+    }
+
+    /// Sends `message`, failing if the connection is closed before it is sent.
+    pub(crate) async fn send(self: &Rc<Self>, message: Message) -> Result<()> {
+        let mut sink = RcRef::map(self, |r| &r.sink).borrow_mut().await;
+        let sink = sink
+            .as_mut()
+            .ok_or_else(|| anyhow!("WebSocket is not connected"))?;
+        let cancel = RcRef::map(self, |r| &r.cancel);
+        sink.send(message).or_cancel(cancel).await??;
+        Ok(())
+    }
+
+    /// Starts the closing handshake.  The connection is closed once the peer answers, which
+    /// `next()` reports.
+    pub(crate) async fn close(self: &Rc<Self>, code: u16, reason: String) -> Result<()> {
+        let frame = CloseFrame {
+            code: CloseCode::from(code),
+            reason: reason.into(),
+        };
+        self.send(Message::Close(Some(frame))).await
+    }
+}